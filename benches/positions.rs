@@ -0,0 +1,54 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use difftastic::lines::NewlinePositions;
+use difftastic::parse::{find_lang, parse, parse_pair, ConfigDir};
+use typed_arena::Arena;
+
+/// A JSON array with one number per line, so there are as many lines
+/// as tokens.
+fn many_lines(num_lines: usize) -> String {
+    let mut src = String::from("[\n");
+    for i in 0..num_lines {
+        src.push_str(&format!("  {},\n", i));
+    }
+    src.push_str("  0\n]\n");
+    src
+}
+
+fn from_offsets(c: &mut Criterion) {
+    let src = many_lines(200_000);
+    let nl_pos = NewlinePositions::from(src.as_str());
+    let offsets: Vec<_> = src.match_indices(',').map(|(i, _)| i).collect();
+
+    c.bench_function("from_offsets every token", |b| {
+        b.iter(|| {
+            for offset in &offsets {
+                black_box(nl_pos.from_offsets(*offset, offset + 1));
+            }
+        })
+    });
+}
+
+fn parse_json(c: &mut Criterion) {
+    let src = many_lines(20_000);
+    let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+
+    let mut group = c.benchmark_group("parse");
+    group.sample_size(10);
+    group.bench_function("json with 40,000 tokens", |b| {
+        b.iter(|| {
+            let arena = Arena::new();
+            black_box(parse(&arena, &src, lang).len());
+        })
+    });
+    group.bench_function("json with 40,000 tokens on both sides", |b| {
+        b.iter(|| {
+            let arena = Arena::new();
+            let (lhs, rhs) = parse_pair(&arena, &src, &src, lang);
+            black_box(lhs.len() + rhs.len());
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, from_offsets, parse_json);
+criterion_main!(benches);
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use crate::parse::{guess_language, lang_by_name, LanguageRegistry};
+use crate::{diff_strings, DiffResult};
+
+/// A request read by `difftastic batch`, one JSON object per line.
+/// Each side is either a path or inline contents.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BatchRequest {
+    /// Returned unchanged in the response, so clients can match
+    /// responses to requests.
+    id: Value,
+    lhs_path: Option<String>,
+    rhs_path: Option<String>,
+    lhs: Option<String>,
+    rhs: Option<String>,
+    /// A language name, alias or file extension, such as `rust` or
+    /// `rs`. If omitted, the language is guessed from `rhs_path` or
+    /// `lhs_path`.
+    language: Option<String>,
+}
+
+/// The response to a `BatchRequest`, written as one line of JSON.
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<DiffResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn side_contents(contents: Option<String>, path: &Option<String>) -> Result<String, String> {
+    match (contents, path) {
+        (Some(contents), _) => Ok(contents),
+        (None, Some(path)) => match fs::read(path) {
+            Ok(bytes) => Ok(String::from_utf8_lossy(&bytes).to_string()),
+            Err(e) => Err(format!("Could not read {}: {}", path, e)),
+        },
+        (None, None) => Err("Expected contents or a path for both sides".to_string()),
+    }
+}
+
+fn diff_request(request: BatchRequest, registry: &LanguageRegistry) -> Result<DiffResult, String> {
+    let lang = match &request.language {
+        Some(name) => lang_by_name(registry, name)
+            .ok_or_else(|| format!("No language found for {:?}", name))?,
+        None => request
+            .rhs_path
+            .iter()
+            .chain(request.lhs_path.iter())
+            .find_map(|path| guess_language(registry, path))
+            .ok_or_else(|| "Could not guess the language, set `language`".to_string())?,
+    };
+    let lhs = side_contents(request.lhs, &request.lhs_path)?;
+    let rhs = side_contents(request.rhs, &request.rhs_path)?;
+    Ok(diff_strings(&lhs, &rhs, lang))
+}
+
+/// Handle one line of input, returning one line of JSON output.
+fn handle_line(line: &str, registry: &LanguageRegistry) -> String {
+    let response = match serde_json::from_str::<BatchRequest>(line) {
+        Ok(request) => {
+            let id = request.id.clone();
+            match diff_request(request, registry) {
+                Ok(result) => BatchResponse {
+                    id,
+                    result: Some(result),
+                    error: None,
+                },
+                Err(e) => BatchResponse {
+                    id,
+                    result: None,
+                    error: Some(e),
+                },
+            }
+        }
+        Err(e) => BatchResponse {
+            id: Value::Null,
+            result: None,
+            error: Some(format!("Invalid request: {}", e)),
+        },
+    };
+    serde_json::to_string(&response).unwrap()
+}
+
+/// Read requests from `input` until it's closed, writing a response
+/// for each. Blank lines are ignored.
+pub fn run_batch(
+    registry: &LanguageRegistry,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(output, "{}", handle_line(&line, registry))?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "lang-json"))]
+mod tests {
+    use super::*;
+    use crate::parse::ConfigDir;
+
+    #[test]
+    fn test_run_batch() {
+        let input = concat!(
+            r#"{"id": 1, "lhs": "[1, 2]", "rhs": "[1, 3]", "language": "json"}"#,
+            "\n\n",
+            r#"{"id": "b", "lhs": "[]", "rhs": "[]", "language": "nope"}"#,
+            "\n",
+            "not json\n",
+        );
+        let mut output = vec![];
+        run_batch(
+            ConfigDir::read_default_toml(),
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        let responses: Vec<Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"]["lhs_changes"][0]["kind"], "novel");
+        assert_eq!(responses[1]["id"], "b");
+        assert!(responses[1]["error"].is_string());
+        assert_eq!(responses[2]["id"], Value::Null);
+        assert!(responses[2]["error"].is_string());
+    }
+}
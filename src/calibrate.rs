@@ -0,0 +1,198 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use typed_arena::Arena;
+
+use crate::dijkstra::{
+    estimated_graph_size, mark_syntax, EdgeCosts, SearchConfig, MAX_ESTIMATED_GRAPH_SIZE,
+};
+use crate::lines::{join_overlapping, visible_groups, MaxLine, CONTEXT_LINES};
+use crate::parse::{parse_pair, Language};
+use crate::syntax::{change_positions, init_info, mark_moved, slide_novel_runs};
+
+/// A before and after file to calibrate with.
+pub struct CalibrationPair<'a> {
+    pub lhs_src: String,
+    pub rhs_src: String,
+    pub lang: &'a Language,
+}
+
+/// The costs found by `calibrate`, and how many hunks they produce.
+#[derive(Debug)]
+pub struct Calibration {
+    pub costs: EdgeCosts,
+    pub initial_hunks: usize,
+    pub hunks: usize,
+}
+
+/// Find the before/after pairs in `dir`. A file whose name contains
+/// `before`, such as `foo_before.rs`, is paired with the file of the
+/// same name with `after` instead, if it exists.
+pub fn find_pairs(dir: &Path) -> std::io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut pairs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let lhs_path = entry?.path();
+        let file_name = match lhs_path.file_name() {
+            Some(file_name) => file_name.to_string_lossy(),
+            None => continue,
+        };
+        if let Some(i) = file_name.rfind("before") {
+            let rhs_name = format!(
+                "{}after{}",
+                &file_name[..i],
+                &file_name[i + "before".len()..]
+            );
+            let rhs_path = lhs_path.with_file_name(rhs_name);
+            if rhs_path.is_file() {
+                pairs.push((lhs_path, rhs_path));
+            }
+        }
+    }
+    pairs.sort();
+    Ok(pairs)
+}
+
+/// The number of hunks shown when diffing `lhs_src` and `rhs_src`
+/// with `config`. Returns None if the files are too different to diff
+/// syntactically, as costs don't affect line diffs.
+pub fn count_hunks(
+    lhs_src: &str,
+    rhs_src: &str,
+    lang: &Language,
+    config: &SearchConfig,
+) -> Option<usize> {
+    let arena = Arena::new();
+    let (lhs, rhs) = parse_pair(&arena, lhs_src, rhs_src, lang);
+    if estimated_graph_size(&lhs, &rhs) > MAX_ESTIMATED_GRAPH_SIZE {
+        return None;
+    }
+
+    init_info(&lhs);
+    init_info(&rhs);
+    mark_syntax(lhs.first().copied(), rhs.first().copied(), config);
+    slide_novel_runs(&lhs);
+    slide_novel_runs(&rhs);
+    mark_moved(&lhs, &rhs);
+
+    let lhs_positions = change_positions(lhs_src, rhs_src, &lhs);
+    let rhs_positions = change_positions(rhs_src, lhs_src, &rhs);
+    let mut groups = visible_groups(&lhs_positions, &rhs_positions);
+    for group in &mut groups {
+        group.pad(CONTEXT_LINES, lhs_src.max_line(), rhs_src.max_line());
+    }
+    Some(join_overlapping(groups).len())
+}
+
+fn total_hunks(pairs: &[CalibrationPair], costs: EdgeCosts) -> usize {
+    let config = SearchConfig {
+        costs,
+        ..SearchConfig::default()
+    };
+    pairs
+        .iter()
+        .filter_map(|pair| count_hunks(&pair.lhs_src, &pair.rhs_src, pair.lang, &config))
+        .sum()
+}
+
+/// Values to try instead of `value` when calibrating.
+fn candidate_values(value: u64) -> Vec<u64> {
+    let mut candidates = vec![
+        value / 2,
+        value * 3 / 4,
+        value.saturating_sub(1),
+        value + 1,
+        value * 5 / 4,
+        value * 3 / 2,
+        value * 2,
+    ];
+    candidates.sort_unstable();
+    candidates.dedup();
+    candidates.retain(|c| *c != value);
+    candidates
+}
+
+/// The most times we try adjusting every cost.
+const MAX_CALIBRATION_ROUNDS: usize = 3;
+
+/// Search for edge costs that minimise the total number of hunks in
+/// `pairs`, starting from `initial`. Each cost is adjusted in turn,
+/// and an adjustment is kept if it reduces the number of hunks.
+pub fn calibrate(pairs: &[CalibrationPair], initial: EdgeCosts) -> Calibration {
+    let fields: [fn(&mut EdgeCosts) -> &mut u64; 5] = [
+        |c| &mut c.max_depth_difference,
+        |c| &mut c.unchanged_delimiter,
+        |c| &mut c.replaced_comment,
+        |c| &mut c.novel,
+        |c| &mut c.non_contiguous,
+    ];
+
+    let initial_hunks = total_hunks(pairs, initial);
+    let mut best = initial;
+    let mut best_hunks = initial_hunks;
+    for _ in 0..MAX_CALIBRATION_ROUNDS {
+        let mut improved = false;
+        for field in &fields {
+            let value = *field(&mut best);
+            for candidate in candidate_values(value) {
+                let mut costs = best;
+                *field(&mut costs) = candidate;
+                let hunks = total_hunks(pairs, costs);
+                if hunks < best_hunks {
+                    best = costs;
+                    best_hunks = hunks;
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    Calibration {
+        costs: best,
+        initial_hunks,
+        hunks: best_hunks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "lang-json")]
+    use crate::parse::{find_lang, ConfigDir};
+
+    #[test]
+    fn test_candidate_values() {
+        assert_eq!(candidate_values(1), vec![0, 2]);
+        assert_eq!(candidate_values(40), vec![20, 30, 39, 41, 50, 60, 80]);
+    }
+
+    #[test]
+    fn test_find_pairs() {
+        let pairs = find_pairs(Path::new("sample_files")).unwrap();
+        let names: Vec<_> = pairs
+            .iter()
+            .map(|(lhs, _)| lhs.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert!(names.contains(&"before.js".to_string()));
+        assert!(names.contains(&"css_before.css".to_string()));
+        assert!(pairs
+            .iter()
+            .all(|(_, rhs)| rhs.to_string_lossy().contains("after")));
+    }
+
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_calibrate_never_adds_hunks() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let pairs = vec![CalibrationPair {
+            lhs_src: "[1,\n2,\n3,\n4,\n5,\n6,\n7,\n8,\n9]\n".into(),
+            rhs_src: "[1,\n2,\n3,\n4,\n5,\n6,\n7,\n8,\n10]\n".into(),
+            lang,
+        }];
+
+        let calibration = calibrate(&pairs, EdgeCosts::default());
+        assert_eq!(calibration.initial_hunks, 1);
+        assert!(calibration.hunks <= calibration.initial_hunks);
+    }
+}
@@ -0,0 +1,97 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::dijkstra::EdgeCosts;
+use crate::parse::read_or_die;
+use crate::style::Theme;
+
+/// Options read from a config file with `--config`. Every section is
+/// optional.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Regexes for volatile content, such as timestamps or UUIDs in
+    /// generated files. The matching parts of atoms are ignored when
+    /// comparing them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignore_patterns: Vec<String>,
+    /// Edge costs for the graph search, such as those found by
+    /// `difftastic calibrate`.
+    pub costs: EdgeCosts,
+    /// How changes are highlighted.
+    pub theme: Theme,
+}
+
+impl Config {
+    /// The compiled `ignore_patterns`. Invalid patterns are rejected
+    /// when reading the config file.
+    pub fn ignore_regexes(&self) -> Vec<Regex> {
+        self.ignore_patterns
+            .iter()
+            .filter_map(|pattern| Regex::new(pattern).ok())
+            .collect()
+    }
+}
+
+fn parse_config(src: &str) -> Result<Config, String> {
+    let config: Config = toml::from_str(src).map_err(|e| e.to_string())?;
+    for pattern in &config.ignore_patterns {
+        if let Err(e) = Regex::new(pattern) {
+            return Err(format!("invalid ignore pattern `{}`: {}", pattern, e));
+        }
+    }
+    Ok(config)
+}
+
+/// Read the config file at `path`, or exit if it's invalid.
+pub fn read_config(path: &str) -> Config {
+    let bytes = read_or_die(path);
+    let src = String::from_utf8_lossy(&bytes);
+    match parse_config(&src) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not parse config file {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::style::Emphasis;
+
+    #[test]
+    fn test_parse_config_costs() {
+        let config = parse_config("[costs]\nnovel = 300\n").unwrap();
+        assert_eq!(config.costs.novel, 300);
+        assert_eq!(
+            config.costs.replaced_comment,
+            EdgeCosts::default().replaced_comment
+        );
+
+        assert_eq!(parse_config("").unwrap().costs, EdgeCosts::default());
+        assert!(parse_config("[costs]\nnovel = \"x\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_config_theme() {
+        let config = parse_config("[theme]\nmoved = \"underline\"\n").unwrap();
+        assert_eq!(config.theme.moved, Emphasis::Underline);
+        assert_eq!(config.theme.novel, Theme::default().novel);
+        assert!(config.theme.syntax_highlight);
+
+        let config = parse_config("[theme]\nsyntax_highlight = false\n").unwrap();
+        assert!(!config.theme.syntax_highlight);
+
+        assert!(parse_config("[theme]\nnovel = \"blink\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_config_ignore_patterns() {
+        let config = parse_config("ignore_patterns = ['[0-9]{4}-[0-9]{2}-[0-9]{2}']\n").unwrap();
+        assert_eq!(config.ignore_regexes().len(), 1);
+
+        assert!(parse_config("ignore_patterns = ['(']\n").is_err());
+    }
+}
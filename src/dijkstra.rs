@@ -1,10 +1,13 @@
-use std::cmp::{min, Ordering, Reverse};
-use std::collections::BinaryHeap;
-use std::hash::{Hash, Hasher};
+use std::cmp::{max, min, Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap};
+use std::panic;
+use std::thread;
+use std::time::Instant;
 
 use crate::lines::LineNumber;
 use crate::syntax::{ChangeKind, Syntax};
 use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
 use strsim::normalized_levenshtein;
 use Edge::*;
 
@@ -15,38 +18,40 @@ struct Vertex<'a> {
     rhs_syntax: Option<&'a Syntax<'a>>,
     rhs_prev_novel: Option<LineNumber>,
 }
-impl<'a> PartialEq for Vertex<'a> {
-    fn eq(&self, other: &Self) -> bool {
-        self.lhs_syntax.map(|node| node.id()) == other.lhs_syntax.map(|node| node.id())
-            && self.rhs_syntax.map(|node| node.id()) == other.rhs_syntax.map(|node| node.id())
-    }
-}
-impl<'a> Eq for Vertex<'a> {}
 
-impl<'a> Hash for Vertex<'a> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.lhs_syntax.map(|node| node.id()).hash(state);
-        self.rhs_syntax.map(|node| node.id()).hash(state);
-    }
-}
+/// The position of a vertex in the graph. Two vertices with the same
+/// key are the same vertex, regardless of their novelty state.
+type VertexKey = (Option<u64>, Option<u64>);
+
+/// An index into the vertices that have been visited by a search.
+type VertexId = usize;
 
 impl<'a> Vertex<'a> {
     fn is_end(&self) -> bool {
         self.lhs_syntax.is_none() && self.rhs_syntax.is_none()
     }
+
+    fn key(&self) -> VertexKey {
+        (
+            self.lhs_syntax.map(|node| node.id()),
+            self.rhs_syntax.map(|node| node.id()),
+        )
+    }
 }
 
 // Rust requires that PartialEq, PartialOrd and Ord agree.
 // https://doc.rust-lang.org/std/cmp/trait.Ord.html
 //
-// For `Vertex`, we want to compare by distance in a priority queue, but
-// equality should only consider LHS/RHS node when deciding if we've
-// visited a vertex. We define separate wrappers for these two use
-// cases.
+// We want to compare by distance in a priority queue, so define a
+// wrapper whose ordering only considers distance. Vertices with equal
+// distance are ordered by when they were pushed, earliest first, so
+// ties are always broken the same way and the diff is reproducible.
 #[derive(Debug)]
 struct OrdVertex<'a> {
     distance: u64,
-    prev: Option<(Vertex<'a>, Edge)>,
+    /// The number of vertices pushed before this one.
+    push_order: u64,
+    prev: Option<(VertexId, Edge)>,
     v: Vertex<'a>,
 }
 
@@ -58,13 +63,15 @@ impl<'a> PartialOrd for OrdVertex<'a> {
 
 impl<'a> Ord for OrdVertex<'a> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.distance.cmp(&other.distance)
+        self.distance
+            .cmp(&other.distance)
+            .then(self.push_order.cmp(&other.push_order))
     }
 }
 
 impl<'a> PartialEq for OrdVertex<'a> {
     fn eq(&self, other: &Self) -> bool {
-        self.distance == other.distance
+        self.distance == other.distance && self.push_order == other.push_order
     }
 }
 impl<'a> Eq for OrdVertex<'a> {}
@@ -82,111 +89,1084 @@ enum Edge {
     NovelTreeRHS { num_descendants: u64 },
 }
 
+/// The costs of edges in the graph search. Lower costs are preferred,
+/// so the defaults favour matching nodes over marking them as novel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EdgeCosts {
+    /// The most that a difference in nesting depth adds to the cost of
+    /// matching two nodes.
+    pub max_depth_difference: u64,
+    /// Matching an outer delimiter, when the children differ.
+    pub unchanged_delimiter: u64,
+    /// Treating a comment as a replacement of another comment.
+    pub replaced_comment: u64,
+    /// Marking a node as novel.
+    pub novel: u64,
+    /// The extra cost of a novel node that isn't adjacent to the
+    /// previous novel node.
+    pub non_contiguous: u64,
+}
+
+impl Default for EdgeCosts {
+    fn default() -> Self {
+        Self {
+            max_depth_difference: 40,
+            unchanged_delimiter: 100,
+            replaced_comment: 150,
+            novel: 200,
+            non_contiguous: 1,
+        }
+    }
+}
+
 impl Edge {
-    fn cost(&self) -> u64 {
+    fn cost(&self, costs: &EdgeCosts) -> u64 {
+        let novel_cost = |contiguous: bool| {
+            if contiguous {
+                costs.novel
+            } else {
+                costs.novel + costs.non_contiguous
+            }
+        };
+
         match self {
             // Matching nodes is always best.
-            UnchangedNode(depth_difference) => min(40, *depth_difference),
+            UnchangedNode(depth_difference) => min(costs.max_depth_difference, *depth_difference),
             // Matching an outer delimiter is good.
-            UnchangedDelimiter(depth_difference) => 100 + min(40, *depth_difference),
+            UnchangedDelimiter(depth_difference) => {
+                costs.unchanged_delimiter + min(costs.max_depth_difference, *depth_difference)
+            }
 
             // Replacing a comment is better than treating it as novel.
-            ReplacedComment => 150,
+            ReplacedComment => costs.replaced_comment,
 
             // Otherwise, we've added/removed a node.
-            NovelAtomLHS { contiguous } | NovelAtomRHS { contiguous } => {
-                if *contiguous {
-                    200
-                } else {
-                    201
-                }
-            }
-            NovelDelimiterLHS { contiguous } | NovelDelimiterRHS { contiguous } => {
-                if *contiguous {
-                    200
-                } else {
-                    201
-                }
-            }
+            NovelAtomLHS { contiguous }
+            | NovelAtomRHS { contiguous }
+            | NovelDelimiterLHS { contiguous }
+            | NovelDelimiterRHS { contiguous } => novel_cost(*contiguous),
 
             // For large trees, it's better to mark the whole tree as
             // novel rather than marking 90% of the children as
             // novel. This stops us matching up completely unrelated trees.
             NovelTreeLHS { num_descendants } | NovelTreeRHS { num_descendants } => {
-                200 + (*num_descendants - 10) * NovelDelimiterLHS { contiguous: false }.cost()
+                costs.novel + (*num_descendants - 10) * novel_cost(false)
             }
         }
     }
 }
 
-fn shortest_path(start: Vertex) -> Vec<(Edge, Vertex)> {
+/// Options that control the graph search.
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    /// When comparing comments for similarity, only consider this
+    /// many characters from each. Levenshtein distance is O(n*m), so
+    /// large comments (e.g. license headers) would otherwise dominate
+    /// the runtime.
+    pub max_comment_similarity_len: usize,
+    /// Pairs of LHS and RHS lines that should be aligned, from
+    /// `--anchor`. Matching nodes in a way that contradicts an anchor
+    /// is heavily penalised.
+    pub anchors: Vec<(LineNumber, LineNumber)>,
+    pub costs: EdgeCosts,
+    /// Give up searching after this time, from `--timeout-ms`.
+    pub deadline: Option<Instant>,
+    /// Search from both the start and the end, from
+    /// `--bidirectional`. See `shortest_path_bidirectional`.
+    pub bidirectional: bool,
+    /// Use a beam search that keeps this many vertices at each step,
+    /// from `--fast`. See `shortest_path_beam`.
+    pub beam_width: Option<usize>,
+    /// Expand vertices on this many threads, from `--threads`. See
+    /// `shortest_path_parallel`.
+    pub threads: usize,
+}
+
+pub const DEFAULT_MAX_COMMENT_SIMILARITY_LEN: usize = 2000;
+
+/// The beam width for `--fast` when `--beam-width` isn't given.
+pub const DEFAULT_BEAM_WIDTH: usize = 100;
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            max_comment_similarity_len: DEFAULT_MAX_COMMENT_SIMILARITY_LEN,
+            anchors: vec![],
+            costs: EdgeCosts::default(),
+            deadline: None,
+            bidirectional: false,
+            beam_width: None,
+            threads: 1,
+        }
+    }
+}
+
+/// Counters describing the graph search, reported with `--stats` and
+/// `--memory-stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SearchStats {
+    /// The number of vertices in the predecessor map when the search
+    /// finished. This is also the number of vertices expanded.
+    pub predecessors: usize,
+    /// The largest number of vertices in the priority queue at any
+    /// point during the search.
+    pub max_queue_len: usize,
+    /// The number of edges found from expanded vertices, including
+    /// edges to vertices that had already been visited.
+    pub edges_generated: usize,
+    /// The number of vertices pushed onto the priority queue.
+    pub heap_pushes: usize,
+    /// The number of edges in the shortest path.
+    pub route_length: usize,
+    /// True if the search reached the deadline before finding a
+    /// route. No nodes are marked in this case.
+    pub timed_out: bool,
+}
+
+impl SearchStats {
+    /// Approximate bytes used by the predecessor map. This ignores
+    /// hash map overhead, so it's a lower bound.
+    pub fn predecessors_bytes(&self) -> usize {
+        self.predecessors
+            * (std::mem::size_of::<(Vertex, Option<(VertexId, Edge)>)>()
+                + std::mem::size_of::<(VertexKey, VertexId)>())
+    }
+
+    /// Approximate bytes used by the priority queue at its
+    /// largest. This ignores spare capacity, so it's a lower bound.
+    pub fn max_queue_bytes(&self) -> usize {
+        self.max_queue_len * std::mem::size_of::<OrdVertex>()
+    }
+}
+
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+fn shortest_path<'a>(
+    start: Vertex<'a>,
+    config: &SearchConfig,
+) -> (Vec<(Edge, Vertex<'a>)>, SearchStats) {
     // We want to visit nodes with the shortest distance first, but
     // BinaryHeap is a max-heap. Ensure nodes are wrapped with Reverse
     // to flip comparisons.
     let mut heap: BinaryHeap<Reverse<_>> = BinaryHeap::new();
 
+    let mut push_order = 0;
+    let mut edges_generated = 0;
     heap.push(Reverse(OrdVertex {
         distance: 0,
+        push_order,
         prev: None,
-        v: start.clone(),
+        v: start,
     }));
 
+    // Every vertex we've visited, along with the vertex and edge we
+    // reached it from. Predecessors are referenced by their index, so
+    // we never need to copy vertices during the search.
+    //
     // TODO: this grows very big. Consider using IDA* to reduce memory
     // usage.
-    let mut predecessors: FxHashMap<Vertex, Option<(Vertex, Edge)>> = FxHashMap::default();
+    let mut visited: Vec<(Vertex, Option<(VertexId, Edge)>)> = vec![];
+    let mut visited_ids: FxHashMap<VertexKey, VertexId> = FxHashMap::default();
+    let mut max_queue_len = heap.len();
+    let mut comment_similarity = CommentSimilarity::new(config.max_comment_similarity_len);
 
-    let end;
+    let mut end_id = None;
     loop {
+        // Checking the time is relatively slow, so only do it
+        // occasionally.
+        if let Some(deadline) = config.deadline {
+            if visited.len().is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() > deadline {
+                break;
+            }
+        }
+
         match heap.pop() {
-            Some(Reverse(OrdVertex { distance, prev, v })) => {
-                if predecessors.contains_key(&v) {
+            Some(Reverse(OrdVertex {
+                distance, prev, v, ..
+            })) => {
+                let key = v.key();
+                if visited_ids.contains_key(&key) {
                     continue;
                 }
-                predecessors.insert(v.clone(), prev);
+                let id = visited.len();
+                visited_ids.insert(key, id);
+                visited.push((v, prev));
 
+                let v = &visited[id].0;
                 if v.is_end() {
-                    end = v;
+                    end_id = Some(id);
                     break;
                 }
 
-                for (edge, new_v) in neighbours(&v) {
-                    if predecessors.contains_key(&new_v) {
+                for (edge, new_v) in neighbours(v, &mut comment_similarity) {
+                    edges_generated += 1;
+                    if visited_ids.contains_key(&new_v.key()) {
                         continue;
                     }
-                    let new_v_distance = distance + edge.cost();
+                    let new_v_distance = distance
+                        + edge.cost(&config.costs)
+                        + anchor_penalty(&config.anchors, v, &edge);
 
+                    push_order += 1;
                     heap.push(Reverse(OrdVertex {
                         distance: new_v_distance,
-                        prev: Some((v.clone(), edge)),
+                        push_order,
+                        prev: Some((id, edge)),
                         v: new_v,
                     }));
                 }
+                max_queue_len = max(max_queue_len, heap.len());
             }
             None => panic!("Ran out of graph nodes before reaching end"),
         }
     }
 
-    let mut current = end;
     let mut res: Vec<(Edge, Vertex)> = vec![];
-    while let Some(Some((node, edge))) = predecessors.remove(&current) {
-        res.push((edge, node.clone()));
-        current = node;
+    if let Some(mut current) = end_id {
+        while let Some((prev_id, edge)) = visited[current].1 {
+            res.push((edge, visited[prev_id].0.clone()));
+            current = prev_id;
+        }
+        res.reverse();
+    }
+
+    let stats = SearchStats {
+        predecessors: visited.len(),
+        max_queue_len,
+        edges_generated,
+        heap_pushes: push_order as usize + 1,
+        route_length: res.len(),
+        timed_out: end_id.is_none(),
+    };
+    (res, stats)
+}
+
+/// The nodes that lead to each node, so the graph can be searched
+/// backwards. Keys are node ids, or None for the end of the file.
+struct Predecessors<'a> {
+    /// Nodes whose `next()` is the key.
+    prev: FxHashMap<Option<u64>, Vec<&'a Syntax<'a>>>,
+    /// Lists that step into the key when entered, which is their
+    /// first child, or their `next()` if they're empty.
+    entered_from: FxHashMap<Option<u64>, Vec<&'a Syntax<'a>>>,
+}
+
+impl<'a> Predecessors<'a> {
+    fn new(first: Option<&'a Syntax<'a>>) -> Self {
+        let mut predecessors = Predecessors {
+            prev: FxHashMap::default(),
+            entered_from: FxHashMap::default(),
+        };
+        // The `next()` of a top-level node is its next sibling.
+        let mut node = first;
+        while let Some(n) = node {
+            predecessors.add(n);
+            node = n.next();
+        }
+        predecessors
+    }
+
+    fn add(&mut self, node: &'a Syntax<'a>) {
+        let next_id = node.next().map(|n| n.id());
+        self.prev.entry(next_id).or_default().push(node);
+        if let Syntax::List { children, .. } = node {
+            let entered_id = match children.first() {
+                Some(child) => Some(child.id()),
+                None => next_id,
+            };
+            self.entered_from.entry(entered_id).or_default().push(node);
+            for child in children {
+                self.add(child);
+            }
+        }
+    }
+
+    fn prev(&self, node: Option<&'a Syntax<'a>>) -> &[&'a Syntax<'a>] {
+        self.prev
+            .get(&node.map(|n| n.id()))
+            .map_or(&[], |nodes| &nodes[..])
+    }
+
+    fn entered_from(&self, node: Option<&'a Syntax<'a>>) -> &[&'a Syntax<'a>] {
+        self.entered_from
+            .get(&node.map(|n| n.id()))
+            .map_or(&[], |nodes| &nodes[..])
+    }
+}
+
+/// A vertex for the backward search, where novelty isn't tracked.
+fn backward_vertex<'a>(
+    lhs_syntax: Option<&'a Syntax<'a>>,
+    rhs_syntax: Option<&'a Syntax<'a>>,
+) -> Vertex<'a> {
+    Vertex {
+        lhs_syntax,
+        lhs_prev_novel: None,
+        rhs_syntax,
+        rhs_prev_novel: None,
+    }
+}
+
+/// The edges that lead to `v`, with the vertex each one starts from.
+/// This is the inverse of `neighbours`, except that novel edges are
+/// always contiguous, as we don't know the novelty of the vertices
+/// before `v`.
+fn predecessors<'a>(
+    v: &Vertex<'a>,
+    lhs_predecessors: &Predecessors<'a>,
+    rhs_predecessors: &Predecessors<'a>,
+    comment_similarity: &mut CommentSimilarity,
+) -> Vec<(Edge, Vertex<'a>)> {
+    let mut res = vec![];
+    let depth_difference = |lhs: &Syntax, rhs: &Syntax| {
+        (lhs.info().num_ancestors.get() as i64 - rhs.info().num_ancestors.get() as i64)
+            .unsigned_abs()
+    };
+
+    for lhs_syntax in lhs_predecessors.prev(v.lhs_syntax) {
+        for rhs_syntax in rhs_predecessors.prev(v.rhs_syntax) {
+            if lhs_syntax.equal_content(rhs_syntax) {
+                res.push((
+                    UnchangedNode(depth_difference(lhs_syntax, rhs_syntax)),
+                    backward_vertex(Some(lhs_syntax), Some(rhs_syntax)),
+                ));
+            }
+            if let (
+                Syntax::Atom {
+                    content: lhs_content,
+                    is_comment: true,
+                    ..
+                },
+                Syntax::Atom {
+                    content: rhs_content,
+                    is_comment: true,
+                    ..
+                },
+            ) = (lhs_syntax, rhs_syntax)
+            {
+                if comment_similarity.score(lhs_syntax, lhs_content, rhs_syntax, rhs_content) > 0.4
+                {
+                    res.push((
+                        ReplacedComment,
+                        backward_vertex(Some(lhs_syntax), Some(rhs_syntax)),
+                    ));
+                }
+            }
+        }
+    }
+
+    for lhs_syntax in lhs_predecessors.entered_from(v.lhs_syntax) {
+        for rhs_syntax in rhs_predecessors.entered_from(v.rhs_syntax) {
+            if let (
+                Syntax::List {
+                    open_content: lhs_open_content,
+                    close_content: lhs_close_content,
+                    ..
+                },
+                Syntax::List {
+                    open_content: rhs_open_content,
+                    close_content: rhs_close_content,
+                    ..
+                },
+            ) = (lhs_syntax, rhs_syntax)
+            {
+                if lhs_open_content == rhs_open_content && lhs_close_content == rhs_close_content {
+                    res.push((
+                        UnchangedDelimiter(depth_difference(lhs_syntax, rhs_syntax)),
+                        backward_vertex(Some(lhs_syntax), Some(rhs_syntax)),
+                    ));
+                }
+            }
+        }
+    }
+
+    for lhs_syntax in lhs_predecessors.prev(v.lhs_syntax) {
+        match lhs_syntax {
+            Syntax::Atom { .. } => res.push((
+                NovelAtomLHS { contiguous: true },
+                backward_vertex(Some(lhs_syntax), v.rhs_syntax),
+            )),
+            Syntax::List {
+                num_descendants, ..
+            } => {
+                if *num_descendants > NOVEL_TREE_THRESHOLD {
+                    res.push((
+                        NovelTreeLHS {
+                            num_descendants: *num_descendants,
+                        },
+                        backward_vertex(Some(lhs_syntax), v.rhs_syntax),
+                    ));
+                }
+            }
+        }
+    }
+    for lhs_syntax in lhs_predecessors.entered_from(v.lhs_syntax) {
+        res.push((
+            NovelDelimiterLHS { contiguous: true },
+            backward_vertex(Some(lhs_syntax), v.rhs_syntax),
+        ));
+    }
+
+    for rhs_syntax in rhs_predecessors.prev(v.rhs_syntax) {
+        match rhs_syntax {
+            Syntax::Atom { .. } => res.push((
+                NovelAtomRHS { contiguous: true },
+                backward_vertex(v.lhs_syntax, Some(rhs_syntax)),
+            )),
+            Syntax::List {
+                num_descendants, ..
+            } => {
+                if *num_descendants > NOVEL_TREE_THRESHOLD {
+                    res.push((
+                        NovelTreeRHS {
+                            num_descendants: *num_descendants,
+                        },
+                        backward_vertex(v.lhs_syntax, Some(rhs_syntax)),
+                    ));
+                }
+            }
+        }
+    }
+    for rhs_syntax in rhs_predecessors.entered_from(v.rhs_syntax) {
+        res.push((
+            NovelDelimiterRHS { contiguous: true },
+            backward_vertex(v.lhs_syntax, Some(rhs_syntax)),
+        ));
     }
 
-    res.reverse();
     res
 }
 
+/// How far `v` is through the files. Node ids are assigned in
+/// pre-order, so every edge moves to a vertex with greater progress.
+fn progress(v: &Vertex) -> u64 {
+    // Greater than any node id.
+    const END: u64 = u64::MAX / 4;
+    v.lhs_syntax.map_or(END, |n| n.id()) + v.rhs_syntax.map_or(END, |n| n.id())
+}
+
+/// Find a route from `start` to the end with a beam search. Vertices
+/// are expanded in order of their progress through the files, and at
+/// each level of progress only the `beam_width` closest vertices are
+/// kept. The search visits at most `beam_width` vertices per level,
+/// so it's fast on huge files, but the route isn't always the
+/// shortest.
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+fn shortest_path_beam<'a>(
+    start: Vertex<'a>,
+    beam_width: usize,
+    config: &SearchConfig,
+) -> (Vec<(Edge, Vertex<'a>)>, SearchStats) {
+    // The vertices found but not yet expanded, by progress. Vertices
+    // reached more than once keep their shortest distance.
+    let mut levels: BTreeMap<u64, FxHashMap<VertexKey, OrdVertex>> = BTreeMap::new();
+    levels.entry(progress(&start)).or_default().insert(
+        start.key(),
+        OrdVertex {
+            distance: 0,
+            push_order: 0,
+            prev: None,
+            v: start,
+        },
+    );
+    let mut num_pending: usize = 1;
+
+    let mut visited: Vec<(Vertex, Option<Step>)> = vec![];
+    let mut comment_similarity = CommentSimilarity::new(config.max_comment_similarity_len);
+    let mut push_order = 0;
+    let mut edges_generated = 0;
+    let mut max_queue_len = 1;
+    let mut end_id = None;
+
+    'search: while let Some((_, level)) = levels.pop_first() {
+        if let Some(deadline) = config.deadline {
+            if Instant::now() > deadline {
+                break;
+            }
+        }
+
+        num_pending -= level.len();
+        let mut level: Vec<_> = level.into_values().collect();
+        level.sort_unstable();
+        level.truncate(beam_width);
+
+        for OrdVertex {
+            distance, prev, v, ..
+        } in level
+        {
+            let id = visited.len();
+            visited.push((v, prev));
+            let v = &visited[id].0;
+            if v.is_end() {
+                end_id = Some(id);
+                break 'search;
+            }
+
+            for (edge, new_v) in neighbours(v, &mut comment_similarity) {
+                edges_generated += 1;
+                let new_v_distance =
+                    distance + edge.cost(&config.costs) + anchor_penalty(&config.anchors, v, &edge);
+
+                let pending = levels.entry(progress(&new_v)).or_default();
+                if let Some(existing) = pending.get(&new_v.key()) {
+                    if existing.distance <= new_v_distance {
+                        continue;
+                    }
+                } else {
+                    num_pending += 1;
+                }
+                push_order += 1;
+                pending.insert(
+                    new_v.key(),
+                    OrdVertex {
+                        distance: new_v_distance,
+                        push_order,
+                        prev: Some((id, edge)),
+                        v: new_v,
+                    },
+                );
+            }
+            max_queue_len = max(max_queue_len, num_pending);
+        }
+    }
+
+    let mut res: Vec<(Edge, Vertex)> = vec![];
+    if let Some(mut current) = end_id {
+        while let Some((prev_id, edge)) = visited[current].1 {
+            res.push((edge, visited[prev_id].0.clone()));
+            current = prev_id;
+        }
+        res.reverse();
+    }
+
+    let stats = SearchStats {
+        predecessors: visited.len(),
+        max_queue_len,
+        edges_generated,
+        heap_pushes: push_order as usize + 1,
+        route_length: res.len(),
+        timed_out: end_id.is_none(),
+    };
+    (res, stats)
+}
+
+/// The vertex a vertex was reached from, and the edge between them.
+type Step = (VertexId, Edge);
+
+/// One direction of a bidirectional search.
+struct Frontier<'a> {
+    heap: BinaryHeap<Reverse<OrdVertex<'a>>>,
+    /// Every vertex this direction has expanded, with its distance
+    /// and the vertex and edge it was reached from. For the backward
+    /// search, that's the vertex it leads to.
+    visited: Vec<(Vertex<'a>, u64, Option<Step>)>,
+    visited_ids: FxHashMap<VertexKey, VertexId>,
+}
+
+impl<'a> Frontier<'a> {
+    fn new(start: Vertex<'a>) -> Self {
+        let mut heap = BinaryHeap::new();
+        heap.push(Reverse(OrdVertex {
+            distance: 0,
+            push_order: 0,
+            prev: None,
+            v: start,
+        }));
+        Frontier {
+            heap,
+            visited: vec![],
+            visited_ids: FxHashMap::default(),
+        }
+    }
+
+    fn min_distance(&self) -> Option<u64> {
+        self.heap.peek().map(|Reverse(ov)| ov.distance)
+    }
+
+    fn distance(&self, v: &Vertex) -> Option<(VertexId, u64)> {
+        self.visited_ids
+            .get(&v.key())
+            .map(|id| (*id, self.visited[*id].1))
+    }
+}
+
+/// Where the two directions of a bidirectional search met: a vertex
+/// expanded forwards, possibly an edge, and a vertex expanded
+/// backwards.
+type Meeting = (VertexId, Option<Edge>, VertexId);
+
+/// Find a route from `start` to the end by searching forwards from
+/// `start` and backwards from the end at the same time, stopping when
+/// the two searches meet. Each direction only explores vertices up to
+/// about half the route's cost, which is far fewer on large files
+/// with many changes.
+///
+/// The backward search can't tell whether novel nodes are contiguous,
+/// so it treats them as contiguous. This means the route can differ
+/// slightly from `shortest_path` when it has several equally good
+/// options.
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+fn shortest_path_bidirectional<'a>(
+    start: Vertex<'a>,
+    config: &SearchConfig,
+) -> (Vec<(Edge, Vertex<'a>)>, SearchStats) {
+    let lhs_predecessors = Predecessors::new(start.lhs_syntax);
+    let rhs_predecessors = Predecessors::new(start.rhs_syntax);
+
+    let mut forward = Frontier::new(start);
+    let mut backward = Frontier::new(backward_vertex(None, None));
+    let mut comment_similarity = CommentSimilarity::new(config.max_comment_similarity_len);
+
+    let mut push_order = 1;
+    let mut edges_generated = 0;
+    let mut max_queue_len = 2;
+    let mut best: Option<(u64, Meeting)> = None;
+    let mut timed_out = false;
+    loop {
+        if let Some(deadline) = config.deadline {
+            let num_visited = forward.visited.len() + backward.visited.len();
+            if num_visited.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() > deadline {
+                timed_out = true;
+                break;
+            }
+        }
+
+        // Once the cheapest vertices on each side are further apart
+        // than the best route found, no cheaper route exists.
+        let (forward_min, backward_min) = (forward.min_distance(), backward.min_distance());
+        let is_forward = match (forward_min, backward_min) {
+            (Some(forward_min), Some(backward_min)) => {
+                if let Some((best_distance, _)) = best {
+                    if forward_min + backward_min >= best_distance {
+                        break;
+                    }
+                }
+                forward_min <= backward_min
+            }
+            _ if best.is_some() => break,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => panic!("Ran out of graph nodes before reaching end"),
+        };
+        let (frontier, opposite) = if is_forward {
+            (&mut forward, &backward)
+        } else {
+            (&mut backward, &forward)
+        };
+
+        let Reverse(OrdVertex {
+            distance, prev, v, ..
+        }) = frontier.heap.pop().unwrap();
+        let key = v.key();
+        if frontier.visited_ids.contains_key(&key) {
+            continue;
+        }
+        let id = frontier.visited.len();
+        frontier.visited_ids.insert(key, id);
+        frontier.visited.push((v, distance, prev));
+        let v = &frontier.visited[id].0;
+
+        let mut consider = |total: u64, meeting: Meeting| {
+            if best.is_none_or(|(best_distance, _)| total < best_distance) {
+                best = Some((total, meeting));
+            }
+        };
+        if let Some((opposite_id, opposite_distance)) = opposite.distance(v) {
+            let meeting = if is_forward {
+                (id, None, opposite_id)
+            } else {
+                (opposite_id, None, id)
+            };
+            consider(distance + opposite_distance, meeting);
+        }
+
+        let edges = if is_forward {
+            neighbours(v, &mut comment_similarity)
+        } else {
+            predecessors(
+                v,
+                &lhs_predecessors,
+                &rhs_predecessors,
+                &mut comment_similarity,
+            )
+        };
+        for (edge, new_v) in edges {
+            edges_generated += 1;
+            // The anchor penalty depends on the vertex the edge starts
+            // from, which is `new_v` when searching backwards.
+            let penalty = if is_forward {
+                anchor_penalty(&config.anchors, v, &edge)
+            } else {
+                anchor_penalty(&config.anchors, &new_v, &edge)
+            };
+            let new_v_distance = distance + edge.cost(&config.costs) + penalty;
+
+            if let Some((opposite_id, opposite_distance)) = opposite.distance(&new_v) {
+                let meeting = if is_forward {
+                    (id, Some(edge), opposite_id)
+                } else {
+                    (opposite_id, Some(edge), id)
+                };
+                consider(new_v_distance + opposite_distance, meeting);
+            }
+            if frontier.visited_ids.contains_key(&new_v.key()) {
+                continue;
+            }
+
+            push_order += 1;
+            frontier.heap.push(Reverse(OrdVertex {
+                distance: new_v_distance,
+                push_order,
+                prev: Some((id, edge)),
+                v: new_v,
+            }));
+        }
+        max_queue_len = max(max_queue_len, forward.heap.len() + backward.heap.len());
+    }
+
+    let mut res: Vec<(Edge, Vertex)> = vec![];
+    if let (Some((_, (forward_id, edge, backward_id))), false) = (best, timed_out) {
+        let mut current = forward_id;
+        while let Some((prev_id, edge)) = forward.visited[current].2 {
+            res.push((edge, forward.visited[prev_id].0.clone()));
+            current = prev_id;
+        }
+        res.reverse();
+
+        if let Some(edge) = edge {
+            res.push((edge, forward.visited[forward_id].0.clone()));
+        }
+        let mut current = backward_id;
+        while let Some((next_id, edge)) = backward.visited[current].2 {
+            res.push((edge, backward.visited[current].0.clone()));
+            current = next_id;
+        }
+    }
+
+    let stats = SearchStats {
+        predecessors: forward.visited.len() + backward.visited.len(),
+        max_queue_len,
+        edges_generated,
+        heap_pushes: push_order as usize,
+        route_length: res.len(),
+        timed_out: timed_out || best.is_none(),
+    };
+    (res, stats)
+}
+
+/// A vertex that a parallel search has reached, with the best
+/// distance to it found so far.
+struct Tentative<'a> {
+    v: Vertex<'a>,
+    distance: u64,
+    prev: Option<Step>,
+    /// Whether the vertex's neighbours have been found at its current
+    /// distance.
+    expanded: bool,
+}
+
+/// An edge found by expanding a vertex: the vertex it's from, the
+/// edge, the vertex it leads to and the distance to that vertex.
+type Relaxation<'a> = (VertexId, Edge, Vertex<'a>, u64);
+
+/// Batches smaller than this aren't worth starting threads for, so
+/// they're expanded on the current thread.
+const MIN_PARALLEL_BATCH: usize = 512;
+
+/// A chunk of a batch, sent to the thread that expands it.
+///
+/// `Vertex` isn't `Send`, because it refers to `Syntax` nodes and
+/// `SyntaxInfo` uses `Cell`s.
+struct BatchChunk<'b, 'a>(&'b [(VertexId, u64, Vertex<'a>)]);
+
+// SAFETY: The `Cell`s in `SyntaxInfo` are only written by
+// `init_info`, before the search starts, and by `set_change` in
+// `mark_route`, after it has finished. `expand_batch` joins every
+// thread before it returns, so the threads only ever read the nodes
+// and never overlap with a write.
+unsafe impl Send for BatchChunk<'_, '_> {}
+
+/// The relaxations found by one thread, sent back to the thread
+/// running the search.
+struct ChunkRelaxations<'a>(Vec<Relaxation<'a>>);
+
+// SAFETY: As for `BatchChunk`. The vertices refer to the same nodes,
+// and the thread that found them has finished with them when they're
+// sent.
+unsafe impl Send for ChunkRelaxations<'_> {}
+
+/// The neighbours of every vertex in `batch`, in order. Each thread
+/// expands one chunk of the batch with its own comment similarity
+/// cache.
+fn expand_batch<'a>(
+    batch: &[(VertexId, u64, Vertex<'a>)],
+    comment_similarities: &mut [CommentSimilarity],
+    config: &SearchConfig,
+) -> Vec<Relaxation<'a>> {
+    let expand = |chunk: &[(VertexId, u64, Vertex<'a>)],
+                  comment_similarity: &mut CommentSimilarity| {
+        let mut res = vec![];
+        for (id, distance, v) in chunk {
+            for (edge, new_v) in neighbours(v, comment_similarity) {
+                let new_v_distance =
+                    distance + edge.cost(&config.costs) + anchor_penalty(&config.anchors, v, &edge);
+                res.push((*id, edge, new_v, new_v_distance));
+            }
+        }
+        res
+    };
+
+    if batch.len() < MIN_PARALLEL_BATCH || comment_similarities.len() == 1 {
+        return expand(batch, &mut comment_similarities[0]);
+    }
+
+    let chunk_size = batch.len().div_ceil(comment_similarities.len());
+    thread::scope(|scope| {
+        let expand = &expand;
+        let handles: Vec<_> = batch
+            .chunks(chunk_size)
+            .zip(comment_similarities.iter_mut())
+            .map(|(chunk, comment_similarity)| {
+                let chunk = BatchChunk(chunk);
+                scope.spawn(move || ChunkRelaxations(expand(chunk.0, comment_similarity)))
+            })
+            .collect();
+
+        let mut res = vec![];
+        for handle in handles {
+            match handle.join() {
+                Ok(relaxations) => res.extend(relaxations.0),
+                Err(e) => panic::resume_unwind(e),
+            }
+        }
+        res
+    })
+}
+
+/// Find the shortest route from `start` to the end using Δ-stepping,
+/// a variant of Dijkstra's algorithm that expands many vertices at
+/// once, so the work can be split between `threads` threads.
+///
+/// Vertices are kept in buckets of distances, each as wide as a novel
+/// node. All the vertices in the nearest bucket are expanded together,
+/// and a vertex is expanded again if a shorter route to it is found
+/// later. Once a bucket is empty, every vertex in it has its final
+/// distance.
+///
+/// Vertices are expanded and relaxed in a fixed order, so the route
+/// doesn't depend on the number of threads. It has the same cost as
+/// the route from `shortest_path`, but may choose differently between
+/// equally good options.
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+fn shortest_path_parallel<'a>(
+    start: Vertex<'a>,
+    threads: usize,
+    config: &SearchConfig,
+) -> (Vec<(Edge, Vertex<'a>)>, SearchStats) {
+    let bucket_width = max(config.costs.novel, 1);
+
+    let mut found: Vec<Tentative> = vec![];
+    let mut found_ids: FxHashMap<VertexKey, VertexId> = FxHashMap::default();
+    found_ids.insert(start.key(), 0);
+    found.push(Tentative {
+        v: start,
+        distance: 0,
+        prev: None,
+        expanded: false,
+    });
+
+    // Vertex ids by distance / bucket_width. A vertex may appear more
+    // than once, or in a bucket it's no longer in, if its distance
+    // has improved since it was added.
+    let mut buckets: BTreeMap<u64, Vec<VertexId>> = BTreeMap::new();
+    buckets.insert(0, vec![0]);
+    let mut comment_similarities: Vec<_> = (0..max(threads, 1))
+        .map(|_| CommentSimilarity::new(config.max_comment_similarity_len))
+        .collect();
+
+    let mut num_expanded = 0;
+    let mut pushes = 1;
+    let mut edges_generated = 0;
+    let mut max_queue_len = 1;
+    let mut end_id = None;
+    'search: while let Some((bucket, mut ids)) = buckets.pop_first() {
+        loop {
+            if let Some(deadline) = config.deadline {
+                if Instant::now() > deadline {
+                    break 'search;
+                }
+            }
+
+            let batch: Vec<_> = ids
+                .drain(..)
+                .filter_map(|id| {
+                    let tentative = &mut found[id];
+                    if tentative.expanded || tentative.distance / bucket_width != bucket {
+                        return None;
+                    }
+                    tentative.expanded = true;
+                    Some((id, tentative.distance, tentative.v.clone()))
+                })
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            num_expanded += batch.len();
+
+            let relaxations = expand_batch(&batch, &mut comment_similarities, config);
+            edges_generated += relaxations.len();
+            for (prev_id, edge, new_v, new_v_distance) in relaxations {
+                let tentative = Tentative {
+                    v: new_v,
+                    distance: new_v_distance,
+                    prev: Some((prev_id, edge)),
+                    expanded: false,
+                };
+                let key = tentative.v.key();
+                let id = match found_ids.get(&key) {
+                    Some(&id) => {
+                        if new_v_distance >= found[id].distance {
+                            continue;
+                        }
+                        found[id] = tentative;
+                        id
+                    }
+                    None => {
+                        let id = found.len();
+                        found_ids.insert(key, id);
+                        found.push(tentative);
+                        id
+                    }
+                };
+
+                pushes += 1;
+                let new_v_bucket = new_v_distance / bucket_width;
+                if new_v_bucket == bucket {
+                    ids.push(id);
+                } else {
+                    buckets.entry(new_v_bucket).or_default().push(id);
+                }
+            }
+            max_queue_len = max(
+                max_queue_len,
+                ids.len() + buckets.values().map(|ids| ids.len()).sum::<usize>(),
+            );
+        }
+
+        if let Some(&id) = found_ids.get(&(None, None)) {
+            if found[id].distance / bucket_width <= bucket {
+                end_id = Some(id);
+                break;
+            }
+        }
+    }
+
+    let mut res: Vec<(Edge, Vertex)> = vec![];
+    if let Some(mut current) = end_id {
+        while let Some((prev_id, edge)) = found[current].prev {
+            res.push((edge, found[prev_id].v.clone()));
+            current = prev_id;
+        }
+        res.reverse();
+    }
+
+    let stats = SearchStats {
+        predecessors: num_expanded,
+        max_queue_len,
+        edges_generated,
+        heap_pushes: pushes,
+        route_length: res.len(),
+        timed_out: end_id.is_none(),
+    };
+    (res, stats)
+}
+
+/// How many vertices to expand between checks of the deadline.
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
 const NOVEL_TREE_THRESHOLD: u64 = 20;
 
-fn neighbours<'a>(v: &Vertex<'a>) -> Vec<(Edge, Vertex<'a>)> {
+/// The cost of matching nodes in a way that contradicts an anchor.
+/// This is much more than marking nodes as novel, so anchors are
+/// respected unless they contradict each other.
+const ANCHOR_PENALTY: u64 = 10_000;
+
+/// The extra cost of taking `edge` from `v`, if it matches an LHS
+/// node and RHS node that are on opposite sides of an anchor, or if
+/// only one of them is on the anchored line.
+fn anchor_penalty(anchors: &[(LineNumber, LineNumber)], v: &Vertex, edge: &Edge) -> u64 {
+    if !matches!(
+        edge,
+        UnchangedNode(_) | UnchangedDelimiter(_) | ReplacedComment
+    ) {
+        return 0;
+    }
+    let (lhs_line, rhs_line) = match (
+        v.lhs_syntax.and_then(|n| n.first_line()),
+        v.rhs_syntax.and_then(|n| n.first_line()),
+    ) {
+        (Some(lhs_line), Some(rhs_line)) => (lhs_line, rhs_line),
+        _ => return 0,
+    };
+
+    let num_contradicted = anchors
+        .iter()
+        .filter(|(lhs_anchor, rhs_anchor)| {
+            (lhs_line == *lhs_anchor) != (rhs_line == *rhs_anchor)
+                || (lhs_line < *lhs_anchor) != (rhs_line < *rhs_anchor)
+        })
+        .count();
+    num_contradicted as u64 * ANCHOR_PENALTY
+}
+
+/// Levenshtein similarity of comment pairs.
+struct CommentSimilarity {
+    max_len: usize,
+    /// Scores keyed by the LHS and RHS node ids. The same pair of
+    /// comments is reached from many different vertices, so this
+    /// avoids repeating the quadratic comparison.
+    scores: FxHashMap<(u64, u64), f64>,
+}
+
+impl CommentSimilarity {
+    fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            scores: FxHashMap::default(),
+        }
+    }
+
+    fn score(&mut self, lhs: &Syntax, lhs_content: &str, rhs: &Syntax, rhs_content: &str) -> f64 {
+        let max_len = self.max_len;
+        *self.scores.entry((lhs.id(), rhs.id())).or_insert_with(|| {
+            // Only compare the start of huge comments.
+            normalized_levenshtein(
+                char_prefix(lhs_content, max_len),
+                char_prefix(rhs_content, max_len),
+            )
+        })
+    }
+}
+
+/// The first `max_chars` characters of `s`.
+fn char_prefix(s: &str, max_chars: usize) -> &str {
+    match s.char_indices().nth(max_chars) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
+fn neighbours<'a>(
+    v: &Vertex<'a>,
+    comment_similarity: &mut CommentSimilarity,
+) -> Vec<(Edge, Vertex<'a>)> {
     let mut res = vec![];
 
     if let (Some(lhs_syntax), Some(rhs_syntax)) = (&v.lhs_syntax, &v.rhs_syntax) {
         if lhs_syntax.equal_content(rhs_syntax) {
             let depth_difference = (lhs_syntax.info().num_ancestors.get() as i64
                 - rhs_syntax.info().num_ancestors.get() as i64)
-                .abs() as u64;
+                .unsigned_abs();
 
             // Both nodes are equal, the happy case.
             res.push((
@@ -230,7 +1210,7 @@ fn neighbours<'a>(v: &Vertex<'a>) -> Vec<(Edge, Vertex<'a>)> {
 
                 let depth_difference = (lhs_syntax.info().num_ancestors.get() as i64
                     - rhs_syntax.info().num_ancestors.get() as i64)
-                    .abs() as u64;
+                    .unsigned_abs();
 
                 res.push((
                     UnchangedDelimiter(depth_difference),
@@ -261,7 +1241,7 @@ fn neighbours<'a>(v: &Vertex<'a>) -> Vec<(Edge, Vertex<'a>)> {
             // similar.
             if *lhs_is_comment
                 && *rhs_is_comment
-                && normalized_levenshtein(lhs_content, rhs_content) > 0.4
+                && comment_similarity.score(lhs_syntax, lhs_content, rhs_syntax, rhs_content) > 0.4
             {
                 res.push((
                     ReplacedComment,
@@ -321,7 +1301,7 @@ fn neighbours<'a>(v: &Vertex<'a>) -> Vec<(Edge, Vertex<'a>)> {
                 if *num_descendants > NOVEL_TREE_THRESHOLD {
                     res.push((
                         NovelTreeLHS {
-                            num_descendants: *num_descendants as u64,
+                            num_descendants: *num_descendants,
                         },
                         Vertex {
                             lhs_syntax: lhs_syntax.next(),
@@ -379,7 +1359,7 @@ fn neighbours<'a>(v: &Vertex<'a>) -> Vec<(Edge, Vertex<'a>)> {
                 if *num_descendants > NOVEL_TREE_THRESHOLD {
                     res.push((
                         NovelTreeRHS {
-                            num_descendants: *num_descendants as u64,
+                            num_descendants: *num_descendants,
                         },
                         Vertex {
                             lhs_syntax: v.lhs_syntax,
@@ -396,15 +1376,86 @@ fn neighbours<'a>(v: &Vertex<'a>) -> Vec<(Edge, Vertex<'a>)> {
     res
 }
 
-pub fn mark_syntax<'a>(lhs_syntax: Option<&'a Syntax<'a>>, rhs_syntax: Option<&'a Syntax<'a>>) {
+/// If the estimated graph size exceeds this, the search will probably
+/// exhaust memory, so callers should use a coarser diff instead.
+pub const MAX_ESTIMATED_GRAPH_SIZE: u64 = 10_000_000;
+
+/// Count the atoms and list delimiters in `nodes`, adding `delta` to
+/// the count for each.
+fn count_tokens<'a>(nodes: &[&'a Syntax<'a>], delta: i64, counts: &mut FxHashMap<&'a str, i64>) {
+    for node in nodes {
+        match node {
+            Syntax::List {
+                open_content,
+                children,
+                ..
+            } => {
+                *counts.entry(open_content).or_insert(0) += delta;
+                count_tokens(children, delta, counts);
+            }
+            Syntax::Atom { content, .. } => {
+                *counts.entry(content).or_insert(0) += delta;
+            }
+        }
+    }
+}
+
+/// Estimate the number of vertices that the search will explore.
+///
+/// The graph has a vertex for every pair of LHS and RHS nodes, but we
+/// only explore the pairs around novel nodes. We approximate novelty
+/// by counting tokens that only occur on one side.
+pub fn estimated_graph_size<'a>(lhs: &[&'a Syntax<'a>], rhs: &[&'a Syntax<'a>]) -> u64 {
+    let mut counts = FxHashMap::default();
+    count_tokens(lhs, 1, &mut counts);
+    count_tokens(rhs, -1, &mut counts);
+
+    let num_nodes = |nodes: &[&Syntax]| -> u64 {
+        nodes
+            .iter()
+            .map(|node| match node {
+                Syntax::List {
+                    num_descendants, ..
+                } => num_descendants + 1,
+                Syntax::Atom { .. } => 1,
+            })
+            .sum()
+    };
+    let lhs_nodes = num_nodes(lhs);
+    let rhs_nodes = num_nodes(rhs);
+    if lhs_nodes + rhs_nodes == 0 {
+        return 0;
+    }
+
+    let num_novel: u64 = counts.values().map(|count| count.unsigned_abs()).sum();
+    lhs_nodes * rhs_nodes * num_novel / (lhs_nodes + rhs_nodes)
+}
+
+/// Find the cheapest way to match `lhs_syntax` with `rhs_syntax`, and
+/// set the `ChangeKind` of every node. If the search times out, nodes
+/// are left unmarked and `timed_out` is set.
+pub fn mark_syntax<'a>(
+    lhs_syntax: Option<&'a Syntax<'a>>,
+    rhs_syntax: Option<&'a Syntax<'a>>,
+    config: &SearchConfig,
+) -> SearchStats {
     let start = Vertex {
         lhs_syntax,
         lhs_prev_novel: None,
         rhs_syntax,
         rhs_prev_novel: None,
     };
-    let route = shortest_path(start);
+    let (route, stats) = if let Some(beam_width) = config.beam_width {
+        shortest_path_beam(start, beam_width, config)
+    } else if config.bidirectional {
+        shortest_path_bidirectional(start, config)
+    } else if config.threads > 1 {
+        shortest_path_parallel(start, config.threads, config)
+    } else {
+        shortest_path(start, config)
+    };
     mark_route(&route);
+    stats
 }
 
 fn mark_route(route: &[(Edge, Vertex)]) {
@@ -454,31 +1505,59 @@ fn mark_route(route: &[(Edge, Vertex)]) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::positions::SingleLineSpan;
+    use crate::positions::{SingleLineSpan, Spans};
     use crate::syntax::init_info;
     use crate::syntax::Syntax::*;
-    use crate::syntax::SyntaxInfo;
+    use crate::syntax::{SyntaxInfo, TokenKind};
 
     use itertools::Itertools;
+    use smallvec::smallvec;
     use std::cell::Cell;
     use typed_arena::Arena;
 
-    fn pos_helper(line: usize) -> Vec<SingleLineSpan> {
-        vec![SingleLineSpan {
+    fn pos_helper(line: usize) -> Spans {
+        smallvec![SingleLineSpan {
             line: line.into(),
             start_col: 0,
             end_col: 1,
         }]
     }
 
-    fn col_helper(line: usize, col: usize) -> Vec<SingleLineSpan> {
-        vec![SingleLineSpan {
+    fn col_helper(line: usize, col: usize) -> Spans {
+        smallvec![SingleLineSpan {
             line: line.into(),
             start_col: col,
             end_col: col + 1,
         }]
     }
 
+    #[test]
+    fn equal_distances_pop_earliest_first() {
+        let vertex = |distance, push_order| {
+            Reverse(OrdVertex {
+                distance,
+                push_order,
+                prev: None,
+                v: Vertex {
+                    lhs_syntax: None,
+                    lhs_prev_novel: None,
+                    rhs_syntax: None,
+                    rhs_prev_novel: None,
+                },
+            })
+        };
+
+        let mut heap = BinaryHeap::new();
+        for (distance, push_order) in [(1, 0), (2, 1), (1, 2), (1, 3)] {
+            heap.push(vertex(distance, push_order));
+        }
+
+        let order: Vec<_> = std::iter::from_fn(|| heap.pop())
+            .map(|Reverse(v)| v.push_order)
+            .collect();
+        assert_eq!(order, vec![0, 2, 3, 1]);
+    }
+
     #[test]
     fn identical_atoms() {
         let arena = Arena::new();
@@ -491,6 +1570,7 @@ mod tests {
             position: pos_helper(0),
             content: "foo".into(),
             is_comment: false,
+            token_kind: TokenKind::Other,
         });
 
         // Same content as LHS.
@@ -502,6 +1582,7 @@ mod tests {
             position: pos_helper(1),
             content: "foo".into(),
             is_comment: false,
+            token_kind: TokenKind::Other,
         });
 
         let start = Vertex {
@@ -510,7 +1591,7 @@ mod tests {
             rhs_syntax: Some(rhs),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(actions, vec![UnchangedNode(0)]);
@@ -522,31 +1603,31 @@ mod tests {
 
         let lhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "[".into(),
+            "[",
             pos_helper(0),
             vec![Syntax::new_atom(&arena, pos_helper(1), "foo")],
-            "]".into(),
+            "]",
             pos_helper(2),
         )];
         init_info(&lhs);
 
         let rhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "[".into(),
+            "[",
             pos_helper(0),
             vec![],
-            "]".into(),
+            "]",
             pos_helper(2),
         )];
         init_info(&rhs);
 
         let start = Vertex {
-            lhs_syntax: lhs.get(0).map(|n| *n),
+            lhs_syntax: lhs.first().copied(),
             lhs_prev_novel: None,
-            rhs_syntax: rhs.get(0).map(|n| *n),
+            rhs_syntax: rhs.first().copied(),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -555,40 +1636,78 @@ mod tests {
         );
     }
 
+    #[test]
+    fn anchors_force_alignment() {
+        let arena = Arena::new();
+
+        let lhs: Vec<&Syntax> = vec![
+            Syntax::new_atom(&arena, pos_helper(0), "a"),
+            Syntax::new_atom(&arena, pos_helper(1), "b"),
+        ];
+        init_info(&lhs);
+        let rhs: Vec<&Syntax> = vec![
+            Syntax::new_atom(&arena, pos_helper(0), "b"),
+            Syntax::new_atom(&arena, pos_helper(1), "a"),
+        ];
+        init_info(&rhs);
+
+        let matched_lhs_lines = |anchor: (usize, usize)| {
+            let start = Vertex {
+                lhs_syntax: lhs.first().copied(),
+                lhs_prev_novel: None,
+                rhs_syntax: rhs.first().copied(),
+                rhs_prev_novel: None,
+            };
+            let config = SearchConfig {
+                anchors: vec![(anchor.0.into(), anchor.1.into())],
+                ..SearchConfig::default()
+            };
+            let (route, _) = shortest_path(start, &config);
+
+            route
+                .iter()
+                .filter(|(action, _)| matches!(action, UnchangedNode(_)))
+                .map(|(_, v)| v.lhs_syntax.unwrap().first_line().unwrap().0)
+                .collect_vec()
+        };
+        assert_eq!(matched_lhs_lines((1, 0)), vec![1]);
+        assert_eq!(matched_lhs_lines((0, 1)), vec![0]);
+    }
+
     #[test]
     fn repeated_atoms() {
         let arena = Arena::new();
 
         let lhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "[".into(),
+            "[",
             pos_helper(0),
             vec![],
-            "]".into(),
+            "]",
             pos_helper(2),
         )];
         init_info(&lhs);
 
         let rhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "[".into(),
+            "[",
             pos_helper(0),
             vec![
                 Syntax::new_atom(&arena, pos_helper(1), "foo"),
                 Syntax::new_atom(&arena, pos_helper(2), "foo"),
             ],
-            "]".into(),
+            "]",
             pos_helper(3),
         )];
         init_info(&rhs);
 
         let start = Vertex {
-            lhs_syntax: lhs.get(0).map(|n| *n),
+            lhs_syntax: lhs.first().copied(),
             lhs_prev_novel: None,
-            rhs_syntax: rhs.get(0).map(|n| *n),
+            rhs_syntax: rhs.first().copied(),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -607,58 +1726,46 @@ mod tests {
 
         let lhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "[".into(),
+            "[",
             pos_helper(0),
             vec![
-                Syntax::new_list(
-                    &arena,
-                    "(".into(),
-                    pos_helper(1),
-                    vec![],
-                    ")".into(),
-                    pos_helper(2),
-                ),
+                Syntax::new_list(&arena, "(", pos_helper(1), vec![], ")", pos_helper(2)),
                 Syntax::new_atom(&arena, pos_helper(3), "foo"),
             ],
-            "]".into(),
+            "]",
             pos_helper(4),
         )];
         init_info(&lhs);
 
         let rhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "{".into(),
+            "{",
             pos_helper(0),
             vec![
-                Syntax::new_list(
-                    &arena,
-                    "(".into(),
-                    pos_helper(1),
-                    vec![],
-                    ")".into(),
-                    pos_helper(2),
-                ),
+                Syntax::new_list(&arena, "(", pos_helper(1), vec![], ")", pos_helper(2)),
                 Syntax::new_atom(&arena, pos_helper(3), "foo"),
             ],
-            "}".into(),
+            "}",
             pos_helper(4),
         )];
         init_info(&rhs);
 
         let start = Vertex {
-            lhs_syntax: lhs.get(0).map(|n| *n),
+            lhs_syntax: lhs.first().copied(),
             lhs_prev_novel: None,
-            rhs_syntax: rhs.get(0).map(|n| *n),
+            rhs_syntax: rhs.first().copied(),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
+        // Both orders cost the same, and ties go to the LHS edge
+        // because it's pushed first.
         assert_eq!(
             actions,
             vec![
-                NovelDelimiterRHS { contiguous: false },
                 NovelDelimiterLHS { contiguous: false },
+                NovelDelimiterRHS { contiguous: false },
                 UnchangedNode(0),
                 UnchangedNode(0)
             ],
@@ -680,12 +1787,12 @@ mod tests {
         init_info(&rhs);
 
         let start = Vertex {
-            lhs_syntax: lhs.get(0).map(|n| *n),
+            lhs_syntax: lhs.first().copied(),
             lhs_prev_novel: None,
-            rhs_syntax: rhs.get(0).map(|n| *n),
+            rhs_syntax: rhs.first().copied(),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -704,10 +1811,10 @@ mod tests {
 
         let lhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "[".into(),
+            "[",
             col_helper(1, 0),
             vec![Syntax::new_atom(&arena, col_helper(1, 2), "1")],
-            "]".into(),
+            "]",
             pos_helper(2),
         )];
         init_info(&lhs);
@@ -715,12 +1822,12 @@ mod tests {
         let rhs: Vec<&Syntax> = vec![];
 
         let start = Vertex {
-            lhs_syntax: lhs.get(0).map(|n| *n),
+            lhs_syntax: lhs.first().copied(),
             lhs_prev_novel: None,
-            rhs_syntax: rhs.get(0).map(|n| *n),
+            rhs_syntax: rhs.first().copied(),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -738,7 +1845,7 @@ mod tests {
 
         let lhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "[".into(),
+            "[",
             pos_helper(0),
             vec![
                 Syntax::new_atom(&arena, pos_helper(1), "1"),
@@ -763,14 +1870,14 @@ mod tests {
                 Syntax::new_atom(&arena, pos_helper(20), "20"),
                 Syntax::new_atom(&arena, pos_helper(21), "21"),
             ],
-            "]".into(),
+            "]",
             pos_helper(100),
         )];
         init_info(&lhs);
 
         let rhs: Vec<&Syntax> = vec![Syntax::new_list(
             &arena,
-            "[".into(),
+            "[",
             pos_helper(0),
             vec![
                 Syntax::new_atom(&arena, pos_helper(1), "d1"),
@@ -796,18 +1903,18 @@ mod tests {
                 Syntax::new_atom(&arena, pos_helper(20), "d20"),
                 Syntax::new_atom(&arena, pos_helper(21), "d21"),
             ],
-            "]".into(),
+            "]",
             pos_helper(100),
         )];
         init_info(&rhs);
 
         let start = Vertex {
-            lhs_syntax: lhs.get(0).map(|n| *n),
+            lhs_syntax: lhs.first().copied(),
             lhs_prev_novel: None,
-            rhs_syntax: rhs.get(0).map(|n| *n),
+            rhs_syntax: rhs.first().copied(),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -841,12 +1948,12 @@ mod tests {
         init_info(&rhs);
 
         let start = Vertex {
-            lhs_syntax: lhs.get(0).map(|n| *n),
+            lhs_syntax: lhs.first().copied(),
             lhs_prev_novel: None,
-            rhs_syntax: rhs.get(0).map(|n| *n),
+            rhs_syntax: rhs.first().copied(),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(actions, vec![ReplacedComment]);
@@ -867,12 +1974,12 @@ mod tests {
         init_info(&rhs);
 
         let start = Vertex {
-            lhs_syntax: lhs.get(0).map(|n| *n),
+            lhs_syntax: lhs.first().copied(),
             lhs_prev_novel: None,
-            rhs_syntax: rhs.get(0).map(|n| *n),
+            rhs_syntax: rhs.first().copied(),
             rhs_prev_novel: None,
         };
-        let route = shortest_path(start);
+        let (route, _) = shortest_path(start, &SearchConfig::default());
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -883,4 +1990,261 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn comment_similarity_is_cached() {
+        let arena = Arena::new();
+
+        let lhs: Vec<&Syntax> = vec![Syntax::new_comment(
+            &arena,
+            pos_helper(1),
+            "the quick brown fox",
+        )];
+        init_info(&lhs);
+
+        let rhs: Vec<&Syntax> = vec![Syntax::new_comment(
+            &arena,
+            pos_helper(1),
+            "the quick brown cat",
+        )];
+        init_info(&rhs);
+
+        let v = Vertex {
+            lhs_syntax: lhs.first().copied(),
+            lhs_prev_novel: None,
+            rhs_syntax: rhs.first().copied(),
+            rhs_prev_novel: None,
+        };
+
+        let mut similarity = CommentSimilarity::new(DEFAULT_MAX_COMMENT_SIMILARITY_LEN);
+        neighbours(&v, &mut similarity);
+        neighbours(&v, &mut similarity);
+
+        assert_eq!(similarity.scores.len(), 1);
+    }
+
+    #[test]
+    fn huge_comments_compare_prefix() {
+        let arena = Arena::new();
+
+        let lhs = Syntax::new_comment(&arena, pos_helper(1), "the quick brown fox");
+        let rhs = Syntax::new_comment(&arena, pos_helper(1), "the quick brown cat");
+
+        let mut similarity = CommentSimilarity::new(10);
+        let score = similarity.score(lhs, "the quick brown fox", rhs, "the quick brown cat");
+        assert_eq!(score, 1.0);
+    }
+
+    #[test]
+    fn huge_comments_compare_prefix_multibyte() {
+        let arena = Arena::new();
+
+        let lhs = Syntax::new_comment(&arena, pos_helper(1), "ééé x");
+        let rhs = Syntax::new_comment(&arena, pos_helper(1), "ééé y");
+
+        let mut similarity = CommentSimilarity::new(4);
+        assert_eq!(similarity.score(lhs, "ééé x", rhs, "ééé y"), 1.0);
+
+        assert_eq!(char_prefix("éé", 1), "é");
+        assert_eq!(char_prefix("éé", 5), "éé");
+    }
+
+    #[test]
+    fn graph_size_identical() {
+        let arena = Arena::new();
+
+        let lhs: Vec<&Syntax> = vec![
+            Syntax::new_atom(&arena, pos_helper(0), "foo"),
+            Syntax::new_atom(&arena, pos_helper(1), "bar"),
+        ];
+        let rhs: Vec<&Syntax> = vec![
+            Syntax::new_atom(&arena, pos_helper(0), "foo"),
+            Syntax::new_atom(&arena, pos_helper(1), "bar"),
+        ];
+
+        assert_eq!(estimated_graph_size(&lhs, &rhs), 0);
+    }
+
+    #[test]
+    fn graph_size_all_novel() {
+        let arena = Arena::new();
+
+        let lhs: Vec<&Syntax> = vec![
+            Syntax::new_atom(&arena, pos_helper(0), "a"),
+            Syntax::new_atom(&arena, pos_helper(1), "b"),
+        ];
+        let rhs: Vec<&Syntax> = vec![
+            Syntax::new_atom(&arena, pos_helper(0), "c"),
+            Syntax::new_atom(&arena, pos_helper(1), "d"),
+        ];
+
+        assert_eq!(estimated_graph_size(&lhs, &rhs), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn bidirectional_same_as_forward() {
+        use crate::parse::{find_lang, parse, ConfigDir};
+        use crate::syntax::{change_positions, MatchKind};
+
+        let lang = find_lang(ConfigDir::read_default_toml(), "js").unwrap();
+        let inputs = [
+            ("foo(1, 2);\n", "foo(1, 3);\n"),
+            ("a;\nb;\n", "b;\na;\n"),
+            ("[1, [2, 3], 4]\n", "[[2, 3, 5], 4]\n"),
+            (
+                "// old comment\nx = {a: 1};\n",
+                "// new comment\nx = {a: 1, b: 2};\n",
+            ),
+            ("", "foo();\n"),
+        ];
+        for (lhs_src, rhs_src) in inputs.iter() {
+            // Routes with equal costs can mark different nodes, such as
+            // which of two swapped lines is novel, so only compare how
+            // many positions changed.
+            let changes = |bidirectional| -> (usize, usize) {
+                let arena = Arena::new();
+                let lhs = parse(&arena, lhs_src, lang);
+                let rhs = parse(&arena, rhs_src, lang);
+                init_info(&lhs);
+                init_info(&rhs);
+                let config = SearchConfig {
+                    bidirectional,
+                    ..SearchConfig::default()
+                };
+                let stats = mark_syntax(lhs.first().copied(), rhs.first().copied(), &config);
+                assert!(!stats.timed_out);
+                let num_changed = |positions: Vec<crate::syntax::MatchedPos>| {
+                    positions
+                        .iter()
+                        .filter(|mp| mp.kind != MatchKind::Unchanged)
+                        .count()
+                };
+                (
+                    num_changed(change_positions(lhs_src, rhs_src, &lhs)),
+                    num_changed(change_positions(rhs_src, lhs_src, &rhs)),
+                )
+            };
+            assert_eq!(changes(true), changes(false), "{:?} {:?}", lhs_src, rhs_src);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn parallel_same_as_forward() {
+        use crate::parse::{find_lang, parse, ConfigDir};
+        use crate::syntax::{change_positions, MatchKind};
+
+        let lang = find_lang(ConfigDir::read_default_toml(), "js").unwrap();
+        // Large enough that batches are expanded on several threads.
+        let lhs_src: String = (0..60)
+            .map(|i| format!("f({}, [{}, {}]);\n", i, i, i))
+            .collect();
+        let rhs_src: String = (0..60)
+            .map(|i| format!("f({}, [{}, {}]);\n", i, i + i % 3, i))
+            .collect();
+        // Changed comments are compared on every thread, each with its
+        // own similarity cache.
+        let lhs_comments: String = (0..60)
+            .map(|i| format!("// step {} of the old loop\nf({});\n", i, i))
+            .collect();
+        let rhs_comments: String = (0..60)
+            .map(|i| format!("// step {} of the new loop\nf({});\n", i, i + i % 2))
+            .collect();
+        let inputs = [
+            ("foo(1, 2);\n", "foo(1, 3);\n"),
+            ("a;\nb;\n", "b;\na;\n"),
+            (
+                "// old comment\nx = {a: 1};\n",
+                "// new comment\nx = {a: 1, b: 2};\n",
+            ),
+            ("", "foo();\n"),
+            (&lhs_src, &rhs_src),
+            (&lhs_comments, &rhs_comments),
+        ];
+        for (lhs_src, rhs_src) in inputs.iter() {
+            let changes = |threads| -> Vec<MatchKind> {
+                let arena = Arena::new();
+                let lhs = parse(&arena, lhs_src, lang);
+                let rhs = parse(&arena, rhs_src, lang);
+                init_info(&lhs);
+                init_info(&rhs);
+                let config = SearchConfig {
+                    threads,
+                    ..SearchConfig::default()
+                };
+                let stats = mark_syntax(lhs.first().copied(), rhs.first().copied(), &config);
+                assert!(!stats.timed_out);
+                change_positions(lhs_src, rhs_src, &lhs)
+                    .into_iter()
+                    .chain(change_positions(rhs_src, lhs_src, &rhs))
+                    .map(|mp| mp.kind)
+                    .collect()
+            };
+            // The route doesn't depend on the number of threads, and
+            // changes as many positions as the sequential search.
+            let parallel = changes(2);
+            assert_eq!(parallel, changes(4), "{:?} {:?}", lhs_src, rhs_src);
+            let num_changed =
+                |kinds: &[MatchKind]| kinds.iter().filter(|k| **k != MatchKind::Unchanged).count();
+            assert_eq!(
+                num_changed(&parallel),
+                num_changed(&changes(1)),
+                "{:?} {:?}",
+                lhs_src,
+                rhs_src
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn beam_search_finds_route() {
+        use crate::parse::{find_lang, parse, ConfigDir};
+
+        let lang = find_lang(ConfigDir::read_default_toml(), "js").unwrap();
+        let lhs_src = "foo(1, 2);\nbar([3, 4]);\n";
+        let rhs_src = "foo(1, 3);\nbaz();\nbar([3, 4, 5]);\n";
+        let route_length = |beam_width| {
+            let arena = Arena::new();
+            let lhs = parse(&arena, lhs_src, lang);
+            let rhs = parse(&arena, rhs_src, lang);
+            init_info(&lhs);
+            init_info(&rhs);
+            let config = SearchConfig {
+                beam_width,
+                ..SearchConfig::default()
+            };
+            let stats = mark_syntax(lhs.first().copied(), rhs.first().copied(), &config);
+            assert!(!stats.timed_out);
+            assert!(lhs
+                .iter()
+                .chain(&rhs)
+                .all(|n| n.info().change.get().is_some()));
+            stats.route_length
+        };
+
+        // A wide enough beam keeps every vertex, so it finds the same
+        // route as Dijkstra.
+        assert_eq!(route_length(Some(usize::MAX)), route_length(None));
+        route_length(Some(1));
+    }
+
+    #[test]
+    fn past_deadline_times_out() {
+        let arena = Arena::new();
+        let lhs = Syntax::new_atom(&arena, pos_helper(0), "a");
+        let rhs = Syntax::new_atom(&arena, pos_helper(0), "b");
+        init_info(&[lhs]);
+        init_info(&[rhs]);
+
+        let config = SearchConfig {
+            deadline: Some(Instant::now()),
+            ..SearchConfig::default()
+        };
+        let stats = mark_syntax(Some(lhs), Some(rhs), &config);
+        assert!(stats.timed_out);
+        assert_eq!(stats.route_length, 0);
+        assert_eq!(lhs.info().change.get(), None);
+    }
 }
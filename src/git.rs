@@ -0,0 +1,118 @@
+//! Running git with difftastic as its external diff tool, so git
+//! finds the changed files and difftastic displays them.
+
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::process::Command;
+
+/// A `git` command that runs the difftastic binary at `difft` for
+/// every changed file, using the seven argument form described in
+/// git's documentation of `GIT_EXTERNAL_DIFF`.
+fn git_with_difftastic(difft: &Path) -> Command {
+    let mut command = Command::new("git");
+    command.env("GIT_EXTERNAL_DIFF", difft);
+    // Git pipes output through its pager, so difftastic won't see a
+    // terminal. Keep the colours if we're writing to one.
+    if io::stdout().is_terminal() {
+        command.env("CLICOLOR_FORCE", "1");
+    }
+    command
+}
+
+/// `git log -p` where each commit's changes are shown by
+/// difftastic. Git walks the commits, prints their headers and
+/// detects renames, and `git_args` are passed to `git log` unchanged.
+pub fn log_command(difft: &Path, git_args: &[String]) -> Command {
+    let mut command = git_with_difftastic(difft);
+    command.args(["log", "-p", "--ext-diff"]).args(git_args);
+    command
+}
+
+/// `git diff` between the two refs in `range`, such as `main..HEAD`,
+/// with each changed file shown by difftastic. Git lists the changed
+/// paths, detects renames and fetches both versions of each file.
+pub fn range_diff_command(difft: &Path, range: &str) -> Command {
+    let mut command = git_with_difftastic(difft);
+    command.args(["diff", "--ext-diff", range]);
+    command
+}
+
+/// `git diff` of local changes, shown by difftastic: the staged
+/// changes (HEAD against the index) if `staged`, otherwise the
+/// unstaged changes (the index against the worktree). Git reads the
+/// blobs from its index and HEAD. Only `paths` are shown, if given.
+pub fn local_diff_command(difft: &Path, staged: bool, paths: &[String]) -> Command {
+    let mut command = git_with_difftastic(difft);
+    command.args(["diff", "--ext-diff"]);
+    if staged {
+        command.arg("--cached");
+    }
+    command.arg("--").args(paths);
+    command
+}
+
+/// `git diff` between the two refs in `range` as a plain unified
+/// diff, with enough context that each hunk covers the whole file.
+/// This lets `parse_patch` recover both versions of every file.
+pub fn whole_file_diff_command(range: &str) -> Command {
+    let mut command = Command::new("git");
+    command.args([
+        "diff",
+        "--no-ext-diff",
+        "--no-color",
+        "--unified=100000000",
+        range,
+    ]);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_log_command() {
+        let command = log_command(Path::new("/bin/difft"), &["-3".into(), "src/".into()]);
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, vec!["log", "-p", "--ext-diff", "-3", "src/"]);
+        assert!(command
+            .get_envs()
+            .any(|(key, value)| key == "GIT_EXTERNAL_DIFF"
+                && value == Some(OsStr::new("/bin/difft"))));
+    }
+
+    #[test]
+    fn test_range_diff_command() {
+        let command = range_diff_command(Path::new("/bin/difft"), "v1..v2");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, vec!["diff", "--ext-diff", "v1..v2"]);
+    }
+
+    #[test]
+    fn test_local_diff_command() {
+        let staged = local_diff_command(Path::new("/bin/difft"), true, &["src/".into()]);
+        let args: Vec<_> = staged.get_args().collect();
+        assert_eq!(args, vec!["diff", "--ext-diff", "--cached", "--", "src/"]);
+
+        let unstaged = local_diff_command(Path::new("/bin/difft"), false, &[]);
+        let args: Vec<_> = unstaged.get_args().collect();
+        assert_eq!(args, vec!["diff", "--ext-diff", "--"]);
+    }
+
+    #[test]
+    fn test_whole_file_diff_command() {
+        let command = whole_file_diff_command("v1..v2");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "diff",
+                "--no-ext-diff",
+                "--no-color",
+                "--unified=100000000",
+                "v1..v2"
+            ]
+        );
+    }
+}
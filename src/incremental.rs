@@ -0,0 +1,414 @@
+//! Re-diffing after an edit, for editors that show a structural diff
+//! of a buffer as it's typed. Top-level nodes that were unchanged in
+//! the last diff are anchors, and an edit only re-parses and re-diffs
+//! the region between the anchors either side of it.
+//!
+//! Nodes can't be matched across an anchor, so the result can differ
+//! from diffing the whole buffer when code moves between regions.
+
+use std::ops::Range;
+
+use typed_arena::Arena;
+
+use crate::dijkstra::{estimated_graph_size, mark_syntax, SearchConfig, MAX_ESTIMATED_GRAPH_SIZE};
+use crate::lines::NewlinePositions;
+use crate::parse::{parse_region, Language};
+use crate::positions::ByteSpan;
+use crate::syntax::{
+    change_positions, init_info, mark_moved, slide_novel_runs, ChangeKind, Syntax,
+};
+use crate::{change_regions, diff_trees, ChangeRegion, DiffResult};
+
+/// A diff of two strings that can be updated as either of them is
+/// edited.
+pub struct IncrementalDiff<'l> {
+    lang: &'l Language,
+    lhs: String,
+    rhs: String,
+    /// Top-level nodes that are unchanged, as the bytes of the LHS
+    /// node and the RHS node, in order.
+    anchors: Vec<(ByteSpan, ByteSpan)>,
+    result: DiffResult,
+}
+
+/// The bytes from the start of `node` to its end.
+fn node_bytes(node: &Syntax, nl_pos: &NewlinePositions) -> ByteSpan {
+    let (first, last) = match node {
+        Syntax::List {
+            open_position,
+            close_position,
+            ..
+        } => (open_position.first(), close_position.last()),
+        Syntax::Atom { position, .. } => (position.first(), position.last()),
+    };
+    match (first, last) {
+        (Some(first), Some(last)) => ByteSpan {
+            start: nl_pos.byte_span(first).start,
+            end: nl_pos.byte_span(last).end,
+        },
+        _ => ByteSpan { start: 0, end: 0 },
+    }
+}
+
+/// Is `node`, and everything inside it, unchanged?
+fn fully_unchanged(node: &Syntax) -> bool {
+    if !matches!(node.info().change.get(), Some(ChangeKind::Unchanged(_))) {
+        return false;
+    }
+    match node {
+        Syntax::List { children, .. } => children.iter().all(|child| fully_unchanged(child)),
+        Syntax::Atom { .. } => true,
+    }
+}
+
+/// The top-level nodes in `lhs_nodes` that are unchanged and matched
+/// with a top-level node in `rhs_nodes`, in order on both sides.
+fn find_anchors<'a>(
+    lhs_src: &str,
+    rhs_src: &str,
+    lhs_nodes: &[&'a Syntax<'a>],
+    rhs_nodes: &[&'a Syntax<'a>],
+) -> Vec<(ByteSpan, ByteSpan)> {
+    let lhs_nl_pos = NewlinePositions::from(lhs_src);
+    let rhs_nl_pos = NewlinePositions::from(rhs_src);
+
+    let mut anchors: Vec<(ByteSpan, ByteSpan)> = vec![];
+    for lhs_node in lhs_nodes {
+        let opposite = match lhs_node.info().change.get() {
+            Some(ChangeKind::Unchanged(opposite)) => opposite,
+            _ => continue,
+        };
+        if !rhs_nodes.iter().any(|n| std::ptr::eq(*n, opposite)) || !fully_unchanged(lhs_node) {
+            continue;
+        }
+
+        let lhs_bytes = node_bytes(lhs_node, &lhs_nl_pos);
+        let rhs_bytes = node_bytes(opposite, &rhs_nl_pos);
+        if anchors.last().is_none_or(|(prev_lhs, prev_rhs)| {
+            prev_lhs.end <= lhs_bytes.start && prev_rhs.end <= rhs_bytes.start
+        }) {
+            anchors.push((lhs_bytes, rhs_bytes));
+        }
+    }
+    anchors
+}
+
+/// Move `region` by `delta` bytes, recomputing its line and column
+/// positions in the edited string.
+fn shift_region(region: &mut ChangeRegion, delta: isize, nl_pos: &NewlinePositions) {
+    for (span, bytes) in region.position.iter_mut().zip(region.bytes.iter_mut()) {
+        bytes.start = bytes.start.wrapping_add_signed(delta);
+        bytes.end = bytes.end.wrapping_add_signed(delta);
+        *span = nl_pos.from_byte_span(*bytes);
+    }
+}
+
+/// Replace the changes in `changes` that are in the region
+/// `old_start..old_end` with `new_changes`. If this side was edited,
+/// `shift` is how many bytes later changes move by, and the newlines
+/// of the edited string.
+fn splice_changes(
+    changes: &mut Vec<ChangeRegion>,
+    old_start: usize,
+    old_end: usize,
+    new_changes: Vec<ChangeRegion>,
+    shift: Option<(isize, &NewlinePositions)>,
+) {
+    let region_start = |region: &ChangeRegion| region.bytes.first().map_or(0, |b| b.start);
+    let before = changes
+        .iter()
+        .take_while(|region| region_start(region) < old_start)
+        .count();
+    let after = before
+        + changes[before..]
+            .iter()
+            .take_while(|region| region_start(region) < old_end)
+            .count();
+
+    let mut later: Vec<_> = changes.drain(after..).collect();
+    // Even if the edit didn't change the length, it may have added or
+    // removed newlines.
+    if let Some((delta, nl_pos)) = shift {
+        for region in &mut later {
+            shift_region(region, delta, nl_pos);
+        }
+    }
+    changes.truncate(before);
+    changes.extend(new_changes);
+    changes.extend(later);
+}
+
+impl<'l> IncrementalDiff<'l> {
+    /// Diff `lhs` and `rhs` as `lang`.
+    pub fn new(lhs: String, rhs: String, lang: &'l Language) -> Self {
+        let mut diff = IncrementalDiff {
+            lang,
+            lhs,
+            rhs,
+            anchors: vec![],
+            result: DiffResult {
+                is_syntactic: true,
+                lhs_changes: vec![],
+                rhs_changes: vec![],
+                stats: Default::default(),
+            },
+        };
+        diff.rediff_all();
+        diff
+    }
+
+    /// The changes between the current LHS and RHS.
+    pub fn result(&self) -> &DiffResult {
+        &self.result
+    }
+
+    /// The LHS, with all edits so far.
+    pub fn lhs(&self) -> &str {
+        &self.lhs
+    }
+
+    /// The RHS, with all edits so far.
+    pub fn rhs(&self) -> &str {
+        &self.rhs
+    }
+
+    /// Replace the bytes in `range` of the LHS with `replacement`,
+    /// and update the diff.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` isn't on `char` boundaries, as
+    /// `String::replace_range` does.
+    pub fn edit_lhs(&mut self, range: Range<usize>, replacement: &str) -> &DiffResult {
+        self.edit(true, range, replacement)
+    }
+
+    /// Replace the bytes in `range` of the RHS with `replacement`,
+    /// and update the diff.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` isn't on `char` boundaries, as
+    /// `String::replace_range` does.
+    pub fn edit_rhs(&mut self, range: Range<usize>, replacement: &str) -> &DiffResult {
+        self.edit(false, range, replacement)
+    }
+
+    fn rediff_all(&mut self) {
+        let arena = Arena::new();
+        let (lhs_nodes, rhs_nodes, is_syntactic, stats) =
+            diff_trees(&arena, &self.lhs, &self.rhs, Some(self.lang));
+
+        self.anchors = if is_syntactic {
+            find_anchors(&self.lhs, &self.rhs, &lhs_nodes, &rhs_nodes)
+        } else {
+            vec![]
+        };
+        self.result = DiffResult {
+            is_syntactic,
+            lhs_changes: change_regions(
+                &self.lhs,
+                change_positions(&self.lhs, &self.rhs, &lhs_nodes),
+            ),
+            rhs_changes: change_regions(
+                &self.rhs,
+                change_positions(&self.rhs, &self.lhs, &rhs_nodes),
+            ),
+            stats,
+        };
+    }
+
+    fn edit(&mut self, is_lhs: bool, range: Range<usize>, replacement: &str) -> &DiffResult {
+        let delta = replacement.len() as isize - range.len() as isize;
+        let edited = if is_lhs { &mut self.lhs } else { &mut self.rhs };
+        let old_len = edited.len();
+        edited.replace_range(range.clone(), replacement);
+
+        if !self.result.is_syntactic {
+            self.rediff_all();
+            return &self.result;
+        }
+
+        // Anchors that the edit touches are no longer known to be
+        // unchanged, so the region to re-diff runs from the anchor
+        // before the first of them to the anchor after the last.
+        let edited_side = |anchor: &(ByteSpan, ByteSpan)| if is_lhs { anchor.0 } else { anchor.1 };
+        let first = self
+            .anchors
+            .iter()
+            .take_while(|anchor| edited_side(anchor).end < range.start)
+            .count();
+        let after = first
+            + self.anchors[first..]
+                .iter()
+                .take_while(|anchor| edited_side(anchor).start <= range.end)
+                .count();
+
+        let (lhs_start, rhs_start) = match first.checked_sub(1) {
+            Some(i) => (self.anchors[i].0.end, self.anchors[i].1.end),
+            None => (0, 0),
+        };
+        // The end of the region before the edit.
+        let (old_lhs_end, old_rhs_end) = match self.anchors.get(after) {
+            Some((lhs_bytes, rhs_bytes)) => (lhs_bytes.start, rhs_bytes.start),
+            None if is_lhs => (old_len, self.rhs.len()),
+            None => (self.lhs.len(), old_len),
+        };
+        let (lhs_end, rhs_end) = if is_lhs {
+            (old_lhs_end.wrapping_add_signed(delta), old_rhs_end)
+        } else {
+            (old_lhs_end, old_rhs_end.wrapping_add_signed(delta))
+        };
+
+        let arena = Arena::new();
+        let lhs_nodes = parse_region(&arena, &self.lhs, lhs_start, lhs_end, self.lang);
+        let rhs_nodes = parse_region(&arena, &self.rhs, rhs_start, rhs_end, self.lang);
+        let (lhs_nodes, rhs_nodes) = match (lhs_nodes, rhs_nodes) {
+            (Some(lhs_nodes), Some(rhs_nodes))
+                if estimated_graph_size(&lhs_nodes, &rhs_nodes) <= MAX_ESTIMATED_GRAPH_SIZE =>
+            {
+                (lhs_nodes, rhs_nodes)
+            }
+            _ => {
+                self.rediff_all();
+                return &self.result;
+            }
+        };
+
+        init_info(&lhs_nodes);
+        init_info(&rhs_nodes);
+        let stats = mark_syntax(
+            lhs_nodes.first().copied(),
+            rhs_nodes.first().copied(),
+            &SearchConfig::default(),
+        );
+        slide_novel_runs(&lhs_nodes);
+        slide_novel_runs(&rhs_nodes);
+        mark_moved(&lhs_nodes, &rhs_nodes);
+
+        let edited_nl_pos =
+            NewlinePositions::from(if is_lhs { &self.lhs } else { &self.rhs }.as_str());
+        let (lhs_shift, rhs_shift) = if is_lhs {
+            (Some((delta, &edited_nl_pos)), None)
+        } else {
+            (None, Some((delta, &edited_nl_pos)))
+        };
+        splice_changes(
+            &mut self.result.lhs_changes,
+            lhs_start,
+            old_lhs_end,
+            change_regions(
+                &self.lhs,
+                change_positions(&self.lhs, &self.rhs, &lhs_nodes),
+            ),
+            lhs_shift,
+        );
+        splice_changes(
+            &mut self.result.rhs_changes,
+            rhs_start,
+            old_rhs_end,
+            change_regions(
+                &self.rhs,
+                change_positions(&self.rhs, &self.lhs, &rhs_nodes),
+            ),
+            rhs_shift,
+        );
+        self.result.stats = stats;
+
+        let mut later: Vec<_> = self.anchors.drain(after..).collect();
+        for (lhs_bytes, rhs_bytes) in &mut later {
+            let bytes = if is_lhs { lhs_bytes } else { rhs_bytes };
+            bytes.start = bytes.start.wrapping_add_signed(delta);
+            bytes.end = bytes.end.wrapping_add_signed(delta);
+        }
+        self.anchors.truncate(first);
+        self.anchors
+            .extend(find_anchors(&self.lhs, &self.rhs, &lhs_nodes, &rhs_nodes));
+        self.anchors.extend(later);
+
+        &self.result
+    }
+}
+
+#[cfg(all(test, feature = "lang-rust", feature = "lang-javascript"))]
+mod tests {
+    use super::*;
+    use crate::diff_strings;
+    use crate::parse::{find_lang, ConfigDir};
+
+    fn assert_same_as_full_diff(diff: &IncrementalDiff) {
+        let full = diff_strings(diff.lhs(), diff.rhs(), diff.lang);
+        assert_eq!(diff.result().lhs_changes, full.lhs_changes);
+        assert_eq!(diff.result().rhs_changes, full.rhs_changes);
+    }
+
+    #[test]
+    fn test_edit_rhs() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "rs").unwrap();
+        let src = "fn a() { 1 }\n\nfn b() { 2 }\n\nfn c() { 3 }\n";
+        let mut diff = IncrementalDiff::new(src.into(), src.into(), lang);
+        assert!(diff.result().rhs_changes.is_empty());
+        // Every top-level token is an anchor.
+        let num_anchors = diff.anchors.len();
+        assert_eq!(num_anchors, 12);
+
+        // Change the body of `b`, so only its `{ 2 }` is re-diffed.
+        let two = diff.rhs().find('2').unwrap();
+        diff.edit_rhs(two..two + 1, "20");
+        assert_same_as_full_diff(&diff);
+        assert_eq!(diff.result().rhs_changes.len(), 1);
+        assert_eq!(diff.result().rhs_changes[0].bytes[0].text(diff.rhs()), "20");
+        assert_eq!(diff.anchors.len(), num_anchors - 1);
+
+        // Insert lines before `a`, which moves the change in `b`.
+        diff.edit_rhs(0..0, "use x;\n\n");
+        assert_same_as_full_diff(&diff);
+        assert_eq!(diff.result().rhs_changes.len(), 4);
+
+        // Undo the change to `b`, so it's an anchor again.
+        let twenty = diff.rhs().find("20").unwrap();
+        diff.edit_rhs(twenty..twenty + 2, "2");
+        assert_same_as_full_diff(&diff);
+        assert_eq!(diff.anchors.len(), num_anchors);
+    }
+
+    #[test]
+    fn test_edit_lhs() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "rs").unwrap();
+        let src = "fn a() { 1 }\n\nfn b() { 2 }\n";
+        let mut diff = IncrementalDiff::new(src.into(), src.into(), lang);
+
+        diff.edit_lhs(0..0, "// old\n");
+        assert_same_as_full_diff(&diff);
+        assert_eq!(diff.result().lhs_changes.len(), 1);
+        assert_eq!(diff.result().lhs_changes[0].position[0].line.0, 0);
+    }
+
+    #[test]
+    fn test_edit_unbalanced_falls_back() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "rs").unwrap();
+        let src = "fn a() { 1 }\n\nfn b() { 2 }\n\nfn c() { 3 }\n";
+        let mut diff = IncrementalDiff::new(src.into(), src.into(), lang);
+
+        // An unclosed `{` swallows the following functions, so the
+        // region around `a` can't be parsed on its own.
+        let one = diff.rhs().find('1').unwrap();
+        diff.edit_rhs(one..one, "{");
+        assert_same_as_full_diff(&diff);
+
+        // Likewise an unterminated string.
+        diff.edit_rhs(0..0, "\"");
+        assert_same_as_full_diff(&diff);
+    }
+
+    #[test]
+    fn test_region_regex_literal() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "js").unwrap();
+        let src = "x = 1;\n\ny = 2;\n";
+        let mut diff = IncrementalDiff::new(src.into(), src.into(), lang);
+
+        let two = diff.rhs().find('2').unwrap();
+        diff.edit_rhs(two..two + 1, "a / b / c");
+        assert_same_as_full_diff(&diff);
+    }
+}
@@ -0,0 +1,208 @@
+//! Difftastic compares files using their syntax. `diff_strings` is
+//! the simplest way to diff two strings as a library.
+
+pub mod batch;
+pub mod calibrate;
+pub mod config;
+pub mod dijkstra;
+pub mod git;
+pub mod incremental;
+pub mod intervals;
+pub mod lines;
+pub mod links;
+pub mod merge;
+pub mod parse;
+pub mod patch;
+pub mod positions;
+#[cfg(feature = "pyo3")]
+mod python;
+pub mod report;
+pub mod serialize;
+pub mod serve;
+pub mod style;
+pub mod summary;
+pub mod syntax;
+
+use serde::Serialize;
+use typed_arena::Arena;
+
+use crate::dijkstra::{
+    estimated_graph_size, mark_syntax, SearchConfig, SearchStats, MAX_ESTIMATED_GRAPH_SIZE,
+};
+use crate::lines::NewlinePositions;
+use crate::parse::{parse_lines, parse_pair, Language};
+use crate::positions::{ByteSpan, SingleLineSpan};
+use crate::syntax::{
+    change_positions, init_info, mark_moved, slide_novel_runs, MatchKind, MatchedPos, Syntax,
+};
+
+/// How a region of a file has changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeRegionKind {
+    /// Added on the RHS, or removed from the LHS.
+    Novel,
+    /// Unchanged, but reordered relative to its siblings.
+    Moved,
+    /// Words that differ between two similar comments.
+    ChangedComment,
+}
+
+/// A changed token or comment word. Multi-line tokens have one span
+/// per line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChangeRegion {
+    pub kind: ChangeRegionKind,
+    pub position: Vec<SingleLineSpan>,
+    /// The bytes of each span in `position`, for slicing the input.
+    pub bytes: Vec<ByteSpan>,
+}
+
+/// The changes between two strings. Unlike `Syntax` trees, this owns
+/// all its data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiffResult {
+    /// False if the strings were too different to diff syntactically,
+    /// so the result is a line diff.
+    pub is_syntactic: bool,
+    /// The changed regions of the LHS, in order.
+    pub lhs_changes: Vec<ChangeRegion>,
+    /// The changed regions of the RHS, in order.
+    pub rhs_changes: Vec<ChangeRegion>,
+    /// Counters from the graph search.
+    pub stats: SearchStats,
+}
+
+fn change_regions(src: &str, positions: Vec<MatchedPos>) -> Vec<ChangeRegion> {
+    let newline_positions = NewlinePositions::from(src);
+    positions
+        .into_iter()
+        .filter_map(|mp| {
+            let kind = match mp.kind {
+                MatchKind::Novel => ChangeRegionKind::Novel,
+                MatchKind::Moved => ChangeRegionKind::Moved,
+                MatchKind::ChangedCommentPart => ChangeRegionKind::ChangedComment,
+                MatchKind::Unchanged | MatchKind::UnchangedCommentPart => return None,
+            };
+            Some(ChangeRegion {
+                kind,
+                bytes: mp
+                    .pos
+                    .iter()
+                    .map(|span| newline_positions.byte_span(span))
+                    .collect(),
+                position: mp.pos,
+            })
+        })
+        .collect()
+}
+
+/// Parse and diff `lhs` and `rhs`, marking the change on every node.
+/// Without a language, or if the inputs are too different to diff
+/// syntactically, this is a line diff. Returns the syntax trees,
+/// whether the diff is syntactic, and counters from the search.
+pub(crate) fn diff_trees<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    lhs: &'a str,
+    rhs: &'a str,
+    lang: Option<&Language>,
+) -> (Vec<&'a Syntax<'a>>, Vec<&'a Syntax<'a>>, bool, SearchStats) {
+    let (mut lhs_nodes, mut rhs_nodes) = match lang {
+        Some(lang) => parse_pair(arena, lhs, rhs, lang),
+        None => (parse_lines(arena, lhs), parse_lines(arena, rhs)),
+    };
+
+    let mut is_syntactic = lang.is_some();
+    if is_syntactic && estimated_graph_size(&lhs_nodes, &rhs_nodes) > MAX_ESTIMATED_GRAPH_SIZE {
+        lhs_nodes = parse_lines(arena, lhs);
+        rhs_nodes = parse_lines(arena, rhs);
+        is_syntactic = false;
+    }
+
+    init_info(&lhs_nodes);
+    init_info(&rhs_nodes);
+    let stats = mark_syntax(
+        lhs_nodes.first().copied(),
+        rhs_nodes.first().copied(),
+        &SearchConfig::default(),
+    );
+    if is_syntactic {
+        slide_novel_runs(&lhs_nodes);
+        slide_novel_runs(&rhs_nodes);
+        mark_moved(&lhs_nodes, &rhs_nodes);
+    }
+
+    (lhs_nodes, rhs_nodes, is_syntactic, stats)
+}
+
+/// Diff `lhs` and `rhs` as `lang`.
+pub fn diff_strings(lhs: &str, rhs: &str, lang: &Language) -> DiffResult {
+    let arena = Arena::new();
+    let (lhs_nodes, rhs_nodes, is_syntactic, stats) = diff_trees(&arena, lhs, rhs, Some(lang));
+
+    DiffResult {
+        is_syntactic,
+        lhs_changes: change_regions(lhs, change_positions(lhs, rhs, &lhs_nodes)),
+        rhs_changes: change_regions(rhs, change_positions(rhs, lhs, &rhs_nodes)),
+        stats,
+    }
+}
+
+#[cfg(all(test, feature = "lang-json"))]
+mod tests {
+    use super::*;
+    use crate::parse::{find_lang, ConfigDir};
+
+    #[test]
+    fn test_diff_strings() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let result = diff_strings("[1, 2]", "[1, 3]", lang);
+
+        assert!(result.is_syntactic);
+        assert!(result.stats.route_length > 0);
+        assert!(result.stats.heap_pushes >= result.stats.predecessors);
+        let span = |start_col| SingleLineSpan {
+            line: 0.into(),
+            start_col,
+            end_col: start_col + 1,
+        };
+        assert_eq!(
+            result.lhs_changes,
+            vec![ChangeRegion {
+                kind: ChangeRegionKind::Novel,
+                position: vec![span(4)],
+                bytes: vec![ByteSpan { start: 4, end: 5 }],
+            }]
+        );
+        assert_eq!(
+            result.rhs_changes,
+            vec![ChangeRegion {
+                kind: ChangeRegionKind::Novel,
+                position: vec![span(4)],
+                bytes: vec![ByteSpan { start: 4, end: 5 }],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_strings_bytes() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let rhs = "[\r\n  1,\r\n  \"a\nb\"\r\n]";
+        let result = diff_strings("[\r\n  1\r\n]", rhs, lang);
+
+        let changed: Vec<_> = result
+            .rhs_changes
+            .iter()
+            .flat_map(|region| region.bytes.iter().map(|span| span.text(rhs)))
+            .collect();
+        assert_eq!(changed, vec![",", "\"a", "b\""]);
+    }
+
+    #[test]
+    fn test_diff_strings_unchanged() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let result = diff_strings("[1, 2]", "[1,\n 2]", lang);
+        assert!(result.lhs_changes.is_empty());
+        assert!(result.rhs_changes.is_empty());
+    }
+}
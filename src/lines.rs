@@ -1,20 +1,22 @@
 use crate::intervals::Interval;
-use crate::positions::SingleLineSpan;
+use crate::positions::{ByteSpan, SingleLineSpan, Spans};
 use crate::syntax::{aligned_lines, MatchKind, MatchedPos};
-use lazy_static::lazy_static;
-use regex::Regex;
+use colored::*;
+use serde::Serialize;
 use std::cmp::{max, min, Ordering};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 
 const SPACER: &str = "  ";
 const MAX_GAP: usize = 1;
 const MIN_WIDTH: usize = 35;
 
+#[cfg(test)]
+use crate::syntax::TokenKind;
 #[cfg(test)]
 use pretty_assertions::assert_eq;
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
 pub struct LineNumber(pub usize);
 
 impl fmt::Debug for LineNumber {
@@ -45,37 +47,31 @@ impl LineGroup {
 
     fn lhs_lines(&self) -> Vec<LineNumber> {
         let mut res = vec![];
-        match &self.lhs_lines {
-            Some(lhs_lines) => {
-                if lhs_lines.is_empty() {
-                    return vec![];
-                }
+        if let Some(lhs_lines) = &self.lhs_lines {
+            if lhs_lines.is_empty() {
+                return vec![];
+            }
 
-                for line in lhs_lines.start.0..=lhs_lines.end.0 {
-                    res.push(line.into());
-                }
+            for line in lhs_lines.start.0..=lhs_lines.end.0 {
+                res.push(line.into());
             }
-            None => {}
         }
         res
     }
 
     fn rhs_lines(&self) -> Vec<LineNumber> {
         let mut res = vec![];
-        match &self.rhs_lines {
-            Some(rhs_lines) => {
-                // TODO: it's not possible to represent an empty range
-                // with RangeInclusive and LineNumber. Define a
-                // separate interval type.
-                if rhs_lines.end.0 == 0 {
-                    return vec![];
-                }
+        if let Some(rhs_lines) = &self.rhs_lines {
+            // TODO: it's not possible to represent an empty range
+            // with RangeInclusive and LineNumber. Define a
+            // separate interval type.
+            if rhs_lines.end.0 == 0 {
+                return vec![];
+            }
 
-                for line in rhs_lines.start.0..=rhs_lines.end.0 {
-                    res.push(line.into());
-                }
+            for line in rhs_lines.start.0..=rhs_lines.end.0 {
+                res.push(line.into());
             }
-            None => {}
         }
         res
     }
@@ -217,6 +213,49 @@ impl LineGroup {
         self.add_lhs_pos(&mp.prev_opposite_pos);
     }
 
+    /// The lines in this group that have changes on the LHS and the
+    /// RHS, in ascending order.
+    pub fn changed_lines(
+        &self,
+        lhs_positions: &[MatchedPos],
+        rhs_positions: &[MatchedPos],
+    ) -> (Vec<LineNumber>, Vec<LineNumber>) {
+        let lines_within = |positions, lines: &Option<Interval<LineNumber>>| {
+            let mut res: Vec<LineNumber> = match lines {
+                Some(lines) => changed_spans(positions)
+                    .map(|span| span.line)
+                    .filter(|line| lines.start <= *line && *line <= lines.end)
+                    .collect(),
+                None => vec![],
+            };
+            res.sort_unstable();
+            res.dedup();
+            res
+        };
+        (
+            lines_within(lhs_positions, &self.lhs_lines),
+            lines_within(rhs_positions, &self.rhs_lines),
+        )
+    }
+
+    /// The lines in this group, with each LHS line next to the RHS
+    /// line it matches. If only one side has lines, such as when a
+    /// file has been added, every line is shown unpaired.
+    pub fn aligned_lines(
+        &self,
+        lhs_line_matches: &HashMap<LineNumber, LineNumber>,
+    ) -> Vec<(Option<LineNumber>, Option<LineNumber>)> {
+        let lhs_lines = self.lhs_lines();
+        let rhs_lines = self.rhs_lines();
+        if lhs_lines.is_empty() {
+            return rhs_lines.into_iter().map(|rhs| (None, Some(rhs))).collect();
+        }
+        if rhs_lines.is_empty() {
+            return lhs_lines.into_iter().map(|lhs| (Some(lhs), None)).collect();
+        }
+        aligned_lines(&lhs_lines, &rhs_lines, lhs_line_matches)
+    }
+
     pub fn max_visible_lhs(&self) -> LineNumber {
         match &self.lhs_lines {
             Some(lhs_lines) => lhs_lines.end,
@@ -239,6 +278,9 @@ fn compare_matched_pos(lhs: &MatchedPos, rhs: &MatchedPos) -> Ordering {
     lhs_line.cmp(&rhs_line)
 }
 
+/// The number of unchanged lines shown around each change.
+pub const CONTEXT_LINES: usize = 3;
+
 pub fn join_overlapping(line_groups: Vec<LineGroup>) -> Vec<LineGroup> {
     let mut res = vec![];
 
@@ -321,10 +363,201 @@ pub fn visible_groups(
     groups
 }
 
+/// The index of the scope in `scopes` containing the first changed
+/// line, or the `fallback` line if there are no changes.
+fn scope_of(
+    scopes: &[(LineNumber, LineNumber)],
+    changed: &[LineNumber],
+    fallback: Option<LineNumber>,
+) -> Option<usize> {
+    let line = changed.first().copied().or(fallback)?;
+    scopes
+        .iter()
+        .position(|(first, last)| *first <= line && line <= *last)
+}
+
+/// Merge consecutive groups whose changes are in the same scope, such
+/// as a top-level function, so all the changes to a function are
+/// shown together even if they're separated by unchanged lines.
+///
+/// A group's scope is the one containing its first changed line. If a
+/// side has no changes, such as a deletion, the line it's opposite is
+/// used instead.
+pub fn join_scopes(
+    groups: Vec<LineGroup>,
+    lhs_positions: &[MatchedPos],
+    rhs_positions: &[MatchedPos],
+    lhs_scopes: &[(LineNumber, LineNumber)],
+    rhs_scopes: &[(LineNumber, LineNumber)],
+) -> Vec<LineGroup> {
+    let mut res: Vec<(LineGroup, Option<usize>, Option<usize>)> = vec![];
+    for group in groups {
+        let (lhs_changed, rhs_changed) = group.changed_lines(lhs_positions, rhs_positions);
+        let lhs_scope = scope_of(
+            lhs_scopes,
+            &lhs_changed,
+            group.lhs_lines.as_ref().map(|lines| lines.end),
+        );
+        let rhs_scope = scope_of(
+            rhs_scopes,
+            &rhs_changed,
+            group.rhs_lines.as_ref().map(|lines| lines.end),
+        );
+
+        match res.last_mut() {
+            Some((prev, prev_lhs_scope, prev_rhs_scope))
+                if (lhs_scope.is_some() && lhs_scope == *prev_lhs_scope)
+                    || (rhs_scope.is_some() && rhs_scope == *prev_rhs_scope) =>
+            {
+                prev.next_extend(&group);
+                *prev_lhs_scope = prev_lhs_scope.or(lhs_scope);
+                *prev_rhs_scope = prev_rhs_scope.or(rhs_scope);
+            }
+            _ => res.push((group, lhs_scope, rhs_scope)),
+        }
+    }
+
+    res.into_iter().map(|(group, _, _)| group).collect()
+}
+
+/// If the padded lines of two groups overlap, move the boundary
+/// between them to the middle of the unchanged lines separating their
+/// changes.
+fn split_overlap(
+    prev: &mut Interval<LineNumber>,
+    next: &mut Interval<LineNumber>,
+    prev_last_changed: Option<LineNumber>,
+    next_first_changed: Option<LineNumber>,
+) {
+    if next.start > prev.end {
+        return;
+    }
+    let mid = match (prev_last_changed, next_first_changed) {
+        (Some(last), Some(first)) if last < first => (last.0 + first.0) / 2,
+        _ => (next.start.0 + prev.end.0) / 2,
+    };
+    if prev.start.0 <= mid && mid < next.end.0 {
+        prev.end = mid.into();
+        next.start = (mid + 1).into();
+    }
+}
+
+/// A group that has been padded with context, and the scopes and last
+/// changed lines of its changes before padding.
+struct PaddedGroup {
+    group: LineGroup,
+    lhs_scope: Option<usize>,
+    rhs_scope: Option<usize>,
+    lhs_last_changed: Option<LineNumber>,
+    rhs_last_changed: Option<LineNumber>,
+}
+
+/// Pad each group with `CONTEXT_LINES` of context, and merge groups
+/// whose padded lines overlap or touch, like `join_overlapping`.
+///
+/// Groups whose changes are in different scopes are kept apart, so
+/// the groups from `join_scopes` stay separate hunks even if they're
+/// in adjacent functions. The context lines that both groups would
+/// show are split between them.
+pub fn pad_scopes(
+    groups: Vec<LineGroup>,
+    max_lhs_line: LineNumber,
+    max_rhs_line: LineNumber,
+    lhs_positions: &[MatchedPos],
+    rhs_positions: &[MatchedPos],
+    lhs_scopes: &[(LineNumber, LineNumber)],
+    rhs_scopes: &[(LineNumber, LineNumber)],
+) -> Vec<LineGroup> {
+    let different = |x: Option<usize>, y: Option<usize>| x.is_some() && y.is_some() && x != y;
+
+    let mut res: Vec<PaddedGroup> = vec![];
+    for mut group in groups {
+        // Find scopes before padding, which may add lines with
+        // changes from the neighbouring groups.
+        let (lhs_changed, rhs_changed) = group.changed_lines(lhs_positions, rhs_positions);
+        let lhs_scope = scope_of(lhs_scopes, &lhs_changed, None);
+        let rhs_scope = scope_of(rhs_scopes, &rhs_changed, None);
+        group.pad(CONTEXT_LINES, max_lhs_line, max_rhs_line);
+
+        match res.last_mut() {
+            Some(prev)
+                if prev.group.next_lg_touches(&group)
+                    && !different(prev.lhs_scope, lhs_scope)
+                    && !different(prev.rhs_scope, rhs_scope) =>
+            {
+                prev.group.next_extend(&group);
+                prev.lhs_scope = prev.lhs_scope.or(lhs_scope);
+                prev.rhs_scope = prev.rhs_scope.or(rhs_scope);
+                prev.lhs_last_changed = lhs_changed.last().copied().or(prev.lhs_last_changed);
+                prev.rhs_last_changed = rhs_changed.last().copied().or(prev.rhs_last_changed);
+                continue;
+            }
+            Some(prev) => {
+                if let (Some(prev_lines), Some(lines)) =
+                    (&mut prev.group.lhs_lines, &mut group.lhs_lines)
+                {
+                    split_overlap(
+                        prev_lines,
+                        lines,
+                        prev.lhs_last_changed,
+                        lhs_changed.first().copied(),
+                    );
+                }
+                if let (Some(prev_lines), Some(lines)) =
+                    (&mut prev.group.rhs_lines, &mut group.rhs_lines)
+                {
+                    split_overlap(
+                        prev_lines,
+                        lines,
+                        prev.rhs_last_changed,
+                        rhs_changed.first().copied(),
+                    );
+                }
+            }
+            None => {}
+        }
+        res.push(PaddedGroup {
+            group,
+            lhs_scope,
+            rhs_scope,
+            lhs_last_changed: lhs_changed.last().copied(),
+            rhs_last_changed: rhs_changed.last().copied(),
+        });
+    }
+
+    res.into_iter().map(|padded| padded.group).collect()
+}
+
+#[test]
+fn test_join_scopes() {
+    let novel = |line: usize| MatchedPos {
+        kind: MatchKind::Novel,
+        token_kind: TokenKind::Other,
+        pos: vec![SingleLineSpan {
+            line: line.into(),
+            start_col: 0,
+            end_col: 1,
+        }],
+        prev_opposite_pos: vec![],
+    };
+    let rhs_positions = vec![novel(1), novel(5), novel(9)];
+    let groups = visible_groups(&[], &rhs_positions);
+    assert_eq!(groups.len(), 3);
+
+    let rhs_scopes = vec![(0.into(), 6.into()), (8.into(), 10.into())];
+    let groups = join_scopes(groups, &[], &rhs_positions, &[], &rhs_scopes);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(
+        groups[0].rhs_lines(),
+        vec![1.into(), 2.into(), 3.into(), 4.into(), 5.into()]
+    );
+}
+
 #[test]
 fn test_visible_groups_ignores_unchanged() {
     let lhs_positions = vec![MatchedPos {
         kind: MatchKind::Unchanged,
+        token_kind: TokenKind::Other,
         pos: vec![SingleLineSpan {
             line: 1.into(),
             start_col: 0,
@@ -334,6 +567,7 @@ fn test_visible_groups_ignores_unchanged() {
     }];
     let rhs_positions = vec![MatchedPos {
         kind: MatchKind::Unchanged,
+        token_kind: TokenKind::Other,
         pos: vec![SingleLineSpan {
             line: 1.into(),
             start_col: 0,
@@ -346,6 +580,367 @@ fn test_visible_groups_ignores_unchanged() {
     assert_eq!(res, vec![]);
 }
 
+/// The lines of `positions` that contain changes, as sorted, inclusive
+/// ranges.
+pub fn changed_line_ranges(positions: &[MatchedPos]) -> Vec<(LineNumber, LineNumber)> {
+    let mut lines: Vec<LineNumber> = changed_spans(positions).map(|span| span.line).collect();
+    lines.sort_unstable();
+    lines.dedup();
+
+    let mut ranges: Vec<(LineNumber, LineNumber)> = vec![];
+    for line in lines {
+        match ranges.last_mut() {
+            Some((_, end)) if end.0 + 1 == line.0 => *end = line,
+            _ => ranges.push((line, line)),
+        }
+    }
+    ranges
+}
+
+/// Format line ranges as `10-14,20`, with 1-indexed line numbers.
+pub fn format_line_ranges(ranges: &[(LineNumber, LineNumber)]) -> String {
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                format!("{}", start.0 + 1)
+            } else {
+                format!("{}-{}", start.0 + 1, end.0 + 1)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[test]
+fn test_changed_line_ranges() {
+    let pos = |kind, line: usize| MatchedPos {
+        kind,
+        token_kind: TokenKind::Other,
+        pos: vec![SingleLineSpan {
+            line: line.into(),
+            start_col: 0,
+            end_col: 1,
+        }],
+        prev_opposite_pos: vec![],
+    };
+    let positions = vec![
+        pos(MatchKind::Novel, 9),
+        pos(MatchKind::Novel, 10),
+        pos(MatchKind::Unchanged, 12),
+        pos(MatchKind::Moved, 11),
+        pos(MatchKind::Novel, 11),
+        pos(MatchKind::UnchangedCommentPart, 15),
+        pos(MatchKind::ChangedCommentPart, 19),
+    ];
+    let ranges = changed_line_ranges(&positions);
+    assert_eq!(format_line_ranges(&ranges), "10-12,20");
+    assert_eq!(format_line_ranges(&[]), "");
+}
+
+/// The lines and columns of `positions` that are changes.
+fn changed_spans(positions: &[MatchedPos]) -> impl Iterator<Item = &SingleLineSpan> {
+    positions
+        .iter()
+        .filter(|mp| {
+            !matches!(
+                mp.kind,
+                MatchKind::Unchanged | MatchKind::UnchangedCommentPart
+            )
+        })
+        .flat_map(|mp| &mp.pos)
+}
+
+/// The first and last changed line of `positions` within `lines`.
+fn changed_lines_within(
+    positions: &[MatchedPos],
+    lines: &Option<Interval<LineNumber>>,
+) -> Option<(LineNumber, LineNumber)> {
+    let lines = lines.as_ref()?;
+    changed_spans(positions)
+        .map(|span| span.line)
+        .filter(|line| lines.start <= *line && *line <= lines.end)
+        .fold(None, |range, line| match range {
+            Some((first, last)) => Some((min(first, line), max(last, line))),
+            None => Some((line, line)),
+        })
+}
+
+/// Describe each group as `path:line:col: message`, using the first
+/// change on the RHS. This matches Vim's default `errorformat`, so the
+/// output can populate a quickfix list.
+pub fn format_quickfix(
+    path: &str,
+    groups: &[LineGroup],
+    lhs_positions: &[MatchedPos],
+    rhs_positions: &[MatchedPos],
+) -> String {
+    let mut res = String::new();
+    for group in groups {
+        let lhs_changed = changed_lines_within(lhs_positions, &group.lhs_lines);
+        let rhs_changed = changed_lines_within(rhs_positions, &group.rhs_lines);
+        let (line, message) = match (lhs_changed, rhs_changed) {
+            (Some((lhs_first, lhs_last)), Some((rhs_first, rhs_last))) => (
+                rhs_first,
+                format!(
+                    "changed {}, previously {}",
+                    describe_lines(rhs_first, rhs_last),
+                    describe_lines(lhs_first, lhs_last)
+                ),
+            ),
+            (None, Some((rhs_first, rhs_last))) => (
+                rhs_first,
+                format!("added {}", describe_lines(rhs_first, rhs_last)),
+            ),
+            (Some((lhs_first, lhs_last)), None) => (
+                // Point at where the removed lines would have been.
+                group.rhs_lines.as_ref().map_or(0.into(), |lines| lines.end),
+                format!("removed {}", describe_lines(lhs_first, lhs_last)),
+            ),
+            (None, None) => continue,
+        };
+        let col = changed_spans(rhs_positions)
+            .filter(|span| span.line == line)
+            .map(|span| span.start_col)
+            .min()
+            .unwrap_or(0);
+        res.push_str(&format!(
+            "{}:{}:{}: {}\n",
+            path,
+            line.0 + 1,
+            col + 1,
+            message
+        ));
+    }
+    res
+}
+
+#[test]
+fn test_format_quickfix() {
+    let span = |line: usize, start_col| SingleLineSpan {
+        line: line.into(),
+        start_col,
+        end_col: start_col + 1,
+    };
+    let lhs_positions = vec![MatchedPos {
+        kind: MatchKind::Novel,
+        token_kind: TokenKind::Other,
+        pos: vec![span(1, 0)],
+        prev_opposite_pos: vec![span(1, 0)],
+    }];
+    let rhs_positions = vec![
+        MatchedPos {
+            kind: MatchKind::Novel,
+            token_kind: TokenKind::Other,
+            pos: vec![span(2, 4)],
+            prev_opposite_pos: vec![span(1, 0)],
+        },
+        MatchedPos {
+            kind: MatchKind::Novel,
+            token_kind: TokenKind::Other,
+            pos: vec![span(9, 0)],
+            prev_opposite_pos: vec![span(5, 0)],
+        },
+    ];
+    let groups = visible_groups(&lhs_positions, &rhs_positions);
+    assert_eq!(
+        format_quickfix("foo.rs", &groups, &lhs_positions, &rhs_positions),
+        "foo.rs:3:5: changed line 3, previously line 2\nfoo.rs:10:1: added line 10\n"
+    );
+}
+
+/// Emacs buffer positions, which count characters from 1, for byte
+/// offsets on each line of `src`.
+struct BufferPositions<'a> {
+    lines: Vec<&'a str>,
+    /// The buffer position of the start of each line.
+    line_starts: Vec<usize>,
+}
+
+impl<'a> BufferPositions<'a> {
+    fn new(src: &'a str) -> Self {
+        let lines: Vec<_> = src.split('\n').collect();
+        let mut line_starts = Vec::with_capacity(lines.len());
+        let mut pos = 1;
+        for line in &lines {
+            line_starts.push(pos);
+            pos += line.chars().count() + 1;
+        }
+        BufferPositions { lines, line_starts }
+    }
+
+    fn position(&self, line: LineNumber, col: usize) -> usize {
+        let start = self.line_starts.get(line.0).copied().unwrap_or(1);
+        let line = self.lines.get(line.0).copied().unwrap_or("");
+        let col = min(col, line.len());
+        match line.get(..col) {
+            Some(prefix) => start + prefix.chars().count(),
+            None => start + col,
+        }
+    }
+}
+
+/// Quote `s` as an Emacs Lisp string.
+fn elisp_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Describe each changed region as a plist that Emacs can `read`, one
+/// per line, e.g. `(:file "foo.rs" :side new :kind novel :line 3
+/// :begin 42 :end 45)`. Positions are buffer positions, so they can be
+/// passed straight to `make-overlay`.
+pub fn format_emacs(
+    path: &str,
+    lhs_src: &str,
+    rhs_src: &str,
+    lhs_positions: &[MatchedPos],
+    rhs_positions: &[MatchedPos],
+) -> String {
+    let mut res = String::new();
+    for (side, src, positions) in [
+        ("old", lhs_src, lhs_positions),
+        ("new", rhs_src, rhs_positions),
+    ] {
+        let buffer_positions = BufferPositions::new(src);
+        for mp in positions {
+            let kind = match mp.kind {
+                MatchKind::Novel => "novel",
+                MatchKind::Moved => "moved",
+                MatchKind::ChangedCommentPart => "changed-comment",
+                MatchKind::Unchanged | MatchKind::UnchangedCommentPart => continue,
+            };
+            for span in &mp.pos {
+                res.push_str(&format!(
+                    "(:file {} :side {} :kind {} :line {} :begin {} :end {})\n",
+                    elisp_string(path),
+                    side,
+                    kind,
+                    span.line.0 + 1,
+                    buffer_positions.position(span.line, span.start_col),
+                    buffer_positions.position(span.line, span.end_col)
+                ));
+            }
+        }
+    }
+    res
+}
+
+#[test]
+fn test_format_emacs() {
+    let span = |line: usize, start_col, end_col| SingleLineSpan {
+        line: line.into(),
+        start_col,
+        end_col,
+    };
+    let lhs_positions = vec![MatchedPos {
+        kind: MatchKind::Novel,
+        token_kind: TokenKind::Other,
+        pos: vec![span(1, 3, 5)],
+        prev_opposite_pos: vec![span(1, 3, 5)],
+    }];
+    let rhs_positions = vec![
+        MatchedPos {
+            kind: MatchKind::Unchanged,
+            token_kind: TokenKind::Other,
+            pos: vec![span(0, 0, 1)],
+            prev_opposite_pos: vec![span(0, 0, 1)],
+        },
+        MatchedPos {
+            kind: MatchKind::Moved,
+            token_kind: TokenKind::Other,
+            pos: vec![span(1, 3, 5)],
+            prev_opposite_pos: vec![span(1, 3, 5)],
+        },
+    ];
+    assert_eq!(
+        format_emacs(
+            "a \"b\".el",
+            "é\n(a 10)\n",
+            "é\n(a 20)\n",
+            &lhs_positions,
+            &rhs_positions
+        ),
+        "(:file \"a \\\"b\\\".el\" :side old :kind novel :line 2 :begin 6 :end 8)\n\
+         (:file \"a \\\"b\\\".el\" :side new :kind moved :line 2 :begin 6 :end 8)\n"
+    );
+}
+
+/// The fraction of the tokens on each line of `positions` that
+/// changed, in line order. Lines with no changes are omitted.
+fn line_densities(positions: &[MatchedPos]) -> Vec<(LineNumber, f64)> {
+    // The number of changed tokens and all tokens on each line.
+    let mut counts: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+    for mp in positions {
+        let changed = !matches!(
+            mp.kind,
+            MatchKind::Unchanged | MatchKind::UnchangedCommentPart
+        );
+        for span in &mp.pos {
+            let (changed_count, total) = counts.entry(span.line.0).or_default();
+            if changed {
+                *changed_count += 1;
+            }
+            *total += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .filter(|(_, (changed_count, _))| *changed_count > 0)
+        .map(|(line, (changed_count, total))| (line.into(), changed_count as f64 / total as f64))
+        .collect()
+}
+
+/// Describe how much of each line changed, with one tab-separated
+/// `path side line score` row per changed line, e.g. `foo.rs new 3
+/// 0.50`. The score is the fraction of the line's tokens that
+/// changed, so editors can shade the gutter. Unlisted lines score 0.
+pub fn format_density(
+    path: &str,
+    lhs_positions: &[MatchedPos],
+    rhs_positions: &[MatchedPos],
+) -> String {
+    let mut res = String::new();
+    for (side, positions) in [("old", lhs_positions), ("new", rhs_positions)] {
+        for (line, density) in line_densities(positions) {
+            res.push_str(&format!(
+                "{}\t{}\t{}\t{:.2}\n",
+                path,
+                side,
+                line.0 + 1,
+                density
+            ));
+        }
+    }
+    res
+}
+
+#[test]
+fn test_format_density() {
+    let span = |line: usize, start_col| SingleLineSpan {
+        line: line.into(),
+        start_col,
+        end_col: start_col + 1,
+    };
+    let pos = |kind, line, start_col| MatchedPos {
+        kind,
+        token_kind: TokenKind::Other,
+        pos: vec![span(line, start_col)],
+        prev_opposite_pos: vec![],
+    };
+    let rhs_positions = vec![
+        pos(MatchKind::Unchanged, 0, 0),
+        pos(MatchKind::Unchanged, 1, 0),
+        pos(MatchKind::Novel, 1, 2),
+        pos(MatchKind::Moved, 1, 4),
+        pos(MatchKind::Unchanged, 1, 6),
+        pos(MatchKind::Novel, 2, 0),
+    ];
+    assert_eq!(
+        format_density("foo.rs", &[], &rhs_positions),
+        "foo.rs\tnew\t2\t0.50\nfoo.rs\tnew\t3\t1.00\n"
+    );
+}
+
 pub fn format_line_num(line_num: usize) -> String {
     format!("{:<2} ", line_num + 1)
 }
@@ -376,11 +971,27 @@ pub fn rhs_printable_width(
     max(MIN_WIDTH, min(longest_line, space_available))
 }
 
+/// Describe a line range as `line 3` or `lines 3-5`.
+fn describe_lines(first: LineNumber, last: LineNumber) -> String {
+    if first == last {
+        format!("line {}", first.0 + 1)
+    } else {
+        format!("lines {}-{}", first.0 + 1, last.0 + 1)
+    }
+}
+
+/// A note saying where a moved region on the RHS came from.
+fn moved_from_note(first: LineNumber, last: LineNumber) -> String {
+    format!("moved from {}", describe_lines(first, last))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn apply_group(
     lhs_lines: &[&str],
     rhs_lines: &[&str],
     group: &LineGroup,
     lhs_line_matches: &HashMap<LineNumber, LineNumber>,
+    rhs_moved_from: &HashMap<LineNumber, (LineNumber, LineNumber)>,
     lhs_content_width: usize,
     lhs_column_width: usize,
     rhs_column_width: usize,
@@ -390,6 +1001,13 @@ fn apply_group(
     for (lhs_line_num, rhs_line_num) in
         aligned_lines(&group.lhs_lines(), &group.rhs_lines(), lhs_line_matches)
     {
+        // Annotate moved code on its own line, above its destination.
+        if let Some((first, last)) = rhs_line_num.and_then(|n| rhs_moved_from.get(&n)) {
+            result.push_str(&" ".repeat(lhs_column_width + lhs_content_width + rhs_column_width));
+            result.push_str(&moved_from_note(*first, *last).dimmed().to_string());
+            result.push('\n');
+        }
+
         match lhs_line_num {
             Some(lhs_line_num) => {
                 result.push_str(&format_line_num_padded(lhs_line_num.0, lhs_column_width));
@@ -400,12 +1018,9 @@ fn apply_group(
                 result.push_str(&" ".repeat(lhs_content_width));
             }
         }
-        match rhs_line_num {
-            Some(rhs_line_num) => {
-                result.push_str(&format_line_num_padded(rhs_line_num.0, rhs_column_width));
-                result.push_str(rhs_lines[rhs_line_num.0]);
-            }
-            None => {}
+        if let Some(rhs_line_num) = rhs_line_num {
+            result.push_str(&format_line_num_padded(rhs_line_num.0, rhs_column_width));
+            result.push_str(rhs_lines[rhs_line_num.0]);
         }
 
         result.push('\n');
@@ -415,12 +1030,18 @@ fn apply_group(
 }
 
 /// Display all the lines in `lhs` and `rhs` that are mentioned in
-/// `groups`. horizontally concatenating the matched lines.
+/// `groups`. horizontally concatenating the matched lines. Moved
+/// regions on the RHS are annotated with their lines in the LHS, and
+/// each group is preceded by its entry in `headings`, if any.
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+#[allow(clippy::too_many_arguments)]
 pub fn apply_groups(
     lhs: &str,
     rhs: &str,
     groups: &[LineGroup],
+    headings: &[Option<String>],
     lhs_line_matches: &HashMap<LineNumber, LineNumber>,
+    rhs_moved_from: &HashMap<LineNumber, (LineNumber, LineNumber)>,
     lhs_content_width: usize,
     rhs_content_width: usize,
     lhs_column_width: usize,
@@ -438,11 +1059,16 @@ pub fn apply_groups(
     spacer.push_str(&"-".repeat(rhs_content_width));
 
     for (i, group) in groups.iter().enumerate() {
+        if let Some(heading) = headings.get(i).and_then(|heading| heading.as_ref()) {
+            result.push_str(&heading.bold().to_string());
+            result.push('\n');
+        }
         result.push_str(&apply_group(
             &lhs_lines,
             &rhs_lines,
             group,
             lhs_line_matches,
+            rhs_moved_from,
             lhs_content_width,
             lhs_column_width,
             rhs_column_width,
@@ -456,12 +1082,168 @@ pub fn apply_groups(
     result
 }
 
-/// A position in a single line of a string.
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub struct LinePosition {
-    /// Both zero-indexed.
-    pub line: LineNumber,
-    column: usize,
+/// The gutter for inline output, with the line number in each file.
+fn inline_gutter(
+    lhs_line_num: Option<LineNumber>,
+    rhs_line_num: Option<LineNumber>,
+    lhs_column_width: usize,
+    rhs_column_width: usize,
+) -> String {
+    let format_num = |line_num: Option<LineNumber>| match line_num {
+        Some(line_num) => (line_num.0 + 1).to_string(),
+        None => String::new(),
+    };
+    format!(
+        "{:>lhs_width$} {:>rhs_width$} ",
+        format_num(lhs_line_num),
+        format_num(rhs_line_num),
+        lhs_width = lhs_column_width,
+        rhs_width = rhs_column_width,
+    )
+}
+
+/// Display all the lines in `lhs` and `rhs` that are mentioned in
+/// `groups` in a single column. Unchanged lines are shown once, and
+/// changed lines are shown as removed lines then added lines. The
+/// gutter shows the line number in both files, as structural changes
+/// can make it hard to tell which lines correspond.
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+#[allow(clippy::too_many_arguments)]
+pub fn apply_groups_inline(
+    lhs: &str,
+    rhs: &str,
+    groups: &[LineGroup],
+    headings: &[Option<String>],
+    lhs_line_matches: &HashMap<LineNumber, LineNumber>,
+    rhs_moved_from: &HashMap<LineNumber, (LineNumber, LineNumber)>,
+    lhs_positions: &[MatchedPos],
+    rhs_positions: &[MatchedPos],
+) -> String {
+    let lhs_lines: Vec<_> = lhs.lines().collect();
+    let rhs_lines: Vec<_> = rhs.lines().collect();
+    let lhs_changed: HashSet<_> = changed_spans(lhs_positions).map(|span| span.line).collect();
+    let rhs_changed: HashSet<_> = changed_spans(rhs_positions).map(|span| span.line).collect();
+
+    let (lhs_column_width, rhs_column_width) = match groups.last() {
+        Some(group) => (
+            (group.max_visible_lhs().0 + 1).to_string().len(),
+            (group.max_visible_rhs().0 + 1).to_string().len(),
+        ),
+        None => (1, 1),
+    };
+    let gutter = |lhs_line_num, rhs_line_num| {
+        inline_gutter(
+            lhs_line_num,
+            rhs_line_num,
+            lhs_column_width,
+            rhs_column_width,
+        )
+    };
+
+    let mut result = String::new();
+    for (i, group) in groups.iter().enumerate() {
+        if i > 0 {
+            result.push_str(&"...".dimmed().to_string());
+            result.push('\n');
+        }
+        if let Some(heading) = headings.get(i).and_then(|heading| heading.as_ref()) {
+            result.push_str(&heading.bold().to_string());
+            result.push('\n');
+        }
+
+        // Show each run of changed lines as all the removed lines,
+        // then all the added lines.
+        let mut removed = String::new();
+        let mut added = String::new();
+        for (lhs_line_num, rhs_line_num) in
+            aligned_lines(&group.lhs_lines(), &group.rhs_lines(), lhs_line_matches)
+        {
+            if let (Some(lhs_line_num), Some(rhs_line_num)) = (lhs_line_num, rhs_line_num) {
+                if !lhs_changed.contains(&lhs_line_num) && !rhs_changed.contains(&rhs_line_num) {
+                    result.push_str(&removed);
+                    result.push_str(&added);
+                    removed.clear();
+                    added.clear();
+
+                    result.push_str(
+                        &gutter(Some(lhs_line_num), Some(rhs_line_num))
+                            .dimmed()
+                            .to_string(),
+                    );
+                    result.push_str("  ");
+                    result.push_str(lhs_lines[lhs_line_num.0]);
+                    result.push('\n');
+                    continue;
+                }
+            }
+
+            if let Some(lhs_line_num) = lhs_line_num {
+                removed.push_str(&gutter(Some(lhs_line_num), None).dimmed().to_string());
+                removed.push_str(&format!("{} ", "-".red()));
+                removed.push_str(lhs_lines[lhs_line_num.0]);
+                removed.push('\n');
+            }
+            if let Some(rhs_line_num) = rhs_line_num {
+                if let Some((first, last)) = rhs_moved_from.get(&rhs_line_num) {
+                    added.push_str(&" ".repeat(lhs_column_width + rhs_column_width + 4));
+                    added.push_str(&moved_from_note(*first, *last).dimmed().to_string());
+                    added.push('\n');
+                }
+                added.push_str(&gutter(None, Some(rhs_line_num)).dimmed().to_string());
+                added.push_str(&format!("{} ", "+".green()));
+                added.push_str(rhs_lines[rhs_line_num.0]);
+                added.push('\n');
+            }
+        }
+        result.push_str(&removed);
+        result.push_str(&added);
+    }
+
+    result
+}
+
+#[test]
+fn test_apply_groups_inline() {
+    colored::control::set_override(false);
+    let span = |line: usize| SingleLineSpan {
+        line: line.into(),
+        start_col: 0,
+        end_col: 1,
+    };
+    let positions = vec![
+        MatchedPos {
+            kind: MatchKind::Unchanged,
+            token_kind: TokenKind::Other,
+            pos: vec![span(0)],
+            prev_opposite_pos: vec![span(0)],
+        },
+        MatchedPos {
+            kind: MatchKind::Novel,
+            token_kind: TokenKind::Other,
+            pos: vec![span(1)],
+            prev_opposite_pos: vec![span(0)],
+        },
+    ];
+    let mut groups = visible_groups(&positions, &positions);
+    for group in &mut groups {
+        group.pad(1, 1.into(), 1.into());
+    }
+    let mut lhs_line_matches = HashMap::new();
+    lhs_line_matches.insert(0.into(), 0.into());
+
+    assert_eq!(
+        apply_groups_inline(
+            "a\nb\n",
+            "a\nc\n",
+            &groups,
+            &[Some("modified a (1 line changed)".into())],
+            &lhs_line_matches,
+            &HashMap::new(),
+            &positions,
+            &positions
+        ),
+        "modified a (1 line changed)\n1 1   a\n2   - b\n  2 + c\n"
+    );
 }
 
 /// A struct for efficiently converting absolute string positions to
@@ -475,11 +1257,8 @@ pub struct NewlinePositions {
 
 impl From<&str> for NewlinePositions {
     fn from(s: &str) -> Self {
-        lazy_static! {
-            static ref NEWLINE_RE: Regex = Regex::new("\n").unwrap();
-        }
-        let mut positions: Vec<_> = NEWLINE_RE.find_iter(s).map(|mat| mat.end()).collect();
-        positions.insert(0, 0);
+        let mut positions = vec![0];
+        positions.extend(s.match_indices('\n').map(|(i, _)| i + 1));
 
         NewlinePositions {
             positions,
@@ -488,21 +1267,54 @@ impl From<&str> for NewlinePositions {
     }
 }
 
+#[allow(clippy::wrong_self_convention)]
 impl NewlinePositions {
+    /// The byte offset of column `col` on `line`.
+    pub fn to_offset(&self, line: LineNumber, col: usize) -> usize {
+        self.positions[line.0] + col
+    }
+
+    /// The bytes covered by `span`, which must have been computed from
+    /// the same string. This is the inverse of `from_offsets`: the
+    /// byte spans of its result cover the original region, except for
+    /// the newlines between lines.
+    pub fn byte_span(&self, span: &SingleLineSpan) -> ByteSpan {
+        ByteSpan {
+            start: self.to_offset(span.line, span.start_col),
+            end: self.to_offset(span.line, span.end_col),
+        }
+    }
+
+    /// The single-line span covering `span`, which must not cross a
+    /// newline. This is the inverse of `byte_span`.
+    pub fn from_byte_span(&self, span: ByteSpan) -> SingleLineSpan {
+        let line = self.positions.partition_point(|start| *start <= span.start) - 1;
+        SingleLineSpan {
+            line: line.into(),
+            start_col: span.start - self.positions[line],
+            end_col: span.end - self.positions[line],
+        }
+    }
+
     /// Convert to single-line spans. If the original span crosses a
     /// newline, the vec will contain multiple items.
-    pub fn from_offsets(&self, region_start: usize, region_end: usize) -> Vec<SingleLineSpan> {
-        let mut res = vec![];
-        for (line_num, line_start) in self.positions.iter().enumerate() {
+    pub fn from_offsets(&self, region_start: usize, region_end: usize) -> Spans {
+        // The first line that ends at or after `region_start`. Lines
+        // are sorted, so use a binary search rather than scanning
+        // every line for every token.
+        let first_line = self
+            .positions
+            .partition_point(|start| *start <= region_start)
+            - 1;
+
+        let mut res = Spans::new();
+        for (line_num, line_start) in self.positions.iter().enumerate().skip(first_line) {
             let line_end = match self.positions.get(line_num + 1) {
                 // TODO: this assumes lines terminate with \n, not \r\n.
                 Some(v) => *v - 1,
                 None => self.str_length,
             };
 
-            if region_start > line_end {
-                continue;
-            }
             if *line_start >= region_end {
                 break;
             }
@@ -554,7 +1366,7 @@ impl NewlinePositions {
 #[test]
 fn from_ranges_first_line() {
     let newline_positions: NewlinePositions = "foo".into();
-    let line_spans = newline_positions.from_offsets(1, 3);
+    let line_spans = newline_positions.from_offsets(1, 3).to_vec();
     assert_eq!(
         line_spans,
         vec![SingleLineSpan {
@@ -568,7 +1380,7 @@ fn from_ranges_first_line() {
 #[test]
 fn from_ranges_split_over_multiple_lines() {
     let newline_positions: NewlinePositions = "foo\nbar\nbaz\naaaaaaaaaaa".into();
-    let line_spans = newline_positions.from_offsets(5, 10);
+    let line_spans = newline_positions.from_offsets(5, 10).to_vec();
 
     assert_eq!(
         line_spans,
@@ -587,6 +1399,53 @@ fn from_ranges_split_over_multiple_lines() {
     );
 }
 
+#[test]
+fn from_ranges_after_newline() {
+    let newline_positions: NewlinePositions = "foo\nbar\nbaz".into();
+    let span = |line: usize, start_col, end_col| SingleLineSpan {
+        line: line.into(),
+        start_col,
+        end_col,
+    };
+
+    // A region starting at a newline includes the end of that line.
+    assert_eq!(
+        newline_positions.from_offsets(3, 5).to_vec(),
+        vec![span(0, 3, 3), span(1, 0, 1)]
+    );
+    assert_eq!(
+        newline_positions.from_offsets(8, 11).to_vec(),
+        vec![span(2, 0, 3)]
+    );
+    assert!(newline_positions.from_offsets(4, 4).is_empty());
+}
+
+#[test]
+fn byte_span_inverts_from_offsets() {
+    let s = "foo\nbar\r\nbaz";
+    let newline_positions: NewlinePositions = s.into();
+    let byte_spans: Vec<_> = newline_positions
+        .from_offsets(2, 11)
+        .iter()
+        .map(|span| newline_positions.byte_span(span))
+        .collect();
+    assert_eq!(
+        byte_spans,
+        vec![
+            ByteSpan { start: 2, end: 3 },
+            ByteSpan { start: 4, end: 8 },
+            ByteSpan { start: 9, end: 11 }
+        ]
+    );
+    let texts: Vec<_> = byte_spans.iter().map(|span| span.text(s)).collect();
+    assert_eq!(texts, vec!["o", "bar\r", "ba"]);
+
+    for span in newline_positions.from_offsets(2, 11) {
+        let bytes = newline_positions.byte_span(&span);
+        assert_eq!(newline_positions.from_byte_span(bytes), span);
+    }
+}
+
 #[test]
 fn from_offsets_relative_to() {
     let newline_positions: NewlinePositions = "foo\nbar".into();
@@ -608,6 +1467,214 @@ fn from_offsets_relative_to() {
     );
 }
 
+/// Find the lines in `lhs` that correspond to lines `start` to `end`
+/// (inclusive) in `rhs`, using a line-oriented diff. Returns a start
+/// and exclusive end, which are equal if the RHS lines were all
+/// added.
+pub fn opposite_line_range(
+    lhs: &str,
+    rhs: &str,
+    start: LineNumber,
+    end: LineNumber,
+) -> (LineNumber, LineNumber) {
+    // The LHS line number when we reached each RHS line.
+    let mut lhs_lines_at = vec![];
+    let mut lhs_line = 0;
+    for res in diff::lines(lhs, rhs) {
+        match res {
+            diff::Result::Left(_) => lhs_line += 1,
+            diff::Result::Both(_, _) => {
+                lhs_lines_at.push(lhs_line);
+                lhs_line += 1;
+            }
+            diff::Result::Right(_) => lhs_lines_at.push(lhs_line),
+        }
+    }
+    lhs_lines_at.push(lhs_line);
+
+    let lhs_line_at = |line: usize| lhs_lines_at[min(line, lhs_lines_at.len() - 1)];
+    (
+        lhs_line_at(start.0).into(),
+        max(lhs_line_at(start.0), lhs_line_at(end.0 + 1)).into(),
+    )
+}
+
+#[test]
+fn aligned_lines_one_side() {
+    let group = LineGroup {
+        lhs_lines: None,
+        rhs_lines: Some(Interval {
+            start: 0.into(),
+            end: 1.into(),
+        }),
+    };
+    assert_eq!(
+        group.aligned_lines(&HashMap::new()),
+        vec![(None, Some(0.into())), (None, Some(1.into()))]
+    );
+}
+
+#[test]
+fn opposite_line_range_unchanged() {
+    assert_eq!(
+        opposite_line_range("a\nb\nc\n", "a\nb\nc\n", 1.into(), 1.into()),
+        (1.into(), 2.into())
+    );
+}
+
+#[test]
+fn opposite_line_range_changes() {
+    let lhs = "a\nold\nb\nc\n";
+    let rhs = "new\na\nb\nextra\nc\n";
+    // `new` and `a` correspond to `a`, plus the removed `old` up to
+    // the next unchanged line.
+    assert_eq!(
+        opposite_line_range(lhs, rhs, 0.into(), 1.into()),
+        (0.into(), 2.into())
+    );
+    // `b` and `extra` correspond to `b`.
+    assert_eq!(
+        opposite_line_range(lhs, rhs, 2.into(), 3.into()),
+        (2.into(), 3.into())
+    );
+    // Added lines have no corresponding lines.
+    assert_eq!(
+        opposite_line_range(lhs, rhs, 3.into(), 3.into()),
+        (3.into(), 3.into())
+    );
+}
+
+/// Files with lines longer than this, such as minified JS, are shown as
+/// column chunks around each change rather than side-by-side.
+pub const LONG_LINE_LENGTH: usize = 1000;
+
+/// The number of columns shown either side of a change in a column
+/// chunk.
+const CONTEXT_COLUMNS: usize = 30;
+
+/// A column range of a line that contains changes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ColumnChunk {
+    pub line: LineNumber,
+    pub start_col: usize,
+    pub end_col: usize,
+}
+
+/// The largest char boundary in `s` that is at most `i`.
+fn floor_char_boundary(s: &str, i: usize) -> usize {
+    let mut i = min(i, s.len());
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+/// Find the column ranges of `src` to show for the changes in
+/// `positions`, with `CONTEXT_COLUMNS` of context either side. Nearby
+/// chunks are joined, and no chunk is wider than `max_width`.
+pub fn column_chunks(src: &str, positions: &[MatchedPos], max_width: usize) -> Vec<ColumnChunk> {
+    let lines: Vec<_> = src.lines().collect();
+    let mut spans: Vec<_> = changed_spans(positions).copied().collect();
+    spans.sort_unstable();
+
+    let mut chunks: Vec<ColumnChunk> = vec![];
+    for span in spans {
+        let line = match lines.get(span.line.0) {
+            Some(line) => line,
+            None => continue,
+        };
+        let start_col = floor_char_boundary(line, span.start_col.saturating_sub(CONTEXT_COLUMNS));
+        let end_col = floor_char_boundary(line, span.end_col + CONTEXT_COLUMNS);
+
+        if let Some(prev) = chunks.last_mut() {
+            if prev.line == span.line && start_col <= prev.end_col {
+                prev.end_col = max(prev.end_col, end_col);
+                continue;
+            }
+        }
+        chunks.push(ColumnChunk {
+            line: span.line,
+            start_col,
+            end_col,
+        });
+    }
+
+    for chunk in &mut chunks {
+        let line = lines[chunk.line.0];
+        chunk.end_col = floor_char_boundary(line, min(chunk.end_col, chunk.start_col + max_width));
+    }
+    chunks
+}
+
+/// The positions of `positions` inside `chunk`, relative to the start
+/// of the chunk.
+pub fn positions_in_chunk(positions: &[MatchedPos], chunk: &ColumnChunk) -> Vec<MatchedPos> {
+    positions
+        .iter()
+        .filter_map(|mp| {
+            let pos: Vec<_> = mp
+                .pos
+                .iter()
+                .filter(|span| {
+                    span.line == chunk.line
+                        && span.start_col < chunk.end_col
+                        && span.end_col > chunk.start_col
+                })
+                .map(|span| SingleLineSpan {
+                    line: 0.into(),
+                    start_col: max(span.start_col, chunk.start_col) - chunk.start_col,
+                    end_col: min(span.end_col, chunk.end_col) - chunk.start_col,
+                })
+                .collect();
+            if pos.is_empty() {
+                return None;
+            }
+            Some(MatchedPos {
+                kind: mp.kind,
+                pos,
+                prev_opposite_pos: vec![],
+                token_kind: mp.token_kind,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_column_chunks() {
+    let src = format!(
+        "{}foo{}bar{}baz{}",
+        "x".repeat(100),
+        "x".repeat(10),
+        "x".repeat(100),
+        "x".repeat(5)
+    );
+    let novel = |start_col| MatchedPos {
+        kind: MatchKind::Novel,
+        token_kind: TokenKind::Other,
+        pos: vec![SingleLineSpan {
+            line: 0.into(),
+            start_col,
+            end_col: start_col + 3,
+        }],
+        prev_opposite_pos: vec![],
+    };
+    let positions = vec![novel(100), novel(113), novel(216)];
+
+    let chunks = column_chunks(&src, &positions, 80);
+    let chunk = |start_col, end_col| ColumnChunk {
+        line: 0.into(),
+        start_col,
+        end_col,
+    };
+    // The first two changes are joined, and the last is limited by
+    // the end of the line.
+    assert_eq!(chunks, vec![chunk(70, 146), chunk(186, 224)]);
+
+    let in_chunk = positions_in_chunk(&positions, &chunks[1]);
+    assert_eq!(in_chunk.len(), 1);
+    assert_eq!(in_chunk[0].pos[0].start_col, 30);
+}
+
 /// Ensure that every line in `s` has this length. Pad short lines and
 /// truncate long lines.
 pub fn enforce_length(s: &str, line_length: usize) -> String {
@@ -643,7 +1710,7 @@ pub trait MaxLine {
     fn max_line(&self) -> LineNumber;
 }
 
-impl MaxLine for String {
+impl MaxLine for str {
     fn max_line(&self) -> LineNumber {
         (max(1, self.lines().count()) - 1).into()
     }
@@ -660,3 +1727,9 @@ fn empty_str_max_line() {
     let line: String = "".into();
     assert_eq!(line.max_line().0, 0);
 }
+
+#[test]
+fn moved_from_note_ranges() {
+    assert_eq!(moved_from_note(2.into(), 2.into()), "moved from line 3");
+    assert_eq!(moved_from_note(2.into(), 4.into()), "moved from lines 3-5");
+}
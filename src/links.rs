@@ -0,0 +1,128 @@
+//! Symlinks and git submodules. These are compared by what they point
+//! to, rather than by following them and diffing the contents.
+
+use std::fmt;
+use std::fs;
+
+/// Git's file mode for a symlink, as passed to `GIT_EXTERNAL_DIFF`.
+const GIT_SYMLINK_MODE: &str = "120000";
+/// Git's file mode for a submodule.
+const GIT_SUBMODULE_MODE: &str = "160000";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Link {
+    Symlink(String),
+    /// A submodule, and the commit it points to.
+    Submodule(String),
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Link::Symlink(target) => write!(f, "symlink to {}", target),
+            Link::Submodule(commit) => write!(f, "submodule at commit {}", commit),
+        }
+    }
+}
+
+impl Link {
+    /// The name shown in the file header, in place of the language.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Link::Symlink(_) => "symlink",
+            Link::Submodule(_) => "submodule",
+        }
+    }
+}
+
+/// Is `path` a symlink or submodule? `git_blob` is the hash and mode
+/// that git passes with each file when it runs difftastic. Git writes
+/// a symlink's target to the temporary file, and doesn't write
+/// anything useful for a submodule.
+///
+/// Without git, a symlink is found from the file system. Symlinks to
+/// pipes or devices aren't links, since their contents are the input.
+pub fn read_link(path: &str, git_blob: Option<(&str, &str)>) -> Option<Link> {
+    match git_blob {
+        Some((_, GIT_SYMLINK_MODE)) => fs::read(path)
+            .ok()
+            .map(|target| Link::Symlink(String::from_utf8_lossy(&target).to_string())),
+        Some((hash, GIT_SUBMODULE_MODE)) => Some(Link::Submodule(hash.to_string())),
+        Some(_) => None,
+        // Process substitution gives paths such as /dev/fd/63, which
+        // are symlinks to pipes. Those are read like files.
+        None if fs::metadata(path)
+            .is_ok_and(|metadata| !metadata.is_file() && !metadata.is_dir()) =>
+        {
+            None
+        }
+        None => match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => fs::read_link(path)
+                .ok()
+                .map(|target| Link::Symlink(target.display().to_string())),
+            _ => None,
+        },
+    }
+}
+
+/// Describe the change between two files where at least one is a
+/// link, e.g. `Changed from symlink to foo to symlink to bar.`. A
+/// side is `None` if it's a regular file, and missing if git passed
+/// `/dev/null` because the file was added or removed.
+pub fn describe_link_change(
+    lhs: Option<&Link>,
+    rhs: Option<&Link>,
+    lhs_exists: bool,
+    rhs_exists: bool,
+) -> String {
+    let describe = |link: Option<&Link>| match link {
+        Some(link) => link.to_string(),
+        None => "regular file".to_string(),
+    };
+
+    if !lhs_exists {
+        format!("Added {}.", describe(rhs))
+    } else if !rhs_exists {
+        format!("Removed {}.", describe(lhs))
+    } else if lhs == rhs {
+        "No changes.".to_string()
+    } else {
+        format!("Changed from {} to {}.", describe(lhs), describe(rhs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_link_from_git() {
+        assert_eq!(
+            read_link("/dev/null", Some(("2d39327", GIT_SUBMODULE_MODE))),
+            Some(Link::Submodule("2d39327".into()))
+        );
+        assert_eq!(read_link("/dev/null", Some(("2d39327", "100644"))), None);
+    }
+
+    #[test]
+    fn test_describe_link_change() {
+        let foo = Link::Symlink("foo".into());
+        let bar = Link::Symlink("bar".into());
+        assert_eq!(
+            describe_link_change(Some(&foo), Some(&bar), true, true),
+            "Changed from symlink to foo to symlink to bar."
+        );
+        assert_eq!(
+            describe_link_change(None, Some(&bar), true, true),
+            "Changed from regular file to symlink to bar."
+        );
+        assert_eq!(
+            describe_link_change(None, Some(&bar), false, true),
+            "Added symlink to bar."
+        );
+        assert_eq!(
+            describe_link_change(Some(&foo), Some(&foo), true, true),
+            "No changes."
+        );
+    }
+}
@@ -1,23 +1,46 @@
-mod dijkstra;
-mod intervals;
-mod lines;
-mod parse;
-mod positions;
-mod style;
-mod syntax;
-use clap::{App, Arg};
-use std::ffi::OsStr;
-use std::path::Path;
+use clap::{App, AppSettings, Arg, SubCommand};
+use colored::*;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
 use typed_arena::Arena;
 
-use crate::dijkstra::mark_syntax;
-use crate::lines::{
-    apply_groups, enforce_length, format_line_num, join_overlapping, lhs_printable_width,
-    rhs_printable_width, visible_groups, MaxLine,
+use difftastic::batch::run_batch;
+use difftastic::calibrate::{calibrate, find_pairs, CalibrationPair};
+use difftastic::config::{read_config, Config};
+use difftastic::dijkstra::{
+    estimated_graph_size, mark_syntax, SearchConfig, SearchStats, DEFAULT_BEAM_WIDTH,
+    MAX_ESTIMATED_GRAPH_SIZE,
+};
+use difftastic::git::{
+    local_diff_command, log_command, range_diff_command, whole_file_diff_command,
+};
+use difftastic::lines::{
+    apply_groups, apply_groups_inline, changed_line_ranges, column_chunks, enforce_length,
+    format_density, format_emacs, format_line_num, format_line_ranges, format_quickfix,
+    join_scopes, lhs_printable_width, opposite_line_range, pad_scopes, positions_in_chunk,
+    rhs_printable_width, visible_groups, LineNumber, MaxLine, LONG_LINE_LENGTH,
+};
+use difftastic::links::{describe_link_change, read_link};
+use difftastic::merge::merge;
+use difftastic::parse::{
+    guess_language, lang_by_name, parse_lines, parse_pair, read_or_die, read_syntax_file,
+    ConfigDir, LanguageRegistry, NumericTolerance,
+};
+use difftastic::patch::{parse_patch, PatchedFile};
+use difftastic::report::{report_pages, write_report};
+use difftastic::serialize::{JsonFileV1, SerializedDiff};
+use difftastic::serve::{directory_files, serve};
+use difftastic::style::{self, apply_colors, invisible_changes, Theme};
+use difftastic::summary::{definitions, group_heading, summarize, top_level_scopes};
+use difftastic::syntax::{
+    change_positions, has_changes, init_info, mark_moved, matching_lines, moved_from,
+    nodes_in_lines, slide_novel_runs, MatchedPos, Syntax,
 };
-use crate::parse::{find_lang, parse, parse_lines, read_or_die, ConfigDir};
-use crate::style::apply_colors;
-use crate::syntax::{change_positions, init_info, matching_lines};
 
 fn term_width() -> Option<usize> {
     term_size::dimensions().map(|(w, _)| w)
@@ -28,7 +51,7 @@ fn term_width() -> Option<usize> {
 fn is_probably_binary(bytes: &[u8]) -> bool {
     // If more than 20 of the first 1,000 characters are not valid
     // UTF-8, we assume it's binary.
-    let num_replaced = String::from_utf8_lossy(&bytes)
+    let num_replaced = String::from_utf8_lossy(bytes)
         .to_string()
         .chars()
         .take(1000)
@@ -37,9 +60,407 @@ fn is_probably_binary(bytes: &[u8]) -> bool {
     num_replaced > 20
 }
 
+/// Describe the memory used when diffing `display_path`.
+fn memory_stats<'a>(display_path: &str, arena: &Arena<Syntax<'a>>, search: &SearchStats) -> String {
+    let arena_len = arena.len();
+    format!(
+        "Memory stats for {}:\n  arena: {} nodes ({} KiB)\n  predecessors: {} entries ({} KiB)\n  largest priority queue: {} vertices ({} KiB)",
+        display_path,
+        arena_len,
+        arena_len * std::mem::size_of::<Syntax>() / 1024,
+        search.predecessors,
+        search.predecessors_bytes() / 1024,
+        search.max_queue_len,
+        search.max_queue_bytes() / 1024,
+    )
+}
+
+/// Describe the work done by the graph search when diffing
+/// `display_path`.
+fn search_counters(display_path: &str, search: &SearchStats) -> String {
+    format!(
+        "Search stats for {}:\n  vertices expanded: {}\n  edges generated: {}\n  heap pushes: {}\n  route length: {}",
+        display_path,
+        search.predecessors,
+        search.edges_generated,
+        search.heap_pushes,
+        search.route_length,
+    )
+}
+
+/// Parse an `--anchor` value such as `10:12`. Line numbers start
+/// from 1, as they're displayed.
+fn parse_anchor(s: &str) -> Result<(LineNumber, LineNumber), String> {
+    let parse_line = |line: &str| match line.trim().parse::<usize>() {
+        Ok(n) if n > 0 => Ok(LineNumber(n - 1)),
+        _ => Err(format!(
+            "Invalid --anchor {}, expected LHS_LINE:RHS_LINE",
+            s
+        )),
+    };
+    match s.split_once(':') {
+        Some((lhs, rhs)) => Ok((parse_line(lhs)?, parse_line(rhs)?)),
+        None => Err(format!(
+            "Invalid --anchor {}, expected LHS_LINE:RHS_LINE",
+            s
+        )),
+    }
+}
+
+/// Parse a `--lines` value such as `100-200`. Line numbers start
+/// from 1, and the range is inclusive.
+fn parse_line_range(s: &str) -> Result<(LineNumber, LineNumber), String> {
+    let err = || format!("Invalid --lines {}, expected START-END", s);
+    let (start, end) = s.split_once('-').ok_or_else(err)?;
+    match (start.trim().parse::<usize>(), end.trim().parse::<usize>()) {
+        (Ok(start), Ok(end)) if start > 0 && start <= end => {
+            Ok(((start - 1).into(), (end - 1).into()))
+        }
+        _ => Err(err()),
+    }
+}
+
+/// For `--lines`, the top-level RHS nodes that intersect the range, and
+/// the LHS nodes on the lines opposite them. Without a range, `lhs`
+/// and `rhs` are returned unchanged.
+fn select_line_range<'a>(
+    lhs: Vec<&'a Syntax<'a>>,
+    rhs: Vec<&'a Syntax<'a>>,
+    lhs_src: &str,
+    rhs_src: &str,
+    line_range: Option<(LineNumber, LineNumber)>,
+) -> (Vec<&'a Syntax<'a>>, Vec<&'a Syntax<'a>>) {
+    let (start, end) = match line_range {
+        Some(range) => range,
+        None => return (lhs, rhs),
+    };
+
+    let rhs = nodes_in_lines(&rhs, start, end);
+    // Expand to the whole top-level nodes, then find the
+    // corresponding LHS nodes.
+    let rhs_start = rhs.first().and_then(|n| n.first_line()).unwrap_or(start);
+    let rhs_end = rhs.last().and_then(|n| n.last_line()).unwrap_or(end);
+    let (lhs_start, lhs_end) = opposite_line_range(lhs_src, rhs_src, rhs_start, rhs_end);
+    let lhs = if lhs_start < lhs_end {
+        nodes_in_lines(&lhs, lhs_start, (lhs_end.0 - 1).into())
+    } else {
+        vec![]
+    };
+    (lhs, rhs)
+}
+
+/// Diff the before/after pairs in `dir` with different edge costs,
+/// and print `config` with the costs that give the fewest hunks.
+fn run_calibrate(dir: &str, registry: &LanguageRegistry, config: Config) {
+    let paths = match find_pairs(Path::new(dir)) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Could not read directory {}: {}", dir, e);
+            std::process::exit(1);
+        }
+    };
+    let pairs: Vec<_> = paths
+        .iter()
+        .filter_map(|(lhs_path, rhs_path)| {
+            let lhs_path = lhs_path.to_string_lossy();
+            let rhs_path = rhs_path.to_string_lossy();
+            Some(CalibrationPair {
+                lang: guess_language(registry, &rhs_path)?,
+                lhs_src: String::from_utf8_lossy(&read_or_die(&lhs_path)).to_string(),
+                rhs_src: String::from_utf8_lossy(&read_or_die(&rhs_path)).to_string(),
+            })
+        })
+        .collect();
+    if pairs.is_empty() {
+        eprintln!(
+            "No before/after file pairs in a supported language found in {}",
+            dir
+        );
+        std::process::exit(1);
+    }
+
+    let calibration = calibrate(&pairs, config.costs);
+    println!(
+        "# Calibrated on {} file pairs: {} hunks, down from {}.",
+        pairs.len(),
+        calibration.hunks,
+        calibration.initial_hunks
+    );
+    let config = Config {
+        costs: calibration.costs,
+        ..config
+    };
+    print!("{}", toml::to_string(&config).unwrap());
+}
+
+/// Print `file` for `--output json`, one object per line.
+fn print_json(file: &JsonFileV1) {
+    match serde_json::to_string(file) {
+        Ok(json) => println!("{}", json),
+        Err(e) => {
+            eprintln!("Could not serialize changes: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// A note shown after the changes displayed, when `--max-changes`
+/// has hidden some.
+fn more_changes_note(hidden: usize) -> String {
+    if hidden == 1 {
+        "… and 1 more change".to_string()
+    } else {
+        format!("… and {} more changes", hidden)
+    }
+}
+
+/// Print the changes as column ranges of each line, rather than
+/// side-by-side. This is much more readable when files have very long
+/// lines, such as minified JS.
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+fn print_column_chunks(
+    lhs_src: &str,
+    rhs_src: &str,
+    lhs_positions: &[MatchedPos],
+    rhs_positions: &[MatchedPos],
+    width: usize,
+    theme: &Theme,
+    max_changes: Option<usize>,
+) {
+    let lhs_lines: Vec<_> = lhs_src.lines().collect();
+    let rhs_lines: Vec<_> = rhs_src.lines().collect();
+
+    let mut chunks: Vec<_> = column_chunks(lhs_src, lhs_positions, width)
+        .into_iter()
+        .map(|chunk| (true, chunk))
+        .chain(
+            column_chunks(rhs_src, rhs_positions, width)
+                .into_iter()
+                .map(|chunk| (false, chunk)),
+        )
+        .collect();
+    chunks.sort_by_key(|(is_lhs, chunk)| (chunk.line, chunk.start_col, !is_lhs));
+    let hidden = match max_changes {
+        Some(max_changes) if chunks.len() > max_changes => {
+            let hidden = chunks.len() - max_changes;
+            chunks.truncate(max_changes);
+            hidden
+        }
+        _ => 0,
+    };
+
+    for (is_lhs, chunk) in chunks {
+        let (side, lines, positions) = if is_lhs {
+            ("lhs", &lhs_lines, lhs_positions)
+        } else {
+            ("rhs", &rhs_lines, rhs_positions)
+        };
+        let header = format!(
+            "{} line {}, cols {}-{}",
+            side,
+            chunk.line.0 + 1,
+            chunk.start_col + 1,
+            chunk.end_col
+        );
+        println!("{}", header.dimmed());
+        print!(
+            "{}",
+            apply_colors(
+                &lines[chunk.line.0][chunk.start_col..chunk.end_col],
+                is_lhs,
+                &positions_in_chunk(positions, &chunk),
+                theme,
+                &HashSet::new(),
+            )
+        );
+    }
+    if hidden > 0 {
+        println!("{}", more_changes_note(hidden));
+    }
+}
+
+/// The path of this binary, so git can run it on each changed file.
+fn current_exe() -> PathBuf {
+    match env::current_exe() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Could not find the difftastic binary: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run a git command that calls this binary, and exit with its status.
+/// The syntax file is passed on, so every file uses the same syntax.
+fn run_git(mut command: Command, syntax_path: &Option<String>) -> ! {
+    if let Some(path) = syntax_path {
+        command.env("DFT_SYNTAX_PATH", path);
+    }
+    match command.status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            eprintln!("Could not run git: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Merge `current` and `other` with their common ancestor `base`, as
+/// `git merge-file` does, and write the result to `current`. Exits
+/// with status 1 if there were conflicts, as a git merge driver should.
+fn run_merge(
+    current_path: &str,
+    base_path: &str,
+    other_path: &str,
+    lang_path: &str,
+    labels: (&str, &str),
+    to_stdout: bool,
+    registry: &LanguageRegistry,
+) -> ! {
+    let read = |path| String::from_utf8_lossy(&read_or_die(path)).to_string();
+    let current = read(current_path);
+    let base = read(base_path);
+    let other = read(other_path);
+
+    let lang = guess_language(registry, lang_path);
+    let result = merge(&base, &current, &other, lang, labels.0, labels.1);
+    if to_stdout {
+        print!("{}", result.merged);
+    } else if let Err(e) = fs::write(current_path, &result.merged) {
+        eprintln!("Could not write {}: {}", current_path, e);
+        std::process::exit(2);
+    }
+
+    if result.num_conflicts > 0 {
+        eprintln!(
+            "{} conflict{} in {}",
+            result.num_conflicts,
+            if result.num_conflicts == 1 { "" } else { "s" },
+            lang_path
+        );
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+/// The files to show in `difftastic serve`: the changes in a git
+/// range, or between two directories, or between two files.
+fn serve_files(range: Option<&str>, paths: &[&str]) -> Vec<PatchedFile> {
+    if let Some(range) = range {
+        let output = match whole_file_diff_command(range).output() {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                eprint!("{}", String::from_utf8_lossy(&output.stderr));
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Could not run git: {}", e);
+                std::process::exit(1);
+            }
+        };
+        return parse_patch(&String::from_utf8_lossy(&output.stdout));
+    }
+
+    let (lhs_path, rhs_path) = match paths {
+        [lhs_path, rhs_path] => (*lhs_path, *rhs_path),
+        _ => {
+            eprintln!("difftastic serve needs two files, two directories, or --git-range");
+            std::process::exit(1);
+        }
+    };
+    if Path::new(lhs_path).is_dir() && Path::new(rhs_path).is_dir() {
+        match directory_files(Path::new(lhs_path), Path::new(rhs_path)) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Could not read {} and {}: {}", lhs_path, rhs_path, e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let read = |path| String::from_utf8_lossy(&read_or_die(path)).to_string();
+        vec![PatchedFile {
+            path: rhs_path.to_string(),
+            lhs: read(lhs_path),
+            rhs: read(rhs_path),
+        }]
+    }
+}
+
+fn read_patch_from_stdin() -> String {
+    let mut patch = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut patch) {
+        eprintln!("Could not read patch from stdin: {}", e);
+        std::process::exit(1);
+    }
+    patch
+}
+
+/// Show every file in the unified diff on stdin, by running this
+/// binary on the contents before and after, as far as the patch shows
+/// them. `args` are passed on to each run.
+fn run_from_patch(args: &[String]) -> ! {
+    let patch = read_patch_from_stdin();
+
+    let dir = env::temp_dir().join(format!("difftastic-patch-{}", std::process::id()));
+    if let Err(e) = fs::create_dir_all(&dir) {
+        eprintln!("Could not create {}: {}", dir.display(), e);
+        std::process::exit(1);
+    }
+
+    let mut all_succeeded = true;
+    for (i, file) in parse_patch(&patch).iter().enumerate() {
+        let lhs_path = dir.join(format!("{}-old", i));
+        let rhs_path = dir.join(format!("{}-new", i));
+        if let Err(e) = fs::write(&lhs_path, &file.lhs).and(fs::write(&rhs_path, &file.rhs)) {
+            eprintln!("Could not write to {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+
+        // Use the same arguments as GIT_EXTERNAL_DIFF, so the
+        // language is guessed from the path in the patch.
+        let status = Command::new(current_exe())
+            .args(args)
+            .arg(&file.path)
+            .arg(&lhs_path)
+            .args([".", "."])
+            .arg(&rhs_path)
+            .args([".", "."])
+            .status();
+        match status {
+            Ok(status) => all_succeeded &= status.success(),
+            Err(e) => {
+                eprintln!("Could not run difftastic: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+    std::process::exit(if all_succeeded { 0 } else { 1 });
+}
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// If `DFT_TRACE_FILE` is set, record the tracing spans of this run to
+/// it as a Chrome trace, which can be opened in Perfetto or
+/// `chrome://tracing`.
+#[cfg(feature = "profiling")]
+fn start_tracing() -> Option<tracing_chrome::FlushGuard> {
+    use tracing_subscriber::prelude::*;
+
+    let path = env::var("DFT_TRACE_FILE").ok()?;
+    let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+        .file(path)
+        .include_args(true)
+        .build();
+    tracing_subscriber::registry().with(layer).init();
+    Some(guard)
+}
+
 fn main() {
+    #[cfg(feature = "profiling")]
+    let trace_guard = std::cell::Cell::new(start_tracing());
+
     let matches = App::new("Difftastic")
         .version(VERSION)
         .about("A syntax aware diff.")
@@ -47,8 +468,22 @@ fn main() {
         .arg(
             Arg::with_name("LANGUAGE")
                 .long("lang")
+                .alias("language")
                 .takes_value(true)
-                .help("Override the language parser"),
+                .value_name("NAME")
+                .help("Parse files as this language rather than guessing from the file name. NAME may be a language name, alias, extension or MIME type, e.g. `javascript`, `node`, `js` or `text/javascript`"),
+        )
+        .arg(
+            Arg::with_name("SYNTAX_FILE")
+                .long("syntax-file")
+                .takes_value(true)
+                .help("Read extra language definitions from this file, extending the built-in syntax.toml. Defaults to $DFT_SYNTAX_PATH if set"),
+        )
+        .arg(
+            Arg::with_name("CONFIG_FILE")
+                .long("config")
+                .takes_value(true)
+                .help("Read options, such as edge costs, from this TOML file"),
         )
         .arg(
             Arg::with_name("COLUMNS")
@@ -56,29 +491,613 @@ fn main() {
                 .takes_value(true)
                 .help("Override terminal width"),
         )
+        .arg(
+            Arg::with_name("COMMENT_SIMILARITY_LIMIT")
+                .long("comment-similarity-limit")
+                .takes_value(true)
+                .help("Only compare this many characters when checking if comments are similar (default 2000)"),
+        )
+        .arg(
+            Arg::with_name("TIMEOUT_MS")
+                .long("timeout-ms")
+                .takes_value(true)
+                .value_name("N")
+                .help("Show a line diff if parsing and diffing takes longer than N milliseconds"),
+        )
+        .arg(
+            Arg::with_name("ANCHOR")
+                .long("anchor")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("LHS_LINE:RHS_LINE")
+                .help("Align these lines when diffing. May be given more than once"),
+        )
+        .arg(
+            Arg::with_name("LABEL")
+                .long("label")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME")
+                .help("Show NAME rather than the file path. Given twice, the first is the old file's name and the second is the new file's name. The language is guessed from these names"),
+        )
+        .arg(
+            Arg::with_name("LINES")
+                .long("lines")
+                .takes_value(true)
+                .value_name("START-END")
+                .help("Only diff the top-level items that overlap these lines of the new file"),
+        )
+        .arg(
+            Arg::with_name("NORMALIZE_LITERALS")
+                .long("normalize-literals")
+                .help("Treat literals that the language considers equivalent as unchanged, e.g. 0x10 and 16, or 'a' and \"a\""),
+        )
+        .arg(
+            Arg::with_name("IGNORE_CASE")
+                .long("ignore-case")
+                .help("Compare atoms case-insensitively, so `SELECT` and `select` are unchanged"),
+        )
+        .arg(
+            Arg::with_name("STRUCTURE_ONLY")
+                .long("structure-only")
+                .help("Only compare the shape of the syntax tree and its delimiters, ignoring the content of atoms. This checks that a refactoring preserved structure"),
+        )
+        .arg(
+            Arg::with_name("ABS_TOLERANCE")
+                .long("abs-tolerance")
+                .takes_value(true)
+                .value_name("EPSILON")
+                .help("Treat numbers that differ by at most EPSILON as unchanged, for regenerated data files"),
+        )
+        .arg(
+            Arg::with_name("REL_TOLERANCE")
+                .long("rel-tolerance")
+                .takes_value(true)
+                .value_name("EPSILON")
+                .help("Treat numbers that differ by at most EPSILON times the larger number as unchanged, so 0.01 is 1%"),
+        )
+        .arg(
+            Arg::with_name("BIDIRECTIONAL")
+                .long("bidirectional")
+                .help("Search for the diff from both ends of the files at once. This is usually faster on large files with many changes, but may choose differently between equally good diffs"),
+        )
+        .arg(
+            Arg::with_name("MAX_DEPTH")
+                .long("max-depth")
+                .takes_value(true)
+                .value_name("N")
+                .help("Parse structures nested more than N levels deep as a single atom. This is faster on deeply nested files, but changes inside them are shown less precisely"),
+        )
+        .arg(
+            Arg::with_name("FAST")
+                .long("fast")
+                .conflicts_with("BIDIRECTIONAL")
+                .help("Use an approximate search, which is much faster on huge files but may not find the smallest diff"),
+        )
+        .arg(
+            Arg::with_name("BEAM_WIDTH")
+                .long("beam-width")
+                .takes_value(true)
+                .value_name("N")
+                .requires("FAST")
+                .help("How many candidate diffs --fast keeps at each step. Larger is slower but more accurate (default 100)"),
+        )
+        .arg(
+            Arg::with_name("THREADS")
+                .long("threads")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with_all(&["BIDIRECTIONAL", "FAST"])
+                .help("Search for the diff on N threads. This is faster on large files with many changes, but may choose differently between equally good diffs"),
+        )
+        .arg(
+            Arg::with_name("SIGNIFICANT_INDENTATION")
+                .long("significant-indentation")
+                .help("Treat changes in indentation as changes, as Python does by default"),
+        )
+        .arg(
+            Arg::with_name("DUMP_SYNTAX")
+                .long("dump-syntax")
+                .help("Print the syntax trees of both files as JSON, with the changes found, rather than a diff"),
+        )
+        .arg(
+            Arg::with_name("OUTPUT")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&["side-by-side", "inline", "vim", "emacs", "json", "density"])
+                .default_value("side-by-side")
+                .help("How to display the changes. `inline` shows old and new lines in a single column, with both line numbers. `vim` prints one `path:line:col: message` line per change, for Vim's quickfix list. `emacs` prints one plist per changed region, with its buffer positions. `json` prints the changed regions of each file as a JSON object, in the format given by --schema. `density` prints the fraction of each changed line's tokens that changed, as tab-separated `path side line score` rows"),
+        )
+        .arg(
+            Arg::with_name("SCHEMA")
+                .long("schema")
+                .takes_value(true)
+                .value_name("VERSION")
+                .possible_values(&["1"])
+                .default_value("1")
+                .help("The version of the JSON format written by --output json. Each version stays compatible as difftastic changes"),
+        )
+        .arg(
+            Arg::with_name("MAX_CHANGES")
+                .long("max-changes")
+                .takes_value(true)
+                .value_name("N")
+                .help("Only show the first N changed regions of each file"),
+        )
+        .arg(
+            Arg::with_name("SUMMARY")
+                .long("summary")
+                .help("Only print a one-line summary of how many top-level items of each kind changed, e.g. `3 functions changed, 1 added, imports reordered`"),
+        )
+        .arg(
+            Arg::with_name("CHANGED_LINES")
+                .long("changed-lines")
+                .help("Only print the changed line numbers of each file, e.g. `lhs: 10-14,20  rhs: 11-16`"),
+        )
+        .arg(
+            Arg::with_name("EXIT_CODE")
+                .long("exit-code")
+                .help("Exit with 1 if there are syntactic changes, and 0 otherwise"),
+        )
+        .arg(
+            Arg::with_name("QUIET")
+                .long("quiet")
+                .help("Print nothing, and stop at the first syntactic change. Implies --exit-code"),
+        )
+        .arg(
+            Arg::with_name("STATS")
+                .long("stats")
+                .help("Print counters from the graph search to stderr"),
+        )
+        .arg(
+            Arg::with_name("MEMORY_STATS")
+                .long("memory-stats")
+                .help("Print memory usage of parsing and diffing to stderr"),
+        )
+        .arg(
+            Arg::with_name("GIT_RANGE")
+                .long("git-range")
+                .takes_value(true)
+                .value_name("REF1..REF2")
+                .help("Diff every file that changed between two git refs, with renames detected by git"),
+        )
+        .arg(
+            Arg::with_name("CACHED")
+                .long("cached")
+                .conflicts_with_all(&["GIT_RANGE", "WORKTREE"])
+                .help("Diff the changes staged in git's index against HEAD, like `git diff --cached`. Positional arguments limit this to those paths"),
+        )
+        .arg(
+            Arg::with_name("WORKTREE")
+                .long("worktree")
+                .conflicts_with("GIT_RANGE")
+                .help("Diff the unstaged changes in the worktree against git's index, like `git diff`. Positional arguments limit this to those paths"),
+        )
+        .arg(
+            Arg::with_name("FROM_PATCH")
+                .long("from-patch")
+                .help("Read a unified diff from stdin, such as the output of `git diff`, and show each file structurally. Lines outside the hunks are shown as blank"),
+        )
         .arg(Arg::with_name("positional_args").multiple(true))
+        .subcommand(
+            SubCommand::with_name("calibrate")
+                .about("Find edge costs that give fewer hunks on a directory of before/after file pairs")
+                .arg(
+                    Arg::with_name("DIR")
+                        .required(true)
+                        .help("A directory of files such as foo_before.rs and foo_after.rs"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("log")
+                .about("Show commit logs like `git log -p`, with each change shown by difftastic")
+                .setting(AppSettings::TrailingVarArg)
+                .setting(AppSettings::AllowLeadingHyphen)
+                .arg(
+                    Arg::with_name("GIT_ARGS")
+                        .multiple(true)
+                        .help("Arguments passed to `git log`, such as `-3` or a path"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Read a unified diff from stdin, and write an HTML page for each file and an index page with statistics")
+                .arg(
+                    Arg::with_name("OUT_DIR")
+                        .required(true)
+                        .help("The directory to write the report to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Start a local web server showing structural diffs, with collapsible hunks, of two files, two directories or a git range")
+                .arg(
+                    Arg::with_name("PORT")
+                        .long("port")
+                        .takes_value(true)
+                        .value_name("PORT")
+                        .default_value("8000")
+                        .help("The port to listen on, on 127.0.0.1"),
+                )
+                .arg(
+                    Arg::with_name("GIT_RANGE")
+                        .long("git-range")
+                        .takes_value(true)
+                        .value_name("RANGE")
+                        .conflicts_with("PATHS")
+                        .help("Show the files changed between two git refs, such as main..HEAD"),
+                )
+                .arg(
+                    Arg::with_name("PATHS")
+                        .multiple(true)
+                        .max_values(2)
+                        .help("Two files, or two directories whose files are paired by relative path"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merge two files changed from a common ancestor, like `git merge-file`. Changes to different tokens are combined, even on the same line. Use as a git merge driver with `difftastic merge --path %P %A %O %B`")
+                .arg(
+                    Arg::with_name("STDOUT")
+                        .short("p")
+                        .long("stdout")
+                        .help("Print the merged file, rather than writing it to CURRENT"),
+                )
+                .arg(
+                    Arg::with_name("PATH")
+                        .long("path")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Guess the language from PATH rather than CURRENT, since git's temporary files have no extension"),
+                )
+                .arg(
+                    Arg::with_name("LABEL")
+                        .short("L")
+                        .long("label")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .max_values(2)
+                        .value_name("LABEL")
+                        .help("Name CURRENT, then OTHER, in conflict markers, rather than using their paths. Newer versions of git provide these as %X and %Y"),
+                )
+                .arg(Arg::with_name("CURRENT").required(true).help("Your version of the file, which is overwritten with the result"))
+                .arg(Arg::with_name("BASE").required(true).help("The common ancestor"))
+                .arg(Arg::with_name("OTHER").required(true).help("The version being merged in")),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Read diff requests from stdin as JSON lines, and write a JSON result for each"),
+        )
         .get_matches();
 
+    let syntax_path = match matches.value_of("SYNTAX_FILE") {
+        Some(path) => Some(path.to_string()),
+        None => env::var("DFT_SYNTAX_PATH").ok(),
+    };
+    if let Some(log_matches) = matches.subcommand_matches("log") {
+        let git_args = log_matches.values_of_lossy("GIT_ARGS").unwrap_or_default();
+        run_git(log_command(&current_exe(), &git_args), &syntax_path);
+    }
+    if matches.is_present("FROM_PATCH") {
+        let args: Vec<_> = env::args()
+            .skip(1)
+            .filter(|arg| arg != "--from-patch")
+            .collect();
+        run_from_patch(&args);
+    }
+    if let Some(range) = matches.value_of("GIT_RANGE") {
+        run_git(range_diff_command(&current_exe(), range), &syntax_path);
+    }
+    if matches.is_present("CACHED") || matches.is_present("WORKTREE") {
+        let paths = matches
+            .values_of_lossy("positional_args")
+            .unwrap_or_default();
+        let staged = matches.is_present("CACHED");
+        run_git(
+            local_diff_command(&current_exe(), staged, &paths),
+            &syntax_path,
+        );
+    }
+
+    let custom_registry;
+    let registry = match syntax_path {
+        Some(path) => {
+            custom_registry = read_syntax_file(&path);
+            &custom_registry
+        }
+        None => ConfigDir::read_default_toml(),
+    };
+    let config = match matches.value_of("CONFIG_FILE") {
+        Some(path) => read_config(path),
+        None => Config::default(),
+    };
+
+    if let Some(calibrate_matches) = matches.subcommand_matches("calibrate") {
+        run_calibrate(calibrate_matches.value_of("DIR").unwrap(), registry, config);
+        return;
+    }
+    if let Some(report_matches) = matches.subcommand_matches("report") {
+        let dir = report_matches.value_of("OUT_DIR").unwrap();
+        let files = parse_patch(&read_patch_from_stdin());
+        if let Err(e) = write_report(Path::new(dir), &files, registry) {
+            eprintln!("Could not write report to {}: {}", dir, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(serve_matches) = matches.subcommand_matches("serve") {
+        let port = serve_matches.value_of("PORT").unwrap();
+        if port.parse::<u16>().is_err() {
+            eprintln!("--port must be a port number, got: {}", port);
+            std::process::exit(1);
+        }
+        let paths: Vec<_> = serve_matches
+            .values_of("PATHS")
+            .into_iter()
+            .flatten()
+            .collect();
+        let files = serve_files(serve_matches.value_of("GIT_RANGE"), &paths);
+        let addr = format!("127.0.0.1:{}", port);
+        if let Err(e) = serve(&addr, report_pages(&files, registry)) {
+            eprintln!("Could not serve on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(merge_matches) = matches.subcommand_matches("merge") {
+        let current_path = merge_matches.value_of("CURRENT").unwrap();
+        let other_path = merge_matches.value_of("OTHER").unwrap();
+        let labels: Vec<_> = merge_matches
+            .values_of("LABEL")
+            .into_iter()
+            .flatten()
+            .collect();
+        run_merge(
+            current_path,
+            merge_matches.value_of("BASE").unwrap(),
+            other_path,
+            merge_matches.value_of("PATH").unwrap_or(current_path),
+            (
+                labels.first().copied().unwrap_or(current_path),
+                labels.get(1).copied().unwrap_or(other_path),
+            ),
+            merge_matches.is_present("STDOUT"),
+            registry,
+        );
+    }
+    if matches.subcommand_matches("batch").is_some() {
+        let stdin = std::io::stdin();
+        if let Err(e) = run_batch(registry, stdin.lock(), std::io::stdout()) {
+            eprintln!("Batch mode failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let args: Vec<_> = matches.values_of_lossy("positional_args").unwrap();
 
     // TODO: document these different ways of calling difftastic.
-    let (display_path, lhs_path, rhs_path) = match &args[..] {
-        [lhs_path, rhs_path] => (rhs_path, lhs_path, rhs_path),
-        [display_path, lhs_tmp_file, _lhs_hash, _lhs_mode, rhs_tmp_file, _rhs_hash, _rhs_mode] => {
+    let (display_path, lhs_path, rhs_path, lhs_git_blob, rhs_git_blob) = match &args[..] {
+        [lhs_path, rhs_path] => (rhs_path, lhs_path, rhs_path, None, None),
+        [display_path, lhs_tmp_file, lhs_hash, lhs_mode, rhs_tmp_file, rhs_hash, rhs_mode] => {
             // https://git-scm.com/docs/git#Documentation/git.txt-codeGITEXTERNALDIFFcode
-            (display_path, lhs_tmp_file, rhs_tmp_file)
+            (
+                display_path,
+                lhs_tmp_file,
+                rhs_tmp_file,
+                Some((lhs_hash.as_str(), lhs_mode.as_str())),
+                Some((rhs_hash.as_str(), rhs_mode.as_str())),
+            )
         }
         _ => panic!("Expected 2 arguments or 7 arguments"),
     };
 
-    let syntax_toml = ConfigDir::read_default_toml();
-    let lang = match Path::new(&display_path).extension() {
-        Some(extension) => find_lang(syntax_toml, &OsStr::to_string_lossy(extension)),
+    // Temporary files, such as from process substitution, have
+    // meaningless paths, so users can name them.
+    let labels: Vec<_> = matches.values_of("LABEL").into_iter().flatten().collect();
+    let (lhs_name, rhs_name) = match labels[..] {
+        [] => (display_path.as_str(), display_path.as_str()),
+        [label] => (label, label),
+        [lhs_label, rhs_label] => (lhs_label, rhs_label),
+        _ => {
+            eprintln!("--label may be given at most twice");
+            std::process::exit(1);
+        }
+    };
+    let display_path = rhs_name;
+    let header_name = if lhs_name == rhs_name {
+        rhs_name.to_string()
+    } else {
+        format!("{} -> {}", lhs_name, rhs_name)
+    };
+
+    let anchors: Vec<_> = matches
+        .values_of("ANCHOR")
+        .into_iter()
+        .flatten()
+        .map(|anchor| match parse_anchor(anchor) {
+            Ok(anchor) => anchor,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        })
+        .collect();
+
+    let line_range = matches
+        .value_of("LINES")
+        .map(|range| match parse_line_range(range) {
+            Ok(range) => range,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        });
+
+    let lang = match matches.value_of("LANGUAGE") {
+        Some(name) => match lang_by_name(registry, name) {
+            Some(lang) => Some(lang),
+            None => {
+                eprintln!("No language found for {:?}", name);
+                std::process::exit(1);
+            }
+        },
+        None => guess_language(registry, rhs_name).or_else(|| guess_language(registry, lhs_name)),
+    };
+    let normalize_literals = matches.is_present("NORMALIZE_LITERALS");
+    let significant_indentation = matches.is_present("SIGNIFICANT_INDENTATION");
+    let ignore_case = matches.is_present("IGNORE_CASE");
+    let structure_only = matches.is_present("STRUCTURE_ONLY");
+    let flatten_depth = matches
+        .value_of("MAX_DEPTH")
+        .map(|depth| match depth.parse::<usize>() {
+            Ok(depth) => depth,
+            Err(_) => {
+                eprintln!("Invalid --max-depth {:?}, expected a number", depth);
+                std::process::exit(1);
+            }
+        });
+    let tolerance = |name: &str, flag: &str| match matches.value_of(name) {
+        Some(epsilon) => match epsilon.parse::<f64>() {
+            Ok(epsilon) if epsilon >= 0.0 => Some(epsilon),
+            _ => {
+                eprintln!(
+                    "Invalid {} {:?}, expected a non-negative number",
+                    flag, epsilon
+                );
+                std::process::exit(1);
+            }
+        },
         None => None,
     };
+    let abs_tolerance = tolerance("ABS_TOLERANCE", "--abs-tolerance");
+    let rel_tolerance = tolerance("REL_TOLERANCE", "--rel-tolerance");
+    let numeric_tolerance = if abs_tolerance.is_some() || rel_tolerance.is_some() {
+        Some(NumericTolerance {
+            absolute: abs_tolerance.unwrap_or(0.0),
+            relative: rel_tolerance.unwrap_or(0.0),
+        })
+    } else {
+        None
+    };
+    let customized_lang;
+    let lang = match lang {
+        Some(lang)
+            if normalize_literals
+                || significant_indentation
+                || ignore_case
+                || structure_only
+                || numeric_tolerance.is_some()
+                || flatten_depth.is_some()
+                || !config.ignore_patterns.is_empty() =>
+        {
+            let mut customized = lang.clone().ignoring(config.ignore_regexes());
+            if normalize_literals {
+                customized = customized.normalizing_literals();
+            }
+            if significant_indentation {
+                customized = customized.with_significant_indentation();
+            }
+            if ignore_case {
+                customized = customized.ignoring_case();
+            }
+            if structure_only {
+                customized = customized.comparing_structure_only();
+            }
+            if let Some(numeric_tolerance) = numeric_tolerance {
+                customized = customized.with_numeric_tolerance(numeric_tolerance);
+            }
+            if let Some(flatten_depth) = flatten_depth {
+                customized = customized.with_flatten_depth(flatten_depth);
+            }
+            customized_lang = customized;
+            Some(&customized_lang)
+        }
+        _ => lang,
+    };
+
+    let max_changes = matches
+        .value_of("MAX_CHANGES")
+        .map(|n| match n.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("Invalid --max-changes {:?}, expected a number", n);
+                std::process::exit(1);
+            }
+        });
+
+    let comment_similarity_limit =
+        matches
+            .value_of("COMMENT_SIMILARITY_LIMIT")
+            .map(|limit| match limit.parse::<usize>() {
+                Ok(limit) => limit,
+                Err(_) => {
+                    eprintln!(
+                        "Invalid --comment-similarity-limit {:?}, expected a number",
+                        limit
+                    );
+                    std::process::exit(1);
+                }
+            });
+
+    let deadline = matches
+        .value_of("TIMEOUT_MS")
+        .map(|ms| match ms.parse::<u64>() {
+            Ok(ms) => Instant::now() + Duration::from_millis(ms),
+            Err(_) => {
+                eprintln!("Invalid --timeout-ms {:?}, expected a number", ms);
+                std::process::exit(1);
+            }
+        });
+
+    let dump_syntax = matches.is_present("DUMP_SYNTAX");
+    let changed_lines = matches.is_present("CHANGED_LINES");
+    let vim_output = matches.value_of("OUTPUT") == Some("vim");
+    let emacs_output = matches.value_of("OUTPUT") == Some("emacs");
+    let density_output = matches.value_of("OUTPUT") == Some("density");
+    // Only version 1 exists so far, and clap rejects other versions.
+    let json_output = matches.value_of("OUTPUT") == Some("json");
+    let summary = matches.is_present("SUMMARY");
+    let quiet = matches.is_present("QUIET");
+    let exit_code = quiet || matches.is_present("EXIT_CODE");
+    // Output intended for other programs, and summaries, have no
+    // header or notes.
+    let machine_output = dump_syntax
+        || changed_lines
+        || vim_output
+        || emacs_output
+        || density_output
+        || json_output
+        || summary
+        || quiet;
 
-    let lhs_bytes = read_or_die(&lhs_path);
-    let rhs_bytes = read_or_die(&rhs_path);
+    // Symlinks and submodules are compared by what they point to.
+    let lhs_link = read_link(lhs_path, lhs_git_blob);
+    let rhs_link = read_link(rhs_path, rhs_git_blob);
+    if let Some(link) = rhs_link.as_ref().or(lhs_link.as_ref()) {
+        let description = describe_link_change(
+            lhs_link.as_ref(),
+            rhs_link.as_ref(),
+            lhs_path != "/dev/null",
+            rhs_path != "/dev/null",
+        );
+        if summary {
+            println!("{}: {}", display_path, description);
+        } else if !machine_output {
+            println!("{}", style::header(&header_name, link.kind()));
+            println!("{}", description);
+        }
+        if exit_code && lhs_link != rhs_link {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let lhs_bytes = read_or_die(lhs_path);
+    let rhs_bytes = read_or_die(rhs_path);
     let lhs_binary = is_probably_binary(&lhs_bytes);
     let rhs_binary = is_probably_binary(&rhs_bytes);
 
@@ -87,9 +1106,20 @@ fn main() {
         Some(lang) => lang.name.clone(),
         None => "plain text".to_string(),
     };
-    println!("{}", style::header(&display_path, &lang_name));
+    if !machine_output {
+        println!("{}", style::header(&header_name, &lang_name));
+    }
 
     if lhs_binary || rhs_binary {
+        if summary {
+            println!("{}: binary", display_path);
+        }
+        if json_output {
+            print_json(&JsonFileV1::binary(display_path, lhs_bytes != rhs_bytes));
+        }
+        if exit_code && lhs_bytes != rhs_bytes {
+            std::process::exit(1);
+        }
         return;
     }
 
@@ -104,36 +1134,325 @@ fn main() {
     let arena = Arena::new();
 
     let (lhs, rhs) = match &lang {
-        Some(lang) => (
-            parse(&arena, &lhs_src, &lang),
-            parse(&arena, &rhs_src, &lang),
-        ),
+        Some(lang) => parse_pair(&arena, &lhs_src, &rhs_src, lang),
         None => (parse_lines(&arena, &lhs_src), parse_lines(&arena, &rhs_src)),
     };
+    let (mut lhs, mut rhs) = select_line_range(lhs, rhs, &lhs_src, &rhs_src, line_range);
+
+    let beam_width = if matches.is_present("FAST") {
+        match matches.value_of("BEAM_WIDTH") {
+            Some(width) => match width.parse::<usize>() {
+                Ok(width) if width > 0 => Some(width),
+                _ => {
+                    eprintln!(
+                        "Invalid --beam-width {:?}, expected a positive number",
+                        width
+                    );
+                    std::process::exit(1);
+                }
+            },
+            None => Some(DEFAULT_BEAM_WIDTH),
+        }
+    } else {
+        None
+    };
+
+    let threads = match matches.value_of("THREADS") {
+        Some(threads) => match threads.parse::<usize>() {
+            Ok(threads) if threads > 0 => threads,
+            _ => {
+                eprintln!(
+                    "Invalid --threads {:?}, expected a positive number",
+                    threads
+                );
+                std::process::exit(1);
+            }
+        },
+        None => 1,
+    };
+
+    // Diffing very large, very different files would take too long
+    // and use too much memory, so fall back to a line-oriented diff.
+    // The beam search is bounded, so it doesn't need to.
+    let mut is_syntactic = lang.is_some();
+    if is_syntactic
+        && beam_width.is_none()
+        && estimated_graph_size(&lhs, &rhs) > MAX_ESTIMATED_GRAPH_SIZE
+    {
+        if !machine_output {
+            println!("Too many changes for a syntactic diff, showing a line diff instead.");
+        }
+        let (lhs_lines, rhs_lines) = select_line_range(
+            parse_lines(&arena, &lhs_src),
+            parse_lines(&arena, &rhs_src),
+            &lhs_src,
+            &rhs_src,
+            line_range,
+        );
+        lhs = lhs_lines;
+        rhs = rhs_lines;
+        is_syntactic = false;
+    }
+
+    // Whether there are any changes doesn't depend on the route
+    // through the graph, so --quiet doesn't need to search it.
+    let changed = exit_code && has_changes(&lhs, &rhs);
+    if quiet {
+        if changed {
+            std::process::exit(1);
+        }
+        return;
+    }
 
     init_info(&lhs);
     init_info(&rhs);
-    mark_syntax(lhs.get(0).copied(), rhs.get(0).copied());
+    let mut search_config = SearchConfig {
+        costs: config.costs,
+        ..SearchConfig::default()
+    };
+    if let Some(limit) = comment_similarity_limit {
+        search_config.max_comment_similarity_len = limit;
+    }
+    search_config.bidirectional = matches.is_present("BIDIRECTIONAL");
+    search_config.beam_width = beam_width;
+    search_config.threads = threads;
+    search_config.anchors = anchors;
+    search_config.deadline = deadline;
+    let mut search_stats = mark_syntax(lhs.first().copied(), rhs.first().copied(), &search_config);
+    if search_stats.timed_out {
+        if !machine_output {
+            println!("Timed out diffing syntactically, showing a line diff instead.");
+        }
+        let (lhs_lines, rhs_lines) = select_line_range(
+            parse_lines(&arena, &lhs_src),
+            parse_lines(&arena, &rhs_src),
+            &lhs_src,
+            &rhs_src,
+            line_range,
+        );
+        lhs = lhs_lines;
+        rhs = rhs_lines;
+        is_syntactic = false;
+        init_info(&lhs);
+        init_info(&rhs);
+        search_config.deadline = None;
+        search_stats = mark_syntax(lhs.first().copied(), rhs.first().copied(), &search_config);
+    }
+    if is_syntactic {
+        slide_novel_runs(&lhs);
+        slide_novel_runs(&rhs);
+        mark_moved(&lhs, &rhs);
+    }
+    // Print stats, then exit with 1 for --exit-code if there were
+    // changes.
+    let finish = || {
+        // The trace is written when its guard is dropped, but exiting
+        // skips destructors.
+        #[cfg(feature = "profiling")]
+        drop(trace_guard.take());
+        if matches.is_present("STATS") {
+            eprintln!("{}", search_counters(display_path, &search_stats));
+        }
+        if matches.is_present("MEMORY_STATS") {
+            eprintln!("{}", memory_stats(display_path, &arena, &search_stats));
+        }
+        if changed {
+            std::process::exit(1);
+        }
+    };
+
+    if summary {
+        println!("{}: {}", display_path, summarize(&lhs, &rhs));
+        finish();
+        return;
+    }
+
+    if dump_syntax {
+        match serde_json::to_string(&SerializedDiff::new(&lhs, &rhs)) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Could not serialize syntax trees: {}", e);
+                std::process::exit(1);
+            }
+        }
+        finish();
+        return;
+    }
 
     let lhs_positions = change_positions(&lhs_src, &rhs_src, &lhs);
     let rhs_positions = change_positions(&rhs_src, &lhs_src, &rhs);
 
+    if json_output {
+        print_json(&JsonFileV1::new(
+            display_path,
+            lang.map(|lang| lang.name.as_str()),
+            is_syntactic,
+            &lhs_src,
+            &rhs_src,
+            &lhs,
+            &rhs,
+            &lhs_positions,
+            &rhs_positions,
+        ));
+        finish();
+        return;
+    }
+
+    if changed_lines {
+        println!(
+            "lhs: {}  rhs: {}",
+            format_line_ranges(&changed_line_ranges(&lhs_positions)),
+            format_line_ranges(&changed_line_ranges(&rhs_positions))
+        );
+        finish();
+        return;
+    }
+
     let lhs_matched_lines = matching_lines(&lhs);
+    let rhs_moved_from = moved_from(&rhs);
 
-    let mut groups = visible_groups(&lhs_positions, &rhs_positions);
-    if groups.is_empty() {
-        if lang.is_some() {
+    let lhs_definitions = definitions(&lhs);
+    let rhs_definitions = definitions(&rhs);
+    let lhs_scopes = top_level_scopes(&lhs_definitions);
+    let rhs_scopes = top_level_scopes(&rhs_definitions);
+    let mut groups = join_scopes(
+        visible_groups(&lhs_positions, &rhs_positions),
+        &lhs_positions,
+        &rhs_positions,
+        &lhs_scopes,
+        &rhs_scopes,
+    );
+    let is_unchanged = groups.is_empty();
+    let mut hidden_changes = 0;
+    if let Some(max_changes) = max_changes {
+        if groups.len() > max_changes {
+            hidden_changes = groups.len() - max_changes;
+            groups.truncate(max_changes);
+        }
+    }
+    if vim_output {
+        print!(
+            "{}",
+            format_quickfix(display_path, &groups, &lhs_positions, &rhs_positions)
+        );
+        finish();
+        return;
+    }
+    if emacs_output {
+        print!(
+            "{}",
+            format_emacs(
+                display_path,
+                &lhs_src,
+                &rhs_src,
+                &lhs_positions,
+                &rhs_positions
+            )
+        );
+        finish();
+        return;
+    }
+    if density_output {
+        print!(
+            "{}",
+            format_density(display_path, &lhs_positions, &rhs_positions)
+        );
+        finish();
+        return;
+    }
+    if is_unchanged {
+        if is_syntactic {
             println!("No syntactic changes.");
         } else {
             println!("No changes.");
         }
+        finish();
         return;
     }
 
-    for group in &mut groups {
-        group.pad(3, lhs_src.max_line(), rhs_src.max_line());
+    if lhs_src
+        .lines()
+        .chain(rhs_src.lines())
+        .any(|line| line.len() > LONG_LINE_LENGTH)
+    {
+        print_column_chunks(
+            &lhs_src,
+            &rhs_src,
+            &lhs_positions,
+            &rhs_positions,
+            terminal_width,
+            &config.theme,
+            max_changes,
+        );
+        finish();
+        return;
+    }
+
+    if groups.is_empty() {
+        // Only possible with --max-changes 0.
+        println!("{}", more_changes_note(hidden_changes));
+        finish();
+        return;
+    }
+
+    groups = pad_scopes(
+        groups,
+        lhs_src.max_line(),
+        rhs_src.max_line(),
+        &lhs_positions,
+        &rhs_positions,
+        &lhs_scopes,
+        &rhs_scopes,
+    );
+
+    let headings: Vec<_> = groups
+        .iter()
+        .map(|group| {
+            group_heading(
+                group,
+                &lhs_positions,
+                &rhs_positions,
+                &lhs_definitions,
+                &rhs_definitions,
+            )
+        })
+        .collect();
+
+    let lhs_invisible = invisible_changes(&lhs_src, &lhs_positions, &rhs_src, &rhs_positions);
+    let rhs_invisible = invisible_changes(&rhs_src, &rhs_positions, &lhs_src, &lhs_positions);
+
+    if matches.value_of("OUTPUT") == Some("inline") {
+        print!(
+            "{}",
+            apply_groups_inline(
+                &apply_colors(
+                    &lhs_src,
+                    true,
+                    &lhs_positions,
+                    &config.theme,
+                    &lhs_invisible
+                ),
+                &apply_colors(
+                    &rhs_src,
+                    false,
+                    &rhs_positions,
+                    &config.theme,
+                    &rhs_invisible
+                ),
+                &groups,
+                &headings,
+                &lhs_matched_lines,
+                &rhs_moved_from,
+                &lhs_positions,
+                &rhs_positions,
+            )
+        );
+        if hidden_changes > 0 {
+            println!("{}", more_changes_note(hidden_changes));
+        }
+        finish();
+        return;
     }
-    groups = join_overlapping(groups);
 
     let lhs_column_width = format_line_num(groups.last().unwrap().max_visible_lhs().0).len();
     let rhs_column_width = format_line_num(groups.last().unwrap().max_visible_rhs().0).len();
@@ -151,8 +1470,20 @@ fn main() {
 
     let lhs_src = enforce_length(&lhs_src, lhs_content_width);
     let rhs_src = enforce_length(&rhs_src, rhs_content_width);
-    let lhs_colored = apply_colors(&lhs_src, true, &lhs_positions);
-    let rhs_colored = apply_colors(&rhs_src, false, &rhs_positions);
+    let lhs_colored = apply_colors(
+        &lhs_src,
+        true,
+        &lhs_positions,
+        &config.theme,
+        &lhs_invisible,
+    );
+    let rhs_colored = apply_colors(
+        &rhs_src,
+        false,
+        &rhs_positions,
+        &config.theme,
+        &rhs_invisible,
+    );
 
     print!(
         "{}",
@@ -160,7 +1491,9 @@ fn main() {
             &lhs_colored,
             &rhs_colored,
             &groups,
+            &headings,
             &lhs_matched_lines,
+            &rhs_moved_from,
             lhs_content_width,
             rhs_content_width,
             lhs_column_width,
@@ -168,4 +1501,8 @@ fn main() {
         )
     );
     println!();
+    if hidden_changes > 0 {
+        println!("{}", more_changes_note(hidden_changes));
+    }
+    finish();
 }
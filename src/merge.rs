@@ -0,0 +1,390 @@
+//! Three-way merges that use the syntax tree, so `difftastic merge`
+//! can be used as a git merge driver. Each side is diffed against
+//! the base, and the two sets of changes are combined whenever they
+//! change different tokens, even if they're on the same line.
+
+use std::cmp::{max, min};
+use typed_arena::Arena;
+
+use crate::diff_trees;
+use crate::lines::NewlinePositions;
+use crate::parse::Language;
+use crate::positions::SingleLineSpan;
+use crate::syntax::{ChangeKind, Syntax};
+
+/// A replacement of `base[start..end]` with `text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Edit {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+impl Edit {
+    /// Whether `self` and `other` can't both be applied, because they
+    /// change the same text or insert at the same point.
+    fn conflicts_with(&self, other: &Edit) -> bool {
+        if self.start == self.end || other.start == other.end {
+            self.start <= other.end && other.start <= self.end
+        } else {
+            self.start < other.end && other.start < self.end
+        }
+    }
+}
+
+/// The result of merging two files with their common ancestor.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MergeResult {
+    /// The merged file, with conflict markers around any regions that
+    /// both sides changed.
+    pub merged: String,
+    pub num_conflicts: usize,
+}
+
+/// A byte range in the base file, and the matching range in the other
+/// file.
+type TokenPair = ((usize, usize), (usize, usize));
+
+/// The byte range of `spans` in the file with `nl_pos`.
+fn span_offsets(nl_pos: &NewlinePositions, spans: &[SingleLineSpan]) -> Option<(usize, usize)> {
+    let first = spans.first()?;
+    let last = spans.last()?;
+    Some((
+        nl_pos.to_offset(first.line, first.start_col),
+        nl_pos.to_offset(last.line, last.end_col),
+    ))
+}
+
+/// Every token in `nodes` that's unchanged on the other side, as the
+/// byte range of the token and of its counterpart.
+fn unchanged_tokens<'a>(
+    nl_pos: &NewlinePositions,
+    opposite_nl_pos: &NewlinePositions,
+    nodes: &[&'a Syntax<'a>],
+    tokens: &mut Vec<TokenPair>,
+) {
+    for node in nodes {
+        let opposite = match node.info().change.get() {
+            Some(ChangeKind::Unchanged(opposite)) => Some(opposite),
+            _ => None,
+        };
+        match (node, opposite) {
+            (
+                Syntax::List {
+                    open_position,
+                    children,
+                    close_position,
+                    ..
+                },
+                opposite,
+            ) => {
+                if let Some(Syntax::List {
+                    open_position: opposite_open_position,
+                    ..
+                }) = opposite
+                {
+                    tokens.extend(
+                        span_offsets(nl_pos, open_position)
+                            .zip(span_offsets(opposite_nl_pos, opposite_open_position)),
+                    );
+                }
+                unchanged_tokens(nl_pos, opposite_nl_pos, children, tokens);
+                if let Some(Syntax::List {
+                    close_position: opposite_close_position,
+                    ..
+                }) = opposite
+                {
+                    tokens.extend(
+                        span_offsets(nl_pos, close_position)
+                            .zip(span_offsets(opposite_nl_pos, opposite_close_position)),
+                    );
+                }
+            }
+            (
+                Syntax::Atom { position, .. },
+                Some(Syntax::Atom {
+                    position: opposite_position,
+                    ..
+                }),
+            ) => {
+                tokens.extend(
+                    span_offsets(nl_pos, position)
+                        .zip(span_offsets(opposite_nl_pos, opposite_position)),
+                );
+            }
+            (Syntax::Atom { .. }, _) => {}
+        }
+    }
+}
+
+/// Shrink the replacement of `base` with `side` to the part that
+/// differs, returning the length of the common prefix and suffix.
+fn common_affixes(base: &str, side: &str) -> (usize, usize) {
+    let prefix = base
+        .char_indices()
+        .zip(side.chars())
+        .find(|((_, b), s)| b != s)
+        .map_or(min(base.len(), side.len()), |((i, _), _)| i);
+    // The suffix can't overlap the prefix.
+    let suffix: usize = base[prefix..]
+        .chars()
+        .rev()
+        .zip(side[prefix..].chars().rev())
+        .take_while(|(b, s)| b == s)
+        .map(|(b, _)| b.len_utf8())
+        .sum();
+    (prefix, suffix)
+}
+
+/// The changes that turn `base` into `side`, found by diffing their
+/// syntax trees. Tokens that are unchanged, in order, anchor the two
+/// files, and any text that differs between two anchors is an edit.
+fn edits(base: &str, side: &str, lang: Option<&Language>) -> Vec<Edit> {
+    let arena = Arena::new();
+    let (base_nodes, _, _, _) = diff_trees(&arena, base, side, lang);
+
+    let base_nl_pos = NewlinePositions::from(base);
+    let side_nl_pos = NewlinePositions::from(side);
+    let mut tokens = vec![];
+    unchanged_tokens(&base_nl_pos, &side_nl_pos, &base_nodes, &mut tokens);
+    tokens.sort_unstable();
+
+    // Only use tokens that are in the same order on both sides, and
+    // whose text is identical, as anchors.
+    let mut anchors: Vec<TokenPair> = vec![((0, 0), (0, 0))];
+    for ((base_start, base_end), (side_start, side_end)) in tokens {
+        let (prev_base, prev_side) = anchors[anchors.len() - 1];
+        if base_start >= prev_base.1
+            && side_start >= prev_side.1
+            && base[base_start..base_end] == side[side_start..side_end]
+        {
+            anchors.push(((base_start, base_end), (side_start, side_end)));
+        }
+    }
+    anchors.push(((base.len(), base.len()), (side.len(), side.len())));
+
+    let mut res = vec![];
+    for pair in anchors.windows(2) {
+        let ((_, base_start), (_, side_start)) = pair[0];
+        let ((base_end, _), (side_end, _)) = pair[1];
+        let base_text = &base[base_start..base_end];
+        let side_text = &side[side_start..side_end];
+        if base_text != side_text {
+            let (prefix, suffix) = common_affixes(base_text, side_text);
+            res.push(Edit {
+                start: base_start + prefix,
+                end: base_end - suffix,
+                text: side_text[prefix..side_text.len() - suffix].to_string(),
+            });
+        }
+    }
+    res
+}
+
+/// `base[start..end]` with `edits` applied. Every edit must be inside
+/// the range, and they must be sorted and not overlap.
+fn apply_edits(base: &str, start: usize, end: usize, edits: &[&Edit]) -> String {
+    let mut res = String::new();
+    let mut i = start;
+    for edit in edits {
+        res.push_str(&base[i..edit.start]);
+        res.push_str(&edit.text);
+        i = edit.end;
+    }
+    res.push_str(&base[i..end]);
+    res
+}
+
+/// The start of the line containing `start`, and the end of the line
+/// containing `end`, including its newline.
+fn expand_to_lines(base: &str, start: usize, end: usize) -> (usize, usize) {
+    let line_start = base[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = if end > line_start && base[..end].ends_with('\n') {
+        end
+    } else {
+        base[end..].find('\n').map_or(base.len(), |i| end + i + 1)
+    };
+    (line_start, line_end)
+}
+
+/// Merge `current` and `other`, which were both changed from `base`.
+/// Changes that only one side made, or that both sides made
+/// identically, are applied. Where both sides changed the same
+/// tokens, the lines involved are shown with conflict markers, named
+/// with `current_label` and `other_label`.
+pub fn merge(
+    base: &str,
+    current: &str,
+    other: &str,
+    lang: Option<&Language>,
+    current_label: &str,
+    other_label: &str,
+) -> MergeResult {
+    let current_edits = edits(base, current, lang);
+    let other_edits = edits(base, other, lang);
+    // Edits that both sides made identically aren't conflicts.
+    let other_only: Vec<&Edit> = other_edits
+        .iter()
+        .filter(|edit| !current_edits.contains(edit))
+        .collect();
+
+    // Find the regions that both sides changed, whole lines at a time.
+    let mut regions: Vec<(usize, usize)> = vec![];
+    for current_edit in &current_edits {
+        for other_edit in &other_only {
+            if current_edit.conflicts_with(other_edit) {
+                regions.push(expand_to_lines(
+                    base,
+                    min(current_edit.start, other_edit.start),
+                    max(current_edit.end, other_edit.end),
+                ));
+            }
+        }
+    }
+    let mut all_edits: Vec<&Edit> = current_edits.iter().chain(other_only).collect();
+    all_edits.sort_by_key(|edit| (edit.start, edit.end));
+
+    // Grow the regions until every edit is either inside a region or
+    // outside all of them, merging regions that overlap.
+    let is_inside =
+        |edit: &Edit, (start, end): (usize, usize)| edit.start >= start && edit.end <= end;
+    loop {
+        regions.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (start, end) in regions.drain(..) {
+            match merged.last_mut() {
+                Some((_, prev_end)) if start < *prev_end => *prev_end = max(*prev_end, end),
+                _ => merged.push((start, end)),
+            }
+        }
+        regions = merged;
+
+        let mut changed = false;
+        for region in &mut regions {
+            let region_edit = Edit {
+                start: region.0,
+                end: region.1,
+                text: String::new(),
+            };
+            for edit in &all_edits {
+                if !is_inside(edit, *region) && edit.conflicts_with(&region_edit) {
+                    *region =
+                        expand_to_lines(base, min(region.0, edit.start), max(region.1, edit.end));
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let side_text = |edits: &[Edit], region: (usize, usize)| {
+        let inside: Vec<&Edit> = edits
+            .iter()
+            .filter(|edit| is_inside(edit, region))
+            .collect();
+        let mut text = apply_edits(base, region.0, region.1, &inside);
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text
+    };
+
+    let mut merged = String::new();
+    let mut i = 0;
+    let mut outside_edits = all_edits
+        .iter()
+        .copied()
+        .filter(|edit| !regions.iter().any(|region| is_inside(edit, *region)))
+        .peekable();
+    for &region in &regions {
+        let mut before = vec![];
+        while let Some(edit) = outside_edits.next_if(|edit| edit.end <= region.0) {
+            before.push(edit);
+        }
+        merged.push_str(&apply_edits(base, i, region.0, &before));
+
+        merged.push_str(&format!("<<<<<<< {}\n", current_label));
+        merged.push_str(&side_text(&current_edits, region));
+        merged.push_str("=======\n");
+        merged.push_str(&side_text(&other_edits, region));
+        merged.push_str(&format!(">>>>>>> {}\n", other_label));
+        i = region.1;
+    }
+    let after: Vec<&Edit> = outside_edits.collect();
+    merged.push_str(&apply_edits(base, i, base.len(), &after));
+
+    MergeResult {
+        merged,
+        num_conflicts: regions.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "lang-javascript")]
+    use crate::parse::{find_lang, ConfigDir};
+
+    #[cfg(feature = "lang-javascript")]
+    fn merge_js(base: &str, current: &str, other: &str) -> MergeResult {
+        let lang = find_lang(ConfigDir::read_default_toml(), "js").unwrap();
+        merge(base, current, other, Some(lang), "current", "other")
+    }
+
+    #[test]
+    fn test_common_affixes() {
+        assert_eq!(common_affixes("a b", "a c"), (2, 0));
+        assert_eq!(common_affixes(" x ", " yy "), (1, 1));
+        assert_eq!(common_affixes("aa", "aaa"), (2, 0));
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_merge_same_line() {
+        let result = merge_js("foo(1, 2);\n", "foo(3, 2);\n", "foo(1, 4);\n");
+        assert_eq!(
+            result,
+            MergeResult {
+                merged: "foo(3, 4);\n".into(),
+                num_conflicts: 0,
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_merge_ignores_reformatting_elsewhere() {
+        let result = merge_js(
+            "a(1);\nb(2);\n",
+            "a(10);\nb(2);\n",
+            "a(1);\nb(\n  2,\n  3\n);\n",
+        );
+        assert_eq!(result.merged, "a(10);\nb(\n  2,\n  3\n);\n");
+        assert_eq!(result.num_conflicts, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_merge_identical_changes() {
+        let result = merge_js("x = 1;\n", "x = 2;\n", "x = 2;\n");
+        assert_eq!(result.merged, "x = 2;\n");
+        assert_eq!(result.num_conflicts, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_merge_conflict() {
+        let result = merge_js(
+            "a(1);\nx = 1;\nb(2);\n",
+            "a(10);\nx = 2;\nb(2);\n",
+            "a(1);\nx = 3;\nb(20);\n",
+        );
+        assert_eq!(
+            result.merged,
+            "a(10);\n<<<<<<< current\nx = 2;\n=======\nx = 3;\n>>>>>>> other\nb(20);\n"
+        );
+        assert_eq!(result.num_conflicts, 1);
+    }
+}
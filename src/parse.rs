@@ -1,10 +1,17 @@
 use crate::lines::NewlinePositions;
-use crate::positions::SingleLineSpan;
-use crate::syntax::Syntax;
-use regex::Regex;
+use crate::positions::{SingleLineSpan, Spans};
+use crate::syntax::{Syntax, TokenKind};
+use once_cell::sync::OnceCell;
+use regex::{CaptureLocations, Regex};
 use rust_embed::RustEmbed;
+use smallvec::smallvec;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs;
-use toml::Value;
+use std::panic;
+use std::path::Path;
+use std::thread;
+use toml::value::{Table, Value};
 use typed_arena::Arena;
 
 pub fn read_or_die(path: &str) -> Vec<u8> {
@@ -18,6 +25,9 @@ pub fn read_or_die(path: &str) -> Vec<u8> {
                 std::io::ErrorKind::PermissionDenied => {
                     eprintln!("Permission denied when reading file: {}", path);
                 }
+                std::io::ErrorKind::IsADirectory => {
+                    eprintln!("Expected a file, but {} is a directory", path);
+                }
                 _ => {
                     eprintln!("Could not read file: {} (error {:?})", path, e.kind());
                 }
@@ -32,89 +42,947 @@ pub fn read_or_die(path: &str) -> Vec<u8> {
 pub struct ConfigDir;
 
 impl ConfigDir {
-    pub fn read_default_toml() -> Vec<Language> {
+    fn default_toml() -> String {
         let syntax_toml_bytes = ConfigDir::get("syntax.toml").unwrap();
-        let syntax_toml = std::str::from_utf8(syntax_toml_bytes.as_ref()).unwrap();
-        read_syntax_toml(syntax_toml)
+        std::str::from_utf8(syntax_toml_bytes.as_ref())
+            .unwrap()
+            .to_string()
+    }
+
+    /// The built-in language definitions, without languages whose
+    /// feature is disabled.
+    fn default_table() -> Table {
+        parse_syntax_table(&ConfigDir::default_toml())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(name, _)| builtin_enabled(name).unwrap_or(true))
+            .collect()
+    }
+
+    /// The built-in language definitions. These are only read once,
+    /// and shared between threads.
+    pub fn read_default_toml() -> &'static LanguageRegistry {
+        static REGISTRY: OnceCell<LanguageRegistry> = OnceCell::new();
+        REGISTRY.get_or_init(|| read_syntax_toml(ConfigDir::default_table(), &[]))
+    }
+}
+
+/// Read language definitions from the syntax.toml at `path`, layered
+/// on top of the copy embedded in the binary.
+///
+/// Languages that aren't built-in must be fully defined. For built-in
+/// languages, array values (e.g. `extensions`) are appended to the
+/// built-in values, and other values replace them.
+pub fn read_syntax_file(path: &str) -> LanguageRegistry {
+    let bytes = read_or_die(path);
+    match std::str::from_utf8(&bytes) {
+        Ok(src) => read_syntax_toml(ConfigDir::default_table(), &[src]),
+        Err(_) => {
+            eprintln!("Syntax file is not valid UTF-8: {}", path);
+            std::process::exit(1);
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct Language {
     pub name: String,
-    extensions: Vec<String>,
+    /// The comment, delimiter and atom patterns, combined so the next
+    /// token is found in one search.
+    token_scanner: TokenScanner,
+    /// As `token_scanner`, but also matching regex literals, for use
+    /// where a value is expected.
+    regex_literal_scanner: Option<TokenScanner>,
+    /// Pairs of open and close strings for comments that may be
+    /// nested, such as `/* /* */ */` in Rust.
+    nested_comment_delimiters: Vec<(String, String)>,
+    /// Raw strings whose terminator depends on how they started, such
+    /// as `r##"foo"##` in Rust. Each entry is a regex for the start
+    /// of the string, and a template for the end, where `${1}` is
+    /// replaced with the first capture group of the start.
+    raw_string_patterns: Vec<(Regex, String)>,
+    /// Heredocs, such as `<<EOF` in shell scripts. Each entry is a
+    /// regex for the start of the heredoc, where the first capture
+    /// group is the terminator. The heredoc ends on the first later
+    /// line that starts with the terminator, ignoring indentation.
+    heredoc_patterns: Vec<Regex>,
+    /// Strings that may span several lines, such as `"""foo"""` in
+    /// Python. Each entry is a regex for the start of the string and
+    /// the literal text that ends it. A backslash escapes the
+    /// following character.
+    string_patterns: Vec<(Regex, String)>,
+    /// Keywords that may precede a regex literal, such as `return`.
+    regex_literal_keywords: Vec<String>,
+    /// Pairs of keywords that open and close a block, such as `do`
+    /// and `end` in Elixir. An atom whose content is exactly one of
+    /// these keywords is treated as a delimiter.
+    keyword_delimiters: Vec<(String, String)>,
+    /// Lists whose entries may appear in any order, such as JSON
+    /// objects. Each entry is the open and close delimiter, and the
+    /// separator between entries (or "" if every child is an entry).
+    unordered_lists: Vec<UnorderedList>,
+    /// If set, `key SEPARATOR value` is parsed as a list whose open
+    /// delimiter is the key, such as `"a": 1` in JSON. Entries are
+    /// then only matched with entries that have the same key.
+    key_value_separator: Option<String>,
+    /// If set, top-level lists with this open delimiter are section
+    /// headers, such as `[package]` in TOML. The following nodes are
+    /// grouped into a list whose open delimiter is the whole header.
+    section_delimiter: Option<String>,
+    /// Patterns for the start and end of code embedded in text, such
+    /// as `<?php` and `?>` in PHP. Files start as text, and each line
+    /// of text is treated as an atom.
+    code_delimiters: Option<(Regex, Regex)>,
+    /// If set, the file is line-oriented, such as a Dockerfile. Lines
+    /// ending with this string are joined with the next, and each
+    /// resulting line is a list headed by its first token.
+    line_continuation: Option<String>,
+    /// For line-oriented files, lines starting with this string are
+    /// children of the previous line, such as tab-indented recipe
+    /// lines in a Makefile.
+    child_line_prefix: Option<String>,
+    /// If set, the file is a table such as CSV, and each line is a
+    /// row of cells separated by this character. Cells may be quoted
+    /// with `"`.
+    cell_separator: Option<char>,
+    /// The cell that identifies a row, counting from zero. Rows are
+    /// only matched with rows that have the same key, so edits within
+    /// a row are highlighted cell by cell.
+    key_column: Option<usize>,
+    /// Lists nested more deeply than this are treated as atoms, so
+    /// very deeply nested files don't exhaust the stack when diffing.
+    /// Defaults to `DEFAULT_MAX_DEPTH`.
+    max_depth: Option<usize>,
+    /// Lists nested more deeply than this are parsed as a single atom,
+    /// which makes diffing pathological inputs faster at the cost of
+    /// precision. Set by `--max-depth`.
+    flatten_depth: Option<usize>,
+    /// Ways that literals in this language can be written
+    /// differently but mean the same thing, such as `0x10` and `16`.
+    literal_normalizations: Vec<Normalization>,
+    /// Whether to apply `literal_normalizations` to atoms when
+    /// parsing. This is opt-in, since it hides real edits.
+    normalize_literals: bool,
+    /// If set, the indentation of the first atom on each line is part
+    /// of its content, so re-indenting is a change. This matters for
+    /// languages such as Python, where indentation is syntax.
+    significant_indentation: bool,
+    /// If set, atoms are compared ignoring case, as in languages such
+    /// as SQL where `SELECT` and `select` are the same keyword.
+    case_insensitive: bool,
+    /// If set, every atom has the same content, so only the shape of
+    /// the tree and its delimiters are compared.
+    structure_only: bool,
+    /// Parts of atoms matching these regexes are replaced with
+    /// `IGNORED_CONTENT` when parsing, so they never differ.
+    ignore_patterns: Vec<Regex>,
+    /// States that change which patterns apply, such as inside a C
+    /// preprocessor line.
+    lexer_states: Vec<LexerState>,
+    /// If set, numbers on the RHS that are within this tolerance of
+    /// the corresponding LHS number are treated as unchanged.
+    numeric_tolerance: Option<NumericTolerance>,
+}
+
+/// A lexer state, such as the inside of a C preprocessor line. Inside
+/// a state only its own patterns apply, and states don't nest. The
+/// tokens in a state are parsed as a list, so `#include <stdio.h>` is
+/// a list `#include` containing `<stdio.h>`.
+#[derive(Clone)]
+struct LexerState {
+    /// Enters the state. The match is the open delimiter of the list.
+    enter: Regex,
+    /// Leaves the state. The match is the close delimiter of the list,
+    /// which is empty if the match is only whitespace, such as the
+    /// newline at the end of a preprocessor line.
+    exit: Regex,
+    /// Atoms inside the state. Surrounding whitespace is trimmed, so a
+    /// pattern can consume a newline without leaving the state.
     atom_patterns: Vec<Regex>,
     comment_patterns: Vec<Regex>,
-    open_delimiter_pattern: Regex,
-    close_delimiter_pattern: Regex,
 }
 
-fn read_syntax_toml(src: &str) -> Vec<Language> {
-    let v = src.parse::<Value>().unwrap();
-    let table = v.as_table().unwrap();
+impl Language {
+    /// This language, but normalizing literals when parsing, so
+    /// equivalent literals are treated as unchanged.
+    pub fn normalizing_literals(self) -> Language {
+        Language {
+            normalize_literals: true,
+            ..self
+        }
+    }
+
+    /// This language, but treating changes in indentation as real
+    /// changes.
+    pub fn with_significant_indentation(self) -> Language {
+        Language {
+            significant_indentation: true,
+            ..self
+        }
+    }
+
+    /// This language, but comparing atoms case-insensitively.
+    pub fn ignoring_case(self) -> Language {
+        Language {
+            case_insensitive: true,
+            ..self
+        }
+    }
+
+    /// This language, but ignoring the content of atoms, so only the
+    /// structure of the files is compared.
+    pub fn comparing_structure_only(self) -> Language {
+        Language {
+            structure_only: true,
+            ..self
+        }
+    }
+
+    /// This language, but treating numbers that are within
+    /// `tolerance` of each other as equal when parsing pairs of files.
+    pub fn with_numeric_tolerance(self, tolerance: NumericTolerance) -> Language {
+        Language {
+            numeric_tolerance: Some(tolerance),
+            ..self
+        }
+    }
+
+    /// This language, but parsing lists nested more than `depth` levels
+    /// deep as a single atom.
+    pub fn with_flatten_depth(self, depth: usize) -> Language {
+        Language {
+            flatten_depth: Some(depth),
+            ..self
+        }
+    }
+
+    /// This language, but treating the parts of atoms that match
+    /// `patterns` as equal, such as timestamps in generated files.
+    pub fn ignoring(self, patterns: Vec<Regex>) -> Language {
+        Language {
+            ignore_patterns: patterns,
+            ..self
+        }
+    }
+}
+
+/// How far apart two numbers can be while still being treated as
+/// equal, such as floating-point results that were regenerated. Two
+/// numbers are equal if they're within either tolerance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumericTolerance {
+    pub absolute: f64,
+    /// A fraction of the larger number, so 0.01 is 1%.
+    pub relative: f64,
+}
+
+impl NumericTolerance {
+    fn is_close(&self, lhs: f64, rhs: f64) -> bool {
+        let difference = (lhs - rhs).abs();
+        difference <= self.absolute || difference <= self.relative * lhs.abs().max(rhs.abs())
+    }
+}
+
+/// A rewrite of atoms before comparison, so literals that the
+/// language treats as equal are compared as equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Normalization {
+    /// Integer literals in any base, with optional `_` separators,
+    /// are written in decimal. Decimal literals are written in their
+    /// shortest form, so `1.50` is `1.5`.
+    Numbers,
+    /// Single-quoted strings are written with double quotes, if that
+    /// doesn't need any escaping.
+    Quotes,
+}
+
+/// Is the built-in language `name` enabled in this build? Each
+/// built-in language has a cargo feature, so builds can turn off
+/// languages they don't need. Returns None for unknown languages.
+fn builtin_enabled(name: &str) -> Option<bool> {
+    let enabled = match name {
+        "JavaScript" => cfg!(feature = "lang-javascript"),
+        "JSON" => cfg!(feature = "lang-json"),
+        "CSS" => cfg!(feature = "lang-css"),
+        "Emacs Lisp" => cfg!(feature = "lang-emacs-lisp"),
+        "Scheme" => cfg!(feature = "lang-scheme"),
+        "OCaml" => cfg!(feature = "lang-ocaml"),
+        "Clojure" => cfg!(feature = "lang-clojure"),
+        "Rust" => cfg!(feature = "lang-rust"),
+        "Go" => cfg!(feature = "lang-go"),
+        "Python" => cfg!(feature = "lang-python"),
+        "Julia" => cfg!(feature = "lang-julia"),
+        "Elixir" => cfg!(feature = "lang-elixir"),
+        "Kotlin" => cfg!(feature = "lang-kotlin"),
+        "Swift" => cfg!(feature = "lang-swift"),
+        "Scala" => cfg!(feature = "lang-scala"),
+        "C" => cfg!(feature = "lang-c"),
+        "C++" => cfg!(feature = "lang-cpp"),
+        "C#" => cfg!(feature = "lang-csharp"),
+        "Java" => cfg!(feature = "lang-java"),
+        "TypeScript" => cfg!(feature = "lang-typescript"),
+        "TSX" => cfg!(feature = "lang-typescript"),
+        "PHP" => cfg!(feature = "lang-php"),
+        "Zig" => cfg!(feature = "lang-zig"),
+        "Nix" => cfg!(feature = "lang-nix"),
+        "HCL" => cfg!(feature = "lang-hcl"),
+        "Dockerfile" => cfg!(feature = "lang-dockerfile"),
+        "Makefile" => cfg!(feature = "lang-makefile"),
+        "Shell" => cfg!(feature = "lang-shell"),
+        "Lua" => cfg!(feature = "lang-lua"),
+        "TOML" => cfg!(feature = "lang-toml"),
+        "XML" => cfg!(feature = "lang-xml"),
+        "HTML" => cfg!(feature = "lang-html"),
+        "Protobuf" => cfg!(feature = "lang-protobuf"),
+        "GraphQL" => cfg!(feature = "lang-graphql"),
+        "CSV" => cfg!(feature = "lang-csv"),
+        "TSV" => cfg!(feature = "lang-csv"),
+        _ => return None,
+    };
+    Some(enabled)
+}
+
+/// Language definitions, indexed by the file names and extensions
+/// they apply to. Compiling every regex in syntax.toml is slow, so
+/// each language is only compiled when it's first used.
+pub struct LanguageRegistry {
+    definitions: Vec<LanguageDefinition>,
+}
+
+struct LanguageDefinition {
+    name: String,
+    value: Value,
+    extensions: Vec<String>,
+    /// File names that always use this language, such as
+    /// `Dockerfile`.
+    filenames: Vec<String>,
+    /// Other names for this language, such as `node` for JavaScript,
+    /// accepted by `lang_by_name`.
+    aliases: Vec<String>,
+    /// Glob patterns for file names that use this language, such as
+    /// `Dockerfile.*`. `*` matches any run of characters and `?`
+    /// matches one character.
+    file_patterns: Vec<String>,
+    /// The compiled language, or None if the definition is invalid.
+    compiled: OnceCell<Option<Language>>,
+}
+
+impl LanguageDefinition {
+    fn new(name: &str, value: Value) -> Result<Self, String> {
+        let table = value.as_table().ok_or("should be a table")?;
+        let extensions = get_string_vec(table, "extensions")?;
+        let filenames = match table.get("filenames") {
+            Some(_) => get_string_vec(table, "filenames")?,
+            None => vec![],
+        };
+        let file_patterns = match table.get("file_patterns") {
+            Some(_) => get_string_vec(table, "file_patterns")?,
+            None => vec![],
+        };
+        let aliases = match table.get("aliases") {
+            Some(_) => get_string_vec(table, "aliases")?,
+            None => vec![],
+        };
+        Ok(LanguageDefinition {
+            name: name.into(),
+            value,
+            extensions,
+            filenames,
+            aliases,
+            file_patterns,
+            compiled: OnceCell::new(),
+        })
+    }
+
+    fn language(&self) -> Option<&Language> {
+        self.compiled
+            .get_or_init(|| match lang_from_value(&self.name, &self.value) {
+                Ok(lang) => Some(lang),
+                Err(e) => {
+                    eprintln!("Skipping invalid language definition {}: {}", self.name, e);
+                    None
+                }
+            })
+            .as_ref()
+    }
+}
+
+#[derive(Clone)]
+struct UnorderedList {
+    open: String,
+    close: String,
+    separator: String,
+}
+
+fn parse_syntax_table(src: &str) -> Option<Table> {
+    let v = match src.parse::<Value>() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Could not parse syntax file: {}", e);
+            return None;
+        }
+    };
+    match v {
+        Value::Table(table) => Some(table),
+        _ => {
+            eprintln!("Syntax file should be a table of languages.");
+            None
+        }
+    }
+}
+
+/// Merge the language definitions in `overrides` into `base`.
+fn merge_languages(base: &mut Table, overrides: Table) {
+    for (name, lang_override) in overrides {
+        match (base.get_mut(&name), lang_override) {
+            (Some(Value::Table(lang)), Value::Table(lang_override)) => {
+                for (key, value) in lang_override {
+                    match (lang.get_mut(&key), value) {
+                        (Some(Value::Array(items)), Value::Array(extra_items)) => {
+                            items.extend(extra_items);
+                        }
+                        (_, value) => {
+                            lang.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (_, lang_override) => {
+                base.insert(name, lang_override);
+            }
+        }
+    }
+}
+
+/// Parse language definitions from `srcs` on top of `table`, where
+/// later sources extend or override languages in earlier ones.
+/// Invalid definitions are reported on stderr and skipped.
+fn read_syntax_toml(mut table: Table, srcs: &[&str]) -> LanguageRegistry {
+    for src in srcs {
+        if let Some(src_table) = parse_syntax_table(src) {
+            merge_languages(&mut table, src_table);
+        }
+    }
+
+    let definitions = table
+        .into_iter()
+        .filter_map(
+            |(name, value)| match LanguageDefinition::new(&name, value) {
+                Ok(definition) => Some(definition),
+                Err(e) => {
+                    eprintln!("Skipping invalid language definition {}: {}", name, e);
+                    None
+                }
+            },
+        )
+        .collect();
+    LanguageRegistry { definitions }
+}
+
+pub fn find_lang<'a>(registry: &'a LanguageRegistry, extension: &str) -> Option<&'a Language> {
+    registry
+        .definitions
+        .iter()
+        .filter(|definition| definition.extensions.iter().any(|e| e == extension))
+        .find_map(|definition| definition.language())
+}
+
+/// Find a language by its name (e.g. `JavaScript`), one of its
+/// `aliases` (e.g. `node`) or one of its extensions (e.g. `js`),
+/// ignoring case. MIME types such as `text/x-python` are also
+/// accepted, using the part after the `/`.
+pub fn lang_by_name<'a>(registry: &'a LanguageRegistry, name: &str) -> Option<&'a Language> {
+    let name = name.trim().to_lowercase();
+    // Ignore MIME parameters, such as `; charset=utf-8`.
+    let name = name.split(';').next().unwrap_or("").trim();
+    let name = match name.split_once('/') {
+        Some((_, subtype)) => subtype.strip_prefix("x-").unwrap_or(subtype),
+        None => name,
+    };
 
-    table
+    registry
+        .definitions
         .iter()
-        .map(|(name, value)| lang_from_value(name, value))
-        .collect()
+        .filter(|definition| {
+            definition.name.to_lowercase() == name
+                || definition.aliases.iter().any(|a| a.to_lowercase() == name)
+        })
+        .find_map(|definition| definition.language())
+        .or_else(|| {
+            registry
+                .definitions
+                .iter()
+                .filter(|definition| {
+                    definition
+                        .extensions
+                        .iter()
+                        .any(|e| e.to_lowercase() == name)
+                })
+                .find_map(|definition| definition.language())
+        })
 }
 
-pub fn find_lang(languages: Vec<Language>, extension: &str) -> Option<Language> {
-    for language in languages {
-        if language.extensions.iter().any(|e| e == extension) {
-            return Some(language);
+/// Does `name` match the glob `pattern`? `*` matches any run of
+/// characters, including none, and `?` matches a single character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // The position after the last `*` seen, and the position in
+    // `name` it's currently matched up to, so we can backtrack.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
         }
     }
-    None
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Find the language for the file at `path`, using its file name if
+/// a language claims it (e.g. `Dockerfile`) or matches one of its
+/// `file_patterns`, otherwise its extension.
+pub fn guess_language<'a>(registry: &'a LanguageRegistry, path: &str) -> Option<&'a Language> {
+    let path = Path::new(path);
+    let file_name = path.file_name()?.to_string_lossy();
+    if let Some(lang) = registry
+        .definitions
+        .iter()
+        .filter(|definition| definition.filenames.iter().any(|f| *f == file_name))
+        .find_map(|definition| definition.language())
+    {
+        return Some(lang);
+    }
+    if let Some(lang) = registry
+        .definitions
+        .iter()
+        .filter(|definition| {
+            definition
+                .file_patterns
+                .iter()
+                .any(|pattern| glob_matches(pattern, &file_name))
+        })
+        .find_map(|definition| definition.language())
+    {
+        return Some(lang);
+    }
+
+    let extension = path.extension()?.to_string_lossy();
+    find_lang(registry, &extension)
+}
+
+fn get_string_vec(table: &Table, key: &str) -> Result<Vec<String>, String> {
+    let arr = table
+        .get(key)
+        .ok_or(format!("missing key `{}`", key))?
+        .as_array()
+        .ok_or(format!("`{}` should be an array of strings", key))?;
+
+    arr.iter()
+        .map(|v| match v.as_str() {
+            Some(s) => Ok(s.into()),
+            None => Err(format!("`{}` should be an array of strings", key)),
+        })
+        .collect()
+}
+
+/// Read an optional array of `[open, close]` string pairs.
+fn get_string_pairs(table: &Table, key: &str) -> Result<Vec<(String, String)>, String> {
+    let arr = match table.get(key) {
+        Some(v) => v
+            .as_array()
+            .ok_or(format!("`{}` should be an array of pairs", key))?,
+        None => return Ok(vec![]),
+    };
+
+    arr.iter()
+        .map(|v| match v.as_array().map(|pair| &pair[..]) {
+            Some([Value::String(open), Value::String(close)]) => Ok((open.clone(), close.clone())),
+            _ => Err(format!("`{}` should be an array of pairs", key)),
+        })
+        .collect()
+}
+
+/// Read an optional array of `[open, close, separator]` strings.
+fn get_unordered_lists(table: &Table) -> Result<Vec<UnorderedList>, String> {
+    let key = "unordered_lists";
+    let arr = match table.get(key) {
+        Some(v) => v
+            .as_array()
+            .ok_or(format!("`{}` should be an array of triples", key))?,
+        None => return Ok(vec![]),
+    };
+
+    arr.iter()
+        .map(|v| match v.as_array().map(|triple| &triple[..]) {
+            Some([Value::String(open), Value::String(close), Value::String(separator)]) => {
+                Ok(UnorderedList {
+                    open: open.clone(),
+                    close: close.clone(),
+                    separator: separator.clone(),
+                })
+            }
+            _ => Err(format!("`{}` should be an array of triples", key)),
+        })
+        .collect()
+}
+
+fn as_regex(key: &str, s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| format!("invalid regex in `{}`: {}", key, e))
 }
 
-fn as_string_vec(v: &Value) -> Vec<String> {
-    // TODO: Make this robust against invalid toml
-    let arr = v.as_array().unwrap();
-    arr.iter().map(|v| v.as_str().unwrap().into()).collect()
+fn get_regex_vec(table: &Table, key: &str) -> Result<Vec<Regex>, String> {
+    get_string_vec(table, key)?
+        .iter()
+        .map(|s| as_regex(key, s))
+        .collect()
+}
+
+fn get_regex_string_pairs(table: &Table, key: &str) -> Result<Vec<(Regex, String)>, String> {
+    get_string_pairs(table, key)?
+        .into_iter()
+        .map(|(open, close)| Ok((as_regex(key, &open)?, close)))
+        .collect()
+}
+
+fn get_optional_string(table: &Table, key: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        Some(v) => match v.as_str() {
+            Some(s) => Ok(Some(s.into())),
+            None => Err(format!("`{}` should be a string", key)),
+        },
+        None => Ok(None),
+    }
 }
 
-fn as_regex_vec(v: &Value) -> Vec<Regex> {
-    // TODO: properly handle malformed user-supplied regexes.
-    as_string_vec(v).iter().map(|s| as_regex(&s)).collect()
+/// The boolean `key`, which is false if it's missing.
+fn get_optional_bool(table: &Table, key: &str) -> Result<bool, String> {
+    match table.get(key) {
+        Some(v) => v.as_bool().ok_or(format!("`{}` should be a boolean", key)),
+        None => Ok(false),
+    }
+}
+
+fn get_optional_regex(table: &Table, key: &str) -> Result<Option<Regex>, String> {
+    match table.get(key) {
+        Some(_) => get_regex(table, key).map(Some),
+        None => Ok(None),
+    }
 }
 
-fn as_regex(s: &str) -> Regex {
-    Regex::new(&s).unwrap()
+fn get_regex(table: &Table, key: &str) -> Result<Regex, String> {
+    let s = table
+        .get(key)
+        .ok_or(format!("missing key `{}`", key))?
+        .as_str()
+        .ok_or(format!("`{}` should be a string", key))?;
+    as_regex(key, s)
 }
 
-fn lang_from_value(name: &str, v: &Value) -> Language {
-    let table = v.as_table().unwrap();
-    Language {
+fn get_code_delimiters(table: &Table) -> Result<Option<(Regex, Regex)>, String> {
+    match (
+        get_optional_regex(table, "code_start_pattern")?,
+        get_optional_regex(table, "code_end_pattern")?,
+    ) {
+        (Some(start), Some(end)) => Ok(Some((start, end))),
+        (None, None) => Ok(None),
+        _ => Err("`code_start_pattern` and `code_end_pattern` must be used together".into()),
+    }
+}
+
+fn lang_from_value(name: &str, v: &Value) -> Result<Language, String> {
+    let table = v.as_table().ok_or("should be a table")?;
+
+    let comment_patterns = get_regex_vec(table, "comment_patterns")?;
+    let open_delimiter_pattern = get_regex(table, "open_delimiter_pattern")?;
+    let close_delimiter_pattern = get_regex(table, "close_delimiter_pattern")?;
+    let atom_patterns = get_regex_vec(table, "atom_patterns")?;
+    // Regex literals are a kind of string, and `/` may also be an atom,
+    // so they take precedence over everything except comments.
+    let token_patterns = |regex_literal_pattern: Option<&Regex>| -> Vec<(LexKind, Regex)> {
+        comment_patterns
+            .iter()
+            .map(|p| (LexKind::Comment, p.clone()))
+            .chain(regex_literal_pattern.map(|p| (LexKind::String, p.clone())))
+            // Delimiters take precedence over atoms starting at the
+            // same position, so `<<` in Elixir is a delimiter even
+            // though `<` is an operator.
+            .chain(std::iter::once((
+                LexKind::OpenDelimiter,
+                open_delimiter_pattern.clone(),
+            )))
+            .chain(std::iter::once((
+                LexKind::CloseDelimiter,
+                close_delimiter_pattern.clone(),
+            )))
+            .chain(atom_patterns.iter().map(|p| (LexKind::Atom, p.clone())))
+            .collect()
+    };
+    let regex_literal_scanner = match get_optional_regex(table, "regex_literal_pattern")? {
+        Some(pattern) => Some(TokenScanner::new(token_patterns(Some(&pattern)))?),
+        None => None,
+    };
+
+    Ok(Language {
         name: name.into(),
-        extensions: as_string_vec(v.get("extensions").unwrap()),
-        atom_patterns: as_regex_vec(v.get("atom_patterns").unwrap()),
-        comment_patterns: as_regex_vec(v.get("comment_patterns").unwrap()),
-        open_delimiter_pattern: as_regex(
-            table
-                .get("open_delimiter_pattern")
-                .unwrap()
-                .as_str()
-                .unwrap(),
-        ),
-        close_delimiter_pattern: as_regex(
-            table
-                .get("close_delimiter_pattern")
-                .unwrap()
-                .as_str()
-                .unwrap(),
-        ),
+        token_scanner: TokenScanner::new(token_patterns(None))?,
+        regex_literal_scanner,
+        nested_comment_delimiters: get_string_pairs(table, "nested_comment_delimiters")?,
+        raw_string_patterns: get_regex_string_pairs(table, "raw_string_patterns")?,
+        heredoc_patterns: match table.get("heredoc_patterns") {
+            Some(_) => get_regex_vec(table, "heredoc_patterns")?,
+            None => vec![],
+        },
+        string_patterns: get_regex_string_pairs(table, "string_patterns")?,
+        regex_literal_keywords: match table.get("regex_literal_keywords") {
+            Some(_) => get_string_vec(table, "regex_literal_keywords")?,
+            None => vec![],
+        },
+        keyword_delimiters: get_string_pairs(table, "keyword_delimiters")?,
+        unordered_lists: get_unordered_lists(table)?,
+        key_value_separator: get_optional_string(table, "key_value_separator")?,
+        section_delimiter: get_optional_string(table, "section_delimiter")?,
+        code_delimiters: get_code_delimiters(table)?,
+        line_continuation: get_optional_string(table, "line_continuation")?,
+        child_line_prefix: get_optional_string(table, "child_line_prefix")?,
+        cell_separator: match table.get("cell_separator").map(|v| v.as_str()) {
+            Some(Some(sep)) if sep.chars().count() == 1 => sep.chars().next(),
+            Some(_) => return Err("`cell_separator` should be a single character".into()),
+            None => None,
+        },
+        key_column: match table.get("key_column") {
+            Some(v) => Some(
+                v.as_integer()
+                    .filter(|i| *i >= 0)
+                    .ok_or("`key_column` should be a non-negative integer")?
+                    as usize,
+            ),
+            None => None,
+        },
+        flatten_depth: None,
+        max_depth: match table.get("max_depth") {
+            Some(v) => Some(
+                v.as_integer()
+                    .filter(|i| *i > 0)
+                    .ok_or("`max_depth` should be a positive integer")? as usize,
+            ),
+            None => None,
+        },
+        literal_normalizations: get_literal_normalizations(table)?,
+        normalize_literals: false,
+        ignore_patterns: vec![],
+        significant_indentation: get_optional_bool(table, "significant_indentation")?,
+        case_insensitive: get_optional_bool(table, "case_insensitive")?,
+        structure_only: false,
+        lexer_states: get_lexer_states(table)?,
+        numeric_tolerance: None,
+    })
+}
+
+fn get_lexer_states(table: &Table) -> Result<Vec<LexerState>, String> {
+    let states = match table.get("lexer_states") {
+        Some(v) => v
+            .as_array()
+            .ok_or("`lexer_states` should be an array of tables")?,
+        None => return Ok(vec![]),
+    };
+    states
+        .iter()
+        .map(|state| {
+            let state = state
+                .as_table()
+                .ok_or("`lexer_states` should be an array of tables")?;
+            Ok(LexerState {
+                enter: get_regex(state, "enter")?,
+                exit: get_regex(state, "exit")?,
+                atom_patterns: get_regex_vec(state, "atom_patterns")?,
+                comment_patterns: match state.get("comment_patterns") {
+                    Some(_) => get_regex_vec(state, "comment_patterns")?,
+                    None => vec![],
+                },
+            })
+        })
+        .collect()
+}
+
+fn get_literal_normalizations(table: &Table) -> Result<Vec<Normalization>, String> {
+    if table.get("literal_normalizations").is_none() {
+        return Ok(vec![]);
+    }
+    get_string_vec(table, "literal_normalizations")?
+        .iter()
+        .map(|name| match name.as_str() {
+            "numbers" => Ok(Normalization::Numbers),
+            "quotes" => Ok(Normalization::Quotes),
+            _ => Err(format!(
+                "unknown literal normalization `{}`, expected `numbers` or `quotes`",
+                name
+            )),
+        })
+        .collect()
+}
+
+/// The value of the number literal `s`, written in a canonical form.
+/// Returns None if `s` isn't a number.
+fn normalize_number(s: &str) -> Option<String> {
+    let digits: String = s.chars().filter(|c| *c != '_').collect();
+    // BigInt literals, such as `10n` in JavaScript.
+    let (digits, suffix) = match digits.strip_suffix('n') {
+        Some(digits) => (digits, "n"),
+        None => (digits.as_str(), ""),
+    };
+    let radix = match digits.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0o" | "0O") => 8,
+        Some("0b" | "0B") => 2,
+        _ => 10,
+    };
+
+    let value = if radix != 10 {
+        u128::from_str_radix(&digits[2..], radix).ok()?.to_string()
+    } else if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    } else if digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse::<u128>().ok()?.to_string()
+    } else {
+        let value: f64 = digits.parse().ok()?;
+        if !value.is_finite() {
+            return None;
+        }
+        value.to_string()
+    };
+    Some(format!("{}{}", value, suffix))
+}
+
+/// The text that replaces ignored parts of atoms.
+const IGNORED_CONTENT: &str = "<ignored>";
+
+/// The content of every atom when comparing structure only.
+const STRUCTURE_ONLY_CONTENT: &str = "<atom>";
+
+/// The leading whitespace of the line containing `offset`, if
+/// `offset` is the first non-whitespace character on its line.
+fn indentation_before(s: &str, offset: usize) -> Option<&str> {
+    let line_start = s[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let indentation = &s[line_start..offset];
+    if indentation.chars().all(|c| c == ' ' || c == '\t') {
+        Some(indentation)
+    } else {
+        None
+    }
+}
+
+/// What kind of token an atom matched by `atom_patterns` is. The
+/// patterns aren't labelled, so this is guessed from how the atom
+/// starts. Strings may have a short prefix, such as `b"foo"` in Rust
+/// or `f"foo"` in Python.
+fn atom_token_kind(content: &str) -> TokenKind {
+    let unprefixed = content.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    if let Some(quote) = unprefixed.chars().next().filter(|c| "\"'`".contains(*c)) {
+        if content.len() - unprefixed.len() <= 2
+            && unprefixed.len() >= 2
+            && unprefixed.ends_with(quote)
+        {
+            return TokenKind::String;
+        }
+    }
+
+    let mut chars = content.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_digit() => TokenKind::Number,
+        Some('-' | '+' | '.') if chars.next().is_some_and(|c| c.is_ascii_digit()) => {
+            TokenKind::Number
+        }
+        Some(c) if c.is_alphabetic() || c == '_' || c == '$' => TokenKind::Identifier,
+        _ => TokenKind::Other,
+    }
+}
+
+/// The content of the atom `m` in `s` for comparison, with literals
+/// normalized, ignored parts replaced and indentation included, if
+/// `lang` asks for them. When comparing structure only, every atom
+/// has the same content.
+fn atom_content<'a>(s: &'a str, m: &LexMatch, lang: &Language) -> Cow<'a, str> {
+    if lang.structure_only {
+        return STRUCTURE_ONLY_CONTENT.into();
+    }
+    let content = &s[m.start..m.end];
+    let mut content = if lang.normalize_literals {
+        normalize_atom(content, &lang.literal_normalizations)
+    } else {
+        content.into()
+    };
+    for pattern in &lang.ignore_patterns {
+        if pattern.is_match(&content) {
+            content = pattern
+                .replace_all(&content, IGNORED_CONTENT)
+                .into_owned()
+                .into();
+        }
+    }
+    if lang.case_insensitive {
+        content = content.to_lowercase().into();
     }
+    if lang.significant_indentation {
+        if let Some(indentation) = indentation_before(s, m.start) {
+            content = format!("{}{}", indentation, content).into();
+        }
+    }
+    content
+}
+
+/// Rewrite `content` with `normalizations`, so equivalent literals
+/// have the same content.
+fn normalize_atom<'a>(content: &'a str, normalizations: &[Normalization]) -> Cow<'a, str> {
+    for normalization in normalizations {
+        match normalization {
+            Normalization::Numbers => {
+                if let Some(number) = normalize_number(content) {
+                    return number.into();
+                }
+            }
+            Normalization::Quotes => {
+                if let Some(inner) = content
+                    .strip_prefix('\'')
+                    .and_then(|s| s.strip_suffix('\''))
+                {
+                    if !inner.contains(['"', '\\']) {
+                        return format!("\"{}\"", inner).into();
+                    }
+                }
+            }
+        }
+    }
+    content.into()
 }
 
+/// The default maximum nesting depth of lists when parsing.
+const DEFAULT_MAX_DEPTH: usize = 500;
+
 /// Split `s` by lines, and treat each line as an atom.
 ///
 /// This is a fallback for files that we don't know how to parse.
-pub fn parse_lines<'a>(arena: &'a Arena<Syntax<'a>>, s: &str) -> Vec<&'a Syntax<'a>> {
+pub fn parse_lines<'a>(arena: &'a Arena<Syntax<'a>>, s: &'a str) -> Vec<&'a Syntax<'a>> {
     let mut res: Vec<&'a Syntax<'a>> = vec![];
     for (i, line) in s.lines().enumerate() {
         res.push(Syntax::new_atom(
             arena,
-            vec![SingleLineSpan {
+            smallvec![SingleLineSpan {
                 line: i.into(),
                 start_col: 0,
                 end_col: line.len(),
@@ -123,274 +991,3191 @@ pub fn parse_lines<'a>(arena: &'a Arena<Syntax<'a>>, s: &str) -> Vec<&'a Syntax<
         ));
     }
 
-    res
-}
-
-/// Parse `s` according to `lang`.
-pub fn parse<'a>(arena: &'a Arena<Syntax<'a>>, s: &str, lang: &Language) -> Vec<&'a Syntax<'a>> {
-    let nl_pos = NewlinePositions::from(s);
-    parse_from(arena, s, &nl_pos, lang, &mut ParseState::new())
-}
+    res
+}
+
+/// Parse `s` according to `lang`.
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+pub fn parse<'a>(arena: &'a Arena<Syntax<'a>>, s: &'a str, lang: &Language) -> Vec<&'a Syntax<'a>> {
+    parse_tokens(arena, s, lang, lex(s, lang))
+}
+
+/// Parse the nodes in `s[start..end]` on their own, for re-parsing
+/// part of a file after an edit. `start` and `end` should be between
+/// top-level nodes, and positions are relative to the whole of `s`.
+///
+/// Returns None if the region might parse differently as part of the
+/// whole file: if a list is still open at `end`, a token reaches
+/// `end` and might continue past it, or the language has syntax
+/// whose meaning depends on what comes before the region.
+pub fn parse_region<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    s: &'a str,
+    start: usize,
+    end: usize,
+    lang: &Language,
+) -> Option<Vec<&'a Syntax<'a>>> {
+    if lang.cell_separator.is_some()
+        || lang.line_continuation.is_some()
+        || lang.code_delimiters.is_some()
+        || lang.key_value_separator.is_some()
+        || lang.section_delimiter.is_some()
+        || lang.numeric_tolerance.is_some()
+    {
+        return None;
+    }
+
+    let lex_region = |regex_allowed| {
+        let mut state = ParseState::new(lang);
+        state.str_i = start;
+        state.regex_allowed = regex_allowed;
+        lex_from(&s[..end], lang, &mut state)
+    };
+    let lexed = lex_region(true);
+    // Whether the first `/` is a regex literal depends on the token
+    // before the region, so only accept regions where it doesn't
+    // matter.
+    if lang.regex_literal_scanner.is_some() {
+        let token_spans = |lexed: &Lexed| -> Vec<_> {
+            lexed
+                .tokens
+                .iter()
+                .map(|m| (m.kind, m.start, m.end))
+                .collect()
+        };
+        if token_spans(&lexed) != token_spans(&lex_region(false)) {
+            return None;
+        }
+    }
+
+    let mut depth: usize = 0;
+    for m in &lexed.tokens {
+        if m.end >= end && end < s.len() {
+            return None;
+        }
+        match m.kind {
+            LexKind::OpenDelimiter => depth += 1,
+            LexKind::CloseDelimiter => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    if depth > 0 {
+        return None;
+    }
+
+    let nl_pos = NewlinePositions::from(s);
+    Some(parse_from(arena, s, &nl_pos, lang, lexed))
+}
+
+/// Parse both sides of a diff according to `lang`. Lexing is the
+/// slowest part of parsing, so the two sides are lexed concurrently
+/// when there's more than one CPU.
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
+pub fn parse_pair<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    lhs: &'a str,
+    rhs: &'a str,
+    lang: &Language,
+) -> (Vec<&'a Syntax<'a>>, Vec<&'a Syntax<'a>>) {
+    let (lhs_nodes, rhs_nodes) = if thread::available_parallelism().is_ok_and(|n| n.get() > 1) {
+        let (lhs_tokens, rhs_tokens) = thread::scope(|scope| {
+            let lhs_tokens = scope.spawn(|| lex(lhs, lang));
+            let rhs_tokens = lex(rhs, lang);
+            match lhs_tokens.join() {
+                Ok(lhs_tokens) => (lhs_tokens, rhs_tokens),
+                Err(e) => panic::resume_unwind(e),
+            }
+        });
+        (
+            parse_tokens(arena, lhs, lang, lhs_tokens),
+            parse_tokens(arena, rhs, lang, rhs_tokens),
+        )
+    } else {
+        (parse(arena, lhs, lang), parse(arena, rhs, lang))
+    };
+
+    match &lang.numeric_tolerance {
+        Some(tolerance) => {
+            let rhs_nodes = apply_numeric_tolerance(arena, &lhs_nodes, rhs_nodes, tolerance);
+            (lhs_nodes, rhs_nodes)
+        }
+        None => (lhs_nodes, rhs_nodes),
+    }
+}
+
+/// A numeric atom, which is equal to numbers within the tolerance.
+struct TolerantNumber<'a> {
+    content: &'a str,
+    position: SingleLineSpan,
+    value: f64,
+    tolerance: NumericTolerance,
+}
+
+impl<'a> PartialEq for TolerantNumber<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.tolerance.is_close(self.value, other.value)
+    }
+}
+
+fn numeric_atoms<'a>(
+    nodes: &[&'a Syntax<'a>],
+    tolerance: NumericTolerance,
+    numbers: &mut Vec<TolerantNumber<'a>>,
+) {
+    for node in nodes {
+        match node {
+            Syntax::List { children, .. } => numeric_atoms(children, tolerance, numbers),
+            Syntax::Atom {
+                content,
+                position,
+                is_comment: false,
+                ..
+            } => {
+                if let (Ok(value), Some(span)) = (content.trim().parse::<f64>(), position.first()) {
+                    if value.is_finite() {
+                        numbers.push(TolerantNumber {
+                            content,
+                            position: *span,
+                            value,
+                            tolerance,
+                        });
+                    }
+                }
+            }
+            Syntax::Atom { .. } => {}
+        }
+    }
+}
+
+/// Rebuild `nodes`, giving the atoms at the positions in `replacements`
+/// their new content.
+fn replace_atoms<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    nodes: &[&'a Syntax<'a>],
+    replacements: &HashMap<SingleLineSpan, &'a str>,
+) -> Vec<&'a Syntax<'a>> {
+    nodes
+        .iter()
+        .map(|node| -> &'a Syntax<'a> {
+            match node {
+                Syntax::List {
+                    open_content,
+                    open_position,
+                    children,
+                    close_content,
+                    close_position,
+                    ..
+                } => {
+                    let new_children = replace_atoms(arena, children, replacements);
+                    if new_children
+                        .iter()
+                        .zip(children)
+                        .all(|(new_child, child)| std::ptr::eq(*new_child, *child))
+                    {
+                        return node;
+                    }
+                    Syntax::new_list(
+                        arena,
+                        open_content.clone(),
+                        open_position.clone(),
+                        new_children,
+                        close_content.clone(),
+                        close_position.clone(),
+                    )
+                }
+                Syntax::Atom {
+                    position,
+                    token_kind,
+                    ..
+                } => match position.first().and_then(|span| replacements.get(span)) {
+                    Some(content) => {
+                        Syntax::new_atom_of_kind(arena, position.clone(), *content, *token_kind)
+                    }
+                    None => node,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Give every number in `rhs_nodes` that's within `tolerance` of the
+/// corresponding number in `lhs_nodes` the LHS content, so they're
+/// compared as equal. Numbers are matched up with a sequence diff, so
+/// inserting a number doesn't affect the numbers after it.
+fn apply_numeric_tolerance<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    lhs_nodes: &[&'a Syntax<'a>],
+    rhs_nodes: Vec<&'a Syntax<'a>>,
+    tolerance: &NumericTolerance,
+) -> Vec<&'a Syntax<'a>> {
+    let mut lhs_numbers = vec![];
+    numeric_atoms(lhs_nodes, *tolerance, &mut lhs_numbers);
+    let mut rhs_numbers = vec![];
+    numeric_atoms(&rhs_nodes, *tolerance, &mut rhs_numbers);
+
+    let mut replacements = HashMap::new();
+
+    for diff_res in diff::slice(&lhs_numbers, &rhs_numbers) {
+        if let diff::Result::Both(lhs_number, rhs_number) = diff_res {
+            if lhs_number.content != rhs_number.content {
+                replacements.insert(rhs_number.position, lhs_number.content);
+            }
+        }
+    }
+    if replacements.is_empty() {
+        return rhs_nodes;
+    }
+    replace_atoms(arena, &rhs_nodes, &replacements)
+}
+
+/// Build syntax trees from the tokens of `s`.
+fn parse_tokens<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    s: &'a str,
+    lang: &Language,
+    tokens: Tokens,
+) -> Vec<&'a Syntax<'a>> {
+    let nl_pos = NewlinePositions::from(s);
+    match tokens {
+        Tokens::Table(separator) => parse_table(arena, s, &nl_pos, separator, lang.key_column),
+        Tokens::LogicalLines(lines) => parse_logical_lines(arena, s, &nl_pos, lang, lines),
+        Tokens::Whole(lexed) => {
+            let nodes = parse_from(arena, s, &nl_pos, lang, lexed);
+            match &lang.section_delimiter {
+                Some(delimiter) => group_sections(arena, nodes, delimiter),
+                None => nodes,
+            }
+        }
+    }
+}
+
+/// A zero-width position at the end of `node`.
+fn end_position(node: &Syntax) -> Spans {
+    let position = match node {
+        Syntax::List { close_position, .. } => close_position,
+        Syntax::Atom { position, .. } => position,
+    };
+    position
+        .last()
+        .map(|span| SingleLineSpan {
+            line: span.line,
+            start_col: span.end_col,
+            end_col: span.end_col,
+        })
+        .into_iter()
+        .collect()
+}
+
+/// The source text of `node`, ignoring whitespace.
+fn flat_content(node: &Syntax) -> String {
+    match node {
+        Syntax::List {
+            open_content,
+            children,
+            close_content,
+            ..
+        } => {
+            let mut res = open_content.to_string();
+            for child in children {
+                res.push_str(&flat_content(child));
+            }
+            res.push_str(close_content);
+            res
+        }
+        Syntax::Atom { content, .. } => content.to_string(),
+    }
+}
+
+/// Group `key separator value` in `nodes` into lists opened by the
+/// key.
+fn group_key_values<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    nodes: Vec<&'a Syntax<'a>>,
+    separator: &str,
+) -> Vec<&'a Syntax<'a>> {
+    let mut result = vec![];
+    let mut i = 0;
+    while i < nodes.len() {
+        if let (
+            Syntax::Atom {
+                content: key,
+                position: key_position,
+                is_comment: false,
+                ..
+            },
+            Some(sep @ Syntax::Atom { content, .. }),
+            Some(value),
+        ) = (nodes[i], nodes.get(i + 1), nodes.get(i + 2))
+        {
+            let value_is_comment = matches!(
+                value,
+                Syntax::Atom {
+                    is_comment: true,
+                    ..
+                }
+            );
+            if content == separator && !value_is_comment {
+                result.push(&*Syntax::new_list(
+                    arena,
+                    key.clone(),
+                    key_position.clone(),
+                    vec![sep, value],
+                    "",
+                    end_position(value),
+                ));
+                i += 3;
+                continue;
+            }
+        }
+
+        result.push(nodes[i]);
+        i += 1;
+    }
+    result
+}
+
+/// Group the nodes after each section header into a list, whose open
+/// delimiter is the header. A header is a top-level list opened with
+/// `delimiter`, such as `[package]` in TOML.
+fn group_sections<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    nodes: Vec<&'a Syntax<'a>>,
+    delimiter: &str,
+) -> Vec<&'a Syntax<'a>> {
+    let mut result = vec![];
+    let mut section: Option<(&'a Syntax<'a>, Vec<&'a Syntax<'a>>)> = None;
+    let finish = |(header, children): (&'a Syntax<'a>, Vec<&'a Syntax<'a>>)| -> &'a Syntax<'a> {
+        let (open_position, close_position) = match header {
+            Syntax::List {
+                open_position,
+                close_position,
+                ..
+            } => (open_position, close_position),
+            Syntax::Atom { .. } => unreachable!(),
+        };
+        let mut header_position = open_position.clone();
+        // Headers span a single line, so the position covers from
+        // the open delimiter to the close delimiter.
+        if let (Some(open), Some(close)) = (header_position.first_mut(), close_position.last()) {
+            if open.line == close.line {
+                open.end_col = close.end_col;
+            }
+        }
+        let last = children.last().copied().unwrap_or(header);
+        Syntax::new_list(
+            arena,
+            flat_content(header),
+            header_position,
+            children,
+            "",
+            end_position(last),
+        )
+    };
+
+    for node in nodes {
+        match node {
+            Syntax::List { open_content, .. } if open_content == delimiter => {
+                if let Some(prev) = section.take() {
+                    result.push(finish(prev));
+                }
+                section = Some((node, vec![]));
+            }
+            _ => match &mut section {
+                Some((_, children)) => children.push(node),
+                None => result.push(node),
+            },
+        }
+    }
+    if let Some(prev) = section {
+        result.push(finish(prev));
+    }
+    result
+}
+
+/// The start and end offsets of each line in `s`, where lines ending
+/// with `continuation` are joined with the following line.
+fn logical_lines(s: &str, continuation: &str) -> Vec<(usize, usize)> {
+    let mut res = vec![];
+    let mut start = 0;
+    let mut line_start = 0;
+    for line in s.split('\n') {
+        let line_end = line_start + line.len();
+        if !line.trim_end().ends_with(continuation) {
+            res.push((start, line_end));
+            start = line_end + 1;
+        }
+        line_start = line_end + 1;
+    }
+    if start < s.len() {
+        res.push((start, s.len()));
+    }
+
+    res
+}
+
+/// Convert the nodes on a line into a list, whose open delimiter is
+/// the first token. If the line doesn't start with an atom, return
+/// the nodes unchanged.
+fn line_list<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    nl_pos: &NewlinePositions,
+    mut nodes: Vec<&'a Syntax<'a>>,
+    close_offset: usize,
+) -> Vec<&'a Syntax<'a>> {
+    match nodes.first() {
+        Some(Syntax::Atom {
+            content,
+            position,
+            is_comment: false,
+            ..
+        }) => {
+            let children = nodes.split_off(1);
+            vec![Syntax::new_list(
+                arena,
+                content.clone(),
+                position.clone(),
+                children,
+                "",
+                nl_pos.from_offsets(close_offset, close_offset),
+            )]
+        }
+        _ => nodes,
+    }
+}
+
+/// Parse each logical line of `s` into a list, whose open delimiter
+/// is the first token on the line. For example, `RUN foo` in a
+/// Dockerfile is a list `RUN` containing `foo`.
+///
+/// Lines starting with `lang.child_line_prefix` are added to the list
+/// of the previous line without the prefix, so a Makefile recipe is
+/// inside its rule.
+fn parse_logical_lines<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    s: &'a str,
+    nl_pos: &NewlinePositions,
+    lang: &Language,
+    lines: Vec<(usize, usize, Lexed)>,
+) -> Vec<&'a Syntax<'a>> {
+    let mut result: Vec<&'a Syntax<'a>> = vec![];
+    // The nodes of the most recent line that may still get children,
+    // and the offset where its list ends.
+    let mut parent: Option<(Vec<&'a Syntax<'a>>, usize)> = None;
+    for (start, end, lexed) in lines {
+        let nodes = parse_from(arena, &s[..end], nl_pos, lang, lexed);
+        if nodes.is_empty() {
+            continue;
+        }
+        let close_offset = start + s[start..end].trim_end().len();
+
+        let is_child = match &lang.child_line_prefix {
+            Some(prefix) => s[start..end].starts_with(prefix.as_str()),
+            None => false,
+        };
+        if let (true, Some((parent_nodes, parent_close_offset))) = (is_child, &mut parent) {
+            let mut child_nodes = line_list(arena, nl_pos, nodes, close_offset);
+            if child_nodes.len() > 1 {
+                // The line starts with a list, e.g. `$(CC) foo.c`, so
+                // group it with an anonymous list.
+                let open_offset = close_offset - s[start..close_offset].trim_start().len();
+                child_nodes = vec![Syntax::new_list(
+                    arena,
+                    "",
+                    nl_pos.from_offsets(open_offset, open_offset),
+                    child_nodes,
+                    "",
+                    nl_pos.from_offsets(close_offset, close_offset),
+                )];
+            }
+            parent_nodes.extend(child_nodes);
+            *parent_close_offset = close_offset;
+            continue;
+        }
+
+        if let Some((parent_nodes, parent_close_offset)) = parent.take() {
+            result.extend(line_list(arena, nl_pos, parent_nodes, parent_close_offset));
+        }
+        parent = Some((nodes, close_offset));
+    }
+    if let Some((parent_nodes, parent_close_offset)) = parent {
+        result.extend(line_list(arena, nl_pos, parent_nodes, parent_close_offset));
+    }
+
+    result
+}
+
+/// The start and end offsets of each cell in `s`, grouped by row. A
+/// quoted cell may contain the separator, newlines and `""`. Blank
+/// lines are ignored.
+fn table_cells(s: &str, separator: char) -> Vec<Vec<(usize, usize)>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut cell_start = 0;
+    let mut in_quotes = false;
+    for (i, c) in s.char_indices() {
+        if in_quotes {
+            // A doubled "" is an escaped quote, which toggles twice.
+            if c == '"' {
+                in_quotes = false;
+            }
+        } else if c == '"' {
+            in_quotes = true;
+        } else if c == separator {
+            row.push((cell_start, i));
+            cell_start = i + c.len_utf8();
+        } else if c == '\n' {
+            let end = if s[..i].ends_with('\r') { i - 1 } else { i };
+            if !row.is_empty() || cell_start < end {
+                row.push((cell_start, end));
+                rows.push(std::mem::take(&mut row));
+            }
+            cell_start = i + 1;
+        }
+    }
+    if !row.is_empty() || cell_start < s.len() {
+        row.push((cell_start, s.len()));
+        rows.push(row);
+    }
+
+    rows
+}
+
+/// Parse `s` as a table, where each row is a list of cells. If
+/// `key_column` is set, the key cell is the open delimiter of the
+/// row, so rows with different keys are never matched.
+fn parse_table<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    s: &'a str,
+    nl_pos: &NewlinePositions,
+    separator: char,
+    key_column: Option<usize>,
+) -> Vec<&'a Syntax<'a>> {
+    // The line of the most recent offset we've seen, so we can
+    // position empty cells at the start of a line.
+    let mut line_num = 0;
+    let mut line_num_offset = 0;
+    let mut position = |start: usize, end: usize| {
+        line_num += s[line_num_offset..start].matches('\n').count();
+        line_num_offset = start;
+
+        let position = nl_pos.from_offsets(start, end);
+        if position.is_empty() {
+            smallvec![SingleLineSpan {
+                line: line_num.into(),
+                start_col: 0,
+                end_col: 0,
+            }]
+        } else {
+            position
+        }
+    };
+
+    let mut result: Vec<&'a Syntax<'a>> = vec![];
+    for cells in table_cells(s, separator) {
+        let row_start = cells[0].0;
+        let row_end = cells[cells.len() - 1].1;
+
+        let mut open_content = "";
+        let mut open_position = position(row_start, row_start);
+        let mut children: Vec<&'a Syntax<'a>> = vec![];
+        for (i, (start, end)) in cells.into_iter().enumerate() {
+            if Some(i) == key_column {
+                open_content = &s[start..end];
+                open_position = position(start, end);
+            } else {
+                children.push(Syntax::new_atom(
+                    arena,
+                    position(start, end),
+                    &s[start..end],
+                ));
+            }
+        }
+
+        result.push(Syntax::new_list(
+            arena,
+            open_content,
+            open_position,
+            children,
+            "",
+            position(row_end, row_end),
+        ));
+    }
+
+    result
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LexKind {
+    Comment,
+    Atom,
+    /// An atom that's known to be a string, such as a match of
+    /// `string_patterns` or a regex literal.
+    String,
+    OpenDelimiter,
+    CloseDelimiter,
+    /// The start of code embedded in text, e.g. `<?php`.
+    CodeStart,
+    /// The end of code embedded in text, e.g. `?>`.
+    CodeEnd,
+}
+
+/// A token found by the lexer, with byte offsets into the whole
+/// input.
+struct LexMatch {
+    kind: LexKind,
+    start: usize,
+    end: usize,
+}
+
+/// The tokens of a region of the input, and the offset where the
+/// lexer stopped.
+struct Lexed {
+    tokens: Vec<LexMatch>,
+    end: usize,
+}
+
+/// The tokens of a whole input, grouped by how they're parsed.
+enum Tokens {
+    /// Tables are split into cells by the separator, without the
+    /// lexer.
+    Table(char),
+    /// The start and end offsets of each logical line, and its tokens.
+    LogicalLines(Vec<(usize, usize, Lexed)>),
+    Whole(Lexed),
+}
+
+/// Several patterns combined into one regex, so the earliest match of
+/// any of them is found in a single pass over the input, rather than
+/// searching with each pattern in turn. Each pattern is wrapped in a
+/// capture group, and alternation prefers earlier patterns, so ties
+/// go to the pattern listed first.
+#[derive(Clone)]
+struct TokenScanner {
+    regex: Regex,
+    /// The capture group of each pattern, and the kind of token it
+    /// matches.
+    groups: Vec<(usize, LexKind)>,
+}
+
+impl TokenScanner {
+    fn new(patterns: Vec<(LexKind, Regex)>) -> Result<Self, String> {
+        let mut alternatives = vec![];
+        let mut groups = vec![];
+        let mut group = 1;
+        for (kind, pattern) in patterns {
+            alternatives.push(format!("({})", pattern.as_str()));
+            groups.push((group, kind));
+            // The wrapping group, plus the pattern's own groups.
+            group += pattern.captures_len();
+        }
+        // An empty alternation matches nothing.
+        let combined = if alternatives.is_empty() {
+            r"\b\B".to_string()
+        } else {
+            alternatives.join("|")
+        };
+        let regex =
+            Regex::new(&combined).map_err(|e| format!("patterns could not be combined: {}", e))?;
+        Ok(TokenScanner { regex, groups })
+    }
+
+    /// The first token in `s` at or after `offset`. This searches the
+    /// whole string, so anchors such as `(?m)^` only match at the
+    /// start of a line.
+    fn find_at(&self, s: &str, offset: usize) -> Option<LexMatch> {
+        let mut locations = self.regex.capture_locations();
+        self.regex.captures_read_at(&mut locations, s, offset)?;
+        let (start, end) = locations.get(0)?;
+        let kind = self
+            .groups
+            .iter()
+            .find(|(group, _)| locations.get(*group).is_some())
+            .map(|(_, kind)| *kind)?;
+        Some(LexMatch { kind, start, end })
+    }
+}
+
+/// The start and end offsets of a match.
+type MatchOffsets = (usize, usize);
+
+/// The next match of each pattern that's searched for separately,
+/// such as heredocs, remembered between tokens. If the lexer hasn't
+/// passed the start of a match, it's still the first match, so a rare
+/// pattern scans the input once rather than once per token.
+#[derive(Debug, Clone, Default)]
+struct NextMatches {
+    /// For each pattern, the offset it was searched from, and the
+    /// start and end of the match found.
+    found: Vec<Option<(usize, Option<MatchOffsets>)>>,
+}
+
+impl NextMatches {
+    /// The first match of pattern `i` at or after `offset`, calling
+    /// `find` to search from `offset` if we don't know it already.
+    fn get(
+        &mut self,
+        i: usize,
+        offset: usize,
+        find: impl FnOnce() -> Option<MatchOffsets>,
+    ) -> Option<MatchOffsets> {
+        if self.found.len() <= i {
+            self.found.resize(i + 1, None);
+        }
+        match self.found[i] {
+            Some((searched_from, found))
+                if searched_from <= offset && found.is_none_or(|(start, _)| start >= offset) =>
+            {
+                found
+            }
+            _ => {
+                let found = find();
+                self.found[i] = Some((offset, found));
+                found
+            }
+        }
+    }
+}
+
+/// Replace `current` with the candidate token if it starts
+/// earlier. When two tokens start at the same position, the one found
+/// first wins.
+fn keep_earliest(current: &mut Option<LexMatch>, candidate: LexMatch) {
+    match current {
+        Some(prev) if prev.start <= candidate.start => {}
+        _ => {
+            *current = Some(candidate);
+        }
+    }
+}
+
+/// Find the first comment in `s` at or after `offset` that starts
+/// with `open`, allowing nested `open`/`close` pairs inside it. Returns
+/// the start and end offsets. Unterminated comments extend to the end
+/// of `s`.
+fn find_nested_comment(s: &str, offset: usize, open: &str, close: &str) -> Option<(usize, usize)> {
+    let start = offset + s[offset..].find(open)?;
+
+    let mut depth = 0;
+    let mut i = start;
+    while i < s.len() {
+        if s[i..].starts_with(open) {
+            depth += 1;
+            i += open.len();
+        } else if s[i..].starts_with(close) {
+            depth -= 1;
+            i += close.len();
+            if depth == 0 {
+                return Some((start, i));
+            }
+        } else {
+            i += s[i..].chars().next().map_or(1, |c| c.len_utf8());
+        }
+    }
+
+    Some((start, s.len()))
+}
+
+/// Replace each `${N}` or `$N` in `template` with the text of
+/// capture group N in `locations`, or nothing if it didn't match.
+fn expand_template(template: &str, s: &str, locations: &CaptureLocations) -> String {
+    let mut res = String::new();
+    let mut rest = template;
+    while let Some(dollar) = rest.find('$') {
+        res.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+
+        let (digits, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(close) => (&braced[..close], &braced[close + 1..]),
+                None => ("", rest),
+            },
+            None => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                (&rest[..len], &rest[len..])
+            }
+        };
+        match digits.parse::<usize>() {
+            Ok(group) => {
+                if let Some((start, end)) = locations.get(group) {
+                    res.push_str(&s[start..end]);
+                }
+                rest = after;
+            }
+            Err(_) => res.push('$'),
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Find the first raw string in `s` at or after `offset` that starts
+/// with `open`. Returns the start and end offsets. Unterminated
+/// strings extend to the end of `s`.
+fn find_raw_string(
+    s: &str,
+    offset: usize,
+    open: &Regex,
+    close_template: &str,
+) -> Option<(usize, usize)> {
+    let mut locations = open.capture_locations();
+    let open_match = open.captures_read_at(&mut locations, s, offset)?;
+    let (open_start, open_end) = (open_match.start(), open_match.end());
+    let close = expand_template(close_template, s, &locations);
+
+    let end = match s[open_end..].find(&close) {
+        Some(close_start) => open_end + close_start + close.len(),
+        None => s.len(),
+    };
+    Some((open_start, end))
+}
+
+/// Find the first heredoc in `s` at or after `offset` that starts with
+/// `open`. Returns the start and end offsets. Unterminated heredocs
+/// extend to the end of `s`.
+fn find_heredoc(s: &str, offset: usize, open: &Regex) -> Option<(usize, usize)> {
+    let mut locations = open.capture_locations();
+    let open_match = open.captures_read_at(&mut locations, s, offset)?;
+    let (open_start, open_end) = (open_match.start(), open_match.end());
+    let terminator = locations.get(1).map_or("", |(start, end)| &s[start..end]);
+
+    let body_start = match s[open_end..].find('\n') {
+        Some(i) => open_end + i + 1,
+        None => return Some((open_start, s.len())),
+    };
+
+    let mut line_start = body_start;
+    for line in s[body_start..].split('\n') {
+        let indent = line.len() - line.trim_start().len();
+        if let Some(after) = line[indent..].strip_prefix(terminator) {
+            let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+            if !after.starts_with(is_word_char) {
+                return Some((open_start, line_start + indent + terminator.len()));
+            }
+        }
+        line_start += line.len() + 1;
+    }
+
+    Some((open_start, s.len()))
+}
+
+/// Find the first string in `s` at or after `offset` that starts with
+/// `open` and runs until `close`, skipping over backslash
+/// escapes. Returns the start and end offsets. Unterminated strings
+/// extend to the end of `s`.
+fn find_escaped_string(
+    s: &str,
+    offset: usize,
+    open: &Regex,
+    close: &str,
+) -> Option<(usize, usize)> {
+    let open_match = open.find_at(s, offset)?;
+
+    let mut chars = s[open_match.end()..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        let i = open_match.end() + i;
+        if s[i..].starts_with(close) {
+            return Some((open_match.start(), i + close.len()));
+        }
+        if c == '\\' {
+            chars.next();
+        }
+    }
+
+    Some((open_match.start(), s.len()))
+}
+
+/// Can a regex literal follow a token of this kind and content?
+///
+/// A `/` after a value (e.g. `x` or `)`) is division, whereas a `/`
+/// after an operator, an open delimiter or a keyword like `return`
+/// starts a regex literal.
+fn allows_regex_after(kind: &LexKind, content: &str, lang: &Language) -> bool {
+    match kind {
+        LexKind::OpenDelimiter | LexKind::CodeStart | LexKind::CodeEnd => true,
+        LexKind::CloseDelimiter | LexKind::String => false,
+        LexKind::Comment => unreachable!("Comments don't affect regex context"),
+        LexKind::Atom => {
+            let ends_with_value = content
+                .chars()
+                .last()
+                .is_some_and(|c| c.is_alphanumeric() || "_$\"'`/".contains(c));
+            !ends_with_value || lang.regex_literal_keywords.iter().any(|k| k == content)
+        }
+    }
+}
+
+/// Find the next token in `s` after `offset`, when `offset` is in
+/// text rather than code. This is either a line of text, or the start
+/// of code.
+fn next_text_token(s: &str, offset: usize, code_start: &Regex) -> Option<LexMatch> {
+    let rest = &s[offset..];
+    let text = rest.trim_start();
+    if text.is_empty() {
+        return None;
+    }
+    let start = offset + rest.len() - text.len();
+
+    let line = match text.find('\n') {
+        Some(i) => &text[..i],
+        None => text,
+    };
+    let code_start = code_start
+        .find_at(s, start)
+        .filter(|m| m.start() < start + line.len());
+    match code_start {
+        Some(m) if m.start() == start => Some(LexMatch {
+            kind: LexKind::CodeStart,
+            start,
+            end: m.end(),
+        }),
+        m => {
+            let line = match m {
+                Some(m) => &s[start..m.start()],
+                None => line,
+            };
+            Some(LexMatch {
+                kind: LexKind::Atom,
+                start,
+                end: start + line.trim_end().len(),
+            })
+        }
+    }
+}
+
+/// Find the next token in `s` after `offset`. `regex_allowed` says
+/// whether the previous token permits a regex literal here.
+fn next_token(
+    s: &str,
+    offset: usize,
+    lang: &Language,
+    regex_allowed: bool,
+    next_matches: &mut NextMatches,
+) -> Option<LexMatch> {
+    let mut current: Option<LexMatch> = None;
+
+    // Patterns that are found separately are numbered in the order
+    // they're searched, so each has its own entry in
+    // `next_matches`. Like the token scanner, they search the whole of
+    // `s` from `offset`, so a match doesn't depend on where the
+    // previous token ended and can be reused.
+    let mut i = 0;
+    let mut next_match = |find: &dyn Fn() -> Option<(usize, usize)>| {
+        i += 1;
+        next_matches.get(i, offset, find)
+    };
+
+    if let Some((_, code_end)) = &lang.code_delimiters {
+        if let Some((start, end)) =
+            next_match(&|| code_end.find_at(s, offset).map(|m| (m.start(), m.end())))
+        {
+            current = Some(LexMatch {
+                kind: LexKind::CodeEnd,
+                start,
+                end,
+            });
+        }
+    }
+
+    for (open, close) in &lang.nested_comment_delimiters {
+        if let Some((start, end)) = next_match(&|| find_nested_comment(s, offset, open, close)) {
+            keep_earliest(
+                &mut current,
+                LexMatch {
+                    kind: LexKind::Comment,
+                    start,
+                    end,
+                },
+            );
+        }
+    }
+
+    for (open, close_template) in &lang.raw_string_patterns {
+        if let Some((start, end)) = next_match(&|| find_raw_string(s, offset, open, close_template))
+        {
+            keep_earliest(
+                &mut current,
+                LexMatch {
+                    kind: LexKind::String,
+                    start,
+                    end,
+                },
+            );
+        }
+    }
+
+    for open in &lang.heredoc_patterns {
+        if let Some((start, end)) = next_match(&|| find_heredoc(s, offset, open)) {
+            keep_earliest(
+                &mut current,
+                LexMatch {
+                    kind: LexKind::String,
+                    start,
+                    end,
+                },
+            );
+        }
+    }
+
+    for (open, close) in &lang.string_patterns {
+        if let Some((start, end)) = next_match(&|| find_escaped_string(s, offset, open, close)) {
+            keep_earliest(
+                &mut current,
+                LexMatch {
+                    kind: LexKind::String,
+                    start,
+                    end,
+                },
+            );
+        }
+    }
+
+    let scanner = match &lang.regex_literal_scanner {
+        Some(scanner) if regex_allowed => scanner,
+        _ => &lang.token_scanner,
+    };
+    if let Some(m) = scanner.find_at(s, offset) {
+        keep_earliest(&mut current, m);
+    }
+
+    if let Some(m) = &mut current {
+        if matches!(m.kind, LexKind::Atom | LexKind::String) {
+            // Atoms never include leading whitespace, so patterns can
+            // use `(?m)^[ \t]*` to match the start of an indented line.
+            let content = s[m.start..m.end].trim_start();
+            m.start = m.end - content.len();
+
+            if lang
+                .keyword_delimiters
+                .iter()
+                .any(|(open, _)| open == content)
+            {
+                m.kind = LexKind::OpenDelimiter;
+            } else if lang
+                .keyword_delimiters
+                .iter()
+                .any(|(_, close)| close == content)
+            {
+                m.kind = LexKind::CloseDelimiter;
+            }
+        }
+    }
+
+    current
+}
+
+/// The first match in `s` after `offset` of a lexer state's `enter`
+/// pattern. Returns the index of the state, where the match starts,
+/// and the token without leading whitespace.
+fn next_state_entry(
+    s: &str,
+    offset: usize,
+    lang: &Language,
+    next_matches: &mut NextMatches,
+) -> Option<(usize, usize, LexMatch)> {
+    let mut current: Option<(usize, usize, LexMatch)> = None;
+    for (i, lexer_state) in lang.lexer_states.iter().enumerate() {
+        let found = next_matches.get(i, offset, || {
+            lexer_state
+                .enter
+                .find_at(s, offset)
+                .map(|m| (m.start(), m.end()))
+        });
+        if let Some((start, end)) = found {
+            if current
+                .as_ref()
+                .is_some_and(|(_, current_start, _)| *current_start <= start)
+            {
+                continue;
+            }
+            let content = s[start..end].trim_start();
+            current = Some((
+                i,
+                start,
+                LexMatch {
+                    kind: LexKind::OpenDelimiter,
+                    start: end - content.len(),
+                    end,
+                },
+            ));
+        }
+    }
+    current
+}
+
+/// Find the next token in `s` after `offset`, inside `lexer_state`.
+/// Returns the token, or None if it's only whitespace, and the offset
+/// to continue lexing from.
+fn next_state_token(
+    s: &str,
+    offset: usize,
+    lexer_state: &LexerState,
+) -> Option<(Option<LexMatch>, usize)> {
+    let mut current = lexer_state.exit.find_at(s, offset).map(|m| LexMatch {
+        kind: LexKind::CloseDelimiter,
+        start: m.start(),
+        end: m.end(),
+    });
+    let patterns = lexer_state
+        .comment_patterns
+        .iter()
+        .map(|p| (LexKind::Comment, p))
+        .chain(lexer_state.atom_patterns.iter().map(|p| (LexKind::Atom, p)));
+    for (kind, pattern) in patterns {
+        if let Some(m) = pattern.find_at(s, offset) {
+            keep_earliest(
+                &mut current,
+                LexMatch {
+                    kind,
+                    start: m.start(),
+                    end: m.end(),
+                },
+            );
+        }
+    }
+
+    let m = current?;
+    let raw_content = &s[m.start..m.end];
+    let content = raw_content.trim();
+    if content.is_empty() {
+        let token = match m.kind {
+            LexKind::CloseDelimiter => Some(LexMatch {
+                kind: LexKind::CloseDelimiter,
+                start: m.start,
+                end: m.start,
+            }),
+            _ => None,
+        };
+        return Some((token, m.end));
+    }
+    let start = m.start + (raw_content.len() - raw_content.trim_start().len());
+    Some((
+        Some(LexMatch {
+            kind: m.kind,
+            start,
+            end: start + content.len(),
+        }),
+        m.end,
+    ))
+}
+
+/// Find the tokens in `s` after `state.str_i`.
+fn lex_from(s: &str, lang: &Language, state: &mut ParseState) -> Lexed {
+    let mut tokens = vec![];
+    while state.str_i < s.len() {
+        if let Some(i) = state.lexer_state {
+            let (token, next_i) = match next_state_token(s, state.str_i, &lang.lexer_states[i]) {
+                Some(found) => found,
+                None => break,
+            };
+            if let Some(m) = token {
+                if matches!(m.kind, LexKind::CloseDelimiter) {
+                    state.lexer_state = None;
+                }
+                if !matches!(m.kind, LexKind::Comment) {
+                    state.regex_allowed = allows_regex_after(&m.kind, &s[m.start..m.end], lang);
+                }
+                tokens.push(m);
+            }
+            state.str_i = next_i;
+            continue;
+        }
+
+        let token = match (&lang.code_delimiters, state.in_text) {
+            (Some((code_start, _)), true) => next_text_token(s, state.str_i, code_start),
+            _ => next_token(
+                s,
+                state.str_i,
+                lang,
+                state.regex_allowed,
+                &mut state.next_tokens,
+            ),
+        };
+        let entry = if state.in_text {
+            None
+        } else {
+            next_state_entry(s, state.str_i, lang, &mut state.next_state_entries)
+        };
+        let m = match (token, entry) {
+            (Some(m), Some((i, entry_start, entry))) if entry_start <= m.start => {
+                state.lexer_state = Some(i);
+                entry
+            }
+            (None, Some((i, _, entry))) => {
+                state.lexer_state = Some(i);
+                entry
+            }
+            (Some(m), _) => m,
+            (None, None) => break,
+        };
+        if !matches!(m.kind, LexKind::Comment) {
+            state.regex_allowed = allows_regex_after(&m.kind, &s[m.start..m.end], lang);
+        }
+        if matches!(m.kind, LexKind::CodeStart | LexKind::CodeEnd) {
+            state.in_text = matches!(m.kind, LexKind::CodeEnd);
+        }
+        state.str_i = m.end;
+        tokens.push(m);
+    }
+
+    // Leave the state at the end of the input if the exit pattern
+    // allows it, such as `\n|$` for a preprocessor line.
+    if let Some(i) = state.lexer_state {
+        if lang.lexer_states[i].exit.find_at(s, s.len()).is_some() {
+            tokens.push(LexMatch {
+                kind: LexKind::CloseDelimiter,
+                start: s.len(),
+                end: s.len(),
+            });
+            state.lexer_state = None;
+        }
+    }
+
+    Lexed {
+        tokens,
+        end: state.str_i,
+    }
+}
+
+/// Find the tokens in `s`. This doesn't depend on the syntax tree, so
+/// it can be done on another thread.
+fn lex(s: &str, lang: &Language) -> Tokens {
+    if let Some(separator) = lang.cell_separator {
+        return Tokens::Table(separator);
+    }
+    if let Some(continuation) = &lang.line_continuation {
+        let lines = logical_lines(s, continuation)
+            .into_iter()
+            .map(|(start, end)| {
+                let mut state = ParseState::new(lang);
+                state.str_i = start;
+                (start, end, lex_from(&s[..end], lang, &mut state))
+            })
+            .collect();
+        return Tokens::LogicalLines(lines);
+    }
+
+    Tokens::Whole(lex_from(s, lang, &mut ParseState::new(lang)))
+}
+
+fn parse_from<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    s: &'a str,
+    nl_pos: &NewlinePositions,
+    lang: &Language,
+    lexed: Lexed,
+) -> Vec<&'a Syntax<'a>> {
+    let max_depth = lang.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+    let mut result: Vec<&'a Syntax<'a>> = vec![];
+    // The lists we're currently inside, innermost last. Each entry is
+    // the open delimiter, its position, and the nodes before it.
+    let mut open_lists: Vec<(&'a str, Spans, Vec<&'a Syntax<'a>>)> = vec![];
+    // How many delimiters we've seen beyond `max_depth`. These are
+    // treated as atoms.
+    let mut flattened_depth = 0;
+    // The list deeper than `flatten_depth` that we're inside: its start
+    // and end offsets so far, its nesting depth, and the content of its
+    // tokens.
+    let mut flattened: Option<(usize, usize, usize, Vec<&'a str>)> = None;
+    let end = lexed.end;
+
+    for m in lexed.tokens {
+        let content = &s[m.start..m.end];
+        if let Some((start, flattened_end, depth, parts)) = &mut flattened {
+            if !content.is_empty() {
+                parts.push(content);
+            }
+            *flattened_end = m.end;
+            match m.kind {
+                LexKind::OpenDelimiter => *depth += 1,
+                LexKind::CloseDelimiter => *depth -= 1,
+                _ => {}
+            }
+            if *depth == 0 {
+                result.push(Syntax::new_atom(
+                    arena,
+                    nl_pos.from_offsets(*start, *flattened_end),
+                    parts.join(" "),
+                ));
+                flattened = None;
+            }
+            continue;
+        }
+
+        match m.kind {
+            LexKind::OpenDelimiter
+                if lang
+                    .flatten_depth
+                    .is_some_and(|depth| open_lists.len() >= depth) =>
+            {
+                flattened = Some((m.start, m.end, 1, vec![content]));
+            }
+            LexKind::Comment => {
+                let content = if lang.structure_only {
+                    STRUCTURE_ONLY_CONTENT
+                } else {
+                    content
+                };
+                let atom = Syntax::new_comment(arena, nl_pos.from_offsets(m.start, m.end), content);
+                result.push(atom);
+            }
+            LexKind::Atom | LexKind::String => {
+                let token_kind = match m.kind {
+                    LexKind::String => TokenKind::String,
+                    _ => atom_token_kind(content),
+                };
+                let atom = Syntax::new_atom_of_kind(
+                    arena,
+                    nl_pos.from_offsets(m.start, m.end),
+                    atom_content(s, &m, lang),
+                    token_kind,
+                );
+                result.push(atom);
+            }
+            LexKind::CodeStart | LexKind::CodeEnd => {
+                let atom = Syntax::new_atom(arena, nl_pos.from_offsets(m.start, m.end), content);
+                result.push(atom);
+            }
+            LexKind::OpenDelimiter if open_lists.len() >= max_depth => {
+                let atom = Syntax::new_atom(arena, nl_pos.from_offsets(m.start, m.end), content);
+                result.push(atom);
+                flattened_depth += 1;
+            }
+            LexKind::CloseDelimiter if flattened_depth > 0 => {
+                let atom = Syntax::new_atom(arena, nl_pos.from_offsets(m.start, m.end), content);
+                result.push(atom);
+                flattened_depth -= 1;
+            }
+            LexKind::OpenDelimiter => {
+                let siblings = std::mem::take(&mut result);
+                open_lists.push((content, nl_pos.from_offsets(m.start, m.end), siblings));
+            }
+            LexKind::CloseDelimiter => match open_lists.pop() {
+                Some((open_content, open_pos, siblings)) => {
+                    let children = std::mem::replace(&mut result, siblings);
+                    let close_pos = nl_pos.from_offsets(m.start, m.end);
+                    result.push(new_list(
+                        arena,
+                        lang,
+                        open_content,
+                        open_pos,
+                        children,
+                        content,
+                        close_pos,
+                    ));
+                }
+                None => {
+                    // A close delimiter without an open delimiter,
+                    // such as in an excerpt of a file. Treat it as an
+                    // atom, so we still parse the rest of the input.
+                    let atom =
+                        Syntax::new_atom(arena, nl_pos.from_offsets(m.start, m.end), content);
+                    result.push(atom);
+                }
+            },
+        }
+    }
+
+    // A flattened list that's unclosed ends at its last token.
+    if let Some((start, flattened_end, _, parts)) = flattened {
+        result.push(Syntax::new_atom(
+            arena,
+            nl_pos.from_offsets(start, flattened_end),
+            parts.join(" "),
+        ));
+    }
+
+    // Lists that are still open at the end of the input are unclosed.
+    while let Some((open_content, open_pos, siblings)) = open_lists.pop() {
+        let children = std::mem::replace(&mut result, siblings);
+        let close_pos = nl_pos.from_offsets(end, end + 1);
+        result.push(new_list(
+            arena,
+            lang,
+            open_content,
+            open_pos,
+            children,
+            "UNCLOSED",
+            close_pos,
+        ));
+    }
+
+    match &lang.key_value_separator {
+        Some(separator) => group_key_values(arena, result, separator),
+        None => result,
+    }
+}
+
+/// Build a list from its delimiters and children, grouping and
+/// sorting the children if `lang` requires it.
+fn new_list<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    lang: &Language,
+    open_content: &'a str,
+    open_pos: Spans,
+    children: Vec<&'a Syntax<'a>>,
+    close_content: &'a str,
+    close_pos: Spans,
+) -> &'a Syntax<'a> {
+    let children = match &lang.key_value_separator {
+        Some(separator) => group_key_values(arena, children, separator),
+        None => children,
+    };
+    let children = match lang
+        .unordered_lists
+        .iter()
+        .find(|list| list.open == open_content && list.close == close_content)
+    {
+        Some(list) => sort_entries(children, &list.separator),
+        None => children,
+    };
+    Syntax::new_list(
+        arena,
+        open_content,
+        open_pos,
+        children,
+        close_content,
+        close_pos,
+    )
+}
+
+/// A string describing the content of `nodes`, ignoring positions.
+fn content_key(nodes: &[&Syntax], key: &mut String) {
+    for node in nodes {
+        match node {
+            Syntax::List {
+                open_content,
+                children,
+                close_content,
+                ..
+            } => {
+                key.push_str(open_content);
+                key.push('\0');
+                content_key(children, key);
+                key.push_str(close_content);
+            }
+            Syntax::Atom { content, .. } => key.push_str(content),
+        }
+        key.push('\0');
+    }
+}
+
+/// Sort the entries in `children`, where entries are separated by
+/// `separator` atoms. Separators stay where they are, so `{a, b}` and
+/// `{b, a}` have the same children after sorting.
+///
+/// Children keep their original positions, so the display is
+/// unaffected.
+fn sort_entries<'a>(children: Vec<&'a Syntax<'a>>, separator: &str) -> Vec<&'a Syntax<'a>> {
+    let mut entries: Vec<Vec<&'a Syntax<'a>>> = vec![vec![]];
+    let mut separators = vec![];
+    for child in children {
+        match child {
+            Syntax::Atom {
+                content,
+                is_comment: false,
+                ..
+            } if content == separator => {
+                separators.push(child);
+                entries.push(vec![]);
+            }
+            _ if separator.is_empty() => entries.push(vec![child]),
+            _ => entries.last_mut().unwrap().push(child),
+        }
+    }
+
+    entries.sort_by_cached_key(|entry| {
+        let mut key = String::new();
+        content_key(entry, &mut key);
+        key
+    });
+
+    let mut separators = separators.into_iter();
+    let mut result = vec![];
+    for entry in entries {
+        result.extend(entry);
+        result.extend(separators.next());
+    }
+    result
+}
+
+#[derive(Debug, Clone)]
+struct ParseState {
+    str_i: usize,
+    /// Whether the previous token permits a regex literal next.
+    regex_allowed: bool,
+    /// Whether we're in text rather than embedded code.
+    in_text: bool,
+    /// The index of the lexer state we're in, if any.
+    lexer_state: Option<usize>,
+    /// The next match of patterns in `next_token` that are searched
+    /// for separately.
+    next_tokens: NextMatches,
+    /// The next match of each lexer state's `enter` pattern.
+    next_state_entries: NextMatches,
+}
+
+impl ParseState {
+    fn new(lang: &Language) -> Self {
+        ParseState {
+            str_i: 0,
+            regex_allowed: true,
+            in_text: lang.code_delimiters.is_some(),
+            lexer_state: None,
+            next_tokens: NextMatches::default(),
+            next_state_entries: NextMatches::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::syntax::Syntax::*;
+
+    #[cfg(feature = "lang-javascript")]
+    fn lang() -> &'static Language {
+        find_lang(ConfigDir::read_default_toml(), "js").unwrap()
+    }
+
+    #[cfg(feature = "lang-rust")]
+    fn rust_lang() -> &'static Language {
+        find_lang(ConfigDir::read_default_toml(), "rs").unwrap()
+    }
+
+    /// The content of every top-level node.
+    fn top_level_content(nodes: &[&Syntax]) -> Vec<String> {
+        nodes
+            .iter()
+            .map(|node| match node {
+                List { open_content, .. } => open_content.to_string(),
+                Atom { content, .. } => content.to_string(),
+            })
+            .collect()
+    }
+
+    fn assert_syntaxes<'a>(actual: &[&'a Syntax<'a>], expected: &[&'a Syntax<'a>]) {
+        if !syntaxes_match(actual, expected) {
+            dbg!(expected, actual);
+            panic!();
+        }
+    }
+
+    fn syntaxes_match<'a>(actual: &[&'a Syntax<'a>], expected: &[&'a Syntax<'a>]) -> bool {
+        if actual.len() != expected.len() {
+            return false;
+        } else {
+            for (lhs_child, rhs_child) in actual.iter().zip(expected.iter()) {
+                if !syntax_matches(lhs_child, rhs_child) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Compare all the fields in a Syntax value, not just
+    /// those used in its Eq implementation.
+    fn syntax_matches<'a>(actual: &'a Syntax<'a>, expected: &'a Syntax<'a>) -> bool {
+        match (actual, expected) {
+            (
+                List {
+                    info: lhs_info,
+                    open_position: lhs_open_position,
+                    open_content: lhs_start_content,
+                    children: lhs_children,
+                    close_content: lhs_end_content,
+                    close_position: lhs_close_position,
+                    num_descendants: lhs_num_descendants,
+                    ..
+                },
+                List {
+                    info: rhs_info,
+                    open_position: rhs_open_position,
+                    open_content: rhs_start_content,
+                    children: rhs_children,
+                    close_content: rhs_end_content,
+                    close_position: rhs_close_position,
+                    num_descendants: rhs_num_descendants,
+                    ..
+                },
+            ) => {
+                if lhs_info.change.get() != rhs_info.change.get() {
+                    dbg!(lhs_info.change.get(), rhs_info.change.get());
+                    return false;
+                }
+                if lhs_open_position != rhs_open_position {
+                    dbg!(lhs_open_position, rhs_open_position);
+                    return false;
+                }
+
+                if lhs_start_content != rhs_start_content {
+                    dbg!(lhs_start_content, rhs_start_content);
+                    return false;
+                }
+                if lhs_end_content != rhs_end_content {
+                    dbg!(lhs_end_content, rhs_end_content);
+                    return false;
+                }
+                if lhs_close_position != rhs_close_position {
+                    dbg!(lhs_close_position, rhs_close_position);
+                    return false;
+                }
+
+                if lhs_num_descendants != rhs_num_descendants {
+                    dbg!(lhs_num_descendants, rhs_num_descendants);
+                    return false;
+                }
+
+                if !syntaxes_match(lhs_children, rhs_children) {
+                    return false;
+                }
+            }
+            (
+                Atom {
+                    info: lhs_info,
+                    position: lhs_position,
+                    content: lhs_content,
+                    is_comment: lhs_is_comment,
+                    ..
+                },
+                Atom {
+                    info: rhs_info,
+                    position: rhs_position,
+                    content: rhs_content,
+                    is_comment: rhs_is_comment,
+                    ..
+                },
+            ) => {
+                if lhs_info.change.get() != rhs_info.change.get() {
+                    dbg!(lhs_info.change.get(), rhs_info.change.get());
+                    return false;
+                }
+                if lhs_position != rhs_position {
+                    dbg!(lhs_position, rhs_position);
+                    return false;
+                }
+
+                if lhs_content != rhs_content {
+                    dbg!(lhs_content, rhs_content);
+                    return false;
+                }
+                if lhs_is_comment != rhs_is_comment {
+                    dbg!(lhs_is_comment, rhs_is_comment);
+                    return false;
+                }
+            }
+            _ => {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn test_default_languages_are_valid() {
+        let table = parse_syntax_table(&ConfigDir::default_toml()).unwrap();
+        for (name, value) in &table {
+            if let Err(e) = lang_from_value(name, value) {
+                panic!("Invalid built-in language {}: {}", name, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_languages_have_features() {
+        let table = parse_syntax_table(&ConfigDir::default_toml()).unwrap();
+        for name in table.keys() {
+            assert!(
+                builtin_enabled(name).is_some(),
+                "Built-in language {} has no cargo feature",
+                name
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "lang-javascript", feature = "lang-rust"))]
+    fn test_parse_region() {
+        let arena = Arena::new();
+        let src = "a;\n(b c)\nd";
+
+        let nodes = parse_region(&arena, src, 3, 9, rust_lang()).unwrap();
+        assert_eq!(nodes.len(), 1);
+        match nodes[0] {
+            List {
+                open_position,
+                children,
+                ..
+            } => {
+                assert_eq!(open_position[0].line.0, 1);
+                assert_eq!(children.len(), 2);
+            }
+            _ => panic!("expected a list"),
+        }
+
+        // Unclosed lists, and tokens that might continue past the end,
+        // need the rest of the file.
+        assert!(parse_region(&arena, src, 3, 6, rust_lang()).is_none());
+        assert!(parse_region(&arena, src, 0, 1, rust_lang()).is_none());
+        // Regex literals depend on the token before the region.
+        assert!(parse_region(&arena, "x\n/a/g", 2, 6, lang()).is_none());
+        assert!(parse_region(&arena, "x\ny", 2, 3, lang()).is_some());
+    }
+
+    #[test]
+    fn test_token_scanner() {
+        let scanner = TokenScanner::new(vec![
+            (LexKind::Comment, Regex::new("//.*").unwrap()),
+            (LexKind::OpenDelimiter, Regex::new(r"(\()").unwrap()),
+            (LexKind::Atom, Regex::new("[a-z]+").unwrap()),
+            // Never wins, as the atom pattern above matches first.
+            (LexKind::String, Regex::new("foo").unwrap()),
+        ])
+        .unwrap();
+
+        let m = scanner.find_at("  foo(", 0).unwrap();
+        assert!(matches!(m.kind, LexKind::Atom));
+        assert_eq!((m.start, m.end), (2, 5));
+
+        // Groups inside a pattern don't confuse which pattern matched.
+        let m = scanner.find_at("  foo(", 5).unwrap();
+        assert!(matches!(m.kind, LexKind::OpenDelimiter));
+
+        assert!(scanner.find_at("  foo(", 6).is_none());
+        assert!(TokenScanner::new(vec![])
+            .unwrap()
+            .find_at("foo", 0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_next_matches() {
+        let mut next_matches = NextMatches::default();
+        assert_eq!(next_matches.get(0, 0, || Some((5, 6))), Some((5, 6)));
+        // Not passed yet, so we don't search again.
+        assert_eq!(next_matches.get(0, 3, || unreachable!()), Some((5, 6)));
+        assert_eq!(next_matches.get(0, 6, || None), None);
+        assert_eq!(next_matches.get(0, 10, || unreachable!()), None);
+        assert_eq!(next_matches.get(2, 10, || Some((12, 13))), Some((12, 13)));
+    }
+
+    /// A language with the given extra TOML, for testing lexer patterns.
+    fn anchor_lang(patterns: &str) -> LanguageRegistry {
+        read_syntax_toml(
+            Table::new(),
+            &[&format!(
+                "[Anchors]\nextensions = [\"anchors\"]\nopen_delimiter_pattern = '\\('\nclose_delimiter_pattern = '\\)'\n{}",
+                patterns
+            )],
+        )
+    }
+
+    #[test]
+    fn test_parse_line_start_comment() {
+        let langs = anchor_lang(
+            r#"
+atom_patterns = ["[a-z#]+"]
+comment_patterns = ['(?m)^#.*']
+"#,
+        );
+        let lang = find_lang(&langs, "anchors").unwrap();
+        let arena = Arena::new();
+        // `^` matches at the start of a line, not after the previous
+        // token.
+        let nodes = parse(&arena, "a #b\n#c", lang);
+        let comments: Vec<_> = nodes
+            .iter()
+            .map(|node| match node {
+                Atom {
+                    content,
+                    is_comment,
+                    ..
+                } => (content.as_ref(), *is_comment),
+                List { .. } => unreachable!(),
+            })
+            .collect();
+        assert_eq!(comments, vec![("a", false), ("#b", false), ("#c", true)]);
+    }
+
+    #[test]
+    fn test_parse_word_boundary_at_token_boundary() {
+        let langs = anchor_lang(
+            r#"
+atom_patterns = ["a", '\bx']
+comment_patterns = []
+string_patterns = [['\bq', 'q']]
+"#,
+        );
+        let lang = find_lang(&langs, "anchors").unwrap();
+        let arena = Arena::new();
+        // After the `a` atom, `\b` doesn't match between `a` and `x`,
+        // since that's not a word boundary in the input.
+        assert_eq!(
+            top_level_content(&parse(&arena, "axx x", lang)),
+            vec!["a", "x"]
+        );
+        assert_eq!(
+            top_level_content(&parse(&arena, "aqzq qzq", lang)),
+            vec!["a", "qzq"]
+        );
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let open = Regex::new(r#"r(#*)(x)?""#).unwrap();
+        let mut locations = open.capture_locations();
+        open.captures_read_at(&mut locations, r##"a r#""##, 0)
+            .unwrap();
+        assert_eq!(
+            expand_template("\"${1}$2$1$", r##"a r#""##, &locations),
+            "\"##$"
+        );
+    }
+
+    #[test]
+    fn test_read_syntax_toml_skips_invalid() {
+        let langs = read_syntax_toml(
+            Table::new(),
+            &[r#"
+[Good]
+extensions = ["good"]
+atom_patterns = ["[a-z]+"]
+comment_patterns = []
+open_delimiter_pattern = '\('
+close_delimiter_pattern = '\)'
+
+[BadRegex]
+extensions = ["bad"]
+atom_patterns = ["[a-z"]
+comment_patterns = []
+open_delimiter_pattern = '\('
+close_delimiter_pattern = '\)'
+
+[MissingKey]
+extensions = ["missing"]
+"#],
+        );
+
+        assert_eq!(find_lang(&langs, "good").unwrap().name, "Good");
+        assert!(find_lang(&langs, "bad").is_none());
+        assert!(find_lang(&langs, "missing").is_none());
+    }
+
+    #[test]
+    fn test_registry_compiles_languages_lazily() {
+        let registry = ConfigDir::read_default_toml();
+        assert!(std::ptr::eq(registry, ConfigDir::read_default_toml()));
+
+        let langs = read_syntax_toml(Table::new(), &[&ConfigDir::default_toml()]);
+        find_lang(&langs, "rs").unwrap();
+        let compiled: Vec<_> = langs
+            .definitions
+            .iter()
+            .filter(|definition| definition.compiled.get().is_some())
+            .map(|definition| definition.name.as_str())
+            .collect();
+        assert_eq!(compiled, vec!["Rust"]);
+    }
+
+    #[test]
+    fn test_read_syntax_toml_extends_language() {
+        let langs = read_syntax_toml(
+            Table::new(),
+            &[
+                r#"
+[Foo]
+extensions = ["foo"]
+atom_patterns = ["[a-z]+"]
+comment_patterns = []
+open_delimiter_pattern = '\('
+close_delimiter_pattern = '\)'
+"#,
+                r#"
+[Foo]
+extensions = ["foo2"]
+open_delimiter_pattern = '\['
+"#,
+            ],
+        );
+
+        assert_eq!(find_lang(&langs, "foo").unwrap().name, "Foo");
+        let lang = find_lang(&langs, "foo2").unwrap();
+        let arena = Arena::new();
+        let res = parse(&arena, "[a b)", lang);
+        assert_eq!(res.len(), 1);
+        match res[0] {
+            List {
+                open_content,
+                children,
+                ..
+            } => {
+                assert_eq!(open_content, "[");
+                assert_eq!(children.len(), 2);
+            }
+            _ => panic!("expected a list"),
+        }
+    }
+
+    #[test]
+    fn test_lang_from_value_reports_key() {
+        let v = r#"
+extensions = ["bad"]
+atom_patterns = ["[a-z"]
+comment_patterns = []
+open_delimiter_pattern = '\('
+close_delimiter_pattern = '\)'
+"#
+        .parse::<Value>()
+        .unwrap();
+
+        let err = lang_from_value("BadRegex", &v).err().unwrap();
+        assert!(err.contains("atom_patterns"));
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_parse_rust_nested_comment() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, "/* a /* b */ c */ x", rust_lang());
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["/* a /* b */ c */".to_string(), "x".to_string()]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_parse_rust_raw_string() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, r###"r#"a "quoted" b"# x"###, rust_lang());
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![r###"r#"a "quoted" b"#"###.to_string(), "x".to_string()]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_parse_rust_raw_string_many_hashes() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, r#####"r####"a "### b"#### x"#####, rust_lang());
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                r#####"r####"a "### b"####"#####.to_string(),
+                "x".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_parse_unterminated_raw_string() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, r##"r#"foo"##, rust_lang());
+
+        assert_eq!(top_level_content(&nodes), vec![r##"r#"foo"##]);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-python")]
+    fn test_parse_python_triple_quoted_strings() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "py").unwrap();
+        let nodes = parse(
+            &arena,
+            r#"f"""a "b"\"""
+c""" + '''d'''"#,
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                r#"f"""a "b"\"""
+c""""#,
+                "+",
+                "'''d'''"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-julia")]
+    fn test_parse_unterminated_triple_quoted_string() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "jl").unwrap();
+        let nodes = parse(&arena, r#"x = """foo)"#, lang);
+
+        assert_eq!(top_level_content(&nodes), vec!["x", "=", r#""""foo)"#]);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_parse_js_regex_literal() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, "x = /a[/]b\\//g; return /c/", lang());
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["x", "=", "/a[/]b\\//g", ";", "return", "/c/"]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_parse_js_division() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, "a / b / c; (d) / e / f", lang());
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["a", "/", "b", "/", "c", ";", "(", "/", "e", "/", "f"]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-clojure")]
+    fn test_parse_clojure_reader_macros() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "clj").unwrap();
+        let nodes = parse(
+            &arena,
+            r#"#{:a ::b/c} #(inc %) #_x @y #'z \newline #"a\"b" foo#"#,
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "#{",
+                "#(",
+                "#_",
+                "x",
+                "@",
+                "y",
+                "#'",
+                "z",
+                r"\newline",
+                r#"#"a\"b""#,
+                "foo#"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-scheme")]
+    fn test_parse_scheme_reader_syntax() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "scm").unwrap();
+        let nodes = parse(
+            &arena,
+            r"#| a #| b |# |# #(1 2) #u8(3) #\space #\x41 #t ,@xs",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "#| a #| b |# |#",
+                "#(",
+                "#u8(",
+                r"#\space",
+                r"#\x41",
+                "#t",
+                ",@",
+                "xs"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-elixir")]
+    fn test_parse_elixir_do_end() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "ex").unwrap();
+        let nodes = parse(
+            &arena,
+            "defmodule Foo do\n  @doc ~S(x)\n  def f(end_x), do: end_x\nend",
+            lang,
+        );
+
+        assert_eq!(top_level_content(&nodes), vec!["defmodule", "Foo", "do"]);
+        match nodes[2] {
+            List {
+                children,
+                close_content,
+                ..
+            } => {
+                assert_eq!(close_content, "end");
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["@doc", "~S(x)", "def", "f", "(", ",", "do:", "end_x"]
+                );
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-elixir")]
+    fn test_parse_elixir_binaries() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "ex").unwrap();
+        let nodes = parse(&arena, "<<1, 2>> <> <<3>>", lang);
+
+        assert_eq!(top_level_content(&nodes), vec!["<<", "<>", "<<"]);
+        match nodes[0] {
+            List {
+                children,
+                close_content,
+                ..
+            } => {
+                assert_eq!(close_content, ">>");
+                assert_eq!(top_level_content(children), vec!["1", ",", "2"]);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-kotlin")]
+    fn test_parse_kotlin_templates_and_annotations() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "kt").unwrap();
+        let nodes = parse(
+            &arena,
+            r#"@Test val s = "a ${m["k"]} b" + """c\""" /* d /* e */ */"#,
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "@Test",
+                "val",
+                "s",
+                "=",
+                r#""a ${m["k"]} b""#,
+                "+",
+                r#""""c\""""#,
+                "/* d /* e */ */"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-swift")]
+    fn test_parse_swift_strings() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "swift").unwrap();
+        let nodes = parse(
+            &arena,
+            "\"a \\(f(\"x\")) b\" \"\"\"\nc \"d\"\n\"\"\" #\"e\\\"\"# /* f /* g */ */",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "\"a \\(f(\"x\")) b\"",
+                "\"\"\"\nc \"d\"\n\"\"\"",
+                "#\"e\\\"\"#",
+                "/* f /* g */ */"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-scala")]
+    fn test_parse_scala_literals() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "scala").unwrap();
+        let nodes = parse(
+            &arena,
+            r#"s"""a "b" \""" 'c' 'sym x <- <a> /* d /* e */ */"#,
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                r#"s"""a "b" \""""#,
+                "'c'",
+                "'sym",
+                "x",
+                "<-",
+                "<",
+                "a",
+                ">",
+                "/* d /* e */ */"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-c")]
+    fn test_parse_c_preprocessor_and_digraphs() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "c").unwrap();
+        let nodes = parse(
+            &arena,
+            "#include <stdio.h>\n#define F(x) \\\n  (x)\nint a<:1:> = <%'\\''%>;",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["#include", "#define", "int", "a", "<:", "=", "<%", ";"]
+        );
+        match nodes[1] {
+            List {
+                children,
+                close_content,
+                ..
+            } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["F", "(", "x", ")", "\\", "(", "x", ")"]
+                );
+                assert_eq!(close_content, "");
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-c")]
+    fn test_parse_lexer_state_at_end() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "c").unwrap();
+        let nodes = parse(
+            &arena,
+            "int a;
+#endif // FOO",
+            lang,
+        );
+
+        assert_eq!(top_level_content(&nodes), vec!["int", "a", ";", "#endif"]);
+        match nodes[3] {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["// FOO"]);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-cpp")]
+    fn test_parse_cpp_literals() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "cpp").unwrap();
+        let nodes = parse(
+            &arena,
+            r#"std::vector<int> v = {1'000, u8'x'}; R"x(a)" b)x""#,
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "std",
+                "::",
+                "vector",
+                "<",
+                "int",
+                ">",
+                "v",
+                "=",
+                "{",
+                ";",
+                r#"R"x(a)" b)x""#
+            ]
+        );
+        match nodes[8] {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["1'000", ",", "u8'x'"]);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-csharp")]
+    fn test_parse_csharp_strings() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "cs").unwrap();
+        let nodes = parse(
+            &arena,
+            "/// <summary>Doc</summary>\n@\"C:\\a \"\"b\"\"\" $\"{d[\"k\"]}\" \"\"\"e \"\" f\"\"\"",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "/// <summary>Doc</summary>",
+                "@\"C:\\a \"\"b\"\"\"",
+                "$\"{d[\"k\"]}\"",
+                "\"\"\"e \"\" f\"\"\""
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-java")]
+    fn test_parse_java_generics_and_text_blocks() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "java").unwrap();
+        let nodes = parse(
+            &arena,
+            "@Override Map<String, List<T>> m = \"\"\"\n  a \"b\"\n  \"\"\";",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "@Override",
+                "Map",
+                "<",
+                "String",
+                ",",
+                "List",
+                "<",
+                "T",
+                ">",
+                ">",
+                "m",
+                "=",
+                "\"\"\"\n  a \"b\"\n  \"\"\"",
+                ";"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-typescript")]
+    fn test_parse_typescript_generics() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "ts").unwrap();
+        let nodes = parse(&arena, "let m: Map<K, Array<V>> = f<T>(x);", lang);
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "let", "m", ":", "Map", "<", "K", ",", "Array", "<", "V", ">", ">", "=", "f", "<",
+                "T", ">", "(", ";"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-typescript")]
+    fn test_parse_tsx_elements() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "tsx").unwrap();
+        let nodes = parse(&arena, "<div id={x}>Hi</div> <Foo />", lang);
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["<div", "id", "=", "{", ">", "Hi", "</div>", "<Foo", "/>"]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-ocaml")]
+    fn test_parse_ocaml_begin_end() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "ml").unwrap();
+        let nodes = parse(
+            &arena,
+            "(* a (* b *) *) let f (x : 'a) = begin x' ; 'c' end",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["(* a (* b *) *)", "let", "f", "(", "=", "begin"]
+        );
+        match nodes[5] {
+            List {
+                children,
+                close_content,
+                ..
+            } => {
+                assert_eq!(close_content, "end");
+                assert_eq!(top_level_content(children), vec!["x'", ";", "'c'"]);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-php")]
+    fn test_parse_php_in_html() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "php").unwrap();
+        let nodes = parse(
+            &arena,
+            "<h1>Hi</h1>\n<?php if ($x) { ?>\n  <p>a</p> <?= $y ?>\n<?php } ?>\n<footer>",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["<h1>Hi</h1>", "<?php", "if", "(", "{", "?>", "<footer>"]
+        );
+        match nodes[4] {
+            List { children, .. } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["?>", "<p>a</p>", "<?=", "$y", "?>", "<?php"]
+                );
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-zig")]
+    fn test_parse_zig_multiline_string() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "zig").unwrap();
+        let nodes = parse(
+            &arena,
+            "const s = @import(\"std\");\nconst t =\n    \\\\a \"b\"\n    \\\\c\n;",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "const",
+                "s",
+                "=",
+                "@import",
+                "(",
+                ";",
+                "const",
+                "t",
+                "=",
+                "\\\\a \"b\"\n    \\\\c",
+                ";"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-nix")]
+    fn test_parse_nix() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "nix").unwrap();
+        let nodes = parse(
+            &arena,
+            "let a = ''\n  x '''y ${b}\n''; in { c = ./d.nix; e = a // { }; }",
+            lang,
+        );
+
+        assert_eq!(top_level_content(&nodes), vec!["let", "{"]);
+        match nodes[0] {
+            List {
+                children,
+                close_content,
+                ..
+            } => {
+                assert_eq!(close_content, "in");
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["a", "=", "''\n  x '''y ${b}\n''", ";"]
+                );
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+        match nodes[1] {
+            List { children, .. } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["c", "=", "./d.nix", ";", "e", "=", "a", "//", "{", ";"]
+                );
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-hcl")]
+    fn test_parse_hcl_heredoc() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "tf").unwrap();
+        let nodes = parse(
+            &arena,
+            "resource \"a\" \"b\" {\n  p = <<-EOT\n    EOTX ${c}\n    EOT\n  n = 1\n}",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["resource", "\"a\"", "\"b\"", "{"]
+        );
+        match nodes[3] {
+            List { children, .. } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["p", "=", "<<-EOT\n    EOTX ${c}\n    EOT", "n", "=", "1"]
+                );
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "lang-dockerfile", feature = "lang-rust"))]
+    fn test_guess_language_by_filename() {
+        let lang = guess_language(ConfigDir::read_default_toml(), "foo/Dockerfile").unwrap();
+        assert_eq!(lang.name, "Dockerfile");
+
+        let lang = guess_language(ConfigDir::read_default_toml(), "foo/bar.rs").unwrap();
+        assert_eq!(lang.name, "Rust");
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "lang-emacs-lisp",
+        feature = "lang-javascript",
+        feature = "lang-json",
+        feature = "lang-python"
+    ))]
+    fn test_lang_by_name() {
+        let registry = ConfigDir::read_default_toml();
+        let name = |name| lang_by_name(registry, name).map(|lang| lang.name.as_str());
+        assert_eq!(name("JavaScript"), Some("JavaScript"));
+        assert_eq!(name("javascript"), Some("JavaScript"));
+        assert_eq!(name("node"), Some("JavaScript"));
+        assert_eq!(name("js"), Some("JavaScript"));
+        assert_eq!(name("emacs lisp"), Some("Emacs Lisp"));
+        assert_eq!(name("text/x-python; charset=utf-8"), Some("Python"));
+        assert_eq!(name("application/json"), Some("JSON"));
+        assert_eq!(name("nope"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_parse_significant_indentation() {
+        let arena = Arena::new();
+        let src = "if x:\n    y = 1\n";
+        let nodes = parse(&arena, src, lang());
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["if", "x", ":", "y", "=", "1"]
+        );
+
+        let nodes = parse(&arena, src, &lang().clone().with_significant_indentation());
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["if", "x", ":", "    y", "=", "1"]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_parse_ignoring_case() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, "SELECT Foo;\n", &lang().clone().ignoring_case());
+        assert_eq!(top_level_content(&nodes), vec!["select", "foo", ";"]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "lang-javascript", feature = "lang-rust"))]
+    fn test_parse_token_kinds() {
+        let token_kinds = |src, lang| -> Vec<TokenKind> {
+            let arena = Arena::new();
+            parse(&arena, src, lang)
+                .iter()
+                .filter_map(|node| match node {
+                    Atom { token_kind, .. } => Some(*token_kind),
+                    List { .. } => None,
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            token_kinds("x = 'a' + 1.5; // c\n", lang()),
+            vec![
+                TokenKind::Identifier,
+                TokenKind::Other,
+                TokenKind::String,
+                TokenKind::Other,
+                TokenKind::Number,
+                TokenKind::Other,
+                TokenKind::Comment,
+            ]
+        );
+        assert_eq!(
+            token_kinds("r#\"a\"# b\"c\"", rust_lang()),
+            vec![TokenKind::String, TokenKind::String]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_parse_structure_only() {
+        let lang = lang().clone().comparing_structure_only();
+        let arena = Arena::new();
+        let nodes = parse(&arena, "foo(1, [bar]); // baz\n", &lang);
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["<atom>", "(", "<atom>", "<atom>"]
+        );
+        match nodes[1] {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["<atom>", "<atom>", "["])
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*.bzl", "defs.bzl"));
+        assert!(glob_matches("Dockerfile.*", "Dockerfile.dev"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(glob_matches("?.py", "a.py"));
+        assert!(!glob_matches("*.bzl", "defs.bzl.bak"));
+        assert!(!glob_matches("?.py", "ab.py"));
+    }
+
+    #[test]
+    #[cfg(feature = "lang-dockerfile")]
+    fn test_guess_language_by_file_pattern() {
+        let lang = guess_language(ConfigDir::read_default_toml(), "foo/Dockerfile.dev").unwrap();
+        assert_eq!(lang.name, "Dockerfile");
+
+        let langs = read_syntax_toml(
+            Table::new(),
+            &[
+                &ConfigDir::default_toml(),
+                r#"
+[JSON]
+file_patterns = ["*.jsonl.in"]
+"#,
+            ],
+        );
+        let lang = guess_language(&langs, "data.jsonl.in").unwrap();
+        assert_eq!(lang.name, "JSON");
+    }
+
+    #[test]
+    #[cfg(feature = "lang-dockerfile")]
+    fn test_parse_dockerfile_instructions() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "dockerfile").unwrap();
+        let nodes = parse(
+            &arena,
+            "# comment\nFROM rust AS build\n\nRUN apt-get update \\\n    && echo a#b\nCMD [\"x\"]\n",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["# comment", "FROM", "RUN", "CMD"]
+        );
+        match nodes[2] {
+            List { children, .. } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["apt-get", "update", "&&", "echo", "a#b"]
+                );
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-makefile")]
+    fn test_parse_makefile_recipes() {
+        let arena = Arena::new();
+        let lang = guess_language(ConfigDir::read_default_toml(), "src/GNUmakefile").unwrap();
+        let nodes = parse(
+            &arena,
+            "CC := gcc\n\nall: main.o \\\n\tutil.o\n\t$(CC) -o $@ $^\n\n\t@echo done # ok\nclean:\n\trm -f *.o\n",
+            lang,
+        );
+
+        assert_eq!(top_level_content(&nodes), vec!["CC", "all", "clean"]);
+        match nodes[1] {
+            List {
+                children,
+                close_position,
+                ..
+            } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec![":", "main.o", "util.o", "", "@echo"]
+                );
+                assert_eq!(close_position[0].line.0, 6);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-protobuf")]
+    fn test_parse_protobuf() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "proto").unwrap();
+        let nodes = parse(
+            &arena,
+            "message A { map<string, .b.C> d = 1 [deprecated = true]; }",
+            lang,
+        );
+
+        assert_eq!(top_level_content(&nodes), vec!["message", "A", "{"]);
+        match nodes[2] {
+            List { children, .. } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["map", "<", "string", ",", ".b.C", ">", "d", "=", "1", "[", ";"]
+                );
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-graphql")]
+    fn test_parse_graphql() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "graphql").unwrap();
+        let nodes = parse(
+            &arena,
+            r#""""Doc "q" """ query Q($id: ID!) { user(id: $id) { ...F @skip(if: true) } }"#,
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![r#""""Doc "q" """"#, "query", "Q", "(", "{"]
+        );
+        match nodes[4] {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["user", "(", "{"]);
+                assert_eq!(top_level_content(&children[1..2]), vec!["("]);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+        match nodes[3] {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["$id", ":", "ID", "!"]);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-csv")]
+    fn test_parse_csv_rows() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "csv").unwrap();
+        let nodes = parse(&arena, "id,name\r\n\n7,\"a, \"\"b\"\"\nc\",\n", lang);
+
+        assert_eq!(top_level_content(&nodes), vec!["id", "7"]);
+        match nodes[1] {
+            List {
+                children,
+                close_position,
+                ..
+            } => {
+                assert_eq!(top_level_content(children), vec!["\"a, \"\"b\"\"\nc\"", ""]);
+                assert_eq!(close_position[0].line.0, 3);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    fn test_table_cells_empty_cells() {
+        assert_eq!(
+            table_cells(",a\t\n\t", '\t'),
+            vec![vec![(0, 2), (3, 3)], vec![(4, 4), (5, 5)]]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-shell")]
+    fn test_parse_shell() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "sh").unwrap();
+        let nodes = parse(
+            &arena,
+            "case $x in\n  a|b) echo \"$(ls ${d})\" #c\n    cat <<-'EOF' > f\n\tfi $(\n\tEOF\n    ;;\nesac",
+            lang,
+        );
+
+        assert_eq!(top_level_content(&nodes), vec!["case"]);
+        match nodes[0] {
+            List {
+                children,
+                close_content,
+                ..
+            } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec![
+                        "$x",
+                        "in",
+                        "a|b)",
+                        "echo",
+                        "\"$(ls ${d})\"",
+                        "#c",
+                        "cat",
+                        "<<-'EOF' > f\n\tfi $(\n\tEOF",
+                        ";;"
+                    ]
+                );
+                assert_eq!(close_content, "esac");
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-shell")]
+    fn test_parse_shell_substitutions() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "bash").unwrap();
+        let nodes = parse(&arena, "echo $(basename ${f#*/}) a#b", lang);
 
-enum LexKind {
-    Comment,
-    Atom,
-    OpenDelimiter,
-    CloseDelimiter,
-}
+        assert_eq!(top_level_content(&nodes), vec!["echo", "$(", "a#b"]);
+        match nodes[1] {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["basename", "${"]);
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
 
-fn parse_from<'a>(
-    arena: &'a Arena<Syntax<'a>>,
-    s: &str,
-    nl_pos: &NewlinePositions,
-    lang: &Language,
-    state: &mut ParseState,
-) -> Vec<&'a Syntax<'a>> {
-    let mut result: Vec<&'a Syntax<'a>> = vec![];
+    #[test]
+    #[cfg(feature = "lang-lua")]
+    fn test_parse_lua() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "lua").unwrap();
+        let nodes = parse(
+            &arena,
+            "--[==[ a ]] b ]==]\nlocal function f(t) for i = 1, #t do s = [[\n]=]] end end -- c",
+            lang,
+        );
 
-    while state.str_i < s.len() {
-        let mut current_match: Option<(LexKind, regex::Match)> = None;
-
-        for pattern in &lang.comment_patterns {
-            if let Some(m) = pattern.find(&s[state.str_i..]) {
-                match current_match {
-                    Some((_, prev_m)) if prev_m.start() <= m.start() => {}
-                    _ => {
-                        current_match = Some((LexKind::Comment, m));
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["--[==[ a ]] b ]==]", "local", "function", "-- c"]
+        );
+        match nodes[2] {
+            List {
+                children,
+                close_content,
+                ..
+            } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["f", "(", "for", "i", "=", "1", ",", "#", "t", "do"]
+                );
+                assert_eq!(close_content, "end");
+                match children[9] {
+                    List { children, .. } => {
+                        assert_eq!(top_level_content(children), vec!["s", "=", "[[\n]=]]"]);
                     }
+                    Atom { .. } => panic!("Expected a list"),
                 }
             }
+            Atom { .. } => panic!("Expected a list"),
         }
+    }
+
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_parse_unordered_json_objects() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let lhs = parse(&arena, "{\"b\": 1, \"a\": [2, 1]}", lang);
+        let rhs = parse(&arena, "{\"a\": [2, 1],\n \"b\": 1}", lang);
 
-        for pattern in &lang.atom_patterns {
-            if let Some(m) = pattern.find(&s[state.str_i..]) {
-                match current_match {
-                    Some((_, prev_m)) if prev_m.start() <= m.start() => {}
-                    _ => {
-                        current_match = Some((LexKind::Atom, m));
+        match lhs[0] {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["\"a\"", ",", "\"b\""]);
+                match children[0] {
+                    List { children, .. } => {
+                        assert_eq!(top_level_content(children), vec![":", "["]);
+                        assert_eq!(top_level_content(&children[1..]), vec!["["]);
                     }
+                    Atom { .. } => panic!("Expected a list"),
                 }
             }
+            Atom { .. } => panic!("Expected a list"),
         }
+        assert!(lhs[0].equal_content(rhs[0]));
+    }
 
-        if let Some(m) = lang.open_delimiter_pattern.find(&s[state.str_i..]) {
-            match current_match {
-                Some((_, prev_m)) if prev_m.start() <= m.start() => {}
-                _ => {
-                    current_match = Some((LexKind::OpenDelimiter, m));
+    #[test]
+    #[cfg(feature = "lang-toml")]
+    fn test_parse_toml_sections_and_keys() {
+        let arena = Arena::new();
+        let lang = guess_language(ConfigDir::read_default_toml(), "Cargo.toml").unwrap();
+        let nodes = parse(
+            &arena,
+            "title = \"x\"\n[dependencies]\nserde = { version = \"1\", features = [\"derive\"] }\n# c\n\n[[bin]]\nname = 'a'\n",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["title", "[dependencies]", "[[bin]]"]
+        );
+        match nodes[1] {
+            List {
+                children,
+                open_position,
+                ..
+            } => {
+                assert_eq!(open_position[0].end_col, 14);
+                assert_eq!(top_level_content(children), vec!["serde", "# c"]);
+                match children[0] {
+                    List { children, .. } => match children[1] {
+                        List { children, .. } => {
+                            assert_eq!(
+                                top_level_content(children),
+                                vec!["features", ",", "version"]
+                            );
+                        }
+                        Atom { .. } => panic!("Expected a list"),
+                    },
+                    Atom { .. } => panic!("Expected a list"),
                 }
             }
-        };
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
 
-        if let Some(m) = lang.close_delimiter_pattern.find(&s[state.str_i..]) {
-            match current_match {
-                Some((_, prev_m)) if prev_m.start() <= m.start() => {}
-                _ => {
-                    current_match = Some((LexKind::CloseDelimiter, m));
-                }
+    #[test]
+    #[cfg(feature = "lang-xml")]
+    fn test_parse_xml() {
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "xml").unwrap();
+        let nodes = parse(
+            &arena,
+            "<?xml version=\"1.0\"?>\n<a x=\"1\" y='2'><!-- c --><br/>hi there</a>",
+            lang,
+        );
+
+        assert_eq!(
+            top_level_content(&nodes),
+            vec![
+                "<?xml version=\"1.0\"?>",
+                "<",
+                "<!-- c -->",
+                "<",
+                "hi",
+                "there",
+                "<"
+            ]
+        );
+        match nodes[1] {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["a", "x", "y"]);
             }
-        };
+            Atom { .. } => panic!("Expected a list"),
+        }
+        match nodes[3] {
+            List {
+                children,
+                close_content,
+                ..
+            } => {
+                assert_eq!(top_level_content(children), vec!["br"]);
+                assert_eq!(close_content, "/>");
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
+    }
 
-        match current_match {
-            Some((match_kind, m)) => match match_kind {
-                LexKind::Comment => {
-                    let atom = Syntax::new_comment(
-                        arena,
-                        nl_pos.from_offsets(state.str_i + m.start(), state.str_i + m.end()),
-                        m.as_str(),
-                    );
-                    result.push(atom);
-                    state.str_i += m.end();
-                }
-                LexKind::Atom => {
-                    let atom = Syntax::new_atom(
-                        arena,
-                        nl_pos.from_offsets(state.str_i + m.start(), state.str_i + m.end()),
-                        m.as_str(),
-                    );
-                    result.push(atom);
-                    state.str_i += m.end();
-                }
-                LexKind::OpenDelimiter => {
-                    let start = state.str_i;
+    #[test]
+    fn test_parse_xml_unordered_attributes() {
+        let langs = read_syntax_toml(
+            Table::new(),
+            &[
+                &ConfigDir::default_toml(),
+                r#"
+[XML]
+unordered_lists = [["<", ">", ""], ["<", "/>", ""]]
+"#,
+            ],
+        );
+        let lang = find_lang(&langs, "xml").unwrap();
 
-                    state.str_i += m.end();
-                    let children = parse_from(arena, s, nl_pos, lang, state);
-                    let (close_brace, close_pos) = state.close_brace.take().unwrap_or((
-                        "UNCLOSED".into(),
-                        nl_pos.from_offsets(state.str_i, state.str_i + 1),
-                    ));
+        let arena = Arena::new();
+        let lhs = parse(&arena, "<a x=\"1\" y=\"2\"/>", lang);
+        let rhs = parse(&arena, "<a y=\"2\"\n   x=\"1\"/>", lang);
+        assert!(lhs[0].equal_content(rhs[0]));
 
-                    let open_pos = nl_pos.from_offsets(start + m.start(), start + m.end());
-                    let items = Syntax::new_list(
-                        arena,
-                        m.as_str(),
-                        open_pos,
-                        children,
-                        &close_brace,
-                        close_pos,
-                    );
-                    result.push(items);
-                }
-                LexKind::CloseDelimiter => {
-                    state.close_brace = Some((
-                        m.as_str().into(),
-                        nl_pos.from_offsets(state.str_i + m.start(), state.str_i + m.end()),
-                    ));
-                    state.str_i += m.end();
-                    return result;
-                }
-            },
-            None => break,
-        };
+        let rhs = parse(&arena, "<a y=\"1\" x=\"2\"/>", lang);
+        assert!(!lhs[0].equal_content(rhs[0]));
     }
 
-    result
-}
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_parse_deeply_nested() {
+        let arena = Arena::new();
+        let depth = 3_000;
+        let src = format!("{}1{}", "[".repeat(depth), "]".repeat(depth));
+        let nodes = parse(
+            &arena,
+            &src,
+            find_lang(ConfigDir::read_default_toml(), "json").unwrap(),
+        );
+        assert_eq!(nodes.len(), 1);
+    }
 
-#[derive(Debug, Clone)]
-struct ParseState {
-    str_i: usize,
-    close_brace: Option<(String, Vec<SingleLineSpan>)>,
-}
+    #[test]
+    fn test_parse_max_depth() {
+        let langs = read_syntax_toml(
+            Table::new(),
+            &[
+                &ConfigDir::default_toml(),
+                r#"
+[JSON]
+max_depth = 2
+"#,
+            ],
+        );
+        let lang = find_lang(&langs, "json").unwrap();
 
-impl ParseState {
-    fn new() -> Self {
-        ParseState {
-            str_i: 0,
-            close_brace: None,
+        let arena = Arena::new();
+        let nodes = parse(&arena, "[[[1]], 2]", lang);
+        let inner = match nodes[0] {
+            List { children, .. } => children[0],
+            Atom { .. } => panic!("Expected a list"),
+        };
+        match inner {
+            List { children, .. } => {
+                assert_eq!(top_level_content(children), vec!["[", "1", "]"]);
+            }
+            Atom { .. } => panic!("Expected a list"),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::cell::Cell;
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_parse_flatten_depth() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "json")
+            .unwrap()
+            .clone()
+            .with_flatten_depth(1);
 
-    use super::*;
-    use crate::syntax::Syntax::*;
+        let arena = Arena::new();
+        let nodes = parse(&arena, "[[1,\n  [2]], 3, [4]]", &lang);
+        match nodes[0] {
+            List { children, .. } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["[ 1 , [ 2 ] ]", ",", "3", ",", "[ 4 ]"]
+                );
+                match children[0] {
+                    Atom { position, .. } => {
+                        assert_eq!(position.len(), 2);
+                        assert_eq!(position[1].end_col, 6);
+                    }
+                    List { .. } => panic!("Expected an atom"),
+                }
+            }
+            Atom { .. } => panic!("Expected a list"),
+        }
 
-    fn lang() -> Language {
-        let syntax_toml = ConfigDir::read_default_toml();
-        find_lang(syntax_toml, "js").unwrap()
+        let nodes = parse(&arena, "[[1", &lang);
+        match nodes[0] {
+            List { children, .. } => assert_eq!(top_level_content(children), vec!["[ 1"]),
+            Atom { .. } => panic!("Expected a list"),
+        }
     }
 
-    fn assert_syntaxes<'a>(actual: &[&'a Syntax<'a>], expected: &[&'a Syntax<'a>]) {
-        if !syntaxes_match(actual, expected) {
-            dbg!(expected, actual);
-            assert!(false);
-        }
+    #[test]
+    #[cfg(feature = "lang-typescript")]
+    fn test_parse_normalizing_literals() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "ts").unwrap();
+        let src = "f(0x1_0, 1.50, 'a', 'b\"', x)";
+
+        let arena = Arena::new();
+        let nodes = parse(&arena, src, lang);
+        let children = match nodes[1] {
+            List { children, .. } => children,
+            Atom { .. } => panic!("Expected a list"),
+        };
+        assert_eq!(
+            top_level_content(children),
+            vec!["0x1_0", ",", "1.50", ",", "'a'", ",", "'b\"'", ",", "x"]
+        );
+
+        let nodes = parse(&arena, src, &lang.clone().normalizing_literals());
+        let children = match nodes[1] {
+            List { children, .. } => children,
+            Atom { .. } => panic!("Expected a list"),
+        };
+        assert_eq!(
+            top_level_content(children),
+            vec!["16", ",", "1.5", ",", "\"a\"", ",", "'b\"'", ",", "x"]
+        );
     }
 
-    fn syntaxes_match<'a>(actual: &[&'a Syntax<'a>], expected: &[&'a Syntax<'a>]) -> bool {
-        if actual.len() != expected.len() {
-            return false;
-        } else {
-            for (lhs_child, rhs_child) in actual.iter().zip(expected.iter()) {
-                if !syntax_matches(lhs_child, rhs_child) {
-                    return false;
-                }
-            }
-        }
-        true
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_parse_ignoring() {
+        let lang = lang()
+            .clone()
+            .ignoring(vec![Regex::new("[0-9]{4}-[0-9]{2}-[0-9]{2}").unwrap()]);
+
+        let arena = Arena::new();
+        let nodes = parse(&arena, "\"built 2024-01-02\" 2024", &lang);
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["\"built <ignored>\"", "2024"]
+        );
     }
 
-    /// Compare all the fields in a Syntax value, not just
-    /// those used in its Eq implementation.
-    fn syntax_matches<'a>(actual: &'a Syntax<'a>, expected: &'a Syntax<'a>) -> bool {
-        match (actual, expected) {
-            (
-                List {
-                    info: lhs_info,
-                    open_position: lhs_open_position,
-                    open_content: lhs_start_content,
-                    children: lhs_children,
-                    close_content: lhs_end_content,
-                    close_position: lhs_close_position,
-                    num_descendants: lhs_num_descendants,
-                    ..
-                },
-                List {
-                    info: rhs_info,
-                    open_position: rhs_open_position,
-                    open_content: rhs_start_content,
-                    children: rhs_children,
-                    close_content: rhs_end_content,
-                    close_position: rhs_close_position,
-                    num_descendants: rhs_num_descendants,
-                    ..
-                },
-            ) => {
-                if lhs_info.change.get() != rhs_info.change.get() {
-                    dbg!(lhs_info.change.get(), rhs_info.change.get());
-                    return false;
-                }
-                if lhs_open_position != rhs_open_position {
-                    dbg!(lhs_open_position, rhs_open_position);
-                    return false;
-                }
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_sort_entries_without_separator() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, "c b a", lang());
+        assert_eq!(
+            top_level_content(&sort_entries(nodes, "")),
+            vec!["a", "b", "c"]
+        );
+    }
 
-                if lhs_start_content != rhs_start_content {
-                    dbg!(lhs_start_content, rhs_start_content);
-                    return false;
-                }
-                if lhs_end_content != rhs_end_content {
-                    dbg!(lhs_end_content, rhs_end_content);
-                    return false;
-                }
-                if lhs_close_position != rhs_close_position {
-                    dbg!(lhs_close_position, rhs_close_position);
-                    return false;
-                }
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_parse_rust_lifetimes_and_chars() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, r"&'a str '\'' 'b'", rust_lang());
 
-                if lhs_num_descendants != rhs_num_descendants {
-                    dbg!(lhs_num_descendants, rhs_num_descendants);
-                    return false;
-                }
+        assert_eq!(
+            top_level_content(&nodes),
+            vec!["&", "'a", "str", r"'\''", "'b'"]
+        );
+    }
 
-                if !syntaxes_match(lhs_children, rhs_children) {
-                    return false;
-                }
-            }
-            (
-                Atom {
-                    info: lhs_info,
-                    position: lhs_position,
-                    content: lhs_content,
-                    is_comment: lhs_is_comment,
-                    ..
-                },
-                Atom {
-                    info: rhs_info,
-                    position: rhs_position,
-                    content: rhs_content,
-                    is_comment: rhs_is_comment,
-                    ..
-                },
-            ) => {
-                if lhs_info.change.get() != rhs_info.change.get() {
-                    dbg!(lhs_info.change.get(), rhs_info.change.get());
-                    return false;
-                }
-                if lhs_position != rhs_position {
-                    dbg!(lhs_position, rhs_position);
-                    return false;
-                }
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_parse_rust_attribute() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, "#[derive(Debug)]", rust_lang());
 
-                if lhs_content != rhs_content {
-                    dbg!(lhs_content, rhs_content);
-                    return false;
-                }
-                if lhs_is_comment != rhs_is_comment {
-                    dbg!(lhs_is_comment, rhs_is_comment);
-                    return false;
-                }
-            }
-            _ => {
-                return false;
-            }
-        }
-        true
+        assert_eq!(top_level_content(&nodes), vec!["#["]);
     }
 
     #[test]
@@ -402,7 +4187,7 @@ mod tests {
             &[
                 Syntax::new_atom(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 0.into(),
                         start_col: 0,
                         end_col: 3,
@@ -411,7 +4196,7 @@ mod tests {
                 ),
                 Syntax::new_atom(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 1.into(),
                         start_col: 0,
                         end_col: 3,
@@ -423,14 +4208,15 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_integer() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "123", &lang()),
+            &parse(&arena, "123", lang()),
             &[Syntax::new_atom(
                 &arena,
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 3,
@@ -441,14 +4227,15 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_empty_string() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "\"\"", &lang()),
+            &parse(&arena, "\"\"", lang()),
             &[Syntax::new_atom(
                 &arena,
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 2,
@@ -459,15 +4246,16 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_string_escaped_doublequote() {
         let arena = Arena::new();
 
         assert_syntaxes(
             // "\""
-            &parse(&arena, "\"\\\"\"", &lang()),
+            &parse(&arena, "\"\\\"\"", lang()),
             &[Syntax::new_atom(
                 &arena,
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 4,
@@ -478,15 +4266,16 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_string_escaped_backlash() {
         let arena = Arena::new();
 
         assert_syntaxes(
             // "\\"
-            &parse(&arena, "\"\\\\\"", &lang()),
+            &parse(&arena, "\"\\\\\"", lang()),
             &[Syntax::new_atom(
                 &arena,
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 4,
@@ -497,14 +4286,15 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_multiline_string() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "\"\n\"", &lang()),
+            &parse(&arena, "\"\n\"", lang()),
             &[Syntax::new_atom(
                 &arena,
-                vec![
+                smallvec![
                     SingleLineSpan {
                         line: 0.into(),
                         start_col: 0,
@@ -522,16 +4312,17 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_string_escaped_backlash_and_second_string() {
         let arena = Arena::new();
 
         assert_syntaxes(
             // "\\" "a"
-            &parse(&arena, "\"\\\\\" \"a\"", &lang()),
+            &parse(&arena, "\"\\\\\" \"a\"", lang()),
             &[
                 Syntax::new_atom(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 0.into(),
                         start_col: 0,
                         end_col: 4,
@@ -540,7 +4331,7 @@ mod tests {
                 ),
                 Syntax::new_atom(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 0.into(),
                         start_col: 5,
                         end_col: 8,
@@ -552,15 +4343,16 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_multiple() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "123 456", &lang()),
+            &parse(&arena, "123 456", lang()),
             &[
                 Syntax::new_atom(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 0.into(),
                         start_col: 0,
                         end_col: 3,
@@ -569,7 +4361,7 @@ mod tests {
                 ),
                 Syntax::new_atom(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 0.into(),
                         start_col: 4,
                         end_col: 7,
@@ -581,14 +4373,15 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_symbol() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, ".foo", &lang()),
+            &parse(&arena, ".foo", lang()),
             &[Syntax::new_atom(
                 &arena,
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 4,
@@ -599,14 +4392,15 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_integer_with_whitespace() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, " 123 ", &lang()),
+            &parse(&arena, " 123 ", lang()),
             &[Syntax::new_atom(
                 &arena,
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 1,
                     end_col: 4,
@@ -617,14 +4411,15 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_string() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "\"abc\"", &lang()),
+            &parse(&arena, "\"abc\"", lang()),
             &[Syntax::new_atom(
                 &arena,
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 5,
@@ -635,15 +4430,16 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_comment() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "// foo\nx", &lang()),
+            &parse(&arena, "// foo\nx", lang()),
             &[
                 Syntax::new_comment(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 0.into(),
                         start_col: 0,
                         end_col: 6,
@@ -652,7 +4448,7 @@ mod tests {
                 ),
                 Syntax::new_atom(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 1.into(),
                         start_col: 0,
                         end_col: 1,
@@ -664,14 +4460,15 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_multiline_comment() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "/* foo\nbar */", &lang()),
+            &parse(&arena, "/* foo\nbar */", lang()),
             &[Syntax::new_comment(
                 &arena,
-                vec![
+                smallvec![
                     SingleLineSpan {
                         line: 0.into(),
                         start_col: 0,
@@ -689,18 +4486,20 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_indented_multiline_comment() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "  /* foo\n  bar */", &lang()),
+            &parse(&arena, "  /* foo\n  bar */", lang()),
             // Deliberately construct an Atom directly, because
             // Syntax::new_comment has the multiline logic.
             &[&Atom {
                 info: crate::syntax::SyntaxInfo::new(0),
                 content: "/* foo\nbar */".into(),
                 is_comment: true,
-                position: vec![
+                token_kind: TokenKind::Comment,
+                position: smallvec![
                     SingleLineSpan {
                         line: 0.into(),
                         start_col: 2,
@@ -717,22 +4516,23 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_list() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "[ 123 ]", &lang()),
+            &parse(&arena, "[ 123 ]", lang()),
             &[Syntax::new_list(
                 &arena,
                 "[",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 1,
                 }],
                 vec![Syntax::new_atom(
                     &arena,
-                    vec![SingleLineSpan {
+                    smallvec![SingleLineSpan {
                         line: 0.into(),
                         start_col: 2,
                         end_col: 5,
@@ -740,7 +4540,7 @@ mod tests {
                     "123",
                 )],
                 "]",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 6,
                     end_col: 7,
@@ -749,22 +4549,23 @@ mod tests {
         );
     }
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_empty_list() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "[]", &lang()),
+            &parse(&arena, "[]", lang()),
             &[Syntax::new_list(
                 &arena,
                 "[",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 1,
                 }],
                 vec![],
                 "]",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 1,
                     end_col: 2,
@@ -774,22 +4575,23 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_parens() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "()", &lang()),
+            &parse(&arena, "()", lang()),
             &[Syntax::new_list(
                 &arena,
                 "(",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 1,
                 }],
                 vec![],
                 ")",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 1,
                     end_col: 2,
@@ -799,15 +4601,16 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_list_with_commas() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "[123, 456]", &lang()),
+            &parse(&arena, "[123, 456]", lang()),
             &[Syntax::new_list(
                 &arena,
                 "[",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 1,
@@ -815,7 +4618,7 @@ mod tests {
                 vec![
                     Syntax::new_atom(
                         &arena,
-                        vec![SingleLineSpan {
+                        smallvec![SingleLineSpan {
                             line: 0.into(),
                             start_col: 1,
                             end_col: 4,
@@ -824,7 +4627,7 @@ mod tests {
                     ),
                     Syntax::new_atom(
                         &arena,
-                        vec![SingleLineSpan {
+                        smallvec![SingleLineSpan {
                             line: 0.into(),
                             start_col: 4,
                             end_col: 5,
@@ -833,7 +4636,7 @@ mod tests {
                     ),
                     Syntax::new_atom(
                         &arena,
-                        vec![SingleLineSpan {
+                        smallvec![SingleLineSpan {
                             line: 0.into(),
                             start_col: 6,
                             end_col: 9,
@@ -842,7 +4645,7 @@ mod tests {
                     ),
                 ],
                 "]",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 9,
                     end_col: 10,
@@ -852,15 +4655,16 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "lang-javascript")]
     fn test_parse_object() {
         let arena = Arena::new();
 
         assert_syntaxes(
-            &parse(&arena, "{x: 1}", &lang()),
+            &parse(&arena, "{x: 1}", lang()),
             &[Syntax::new_list(
                 &arena,
                 "{",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 0,
                     end_col: 1,
@@ -868,7 +4672,7 @@ mod tests {
                 vec![
                     Syntax::new_atom(
                         &arena,
-                        vec![SingleLineSpan {
+                        smallvec![SingleLineSpan {
                             line: 0.into(),
                             start_col: 1,
                             end_col: 2,
@@ -877,7 +4681,7 @@ mod tests {
                     ),
                     Syntax::new_atom(
                         &arena,
-                        vec![SingleLineSpan {
+                        smallvec![SingleLineSpan {
                             line: 0.into(),
                             start_col: 2,
                             end_col: 3,
@@ -886,7 +4690,7 @@ mod tests {
                     ),
                     Syntax::new_atom(
                         &arena,
-                        vec![SingleLineSpan {
+                        smallvec![SingleLineSpan {
                             line: 0.into(),
                             start_col: 4,
                             end_col: 5,
@@ -895,7 +4699,7 @@ mod tests {
                     ),
                 ],
                 "}",
-                vec![SingleLineSpan {
+                smallvec![SingleLineSpan {
                     line: 0.into(),
                     start_col: 5,
                     end_col: 6,
@@ -903,4 +4707,66 @@ mod tests {
             )],
         );
     }
+
+    #[test]
+    #[cfg(feature = "lang-javascript")]
+    fn test_parse_unmatched_close_delimiter() {
+        let arena = Arena::new();
+        let nodes = parse(&arena, "a }\n(b)", lang());
+        assert_eq!(top_level_content(&nodes), vec!["a", "}", "("]);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_parse_pair_numeric_tolerance() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "json")
+            .unwrap()
+            .clone()
+            .with_numeric_tolerance(NumericTolerance {
+                absolute: 0.01,
+                relative: 0.0,
+            });
+        let arena = Arena::new();
+        let (lhs, rhs) = parse_pair(&arena, "[1.0, 2.0, 3.0]", "[0.5, 1.001, 2.0, 3.5]", &lang);
+        assert_eq!(lhs.len(), 1);
+        match rhs[0] {
+            Syntax::List { children, .. } => {
+                assert_eq!(
+                    top_level_content(children),
+                    vec!["0.5", ",", "1.0", ",", "2.0", ",", "3.5"]
+                );
+                // The replaced number is still highlighted as a number.
+                assert!(matches!(
+                    children[2],
+                    Atom {
+                        token_kind: TokenKind::Number,
+                        ..
+                    }
+                ));
+            }
+            Syntax::Atom { .. } => panic!("Expected a list"),
+        }
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "lang-csv",
+        feature = "lang-javascript",
+        feature = "lang-makefile"
+    ))]
+    fn test_parse_pair_same_as_parse() {
+        let registry = ConfigDir::read_default_toml();
+        let inputs = [
+            ("js", "foo(/x/, [1, 2]);\n", "/* a */ bar({a: 1})\n"),
+            ("mk", "all: foo \\\n  bar\n\tcc -o $@\n", "all:\n\tcc\n"),
+            ("csv", "a,b\n1,2\n", "a,b\n1,3\n"),
+        ];
+        for (extension, lhs_src, rhs_src) in inputs.iter() {
+            let lang = find_lang(registry, extension).unwrap();
+            let arena = Arena::new();
+            let (lhs, rhs) = parse_pair(&arena, lhs_src, rhs_src, lang);
+            assert_syntaxes(&lhs, &parse(&arena, lhs_src, lang));
+            assert_syntaxes(&rhs, &parse(&arena, rhs_src, lang));
+        }
+    }
 }
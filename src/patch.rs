@@ -0,0 +1,196 @@
+//! Reading unified diffs, such as the output of `git diff`, so they
+//! can be shown structurally without a checkout.
+
+/// The contents of a file before and after a patch, as far as the
+/// patch shows them. Lines outside the hunks are unknown, so they're
+/// left blank. This keeps the line numbers the same as in the real
+/// file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PatchedFile {
+    pub path: String,
+    pub lhs: String,
+    pub rhs: String,
+}
+
+/// Parse the start line and number of lines of each side from a hunk
+/// header such as `@@ -10,7 +10,8 @@ fn foo() {`.
+fn parse_hunk_header(line: &str) -> Option<((usize, usize), (usize, usize))> {
+    let mut parts = line.strip_prefix("@@ ")?.split(' ');
+    let parse_range = |part: Option<&str>, sign: char| -> Option<(usize, usize)> {
+        let mut range = part?.strip_prefix(sign)?.split(',');
+        let start = range.next()?.parse().ok()?;
+        let len = match range.next() {
+            Some(len) => len.parse().ok()?,
+            None => 1,
+        };
+        Some((start, len))
+    };
+    let lhs_range = parse_range(parts.next(), '-')?;
+    let rhs_range = parse_range(parts.next(), '+')?;
+    Some((lhs_range, rhs_range))
+}
+
+/// The path in a `---` or `+++` line, without git's `a/` or `b/`
+/// prefix. Returns `None` for `/dev/null`.
+fn parse_path(path: &str) -> Option<String> {
+    // Git adds a tab after paths that contain spaces.
+    let path = path.trim_end_matches('\t');
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    Some(path.to_string())
+}
+
+#[derive(Default)]
+struct FileInProgress {
+    lhs_path: Option<String>,
+    rhs_path: Option<String>,
+    lhs_lines: Vec<String>,
+    rhs_lines: Vec<String>,
+    has_hunks: bool,
+}
+
+impl FileInProgress {
+    fn finish(self) -> Option<PatchedFile> {
+        if !self.has_hunks {
+            return None;
+        }
+        let join = |lines: Vec<String>| {
+            let mut s = lines.join("\n");
+            if !s.is_empty() {
+                s.push('\n');
+            }
+            s
+        };
+        Some(PatchedFile {
+            path: self.rhs_path.or(self.lhs_path)?,
+            lhs: join(self.lhs_lines),
+            rhs: join(self.rhs_lines),
+        })
+    }
+}
+
+/// Every file changed in `patch` that has at least one hunk. Binary
+/// files, and files that were only renamed, are skipped.
+pub fn parse_patch(patch: &str) -> Vec<PatchedFile> {
+    let mut files = vec![];
+    let mut current = FileInProgress::default();
+    // The number of lines left in the current hunk on each side.
+    let mut lhs_remaining: usize = 0;
+    let mut rhs_remaining: usize = 0;
+
+    for line in patch.lines() {
+        if lhs_remaining > 0 || rhs_remaining > 0 {
+            match line.chars().next() {
+                Some('-') => {
+                    current.lhs_lines.push(line[1..].to_string());
+                    lhs_remaining = lhs_remaining.saturating_sub(1);
+                }
+                Some('+') => {
+                    current.rhs_lines.push(line[1..].to_string());
+                    rhs_remaining = rhs_remaining.saturating_sub(1);
+                }
+                // `\ No newline at end of file`
+                Some('\\') => {}
+                // Context lines start with a space, but some tools
+                // strip trailing whitespace from empty lines.
+                _ => {
+                    let context = line.strip_prefix(' ').unwrap_or(line);
+                    current.lhs_lines.push(context.to_string());
+                    current.rhs_lines.push(context.to_string());
+                    lhs_remaining = lhs_remaining.saturating_sub(1);
+                    rhs_remaining = rhs_remaining.saturating_sub(1);
+                }
+            }
+            continue;
+        }
+
+        if let Some(((lhs_start, lhs_len), (rhs_start, rhs_len))) = parse_hunk_header(line) {
+            // Leave a blank line for every line the patch doesn't
+            // show. A start of 0 means the file is empty.
+            let lhs_start = lhs_start.saturating_sub(1);
+            let rhs_start = rhs_start.saturating_sub(1);
+            if current.lhs_lines.len() < lhs_start {
+                current.lhs_lines.resize(lhs_start, String::new());
+            }
+            if current.rhs_lines.len() < rhs_start {
+                current.rhs_lines.resize(rhs_start, String::new());
+            }
+            current.has_hunks = true;
+            lhs_remaining = lhs_len;
+            rhs_remaining = rhs_len;
+        } else if line.starts_with("diff ") {
+            files.extend(std::mem::take(&mut current).finish());
+        } else if let Some(path) = line.strip_prefix("--- ") {
+            if current.has_hunks {
+                // A patch without `diff` lines between files.
+                files.extend(std::mem::take(&mut current).finish());
+            }
+            current.lhs_path = parse_path(path);
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            current.rhs_path = parse_path(path);
+        }
+    }
+    files.extend(current.finish());
+
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hunk_header() {
+        assert_eq!(
+            parse_hunk_header("@@ -10,7 +12,8 @@ fn foo() {"),
+            Some(((10, 7), (12, 8)))
+        );
+        assert_eq!(parse_hunk_header("@@ -1 +1 @@"), Some(((1, 1), (1, 1))));
+        assert_eq!(parse_hunk_header("@@ -0,0 +1,2 @@"), Some(((0, 0), (1, 2))));
+        assert_eq!(parse_hunk_header("@@@ -1 -1 +1 @@@"), None);
+    }
+
+    #[test]
+    fn test_parse_patch() {
+        let patch = "\
+diff --git a/foo.rs b/foo.rs
+index 1234567..89abcde 100644
+--- a/foo.rs
++++ b/foo.rs
+@@ -2,2 +2,2 @@
+ fn foo() {
+-    1
++    2
+@@ -6 +6,2 @@
+ }
++// end
+diff --git a/new.json b/new.json
+new file mode 100644
+--- /dev/null
++++ b/new.json
+@@ -0,0 +1 @@
++[]
+\\ No newline at end of file
+";
+        assert_eq!(
+            parse_patch(patch),
+            vec![
+                PatchedFile {
+                    path: "foo.rs".into(),
+                    lhs: "\nfn foo() {\n    1\n\n\n}\n".into(),
+                    rhs: "\nfn foo() {\n    2\n\n\n}\n// end\n".into(),
+                },
+                PatchedFile {
+                    path: "new.json".into(),
+                    lhs: "".into(),
+                    rhs: "[]\n".into(),
+                },
+            ]
+        );
+    }
+}
@@ -1,10 +1,32 @@
 use crate::lines::LineNumber;
+use serde::Serialize;
+use smallvec::SmallVec;
 
 /// A range within a single line of a string.
-#[derive(Debug, PartialEq, Clone, Copy, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, PartialEq, Clone, Copy, Eq, PartialOrd, Ord, Hash, Serialize)]
 pub struct SingleLineSpan {
     /// All zero-indexed.
     pub line: LineNumber,
     pub start_col: usize,
     pub end_col: usize,
 }
+
+/// A range of bytes in the original string. Unlike a
+/// `SingleLineSpan`, this can be used to slice the string directly.
+#[derive(Debug, PartialEq, Clone, Copy, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct ByteSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ByteSpan {
+    /// The text of this span in `src`, which must be the string it
+    /// was computed from.
+    pub fn text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.start..self.end]
+    }
+}
+
+/// The spans of a syntax node. Most nodes are on a single line, so
+/// one span is stored without allocating.
+pub type Spans = SmallVec<[SingleLineSpan; 1]>;
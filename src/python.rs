@@ -0,0 +1,154 @@
+//! Python bindings, built with the `pyo3` feature, so notebooks and
+//! review bots can diff in-process:
+//!
+//! ```python
+//! import difftastic
+//! result = difftastic.diff("[1, 2]", "[1, 3]", "json")
+//! result.rhs_changes[0].position[0].start_col  # 4
+//! ```
+//!
+//! Languages are given as a file name, name or extension, e.g.
+//! `foo.rs`, `rust` or `rs`.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use typed_arena::Arena;
+
+use crate::parse::{guess_language, lang_by_name, ConfigDir, Language};
+use crate::positions::{ByteSpan, SingleLineSpan};
+use crate::serialize::SerializedSyntax;
+use crate::syntax::init_info;
+use crate::{diff_strings, ChangeRegion, ChangeRegionKind};
+
+fn find_language(name: &str) -> PyResult<&'static Language> {
+    let registry = ConfigDir::read_default_toml();
+    guess_language(registry, name)
+        .or_else(|| lang_by_name(registry, name))
+        .ok_or_else(|| PyValueError::new_err(format!("Unknown language: {}", name)))
+}
+
+/// A range within a single line. Lines and columns count from zero,
+/// and columns are byte offsets. `start_byte` and `end_byte` are
+/// offsets into the whole string.
+#[pyclass(name = "Span", frozen, get_all)]
+#[derive(Clone)]
+struct PySpan {
+    line: usize,
+    start_col: usize,
+    end_col: usize,
+    start_byte: usize,
+    end_byte: usize,
+}
+
+#[pymethods]
+impl PySpan {
+    fn __repr__(&self) -> String {
+        format!(
+            "Span(line={}, start_col={}, end_col={})",
+            self.line, self.start_col, self.end_col
+        )
+    }
+}
+
+impl PySpan {
+    fn new(span: &SingleLineSpan, bytes: &ByteSpan) -> Self {
+        PySpan {
+            line: span.line.0,
+            start_col: span.start_col,
+            end_col: span.end_col,
+            start_byte: bytes.start,
+            end_byte: bytes.end,
+        }
+    }
+}
+
+/// A changed token or comment word. `kind` is `"novel"`, `"moved"`
+/// or `"changed_comment"`.
+#[pyclass(name = "ChangeRegion", frozen, get_all)]
+#[derive(Clone)]
+struct PyChangeRegion {
+    kind: &'static str,
+    position: Vec<PySpan>,
+}
+
+#[pymethods]
+impl PyChangeRegion {
+    fn __repr__(&self) -> String {
+        let position: Vec<_> = self.position.iter().map(|span| span.__repr__()).collect();
+        format!(
+            "ChangeRegion(kind={:?}, position=[{}])",
+            self.kind,
+            position.join(", ")
+        )
+    }
+}
+
+impl From<&ChangeRegion> for PyChangeRegion {
+    fn from(region: &ChangeRegion) -> Self {
+        PyChangeRegion {
+            kind: match region.kind {
+                ChangeRegionKind::Novel => "novel",
+                ChangeRegionKind::Moved => "moved",
+                ChangeRegionKind::ChangedComment => "changed_comment",
+            },
+            position: region
+                .position
+                .iter()
+                .zip(&region.bytes)
+                .map(|(span, bytes)| PySpan::new(span, bytes))
+                .collect(),
+        }
+    }
+}
+
+/// The changes between two strings, as returned by `diff`.
+#[pyclass(name = "DiffResult", frozen, get_all)]
+struct PyDiffResult {
+    is_syntactic: bool,
+    lhs_changes: Vec<PyChangeRegion>,
+    rhs_changes: Vec<PyChangeRegion>,
+}
+
+/// Diff `lhs` and `rhs` as `language`.
+#[pyfunction(name = "diff")]
+fn diff_py(lhs: &str, rhs: &str, language: &str) -> PyResult<PyDiffResult> {
+    let lang = find_language(language)?;
+    let result = diff_strings(lhs, rhs, lang);
+    Ok(PyDiffResult {
+        is_syntactic: result.is_syntactic,
+        lhs_changes: result
+            .lhs_changes
+            .iter()
+            .map(PyChangeRegion::from)
+            .collect(),
+        rhs_changes: result
+            .rhs_changes
+            .iter()
+            .map(PyChangeRegion::from)
+            .collect(),
+    })
+}
+
+/// Parse `src` as `language`, returning the syntax tree as lists and
+/// dicts in the same format as `--dump-syntax`.
+#[pyfunction(name = "parse")]
+fn parse_py(py: Python<'_>, src: &str, language: &str) -> PyResult<PyObject> {
+    let lang = find_language(language)?;
+    let arena = Arena::new();
+    let nodes = crate::parse::parse(&arena, src, lang);
+    init_info(&nodes);
+
+    let nodes: Vec<_> = nodes.iter().map(|n| SerializedSyntax::from(*n)).collect();
+    let json = serde_json::to_string(&nodes).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+#[pymodule]
+fn difftastic(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(diff_py, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_py, m)?)?;
+    m.add_class::<PySpan>()?;
+    m.add_class::<PyChangeRegion>()?;
+    m.add_class::<PyDiffResult>()?;
+    Ok(())
+}
@@ -0,0 +1,375 @@
+//! HTML pages showing the changes to many files, with an index page
+//! that summarises them. These are written to a directory for
+//! attaching to CI runs, or served by `difftastic serve`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use typed_arena::Arena;
+
+use crate::diff_trees;
+use crate::lines::{join_scopes, pad_scopes, visible_groups, LineNumber, MaxLine};
+use crate::parse::{guess_language, LanguageRegistry};
+use crate::patch::PatchedFile;
+use crate::summary::{definitions, group_heading, summarize, top_level_scopes, Summary};
+use crate::syntax::{change_positions, matching_lines, MatchKind, MatchedPos};
+
+const STYLE: &str = "
+body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+th, td { padding: 0 0.5em; text-align: left; }
+.diff td { font-family: monospace; white-space: pre; vertical-align: top; }
+.diff .line-num { color: #888; text-align: right; }
+details { margin-bottom: 0.5em; }
+summary { cursor: pointer; font-weight: bold; }
+.removed { background: #fdd; color: #a00; }
+.added { background: #dfd; color: #070; }
+.moved { background: #eef; }
+";
+
+/// The result of diffing one file, for the index page.
+struct FileReport {
+    path: String,
+    lang_name: String,
+    summary: Summary,
+    lhs_changed_lines: usize,
+    rhs_changed_lines: usize,
+}
+
+fn escape_html(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+/// The CSS class for each changed region on each line.
+fn changed_spans(
+    positions: &[MatchedPos],
+    novel_class: &'static str,
+) -> HashMap<LineNumber, Vec<(usize, usize, &'static str)>> {
+    let mut res: HashMap<_, Vec<_>> = HashMap::new();
+    for mp in positions {
+        let class = match mp.kind {
+            MatchKind::Novel | MatchKind::ChangedCommentPart => novel_class,
+            MatchKind::Moved => "moved",
+            MatchKind::Unchanged | MatchKind::UnchangedCommentPart => continue,
+        };
+        for span in &mp.pos {
+            res.entry(span.line)
+                .or_default()
+                .push((span.start_col, span.end_col, class));
+        }
+    }
+    for spans in res.values_mut() {
+        spans.sort_unstable();
+    }
+    res
+}
+
+/// Escape `line`, wrapping each of `spans` in a `<span>` with its
+/// class. `spans` must be sorted.
+fn highlight_line(line: &str, spans: &[(usize, usize, &str)]) -> String {
+    let mut res = String::new();
+    let mut i = 0;
+    for (start, end, class) in spans {
+        let start = (*start).clamp(i, line.len());
+        let end = (*end).clamp(start, line.len());
+        if !line.is_char_boundary(start) || !line.is_char_boundary(end) {
+            continue;
+        }
+        res.push_str(&escape_html(&line[i..start]));
+        res.push_str(&format!(
+            "<span class=\"{}\">{}</span>",
+            class,
+            escape_html(&line[start..end])
+        ));
+        i = end;
+    }
+    res.push_str(&escape_html(&line[i..]));
+    res
+}
+
+/// Buttons that open or close every hunk on a page.
+const TOGGLE_ALL: &str = "<p><button onclick=\"toggleAll(true)\">Expand all</button> <button onclick=\"toggleAll(false)\">Collapse all</button></p>
+<script>
+function toggleAll(open) {
+  document.querySelectorAll('details').forEach(function (d) { d.open = open; });
+}
+</script>
+";
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape_html(title),
+        STYLE,
+        body
+    )
+}
+
+/// Diff `file`, returning its statistics and an HTML page showing the
+/// changes side-by-side. Each hunk can be collapsed.
+fn report_file(file: &PatchedFile, registry: &LanguageRegistry) -> (FileReport, String) {
+    let lang = guess_language(registry, &file.path);
+    let arena = Arena::new();
+    let (lhs, rhs, is_syntactic, _) = diff_trees(&arena, &file.lhs, &file.rhs, lang);
+    let lang_name = match lang {
+        Some(lang) if is_syntactic => lang.name.clone(),
+        Some(lang) => format!("{}, as lines", lang.name),
+        None => "plain text".to_string(),
+    };
+
+    let lhs_positions = change_positions(&file.lhs, &file.rhs, &lhs);
+    let rhs_positions = change_positions(&file.rhs, &file.lhs, &rhs);
+    let lhs_spans = changed_spans(&lhs_positions, "removed");
+    let rhs_spans = changed_spans(&rhs_positions, "added");
+
+    let lhs_definitions = definitions(&lhs);
+    let rhs_definitions = definitions(&rhs);
+    let lhs_scopes = top_level_scopes(&lhs_definitions);
+    let rhs_scopes = top_level_scopes(&rhs_definitions);
+    let groups = join_scopes(
+        visible_groups(&lhs_positions, &rhs_positions),
+        &lhs_positions,
+        &rhs_positions,
+        &lhs_scopes,
+        &rhs_scopes,
+    );
+    let groups = pad_scopes(
+        groups,
+        file.lhs.max_line(),
+        file.rhs.max_line(),
+        &lhs_positions,
+        &rhs_positions,
+        &lhs_scopes,
+        &rhs_scopes,
+    );
+
+    let headings: Vec<_> = groups
+        .iter()
+        .map(|group| {
+            group_heading(
+                group,
+                &lhs_positions,
+                &rhs_positions,
+                &lhs_definitions,
+                &rhs_definitions,
+            )
+        })
+        .collect();
+
+    let summary = summarize(&lhs, &rhs);
+    let mut body = format!(
+        "<p><a href=\"index.html\">All files</a></p>\n<h1>{} ({})</h1>\n<p>{}</p>\n",
+        escape_html(&file.path),
+        escape_html(&lang_name),
+        summary
+    );
+
+    let lhs_lines: Vec<_> = file.lhs.lines().collect();
+    let rhs_lines: Vec<_> = file.rhs.lines().collect();
+    let lhs_line_matches = matching_lines(&lhs);
+    let cells = |line_num: Option<LineNumber>,
+                 lines: &[&str],
+                 spans: &HashMap<LineNumber, Vec<(usize, usize, &'static str)>>| {
+        match line_num {
+            Some(line_num) => format!(
+                "<td class=\"line-num\">{}</td><td>{}</td>",
+                line_num.0 + 1,
+                highlight_line(
+                    lines.get(line_num.0).unwrap_or(&""),
+                    spans.get(&line_num).map_or(&[][..], |spans| &spans[..])
+                )
+            ),
+            None => "<td class=\"line-num\"></td><td></td>".to_string(),
+        }
+    };
+
+    if !groups.is_empty() {
+        body.push_str(TOGGLE_ALL);
+    }
+    for (group, heading) in groups.iter().zip(&headings) {
+        let rows = group.aligned_lines(&lhs_line_matches);
+        let summary = match heading {
+            Some(heading) => escape_html(heading),
+            None => line_range_summary(&rows),
+        };
+        body.push_str(&format!(
+            "<details open>\n<summary>{}</summary>\n<table class=\"diff\">\n",
+            summary
+        ));
+        for (lhs_line_num, rhs_line_num) in rows {
+            body.push_str("<tr>");
+            body.push_str(&cells(lhs_line_num, &lhs_lines, &lhs_spans));
+            body.push_str(&cells(rhs_line_num, &rhs_lines, &rhs_spans));
+            body.push_str("</tr>\n");
+        }
+        body.push_str("</table>\n</details>\n");
+    }
+
+    let report = FileReport {
+        path: file.path.clone(),
+        lang_name,
+        summary,
+        lhs_changed_lines: lhs_spans.len(),
+        rhs_changed_lines: rhs_spans.len(),
+    };
+    (report, page(&file.path, &body))
+}
+
+/// A description of a hunk without a definition heading, such as
+/// `Lines 3-10`, using the new file's line numbers where possible.
+fn line_range_summary(rows: &[(Option<LineNumber>, Option<LineNumber>)]) -> String {
+    let line_nums: Vec<_> = match rows.iter().filter_map(|(_, rhs)| *rhs).collect::<Vec<_>>() {
+        rhs_nums if !rhs_nums.is_empty() => rhs_nums,
+        _ => rows.iter().filter_map(|(lhs, _)| *lhs).collect(),
+    };
+    match (line_nums.first(), line_nums.last()) {
+        (Some(first), Some(last)) if first == last => format!("Line {}", first.0 + 1),
+        (Some(first), Some(last)) => format!("Lines {}-{}", first.0 + 1, last.0 + 1),
+        _ => "Changes".to_string(),
+    }
+}
+
+/// The index page, with statistics for every file and in total.
+fn index_page(reports: &[FileReport]) -> String {
+    let mut total = Summary::default();
+    for report in reports {
+        total.add(&report.summary);
+    }
+    let lhs_total: usize = reports.iter().map(|report| report.lhs_changed_lines).sum();
+    let rhs_total: usize = reports.iter().map(|report| report.rhs_changed_lines).sum();
+
+    let mut body = format!(
+        "<h1>Difftastic report</h1>\n<p>{} file{}: {}. Lines changed: -{} +{}.</p>\n",
+        reports.len(),
+        if reports.len() == 1 { "" } else { "s" },
+        total,
+        lhs_total,
+        rhs_total
+    );
+    body.push_str(
+        "<table>\n<tr><th>File</th><th>Language</th><th>Changes</th><th>Lines changed</th></tr>\n",
+    );
+    for (i, report) in reports.iter().enumerate() {
+        body.push_str(&format!(
+            "<tr><td><a href=\"file-{}.html\">{}</a></td><td>{}</td><td>{}</td><td>-{} +{}</td></tr>\n",
+            i,
+            escape_html(&report.path),
+            escape_html(&report.lang_name),
+            report.summary,
+            report.lhs_changed_lines,
+            report.rhs_changed_lines
+        ));
+    }
+    body.push_str("</table>\n");
+
+    page("Difftastic report", &body)
+}
+
+/// Diff every file in `files`, returning an HTML page for each one
+/// and `index.html` linking to them, along with their file names.
+pub fn report_pages(files: &[PatchedFile], registry: &LanguageRegistry) -> Vec<(String, String)> {
+    let mut pages = vec![];
+    let mut reports = vec![];
+    for (i, file) in files.iter().enumerate() {
+        let (report, page) = report_file(file, registry);
+        pages.push((format!("file-{}.html", i), page));
+        reports.push(report);
+    }
+    pages.push(("index.html".to_string(), index_page(&reports)));
+    pages
+}
+
+/// Diff every file in `files`, and write an HTML page for each one
+/// to `dir`, along with `index.html` linking to them.
+pub fn write_report(
+    dir: &Path,
+    files: &[PatchedFile],
+    registry: &LanguageRegistry,
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for (name, page) in report_pages(files, registry) {
+        fs::write(dir.join(name), page)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "lang-json", feature = "lang-rust"))]
+    use crate::parse::ConfigDir;
+
+    #[test]
+    fn test_highlight_line() {
+        assert_eq!(
+            highlight_line("a < b", &[(2, 3, "added")]),
+            "a <span class=\"added\">&lt;</span> b"
+        );
+        assert_eq!(highlight_line("a", &[]), "a");
+    }
+
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_write_report() {
+        let dir = std::env::temp_dir().join(format!("difftastic-report-{}", std::process::id()));
+        let files = vec![PatchedFile {
+            path: "foo.json".into(),
+            lhs: "[\n  1,\n  2\n]\n".into(),
+            rhs: "[\n  1,\n  3\n]\n".into(),
+        }];
+        write_report(&dir, &files, ConfigDir::read_default_toml()).unwrap();
+
+        let index = fs::read_to_string(dir.join("index.html")).unwrap();
+        assert!(index.contains("<a href=\"file-0.html\">foo.json</a>"));
+        assert!(index.contains("1 file: 1 item added, 1 removed. Lines changed: -1 +1."));
+        let file_page = fs::read_to_string(dir.join("file-0.html")).unwrap();
+        assert!(file_page.contains("<td>  <span class=\"added\">3</span></td>"));
+        assert!(file_page.contains("<details open>\n<summary>Lines 1-4</summary>"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_report_pages_joined_headings() {
+        // The first two changes are close enough to be shown as one
+        // hunk, which must not shift the heading of the last hunk.
+        let filler = "\n".repeat(20);
+        let files = vec![PatchedFile {
+            path: "foo.rs".into(),
+            lhs: format!(
+                "use a;\n\nfn foo() {{\n    1\n}}\n{}fn bar() {{\n    2\n}}\n",
+                filler
+            ),
+            rhs: format!(
+                "use b;\n\nfn foo() {{\n    3\n}}\n{}fn bar() {{\n    4\n}}\n",
+                filler
+            ),
+        }];
+        let pages = report_pages(&files, ConfigDir::read_default_toml());
+        let file_page = &pages[0].1;
+
+        let summaries: Vec<_> = file_page
+            .match_indices("<summary>")
+            .map(|(i, _)| &file_page[i..file_page[i..].find('\n').unwrap() + i])
+            .collect();
+        assert_eq!(
+            summaries,
+            vec![
+                "<summary>modified fn foo (1 line changed)</summary>",
+                "<summary>modified fn bar (1 line changed)</summary>",
+            ]
+        );
+    }
+}
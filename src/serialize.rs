@@ -0,0 +1,425 @@
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+
+use crate::lines::LineNumber;
+use crate::lines::NewlinePositions;
+use crate::positions::SingleLineSpan;
+use crate::syntax::{ChangeKind, MatchKind, MatchedPos, Syntax};
+
+/// An owned copy of a `Syntax` node, without references into the
+/// arena.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SerializedSyntax {
+    List {
+        info: SerializedInfo,
+        open_position: Vec<SingleLineSpan>,
+        open_content: String,
+        children: Vec<SerializedSyntax>,
+        close_position: Vec<SingleLineSpan>,
+        close_content: String,
+        num_descendants: u64,
+    },
+    Atom {
+        info: SerializedInfo,
+        position: Vec<SingleLineSpan>,
+        content: String,
+        is_comment: bool,
+    },
+}
+
+/// The fields of `SyntaxInfo` that are meaningful outside the arena.
+#[derive(Debug, Serialize)]
+pub struct SerializedInfo {
+    pub unique_id: u64,
+    pub num_ancestors: u64,
+    /// None if this node hasn't been diffed.
+    pub change: Option<SerializedChange>,
+}
+
+/// A `ChangeKind`, where other nodes are referred to by their
+/// `unique_id` in the other tree.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SerializedChange {
+    Unchanged { opposite_id: u64 },
+    ReplacedComment { opposite_id: u64 },
+    Moved { opposite_id: u64 },
+    Novel,
+}
+
+impl<'a> From<ChangeKind<'a>> for SerializedChange {
+    fn from(change: ChangeKind<'a>) -> Self {
+        match change {
+            ChangeKind::Unchanged(opposite) => SerializedChange::Unchanged {
+                opposite_id: opposite.id(),
+            },
+            ChangeKind::ReplacedComment(_, opposite) => SerializedChange::ReplacedComment {
+                opposite_id: opposite.id(),
+            },
+            ChangeKind::Moved(opposite) => SerializedChange::Moved {
+                opposite_id: opposite.id(),
+            },
+            ChangeKind::Novel => SerializedChange::Novel,
+        }
+    }
+}
+
+impl<'a> From<&Syntax<'a>> for SerializedSyntax {
+    fn from(node: &Syntax<'a>) -> Self {
+        let info = node.info();
+        let info = SerializedInfo {
+            unique_id: info.unique_id.get(),
+            num_ancestors: info.num_ancestors.get(),
+            change: info.change.get().map(SerializedChange::from),
+        };
+
+        match node {
+            Syntax::List {
+                open_position,
+                open_content,
+                children,
+                close_position,
+                close_content,
+                num_descendants,
+                ..
+            } => SerializedSyntax::List {
+                info,
+                open_position: open_position.to_vec(),
+                open_content: open_content.to_string(),
+                children: children
+                    .iter()
+                    .map(|c| SerializedSyntax::from(*c))
+                    .collect(),
+                close_position: close_position.to_vec(),
+                close_content: close_content.to_string(),
+                num_descendants: *num_descendants,
+            },
+            Syntax::Atom {
+                position,
+                content,
+                is_comment,
+                ..
+            } => SerializedSyntax::Atom {
+                info,
+                position: position.to_vec(),
+                content: content.to_string(),
+                is_comment: *is_comment,
+            },
+        }
+    }
+}
+
+/// The syntax trees of both files, after diffing.
+#[derive(Debug, Serialize)]
+pub struct SerializedDiff {
+    pub lhs: Vec<SerializedSyntax>,
+    pub rhs: Vec<SerializedSyntax>,
+}
+
+impl SerializedDiff {
+    pub fn new(lhs: &[&Syntax], rhs: &[&Syntax]) -> Self {
+        SerializedDiff {
+            lhs: lhs.iter().map(|n| SerializedSyntax::from(*n)).collect(),
+            rhs: rhs.iter().map(|n| SerializedSyntax::from(*n)).collect(),
+        }
+    }
+}
+
+/// One file's changes, as written by `--output json --schema 1`.
+/// Unlike `SerializedDiff`, this doesn't expose internal types, so it
+/// stays stable as difftastic changes. Fields may be added within a
+/// schema version, but are never removed or renamed, so consumers
+/// should ignore fields they don't recognise.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct JsonFileV1 {
+    pub schema_version: u32,
+    pub path: String,
+    /// None for plain text and binary files.
+    pub language: Option<String>,
+    pub status: JsonStatusV1,
+    /// False if the file was diffed line by line.
+    pub syntactic: bool,
+    pub lhs_changes: Vec<JsonChangeV1>,
+    pub rhs_changes: Vec<JsonChangeV1>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonStatusV1 {
+    Changed,
+    Unchanged,
+    /// A binary file, whose changes aren't shown.
+    BinaryChanged,
+    BinaryUnchanged,
+}
+
+/// A changed region on a single line. Lines and columns count from
+/// zero, and columns are byte offsets.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct JsonChangeV1 {
+    pub kind: JsonChangeKindV1,
+    pub line: usize,
+    pub start_col: usize,
+    pub end_col: usize,
+    /// The byte offsets of the change in the whole file, so it can be
+    /// sliced without counting lines.
+    pub start_byte: usize,
+    pub end_byte: usize,
+    /// The number of nodes inside the changed node, so 0 for atoms.
+    /// For a list, both delimiters report the whole list.
+    pub num_descendants: u64,
+    /// The number of lists enclosing the changed node.
+    pub depth: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonChangeKindV1 {
+    Novel,
+    Moved,
+    ChangedComment,
+}
+
+/// The spans of every node on each line, with the node's
+/// `num_descendants` and depth.
+type NodeSpans = FxHashMap<LineNumber, Vec<(SingleLineSpan, u64, u64)>>;
+
+fn node_spans(nodes: &[&Syntax], spans: &mut NodeSpans) {
+    for node in nodes {
+        let depth = node.info().num_ancestors.get();
+        let mut add = |positions: &[SingleLineSpan], num_descendants| {
+            for span in positions {
+                spans
+                    .entry(span.line)
+                    .or_default()
+                    .push((*span, num_descendants, depth));
+            }
+        };
+        match node {
+            Syntax::List {
+                open_position,
+                children,
+                close_position,
+                num_descendants,
+                ..
+            } => {
+                add(open_position, *num_descendants);
+                add(close_position, *num_descendants);
+                node_spans(children, spans);
+            }
+            Syntax::Atom { position, .. } => add(position, 0),
+        }
+    }
+}
+
+/// The `num_descendants` and depth of the node containing `span`.
+/// Changed comment words are inside their comment's span.
+fn span_metrics(spans: &NodeSpans, span: &SingleLineSpan) -> (u64, u64) {
+    spans
+        .get(&span.line)
+        .and_then(|line_spans| {
+            line_spans.iter().find(|(node_span, _, _)| {
+                node_span.start_col <= span.start_col && span.end_col <= node_span.end_col
+            })
+        })
+        .map_or((0, 0), |(_, num_descendants, depth)| {
+            (*num_descendants, *depth)
+        })
+}
+
+fn json_changes_v1(src: &str, nodes: &[&Syntax], positions: &[MatchedPos]) -> Vec<JsonChangeV1> {
+    let mut spans = NodeSpans::default();
+    node_spans(nodes, &mut spans);
+    let newline_positions = NewlinePositions::from(src);
+
+    let mut changes = vec![];
+    for mp in positions {
+        let kind = match mp.kind {
+            MatchKind::Novel => JsonChangeKindV1::Novel,
+            MatchKind::Moved => JsonChangeKindV1::Moved,
+            MatchKind::ChangedCommentPart => JsonChangeKindV1::ChangedComment,
+            MatchKind::Unchanged | MatchKind::UnchangedCommentPart => continue,
+        };
+        for span in &mp.pos {
+            let (num_descendants, depth) = span_metrics(&spans, span);
+            let bytes = newline_positions.byte_span(span);
+            changes.push(JsonChangeV1 {
+                kind,
+                line: span.line.0,
+                start_col: span.start_col,
+                end_col: span.end_col,
+                start_byte: bytes.start,
+                end_byte: bytes.end,
+                num_descendants,
+                depth,
+            });
+        }
+    }
+    changes
+}
+
+impl JsonFileV1 {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        path: &str,
+        language: Option<&str>,
+        syntactic: bool,
+        lhs_src: &str,
+        rhs_src: &str,
+        lhs_nodes: &[&Syntax],
+        rhs_nodes: &[&Syntax],
+        lhs_positions: &[MatchedPos],
+        rhs_positions: &[MatchedPos],
+    ) -> Self {
+        let lhs_changes = json_changes_v1(lhs_src, lhs_nodes, lhs_positions);
+        let rhs_changes = json_changes_v1(rhs_src, rhs_nodes, rhs_positions);
+        let status = if lhs_changes.is_empty() && rhs_changes.is_empty() {
+            JsonStatusV1::Unchanged
+        } else {
+            JsonStatusV1::Changed
+        };
+        JsonFileV1 {
+            schema_version: 1,
+            path: path.to_string(),
+            language: language.map(|l| l.to_string()),
+            status,
+            syntactic,
+            lhs_changes,
+            rhs_changes,
+        }
+    }
+
+    pub fn binary(path: &str, changed: bool) -> Self {
+        JsonFileV1 {
+            schema_version: 1,
+            path: path.to_string(),
+            language: None,
+            status: if changed {
+                JsonStatusV1::BinaryChanged
+            } else {
+                JsonStatusV1::BinaryUnchanged
+            },
+            syntactic: false,
+            lhs_changes: vec![],
+            rhs_changes: vec![],
+        }
+    }
+}
+
+#[cfg(all(test, feature = "lang-json"))]
+mod tests {
+    use super::*;
+    use crate::dijkstra::{mark_syntax, SearchConfig};
+    use crate::parse::{find_lang, parse, ConfigDir};
+    use crate::syntax::{change_positions, init_info};
+    use typed_arena::Arena;
+
+    #[test]
+    fn test_serialize_diff() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let arena = Arena::new();
+        let lhs = parse(&arena, "[1]", lang);
+        let rhs = parse(&arena, "[1, 2]", lang);
+        init_info(&lhs);
+        init_info(&rhs);
+        mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &SearchConfig::default(),
+        );
+
+        let diff = SerializedDiff::new(&lhs, &rhs);
+        match &diff.rhs[0] {
+            SerializedSyntax::List { children, .. } => {
+                let changes: Vec<_> = children
+                    .iter()
+                    .map(|child| match child {
+                        SerializedSyntax::Atom { info, .. } => info.change.as_ref().unwrap(),
+                        SerializedSyntax::List { .. } => panic!("Expected an atom"),
+                    })
+                    .collect();
+                assert_eq!(
+                    changes,
+                    vec![
+                        &SerializedChange::Unchanged { opposite_id: 2 },
+                        &SerializedChange::Novel,
+                        &SerializedChange::Novel
+                    ]
+                );
+            }
+            SerializedSyntax::Atom { .. } => panic!("Expected a list"),
+        }
+
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["lhs"][0]["kind"], "list");
+        assert_eq!(json["lhs"][0]["open_position"][0]["line"], 0);
+        assert_eq!(json["lhs"][0]["children"][0]["content"], "1");
+    }
+
+    #[test]
+    fn test_json_file_v1() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let arena = Arena::new();
+        let lhs_src = "[\n  1\n]\n";
+        let rhs_src = "[\n  1,\n  [2, 3]\n]\n";
+        let lhs = parse(&arena, lhs_src, lang);
+        let rhs = parse(&arena, rhs_src, lang);
+        init_info(&lhs);
+        init_info(&rhs);
+        mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &SearchConfig::default(),
+        );
+        let lhs_positions = change_positions(lhs_src, rhs_src, &lhs);
+        let rhs_positions = change_positions(rhs_src, lhs_src, &rhs);
+        let file = JsonFileV1::new(
+            "foo.json",
+            Some("JSON"),
+            true,
+            lhs_src,
+            rhs_src,
+            &lhs,
+            &rhs,
+            &lhs_positions,
+            &rhs_positions,
+        );
+
+        let change = |line, start_col, start_byte, num_descendants, depth| {
+            serde_json::json!({
+                "kind": "novel",
+                "line": line,
+                "start_col": start_col,
+                "end_col": start_col + 1,
+                "start_byte": start_byte,
+                "end_byte": start_byte + 1,
+                "num_descendants": num_descendants,
+                "depth": depth,
+            })
+        };
+        assert_eq!(
+            serde_json::to_value(&file).unwrap(),
+            serde_json::json!({
+                "schema_version": 1,
+                "path": "foo.json",
+                "language": "JSON",
+                "status": "changed",
+                "syntactic": true,
+                "lhs_changes": [],
+                "rhs_changes": [
+                    change(1, 3, 5, 0, 1),
+                    change(2, 2, 9, 3, 1),
+                    change(2, 3, 10, 0, 2),
+                    change(2, 4, 11, 0, 2),
+                    change(2, 6, 13, 0, 2),
+                    change(2, 7, 14, 3, 1),
+                ],
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(JsonFileV1::binary("a.png", false)).unwrap()["status"],
+            "binary_unchanged"
+        );
+    }
+}
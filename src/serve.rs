@@ -0,0 +1,225 @@
+//! A local web server showing structural diffs in the browser, with
+//! every hunk collapsible. The pages are the same as `report` writes,
+//! but are generated once at startup and served from memory.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::patch::PatchedFile;
+
+/// How long to wait for a client to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The path requested by an HTTP request line such as
+/// `GET /file-0.html HTTP/1.1`, ignoring any query string. Returns
+/// `None` for methods other than GET and HEAD.
+fn request_path(request_line: &str) -> Option<&str> {
+    let mut parts = request_line.split_whitespace();
+    match parts.next()? {
+        "GET" | "HEAD" => {}
+        _ => return None,
+    }
+    let target = parts.next()?;
+    Some(target.split('?').next().unwrap_or(target))
+}
+
+/// The full HTTP response to `request_line`, looking up pages by
+/// file name. `/` is the index page.
+fn respond(request_line: &str, pages: &HashMap<String, String>) -> String {
+    let (status, body) = match request_path(request_line) {
+        None => ("405 Method Not Allowed", "Method not allowed\n"),
+        Some(path) => {
+            let name = match path.trim_start_matches('/') {
+                "" => "index.html",
+                name => name,
+            };
+            match pages.get(name) {
+                Some(page) => ("200 OK", page.as_str()),
+                None => ("404 Not Found", "Not found\n"),
+            }
+        }
+    };
+    let content_type = if status == "200 OK" {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    // HEAD gets the same headers as GET, including the length of the
+    // body it would have sent.
+    let content_length = body.len();
+    let body = if request_line.starts_with("HEAD ") {
+        ""
+    } else {
+        body
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, content_length, body
+    )
+}
+
+fn handle_connection(stream: TcpStream, pages: &HashMap<String, String>) -> io::Result<()> {
+    // Browsers open spare connections and leave them idle, so don't
+    // keep a thread waiting on one forever.
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Read the headers, which we don't need, so the client doesn't see
+    // the connection reset before it's finished sending.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut stream = reader.into_inner();
+    stream.write_all(respond(request_line.trim_end(), pages).as_bytes())?;
+    stream.flush()
+}
+
+/// Serve `pages`, named as `report::report_pages` names them, on
+/// `addr` until the process is killed. Each connection is handled on
+/// its own thread, so an idle connection doesn't hold up the others.
+pub fn serve(addr: &str, pages: Vec<(String, String)>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Serving diffs at http://{}/", listener.local_addr()?);
+
+    let pages: Arc<HashMap<String, String>> = Arc::new(pages.into_iter().collect());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Could not accept connection: {}", e);
+                continue;
+            }
+        };
+        let pages = Arc::clone(&pages);
+        thread::spawn(move || {
+            match handle_connection(stream, &pages) {
+                Ok(()) => {}
+                // An idle connection that timed out, not worth reporting.
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => eprintln!("Could not handle request: {}", e),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Every file under `dir`, as paths relative to it.
+fn relative_files(dir: &Path, prefix: &Path, files: &mut BTreeSet<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir.join(prefix))? {
+        let entry = entry?;
+        let path = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            relative_files(dir, &path, files)?;
+        } else {
+            files.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Pair the files in `lhs_dir` and `rhs_dir` by their relative path,
+/// treating a file that only exists on one side as empty on the
+/// other. Files that are identical on both sides are skipped.
+pub fn directory_files(lhs_dir: &Path, rhs_dir: &Path) -> io::Result<Vec<PatchedFile>> {
+    let mut paths = BTreeSet::new();
+    relative_files(lhs_dir, Path::new(""), &mut paths)?;
+    relative_files(rhs_dir, Path::new(""), &mut paths)?;
+
+    let read = |path: PathBuf| -> io::Result<String> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(String::from_utf8_lossy(&bytes).to_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e),
+        }
+    };
+
+    let mut files = vec![];
+    for path in paths {
+        let lhs = read(lhs_dir.join(&path))?;
+        let rhs = read(rhs_dir.join(&path))?;
+        if lhs != rhs {
+            files.push(PatchedFile {
+                path: path.display().to_string(),
+                lhs,
+                rhs,
+            });
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path("GET / HTTP/1.1"), Some("/"));
+        assert_eq!(
+            request_path("GET /file-0.html?x=1 HTTP/1.1"),
+            Some("/file-0.html")
+        );
+        assert_eq!(request_path("POST / HTTP/1.1"), None);
+        assert_eq!(request_path(""), None);
+    }
+
+    #[test]
+    fn test_respond() {
+        let pages: HashMap<_, _> = vec![
+            ("index.html".to_string(), "index".to_string()),
+            ("file-0.html".to_string(), "file".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        let index = respond("GET / HTTP/1.1", &pages);
+        assert!(index.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(index.contains("Content-Length: 5\r\n"));
+        assert!(index.ends_with("\r\n\r\nindex"));
+
+        assert!(respond("GET /file-0.html HTTP/1.1", &pages).ends_with("\r\n\r\nfile"));
+        let head = respond("HEAD /file-0.html HTTP/1.1", &pages);
+        assert!(head.contains("Content-Length: 4\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+        assert!(respond("GET /../etc HTTP/1.1", &pages).starts_with("HTTP/1.1 404"));
+        assert!(respond("DELETE / HTTP/1.1", &pages).starts_with("HTTP/1.1 405"));
+    }
+
+    #[test]
+    fn test_directory_files() {
+        let dir = std::env::temp_dir().join(format!("difftastic-serve-{}", std::process::id()));
+        let (lhs, rhs) = (dir.join("lhs"), dir.join("rhs"));
+        fs::create_dir_all(lhs.join("src")).unwrap();
+        fs::create_dir_all(rhs.join("src")).unwrap();
+        fs::write(lhs.join("same.txt"), "a\n").unwrap();
+        fs::write(rhs.join("same.txt"), "a\n").unwrap();
+        fs::write(lhs.join("src/changed.rs"), "1\n").unwrap();
+        fs::write(rhs.join("src/changed.rs"), "2\n").unwrap();
+        fs::write(rhs.join("added.txt"), "new\n").unwrap();
+
+        let files = directory_files(&lhs, &rhs).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let summary: Vec<_> = files
+            .iter()
+            .map(|f| (f.path.as_str(), f.lhs.as_str(), f.rhs.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("added.txt", "", "new\n"), ("src/changed.rs", "1\n", "2\n")]
+        );
+    }
+}
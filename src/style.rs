@@ -1,23 +1,51 @@
 use crate::lines::LineNumber;
 use crate::positions::SingleLineSpan;
-use crate::syntax::{MatchKind, MatchedPos};
+use crate::syntax::{MatchKind, MatchedPos, TokenKind};
 use colored::*;
-use std::cmp::min;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Style {
     foreground: Color,
     background: Option<Color>,
     bold: bool,
+    underline: bool,
+    reversed: bool,
+    /// Draw invisible characters and trailing whitespace, see
+    /// `invisible_changes`.
+    show_invisible: bool,
 }
 
 impl Style {
+    fn plain(foreground: Color) -> Self {
+        Style {
+            foreground,
+            background: None,
+            bold: false,
+            underline: false,
+            reversed: false,
+            show_invisible: false,
+        }
+    }
+
     fn apply(&self, s: &str) -> String {
-        let mut res = s.color(self.foreground);
+        let s = if self.show_invisible {
+            show_invisible(s)
+        } else {
+            s.to_string()
+        };
+        let mut res = s.as_str().color(self.foreground);
         if self.bold {
             res = res.bold();
         }
+        if self.underline {
+            res = res.underline();
+        }
+        if self.reversed {
+            res = res.reversed();
+        }
         if let Some(background) = self.background {
             res = res.on_color(background);
         };
@@ -25,23 +53,204 @@ impl Style {
     }
 }
 
+/// How a kind of change is highlighted, in addition to its color.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Emphasis {
+    /// Only color the text.
+    Color,
+    /// Use the color as the background, with black text.
+    Background,
+    Bold,
+    Underline,
+    /// Swap the foreground and background colors.
+    Reverse,
+}
+
+impl Emphasis {
+    fn style(self, color: Color) -> Style {
+        let style = Style::plain(color);
+        match self {
+            Emphasis::Color => style,
+            Emphasis::Background => Style {
+                foreground: Color::Black,
+                background: Some(color),
+                ..style
+            },
+            Emphasis::Bold => Style {
+                bold: true,
+                ..style
+            },
+            Emphasis::Underline => Style {
+                underline: true,
+                ..style
+            },
+            Emphasis::Reverse => Style {
+                reversed: true,
+                ..style
+            },
+        }
+    }
+}
+
+/// The emphasis for each kind of change, read from the `[theme]`
+/// section of the config file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    pub novel: Emphasis,
+    pub moved: Emphasis,
+    pub changed_comment: Emphasis,
+    /// Color unchanged strings, numbers and comments, so context
+    /// lines are easier to read.
+    pub syntax_highlight: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            novel: Emphasis::Bold,
+            moved: Emphasis::Color,
+            changed_comment: Emphasis::Bold,
+            syntax_highlight: true,
+        }
+    }
+}
+
+/// Characters that take up no space in most terminals, such as
+/// zero-width spaces, byte order marks and bidirectional text controls.
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{AD}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}'
+    )
+}
+
+/// Replace invisible characters in `s` with `◌`, and trailing
+/// whitespace with `·` or `→`.
+fn show_invisible(s: &str) -> String {
+    let visible_end = s
+        .trim_end_matches(|c: char| c.is_whitespace() || is_invisible(c))
+        .len();
+    let mut res = String::with_capacity(s.len());
+    for (i, c) in s.char_indices() {
+        res.push(match c {
+            c if is_invisible(c) => '◌',
+            '\t' if i >= visible_end => '→',
+            c if i >= visible_end && c.is_whitespace() => '·',
+            c => c,
+        });
+    }
+    res
+}
+
+fn is_changed(pos: &&MatchedPos) -> bool {
+    matches!(pos.kind, MatchKind::Novel | MatchKind::ChangedCommentPart)
+}
+
+/// The text of each span in `pos`.
+fn span_text<'a>(lines: &[&'a str], pos: &MatchedPos) -> Vec<&'a str> {
+    pos.pos
+        .iter()
+        .map(|span| {
+            lines
+                .get(span.line.0)
+                .and_then(|line| line.get(span.start_col..span.end_col))
+                .unwrap_or("")
+        })
+        .collect()
+}
+
+fn without_invisible(text: &[&str]) -> Vec<String> {
+    text.iter()
+        .map(|part| {
+            let visible: String = part.chars().filter(|c| !is_invisible(*c)).collect();
+            visible.trim_end().to_string()
+        })
+        .collect()
+}
+
+/// Find changes in `src` that are only changed because of invisible
+/// characters or trailing whitespace, compared with a change in
+/// `other_src`. These look identical to the other side unless we
+/// draw the invisible characters.
+pub fn invisible_changes(
+    src: &str,
+    positions: &[MatchedPos],
+    other_src: &str,
+    other_positions: &[MatchedPos],
+) -> HashSet<SingleLineSpan> {
+    let lines: Vec<_> = src.lines().collect();
+    let other_lines: Vec<_> = other_src.lines().collect();
+    let other_visible: HashSet<_> = other_positions
+        .iter()
+        .filter(is_changed)
+        .map(|pos| without_invisible(&span_text(&other_lines, pos)))
+        .collect();
+
+    let mut res = HashSet::new();
+    for pos in positions.iter().filter(is_changed) {
+        let text = span_text(&lines, pos);
+        let visible = without_invisible(&text);
+        if visible.iter().zip(&text).any(|(v, t)| v.as_str() != *t)
+            && other_visible.contains(&visible)
+        {
+            res.extend(pos.pos.iter().copied());
+        }
+    }
+    res
+}
+
+/// Merge consecutive spans with the same style, if they're only
+/// separated by whitespace. This produces fewer, larger highlighted
+/// regions and fewer escape sequences.
+fn merge_adjacent(line: &str, styles: &[(SingleLineSpan, Style)]) -> Vec<(SingleLineSpan, Style)> {
+    let mut res: Vec<(SingleLineSpan, Style)> = Vec::with_capacity(styles.len());
+    for (span, style) in styles {
+        if let Some((prev_span, prev_style)) = res.last_mut() {
+            let only_whitespace_between = match line.get(prev_span.end_col..span.start_col) {
+                Some(between) => between.trim().is_empty(),
+                // The spans overlap, or are beyond the end of the line.
+                None => prev_span.end_col >= span.start_col,
+            };
+            if prev_style == style && prev_span.line == span.line && only_whitespace_between {
+                prev_span.end_col = max(prev_span.end_col, span.end_col);
+                continue;
+            }
+        }
+        res.push((*span, *style));
+    }
+    res
+}
+
 pub fn apply_line(line: &str, styles: &[(SingleLineSpan, Style)]) -> String {
     if styles.is_empty() {
         return line.dimmed().to_string();
     }
 
+    let styles = merge_adjacent(line, styles);
     let mut res = String::with_capacity(line.len());
     let mut i = 0;
-    for (span, style) in styles {
+    for (span, style) in &styles {
         if span.start_col >= line.len() {
             break;
         }
+        // Skip empty spans, and spans we've already written.
+        if span.end_col <= max(i, span.start_col) {
+            continue;
+        }
 
         if i < span.start_col {
             res.push_str(&line[i..span.start_col].dimmed());
         }
 
-        res.push_str(&style.apply(&line[span.start_col..min(line.len(), span.end_col)]));
+        let start_col = max(i, span.start_col);
+        res.push_str(&style.apply(&line[start_col..min(line.len(), span.end_col)]));
         i = span.end_col;
     }
 
@@ -75,38 +284,65 @@ pub fn apply(s: &str, styles: &[(SingleLineSpan, Style)]) -> String {
 
     let mut res = String::with_capacity(s.len());
     for (i, line) in s.lines().enumerate() {
-        let ranges = ranges_by_line.remove(&i.into()).unwrap_or_default();
+        let mut ranges = ranges_by_line.remove(&i.into()).unwrap_or_default();
+        // Children of unordered lists may not be in source order.
+        ranges.sort_by_key(|(span, _)| span.start_col);
         res.push_str(&apply_line(line, &ranges));
         res.push('\n');
     }
     res
 }
 
-pub fn apply_colors(s: &str, is_lhs: bool, positions: &[MatchedPos]) -> String {
+/// The style of an unchanged token. With `syntax_highlight`, this
+/// depends on what kind of token it is.
+fn unchanged_style(token_kind: TokenKind, theme: &Theme) -> Style {
+    if !theme.syntax_highlight {
+        return Style::plain(Color::White);
+    }
+    match token_kind {
+        TokenKind::String => Style::plain(Color::Yellow),
+        TokenKind::Number => Style::plain(Color::Blue),
+        TokenKind::Comment => Style::plain(Color::BrightBlack),
+        TokenKind::Delimiter | TokenKind::Identifier | TokenKind::Other => {
+            Style::plain(Color::White)
+        }
+    }
+}
+
+/// Color `s` according to `positions`. Spans in `invisible` have
+/// their invisible characters drawn.
+pub fn apply_colors(
+    s: &str,
+    is_lhs: bool,
+    positions: &[MatchedPos],
+    theme: &Theme,
+    invisible: &HashSet<SingleLineSpan>,
+) -> String {
+    let novel_color = if is_lhs {
+        Color::BrightRed
+    } else {
+        Color::BrightGreen
+    };
     let mut styles = vec![];
     for pos in positions {
         let style = match pos.kind {
-            MatchKind::Unchanged => Style {
-                foreground: Color::White,
-                background: None,
-                bold: false,
-            },
-            MatchKind::Novel | MatchKind::ChangedCommentPart => Style {
-                foreground: if is_lhs {
-                    Color::BrightRed
-                } else {
-                    Color::BrightGreen
-                },
-                background: None,
-                bold: true,
-            },
-            MatchKind::UnchangedCommentPart => Style {
-                foreground: if is_lhs { Color::Red } else { Color::Green },
-                background: None,
-                bold: false,
-            },
+            MatchKind::Unchanged => unchanged_style(pos.token_kind, theme),
+            MatchKind::Moved => {
+                theme
+                    .moved
+                    .style(if is_lhs { Color::Magenta } else { Color::Cyan })
+            }
+            MatchKind::Novel => theme.novel.style(novel_color),
+            MatchKind::ChangedCommentPart => theme.changed_comment.style(novel_color),
+            MatchKind::UnchangedCommentPart => {
+                Style::plain(if is_lhs { Color::Red } else { Color::Green })
+            }
         };
         for line_pos in &pos.pos {
+            let style = Style {
+                show_invisible: invisible.contains(line_pos),
+                ..style
+            };
             styles.push((*line_pos, style));
         }
     }
@@ -117,3 +353,117 @@ pub fn apply_colors(s: &str, is_lhs: bool, positions: &[MatchedPos]) -> String {
 pub fn header(file_name: &str, language_name: &str) -> String {
     format!("{} ({})", file_name.yellow().bold(), language_name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start_col: usize, end_col: usize) -> SingleLineSpan {
+        SingleLineSpan {
+            line: 0.into(),
+            start_col,
+            end_col,
+        }
+    }
+
+    const NOVEL: Style = Style {
+        foreground: Color::BrightGreen,
+        background: None,
+        bold: true,
+        underline: false,
+        reversed: false,
+        show_invisible: false,
+    };
+    const UNCHANGED: Style = Style {
+        foreground: Color::White,
+        background: None,
+        bold: false,
+        underline: false,
+        reversed: false,
+        show_invisible: false,
+    };
+
+    #[test]
+    fn default_theme_emphasis() {
+        let theme = Theme::default();
+        assert_eq!(theme.novel.style(Color::BrightGreen), NOVEL);
+        assert_eq!(
+            Emphasis::Background.style(Color::Cyan),
+            Style {
+                foreground: Color::Black,
+                background: Some(Color::Cyan),
+                ..Style::plain(Color::Cyan)
+            }
+        );
+    }
+
+    #[test]
+    fn apply_line_ignores_empty_spans() {
+        colored::control::set_override(false);
+        let styles = vec![
+            (span(0, 2), NOVEL),
+            (span(2, 3), NOVEL),
+            (span(2, 2), UNCHANGED),
+        ];
+        assert_eq!(apply_line("[],", &styles), "[],");
+    }
+
+    #[test]
+    fn unchanged_style_highlights_tokens() {
+        let theme = Theme::default();
+        assert_eq!(
+            unchanged_style(TokenKind::String, &theme),
+            Style::plain(Color::Yellow)
+        );
+        assert_eq!(unchanged_style(TokenKind::Identifier, &theme), UNCHANGED);
+
+        let theme = Theme {
+            syntax_highlight: false,
+            ..theme
+        };
+        assert_eq!(unchanged_style(TokenKind::String, &theme), UNCHANGED);
+    }
+
+    #[test]
+    fn show_invisible_chars() {
+        assert_eq!(show_invisible("a\u{200B}b \t"), "a◌b·→");
+        assert_eq!(show_invisible("a b"), "a b");
+    }
+
+    #[test]
+    fn invisible_changes_only_whitespace() {
+        let novel = |start_col, end_col| MatchedPos {
+            kind: MatchKind::Novel,
+            token_kind: TokenKind::Other,
+            pos: vec![span(start_col, end_col)],
+            prev_opposite_pos: vec![],
+        };
+        let lhs = "foo\u{FEFF} bar";
+        let rhs = "foo baz";
+        let invisible = invisible_changes(
+            lhs,
+            &[novel(0, 6), novel(7, 10)],
+            rhs,
+            &[novel(0, 3), novel(4, 7)],
+        );
+        assert_eq!(invisible, [span(0, 6)].iter().copied().collect());
+    }
+
+    #[test]
+    fn merge_adjacent_same_style() {
+        let merged = merge_adjacent("foo bar", &[(span(0, 3), NOVEL), (span(4, 7), NOVEL)]);
+        assert_eq!(merged, vec![(span(0, 7), NOVEL)]);
+    }
+
+    #[test]
+    fn merge_adjacent_different_style() {
+        let styles = vec![(span(0, 3), NOVEL), (span(4, 7), UNCHANGED)];
+        assert_eq!(merge_adjacent("foo bar", &styles), styles);
+    }
+
+    #[test]
+    fn merge_adjacent_not_whitespace() {
+        let styles = vec![(span(0, 3), NOVEL), (span(4, 7), NOVEL)];
+        assert_eq!(merge_adjacent("foo.bar", &styles), styles);
+    }
+}
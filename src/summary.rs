@@ -0,0 +1,816 @@
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::lines::{LineGroup, LineNumber};
+use crate::syntax::ChangeKind::*;
+use crate::syntax::MatchedPos;
+use crate::syntax::Syntax::{self, *};
+
+/// What a top-level item is, such as a function or an import. Kinds
+/// are listed in this order in a summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ItemKind {
+    Function,
+    Macro,
+    Class,
+    Struct,
+    Enum,
+    Trait,
+    Interface,
+    Impl,
+    Module,
+    Definition,
+    Item,
+    Import,
+}
+
+impl ItemKind {
+    fn noun(self, count: usize) -> &'static str {
+        let (singular, plural) = match self {
+            ItemKind::Function => ("function", "functions"),
+            ItemKind::Macro => ("macro", "macros"),
+            ItemKind::Class => ("class", "classes"),
+            ItemKind::Struct => ("struct", "structs"),
+            ItemKind::Enum => ("enum", "enums"),
+            ItemKind::Trait => ("trait", "traits"),
+            ItemKind::Interface => ("interface", "interfaces"),
+            ItemKind::Impl => ("impl block", "impl blocks"),
+            ItemKind::Module => ("module", "modules"),
+            ItemKind::Definition => ("definition", "definitions"),
+            ItemKind::Item => ("item", "items"),
+            ItemKind::Import => ("import", "imports"),
+        };
+        if count == 1 {
+            singular
+        } else {
+            plural
+        }
+    }
+}
+
+/// How many top-level items of one kind have changed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KindCounts {
+    pub changed: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub moved: usize,
+}
+
+/// How many top-level items in a file have changed, by kind, for
+/// `--summary`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub counts: BTreeMap<ItemKind, KindCounts>,
+    /// The imports are the same on both sides, but in a different
+    /// order. Reordered imports aren't included in `counts`.
+    pub imports_reordered: bool,
+}
+
+impl Summary {
+    fn counts_mut(&mut self, kind: ItemKind) -> &mut KindCounts {
+        self.counts.entry(kind).or_default()
+    }
+
+    /// Add the counts in `other`, such as when summarising several
+    /// files.
+    pub fn add(&mut self, other: &Summary) {
+        for (kind, counts) in &other.counts {
+            let total = self.counts_mut(*kind);
+            total.changed += counts.changed;
+            total.added += counts.added;
+            total.removed += counts.removed;
+            total.moved += counts.moved;
+        }
+        self.imports_reordered |= other.imports_reordered;
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts: Vec<String> = vec![];
+        for (kind, counts) in &self.counts {
+            let kind_parts = [
+                (counts.changed, "changed"),
+                (counts.added, "added"),
+                (counts.removed, "removed"),
+                (counts.moved, "moved"),
+            ];
+            // Only the first part for each kind says what's being
+            // counted: "3 functions changed, 1 added".
+            for (i, (count, verb)) in kind_parts
+                .iter()
+                .filter(|(count, _)| *count > 0)
+                .enumerate()
+            {
+                if i > 0 {
+                    parts.push(format!("{} {}", count, verb));
+                } else {
+                    parts.push(format!("{} {} {}", count, kind.noun(*count), verb));
+                }
+            }
+        }
+        if self.imports_reordered {
+            parts.push("imports reordered".to_string());
+        }
+
+        if parts.is_empty() {
+            write!(f, "no changes")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ItemChange {
+    Unchanged,
+    /// Every node is novel, so the item was added or removed.
+    Novel,
+    Moved,
+    Changed,
+}
+
+#[derive(Default)]
+struct ChangeCounts {
+    total: usize,
+    novel: usize,
+    replaced: usize,
+    moved: usize,
+}
+
+fn count_changes(node: &Syntax, counts: &mut ChangeCounts) {
+    counts.total += 1;
+    match node.info().change.get() {
+        Some(Novel) => counts.novel += 1,
+        Some(ReplacedComment(_, _)) => counts.replaced += 1,
+        Some(Moved(_)) => counts.moved += 1,
+        Some(Unchanged(_)) | None => {}
+    }
+    if let List { children, .. } = node {
+        for child in children {
+            count_changes(child, counts);
+        }
+    }
+}
+
+fn classify(item: &[&Syntax]) -> ItemChange {
+    let mut counts = ChangeCounts::default();
+    for node in item {
+        count_changes(node, &mut counts);
+    }
+
+    if counts.novel == counts.total {
+        ItemChange::Novel
+    } else if counts.moved == counts.total {
+        ItemChange::Moved
+    } else if counts.novel + counts.replaced + counts.moved == 0 {
+        ItemChange::Unchanged
+    } else {
+        ItemChange::Changed
+    }
+}
+
+/// The column that `node` starts at.
+fn first_col(node: &Syntax) -> Option<usize> {
+    let position = match node {
+        List { open_position, .. } => open_position,
+        Atom { position, .. } => position,
+    };
+    position.first().map(|span| span.start_col)
+}
+
+/// Split `nodes` into items, such as function definitions. An item is
+/// a run of nodes where each node starts on the line that the
+/// previous node ended, or is indented more than the first node of
+/// the item, like the body of a Python function. A file with a single
+/// root list, such as a JSON document, is split by the children of
+/// the root.
+fn top_level_items<'a>(nodes: &[&'a Syntax<'a>]) -> Vec<Vec<&'a Syntax<'a>>> {
+    let nodes = match nodes {
+        [List { children, .. }] => &children[..],
+        _ => nodes,
+    };
+
+    let mut items: Vec<Vec<&'a Syntax<'a>>> = vec![];
+    let mut prev_last_line: Option<LineNumber> = None;
+    for node in nodes {
+        let is_indented = match (
+            items.last().and_then(|item| first_col(item[0])),
+            first_col(node),
+        ) {
+            (Some(item_col), Some(col)) => col > item_col,
+            _ => false,
+        };
+        let continues_item = match (prev_last_line, node.first_line()) {
+            (Some(prev_last_line), Some(first_line)) => first_line <= prev_last_line || is_indented,
+            _ => false,
+        };
+        match items.last_mut() {
+            Some(item) if continues_item => item.push(node),
+            _ => items.push(vec![node]),
+        }
+        prev_last_line = node.last_line();
+    }
+    items
+}
+
+/// The first line in the other file of a node in `node` that is
+/// unchanged.
+fn opposite_line(node: &Syntax) -> Option<LineNumber> {
+    if let Some(Unchanged(opposite)) = node.info().change.get() {
+        return opposite.first_line();
+    }
+    match node {
+        List { children, .. } => children.iter().find_map(|child| opposite_line(child)),
+        Atom { .. } => None,
+    }
+}
+
+/// Atoms that start an import, such as `use` in Rust or `from` in
+/// Python.
+const IMPORT_KEYWORDS: &[&str] = &["import", "use", "from", "require", "using", "#include"];
+
+/// The kind of `item`, from the keyword that starts it. A definition
+/// keyword may follow modifiers such as `pub` or `export`, and Lisp
+/// definitions are lists starting with the keyword.
+fn item_kind<'a>(item: &[&'a Syntax<'a>]) -> ItemKind {
+    let first = match item.first() {
+        Some(List {
+            open_content,
+            children,
+            ..
+        }) if open_content == "(" => children.first(),
+        first => first,
+    };
+    let first_content = match first {
+        Some(Atom { content, .. }) => content.trim_start(),
+        Some(List { open_content, .. }) => open_content.trim_start(),
+        None => "",
+    };
+    if IMPORT_KEYWORDS.contains(&first_content) {
+        return ItemKind::Import;
+    }
+
+    let first_line = item.first().and_then(|node| node.first_line());
+    let keyword = definitions(item)
+        .into_iter()
+        .find(|def| Some(def.first_line) == first_line)
+        .map(|def| def.keyword);
+    match keyword.as_deref() {
+        Some("fn" | "func" | "function" | "def" | "defn" | "defun" | "sub") => ItemKind::Function,
+        Some("defmacro") => ItemKind::Macro,
+        Some("class") => ItemKind::Class,
+        Some("struct") => ItemKind::Struct,
+        Some("enum") => ItemKind::Enum,
+        Some("trait") => ItemKind::Trait,
+        Some("interface") => ItemKind::Interface,
+        Some("impl") => ItemKind::Impl,
+        Some("module") => ItemKind::Module,
+        Some("define") => ItemKind::Definition,
+        _ => ItemKind::Item,
+    }
+}
+
+fn same_item<'a>(lhs: &[&'a Syntax<'a>], rhs: &[&'a Syntax<'a>]) -> bool {
+    lhs.len() == rhs.len() && lhs.iter().zip(rhs).all(|(x, y)| x.equal_content(y))
+}
+
+/// The items in `items` that are imports.
+fn imports<'i, 'a>(
+    items: &'i [Vec<&'a Syntax<'a>>],
+    kinds: &[ItemKind],
+) -> Vec<&'i [&'a Syntax<'a>]> {
+    items
+        .iter()
+        .zip(kinds)
+        .filter(|(_, kind)| **kind == ItemKind::Import)
+        .map(|(item, _)| &item[..])
+        .collect()
+}
+
+/// Are the imports in `lhs` and `rhs` the same, but in a different
+/// order?
+fn imports_reordered<'a>(lhs: &[&[&'a Syntax<'a>]], rhs: &[&[&'a Syntax<'a>]]) -> bool {
+    if lhs.len() != rhs.len() || lhs.iter().zip(rhs).all(|(x, y)| same_item(x, y)) {
+        return false;
+    }
+
+    let mut unmatched: Vec<_> = rhs.to_vec();
+    for x in lhs {
+        match unmatched.iter().position(|y| same_item(x, y)) {
+            Some(i) => {
+                unmatched.swap_remove(i);
+            }
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Summarise the changes between `lhs` and `rhs`, after they have been
+/// diffed.
+pub fn summarize<'a>(lhs: &[&'a Syntax<'a>], rhs: &[&'a Syntax<'a>]) -> Summary {
+    let mut summary = Summary::default();
+
+    let lhs_items = top_level_items(lhs);
+    let rhs_items = top_level_items(rhs);
+    let lhs_kinds: Vec<_> = lhs_items.iter().map(|item| item_kind(item)).collect();
+    let rhs_kinds: Vec<_> = rhs_items.iter().map(|item| item_kind(item)).collect();
+
+    // If only the order of the imports has changed, don't count
+    // them separately.
+    summary.imports_reordered = imports_reordered(
+        &imports(&lhs_items, &lhs_kinds),
+        &imports(&rhs_items, &rhs_kinds),
+    );
+    let imports_reordered = summary.imports_reordered;
+    let is_counted = |kind: ItemKind| !(imports_reordered && kind == ItemKind::Import);
+
+    let mut rhs_changed = vec![false; rhs_items.len()];
+    for (i, item) in rhs_items.iter().enumerate() {
+        let kind = rhs_kinds[i];
+        if !is_counted(kind) {
+            continue;
+        }
+        match classify(item) {
+            ItemChange::Novel => summary.counts_mut(kind).added += 1,
+            ItemChange::Moved => summary.counts_mut(kind).moved += 1,
+            ItemChange::Changed => rhs_changed[i] = true,
+            ItemChange::Unchanged => {}
+        }
+    }
+
+    for (item, kind) in lhs_items.iter().zip(&lhs_kinds) {
+        if !is_counted(*kind) {
+            continue;
+        }
+        match classify(item) {
+            ItemChange::Novel => summary.counts_mut(*kind).removed += 1,
+            ItemChange::Changed => {
+                // Items that only lost nodes are unchanged on the
+                // RHS, so find the corresponding RHS item.
+                let rhs_item = item
+                    .iter()
+                    .find_map(|node| opposite_line(node))
+                    .and_then(|line| {
+                        rhs_items.iter().position(|rhs_item| {
+                            match (
+                                rhs_item[0].first_line(),
+                                rhs_item[rhs_item.len() - 1].last_line(),
+                            ) {
+                                (Some(first), Some(last)) => first <= line && line <= last,
+                                _ => false,
+                            }
+                        })
+                    });
+                match rhs_item {
+                    Some(i) if is_counted(rhs_kinds[i]) => rhs_changed[i] = true,
+                    _ => summary.counts_mut(*kind).changed += 1,
+                }
+            }
+            // Moved items are counted on the RHS.
+            ItemChange::Moved | ItemChange::Unchanged => {}
+        }
+    }
+    for (i, changed) in rhs_changed.iter().enumerate() {
+        if *changed {
+            summary.counts_mut(rhs_kinds[i]).changed += 1;
+        }
+    }
+
+    summary
+}
+
+/// Atoms that start a named definition, such as `fn` in `fn foo() {}`
+/// or `defun` in `(defun foo () ...)`.
+const DEFINITION_KEYWORDS: &[&str] = &[
+    "class",
+    "def",
+    "defmacro",
+    "defn",
+    "defun",
+    "define",
+    "enum",
+    "fn",
+    "func",
+    "function",
+    "impl",
+    "interface",
+    "module",
+    "struct",
+    "sub",
+    "trait",
+];
+
+/// A named definition, such as a function, and the lines it occupies.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Definition {
+    pub keyword: String,
+    pub name: String,
+    pub first_line: LineNumber,
+    pub last_line: LineNumber,
+    /// Is the definition keyword novel, so the whole definition has
+    /// been added or removed?
+    pub is_novel: bool,
+    /// The line of the definition keyword in the other file, if it's
+    /// unchanged.
+    pub opposite_line: Option<LineNumber>,
+}
+
+fn is_name(content: &str) -> bool {
+    match content.chars().next() {
+        Some(c) if c.is_alphabetic() || c == '_' => content
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_-!?*".contains(c)),
+        _ => false,
+    }
+}
+
+/// If `nodes` starts with a definition keyword followed by a name,
+/// return them.
+fn definition_start<'a>(nodes: &[&'a Syntax<'a>]) -> Option<(&'a Syntax<'a>, String, String)> {
+    match nodes {
+        [keyword @ Atom {
+            content: keyword_content,
+            is_comment: false,
+            ..
+        }, Atom {
+            content: name,
+            is_comment: false,
+            ..
+        }, ..]
+            // Keywords include their indentation in languages where
+            // it's significant.
+            if DEFINITION_KEYWORDS.contains(&keyword_content.trim_start()) && is_name(name) =>
+        {
+            Some((keyword, keyword_content.trim_start().to_string(), name.to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn new_definition(
+    keyword_node: &Syntax,
+    keyword: String,
+    name: String,
+    last_node: &Syntax,
+) -> Option<Definition> {
+    Some(Definition {
+        keyword,
+        name,
+        first_line: keyword_node.first_line()?,
+        last_line: last_node.last_line()?,
+        is_novel: keyword_node.info().change.get() == Some(Novel),
+        opposite_line: match keyword_node.info().change.get() {
+            Some(Unchanged(opposite)) => opposite.first_line(),
+            _ => None,
+        },
+    })
+}
+
+fn find_definitions<'a>(nodes: &[&'a Syntax<'a>], defs: &mut Vec<Definition>) {
+    for (i, node) in nodes.iter().enumerate() {
+        // A definition without enclosing delimiters, like `fn foo() {
+        // ... }`, runs until its body, a semicolon, or the next
+        // definition.
+        if let Some((keyword_node, keyword, name)) = definition_start(&nodes[i..]) {
+            let mut last_node = nodes[i + 1];
+            for (j, next) in nodes.iter().enumerate().skip(i + 2) {
+                if definition_start(&nodes[j..]).is_some() {
+                    break;
+                }
+                last_node = next;
+                match next {
+                    List { open_content, .. } if open_content == "{" => break,
+                    Atom { content, .. } if content == ";" => break,
+                    _ => {}
+                }
+            }
+            defs.extend(new_definition(keyword_node, keyword, name, last_node));
+        }
+
+        if let List {
+            open_content,
+            children,
+            ..
+        } = node
+        {
+            // A definition that is a whole list, like `(defun foo ()
+            // ...)`.
+            match definition_start(children) {
+                Some((keyword_node, keyword, name)) if open_content == "(" => {
+                    defs.extend(new_definition(keyword_node, keyword, name, node));
+                    find_definitions(&children[2..], defs);
+                }
+                _ => find_definitions(children, defs),
+            }
+        }
+    }
+}
+
+/// All the named definitions in `nodes`, including nested ones. This
+/// is a heuristic based on keywords, so it doesn't need any
+/// language-specific parsing.
+pub fn definitions<'a>(nodes: &[&'a Syntax<'a>]) -> Vec<Definition> {
+    let mut defs = vec![];
+    find_definitions(nodes, &mut defs);
+    defs
+}
+
+/// The lines of each definition in `defs` that isn't nested inside
+/// another definition.
+pub fn top_level_scopes(defs: &[Definition]) -> Vec<(LineNumber, LineNumber)> {
+    defs.iter()
+        .filter(|def| {
+            !defs.iter().any(|other| {
+                other != *def
+                    && other.first_line <= def.first_line
+                    && def.last_line <= other.last_line
+            })
+        })
+        .map(|def| (def.first_line, def.last_line))
+        .collect()
+}
+
+/// The smallest definition in `defs` that includes `line`.
+fn innermost_definition(defs: &[Definition], line: LineNumber) -> Option<&Definition> {
+    defs.iter()
+        .filter(|def| def.first_line <= line && line <= def.last_line)
+        .min_by_key(|def| def.last_line.0 - def.first_line.0)
+}
+
+/// A definition with changes in a group, and how many of the changed
+/// lines on each side belong to it.
+struct ChangedDefinition<'d> {
+    def: &'d Definition,
+    novel_verb: &'static str,
+    lhs_lines: usize,
+    rhs_lines: usize,
+}
+
+/// Describe the definitions changed in `group`, such as `modified fn
+/// shortest_path (12 lines changed)`. Each changed line is counted
+/// towards the innermost definition that contains it, and every
+/// definition with changes is listed. Returns `None` if none of the
+/// changes are inside a definition.
+pub fn group_heading(
+    group: &LineGroup,
+    lhs_positions: &[MatchedPos],
+    rhs_positions: &[MatchedPos],
+    lhs_defs: &[Definition],
+    rhs_defs: &[Definition],
+) -> Option<String> {
+    let (lhs_lines, rhs_lines) = group.changed_lines(lhs_positions, rhs_positions);
+
+    let mut changed: Vec<ChangedDefinition> = vec![];
+    for line in rhs_lines {
+        if let Some(def) = innermost_definition(rhs_defs, line) {
+            match changed.iter_mut().find(|c| std::ptr::eq(c.def, def)) {
+                Some(c) => c.rhs_lines += 1,
+                None => changed.push(ChangedDefinition {
+                    def,
+                    novel_verb: "added",
+                    lhs_lines: 0,
+                    rhs_lines: 1,
+                }),
+            }
+        }
+    }
+    // Changes on the LHS count towards the same definition on the RHS,
+    // if there is one, so definitions that only lost lines are named
+    // after their new version. Definitions that only exist on the LHS
+    // are listed after the others.
+    for line in lhs_lines {
+        if let Some(lhs_def) = innermost_definition(lhs_defs, line) {
+            let def = lhs_def
+                .opposite_line
+                .and_then(|opposite_line| {
+                    rhs_defs.iter().find(|def| {
+                        def.first_line == opposite_line && def.keyword == lhs_def.keyword
+                    })
+                })
+                .unwrap_or(lhs_def);
+            match changed.iter_mut().find(|c| std::ptr::eq(c.def, def)) {
+                Some(c) => c.lhs_lines += 1,
+                None => changed.push(ChangedDefinition {
+                    def,
+                    novel_verb: "removed",
+                    lhs_lines: 1,
+                    rhs_lines: 0,
+                }),
+            }
+        }
+    }
+
+    if changed.is_empty() {
+        return None;
+    }
+    let descriptions: Vec<_> = changed
+        .iter()
+        .map(|c| {
+            let verb = if c.def.is_novel {
+                c.novel_verb
+            } else {
+                "modified"
+            };
+            let num_lines = max(c.lhs_lines, c.rhs_lines);
+            format!(
+                "{} {} {} ({} line{} changed)",
+                verb,
+                c.def.keyword,
+                c.def.name,
+                num_lines,
+                if num_lines == 1 { "" } else { "s" }
+            )
+        })
+        .collect();
+    Some(descriptions.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "lang-emacs-lisp", feature = "lang-rust"))]
+    use crate::dijkstra::{mark_syntax, SearchConfig};
+    #[cfg(any(feature = "lang-emacs-lisp", feature = "lang-rust"))]
+    use crate::lines::visible_groups;
+    #[cfg(feature = "lang-rust")]
+    use crate::lines::{join_overlapping, MaxLine, CONTEXT_LINES};
+    #[cfg(any(feature = "lang-emacs-lisp", feature = "lang-rust"))]
+    use crate::parse::{find_lang, parse, ConfigDir};
+    #[cfg(feature = "lang-emacs-lisp")]
+    use crate::syntax::mark_moved;
+    #[cfg(any(feature = "lang-emacs-lisp", feature = "lang-rust"))]
+    use crate::syntax::{change_positions, init_info};
+    #[cfg(any(feature = "lang-emacs-lisp", feature = "lang-rust"))]
+    use typed_arena::Arena;
+
+    #[test]
+    #[cfg(feature = "lang-emacs-lisp")]
+    fn test_summarize() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "el").unwrap();
+        let arena = Arena::new();
+        let lhs = parse(&arena, "(a 1)\n(b 2 3)\n(c 3)\n(d 4)\n(f 7 8)\n", lang);
+        let rhs = parse(&arena, "(b 2 3)\n(a 1)\n(c 5)\n(f 7)\n[e 6]\n", lang);
+        init_info(&lhs);
+        init_info(&rhs);
+        mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &SearchConfig::default(),
+        );
+        mark_moved(&lhs, &rhs);
+
+        let summary = summarize(&lhs, &rhs);
+        assert_eq!(
+            summary.counts[&ItemKind::Item],
+            KindCounts {
+                changed: 2,
+                added: 1,
+                removed: 1,
+                moved: 1,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "2 items changed, 1 added, 1 removed, 1 moved"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_definitions() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "rs").unwrap();
+        let arena = Arena::new();
+        let nodes = parse(
+            &arena,
+            "impl Foo {\n    fn bar() {\n        1\n    }\n}\n",
+            lang,
+        );
+
+        let defs = definitions(&nodes);
+        let summary: Vec<_> = defs
+            .iter()
+            .map(|def| (def.name.as_str(), def.first_line.0, def.last_line.0))
+            .collect();
+        assert_eq!(summary, vec![("Foo", 0, 4), ("bar", 1, 3)]);
+        assert_eq!(innermost_definition(&defs, 2.into()).unwrap().name, "bar");
+        assert_eq!(innermost_definition(&defs, 4.into()).unwrap().name, "Foo");
+        assert_eq!(top_level_scopes(&defs), vec![(0.into(), 4.into())]);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-emacs-lisp")]
+    fn test_group_heading() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "el").unwrap();
+        let arena = Arena::new();
+        let lhs_src = "(defun foo (x)\n  (+ x 1))\n";
+        let rhs_src = "(defun foo (x)\n  (+ x 2))\n";
+        let lhs = parse(&arena, lhs_src, lang);
+        let rhs = parse(&arena, rhs_src, lang);
+        init_info(&lhs);
+        init_info(&rhs);
+        mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &SearchConfig::default(),
+        );
+
+        let lhs_positions = change_positions(lhs_src, rhs_src, &lhs);
+        let rhs_positions = change_positions(rhs_src, lhs_src, &rhs);
+        let groups = visible_groups(&lhs_positions, &rhs_positions);
+        assert_eq!(
+            group_heading(
+                &groups[0],
+                &lhs_positions,
+                &rhs_positions,
+                &definitions(&lhs),
+                &definitions(&rhs)
+            ),
+            Some("modified defun foo (1 line changed)".to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_group_heading_several_definitions() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "rs").unwrap();
+        let arena = Arena::new();
+        let lhs_src = "fn foo() {\n    1\n}\nfn baz() {\n    2\n}\n";
+        let rhs_src = "fn foo() {\n    3\n}\nfn qux() {\n    2\n}\n";
+        let lhs = parse(&arena, lhs_src, lang);
+        let rhs = parse(&arena, rhs_src, lang);
+        init_info(&lhs);
+        init_info(&rhs);
+        mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &SearchConfig::default(),
+        );
+
+        let lhs_positions = change_positions(lhs_src, rhs_src, &lhs);
+        let rhs_positions = change_positions(rhs_src, lhs_src, &rhs);
+        // The changes are close enough to be shown as one hunk.
+        let mut groups = visible_groups(&lhs_positions, &rhs_positions);
+        for group in &mut groups {
+            group.pad(CONTEXT_LINES, lhs_src.max_line(), rhs_src.max_line());
+        }
+        let groups = join_overlapping(groups);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            group_heading(
+                &groups[0],
+                &lhs_positions,
+                &rhs_positions,
+                &definitions(&lhs),
+                &definitions(&rhs)
+            ),
+            Some("modified fn foo (1 line changed), modified fn qux (1 line changed)".to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_summarize_kinds() {
+        let lang = find_lang(ConfigDir::read_default_toml(), "rs").unwrap();
+        let arena = Arena::new();
+        let lhs = parse(
+            &arena,
+            "use a;\nuse b;\n\nfn foo() {\n    1\n}\n\npub fn bar() {}\n\nstruct S;\n",
+            lang,
+        );
+        let rhs = parse(
+            &arena,
+            "use b;\nuse a;\n\nfn foo() {\n    2\n}\n\npub fn bar() {}\n\nfn baz() {}\n",
+            lang,
+        );
+        init_info(&lhs);
+        init_info(&rhs);
+        mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &SearchConfig::default(),
+        );
+
+        assert_eq!(
+            summarize(&lhs, &rhs).to_string(),
+            "1 function changed, 1 added, 1 struct removed, imports reordered"
+        );
+    }
+
+    #[test]
+    fn test_summary_display() {
+        assert_eq!(Summary::default().to_string(), "no changes");
+        let mut summary = Summary::default();
+        summary.counts_mut(ItemKind::Item).added = 1;
+        assert_eq!(summary.to_string(), "1 item added");
+        summary.counts_mut(ItemKind::Class).changed = 2;
+        summary.counts_mut(ItemKind::Import).removed = 1;
+        assert_eq!(
+            summary.to_string(),
+            "2 classes changed, 1 item added, 1 import removed"
+        );
+    }
+}
@@ -3,6 +3,7 @@
 use itertools::{EitherOrBoth, Itertools};
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::borrow::Cow;
 use std::cell::Cell;
 use std::cmp::{max, min};
 use std::collections::hash_map::DefaultHasher;
@@ -12,7 +13,7 @@ use std::hash::{Hash, Hasher};
 use typed_arena::Arena;
 
 use crate::lines::{LineNumber, NewlinePositions};
-use crate::positions::SingleLineSpan;
+use crate::positions::{SingleLineSpan, Spans};
 use ChangeKind::*;
 use Syntax::*;
 
@@ -20,6 +21,9 @@ use Syntax::*;
 pub enum ChangeKind<'a> {
     Unchanged(&'a Syntax<'a>),
     ReplacedComment(&'a Syntax<'a>, &'a Syntax<'a>),
+    /// The node is unchanged, but it has been reordered relative to
+    /// its siblings.
+    Moved(&'a Syntax<'a>),
     Novel,
 }
 
@@ -31,6 +35,7 @@ impl<'a> fmt::Debug for ChangeKind<'a> {
         let desc = match self {
             Unchanged(_) => "Unchanged",
             ReplacedComment(_, _) => "ReplacedComment",
+            Moved(_) => "Moved",
             Novel => "Novel",
         };
         f.write_str(desc)
@@ -59,21 +64,38 @@ impl<'a> SyntaxInfo<'a> {
     }
 }
 
+/// What a token is, as far as the lexer can tell. This is only used
+/// to highlight unchanged code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// An open or close delimiter of a list.
+    Delimiter,
+    Comment,
+    String,
+    Number,
+    Identifier,
+    /// Operators, punctuation and anything else.
+    Other,
+}
+
 pub enum Syntax<'a> {
     List {
         info: SyntaxInfo<'a>,
-        open_position: Vec<SingleLineSpan>,
-        open_content: String,
+        open_position: Spans,
+        open_content: Cow<'a, str>,
         children: Vec<&'a Syntax<'a>>,
-        close_position: Vec<SingleLineSpan>,
-        close_content: String,
+        close_position: Spans,
+        close_content: Cow<'a, str>,
         num_descendants: u64,
     },
     Atom {
         info: SyntaxInfo<'a>,
-        position: Vec<SingleLineSpan>,
-        content: String,
+        position: Spans,
+        /// Usually a slice of the input, unless it's a comment whose
+        /// indentation has been removed.
+        content: Cow<'a, str>,
         is_comment: bool,
+        token_kind: TokenKind,
     },
 }
 
@@ -161,12 +183,14 @@ impl<'a> Syntax<'a> {
     #[allow(clippy::mut_from_ref)] // Clippy doesn't understand arenas.
     pub fn new_list(
         arena: &'a Arena<Syntax<'a>>,
-        open_content: &str,
-        open_position: Vec<SingleLineSpan>,
+        open_content: impl Into<Cow<'a, str>>,
+        open_position: Spans,
         children: Vec<&'a Syntax<'a>>,
-        close_content: &str,
-        close_position: Vec<SingleLineSpan>,
+        close_content: impl Into<Cow<'a, str>>,
+        close_position: Spans,
     ) -> &'a mut Syntax<'a> {
+        let open_content = open_content.into();
+        let close_content = close_content.into();
         let mut num_descendants = 0;
         for child in &children {
             num_descendants += match child {
@@ -190,8 +214,8 @@ impl<'a> Syntax<'a> {
         arena.alloc(List {
             info: SyntaxInfo::new(hasher.finish()),
             open_position,
-            open_content: open_content.into(),
-            close_content: close_content.into(),
+            open_content,
+            close_content,
             close_position,
             children,
             num_descendants,
@@ -201,17 +225,29 @@ impl<'a> Syntax<'a> {
     #[allow(clippy::mut_from_ref)] // Clippy doesn't understand arenas.
     pub fn new_atom(
         arena: &'a Arena<Syntax<'a>>,
-        position: Vec<SingleLineSpan>,
-        content: &str,
+        position: Spans,
+        content: impl Into<Cow<'a, str>>,
     ) -> &'a mut Syntax<'a> {
-        Self::new_atom_(arena, position, content, false)
+        Self::new_atom_(arena, position, content.into(), TokenKind::Other)
+    }
+
+    /// An atom that the lexer knows to be a string, number or
+    /// identifier.
+    #[allow(clippy::mut_from_ref)] // Clippy doesn't understand arenas.
+    pub fn new_atom_of_kind(
+        arena: &'a Arena<Syntax<'a>>,
+        position: Spans,
+        content: impl Into<Cow<'a, str>>,
+        token_kind: TokenKind,
+    ) -> &'a mut Syntax<'a> {
+        Self::new_atom_(arena, position, content.into(), token_kind)
     }
 
     #[allow(clippy::mut_from_ref)] // Clippy doesn't understand arenas.
     pub fn new_comment(
         arena: &'a Arena<Syntax<'a>>,
-        position: Vec<SingleLineSpan>,
-        content: &str,
+        position: Spans,
+        content: &'a str,
     ) -> &'a mut Syntax<'a> {
         // Ignore leading whitespace in multiline comments, so changes
         // in comment indentation are ignored.
@@ -221,7 +257,7 @@ impl<'a> Syntax<'a> {
         };
 
         let mut new_lines: Vec<String> = vec![];
-        let mut new_position = vec![];
+        let mut new_position = Spans::new();
         for (i, (line, span)) in content.lines().zip(position).enumerate() {
             if i == 0 {
                 new_lines.push(line.to_string());
@@ -233,15 +269,21 @@ impl<'a> Syntax<'a> {
             }
         }
 
-        Self::new_atom_(arena, new_position, &new_lines.join("\n"), true)
+        let new_content = new_lines.join("\n");
+        let content = if new_content == content {
+            Cow::Borrowed(content)
+        } else {
+            Cow::Owned(new_content)
+        };
+        Self::new_atom_(arena, new_position, content, TokenKind::Comment)
     }
 
     #[allow(clippy::mut_from_ref)] // Clippy doesn't understand arenas.
     fn new_atom_(
         arena: &'a Arena<Syntax<'a>>,
-        position: Vec<SingleLineSpan>,
-        content: &str,
-        is_comment: bool,
+        position: Spans,
+        content: Cow<'a, str>,
+        token_kind: TokenKind,
     ) -> &'a mut Syntax<'a> {
         let mut hasher = DefaultHasher::new();
 
@@ -251,8 +293,9 @@ impl<'a> Syntax<'a> {
         arena.alloc(Atom {
             info: SyntaxInfo::new(hasher.finish()),
             position,
-            content: content.into(),
-            is_comment,
+            content,
+            is_comment: token_kind == TokenKind::Comment,
+            token_kind,
         })
     }
 
@@ -297,19 +340,65 @@ impl<'a> Syntax<'a> {
         if let List { children, .. } = self {
             // For unchanged lists, match up children with the
             // unchanged children on the other side.
-            if let Unchanged(List {
-                children: other_children,
-                ..
-            }) = ck
-            {
-                for (child, other_child) in children.iter().zip(other_children) {
-                    child.set_change_deep(Unchanged(other_child));
+            match ck {
+                Unchanged(List {
+                    children: other_children,
+                    ..
+                }) => {
+                    for (child, other_child) in children.iter().zip(other_children) {
+                        child.set_change_deep(Unchanged(other_child));
+                    }
                 }
-            } else {
-                for child in children {
-                    child.set_change_deep(ck);
+                Moved(List {
+                    children: other_children,
+                    ..
+                }) => {
+                    for (child, other_child) in children.iter().zip(other_children) {
+                        child.set_change_deep(Moved(other_child));
+                    }
                 }
-            };
+                _ => {
+                    for child in children {
+                        child.set_change_deep(ck);
+                    }
+                }
+            }
+        }
+    }
+
+    /// A cheap summary of this node's content. Nodes with equal
+    /// content always have the same key.
+    fn shallow_key(&self) -> (&str, &str, u64) {
+        match self {
+            List {
+                open_content,
+                close_content,
+                num_descendants,
+                ..
+            } => (open_content, close_content, *num_descendants),
+            Atom { content, .. } => (content, "", 0),
+        }
+    }
+
+    /// Is this node, and all of its descendants, unchanged?
+    fn is_unchanged_deep(&self) -> bool {
+        if !matches!(self.info().change.get(), Some(Unchanged(_))) {
+            return false;
+        }
+        match self {
+            List { children, .. } => children.iter().all(|child| child.is_unchanged_deep()),
+            Atom { .. } => true,
+        }
+    }
+
+    /// Is this node, and all of its descendants, novel?
+    fn is_novel_deep(&self) -> bool {
+        if !matches!(self.info().change.get(), Some(Novel)) {
+            return false;
+        }
+        match self {
+            List { children, .. } => children.iter().all(|child| child.is_novel_deep()),
+            Atom { .. } => true,
         }
     }
 
@@ -411,6 +500,219 @@ impl<'a> Syntax<'a> {
     }
 }
 
+/// Is there a blank line between `before` and `after`? The start and
+/// end of a list count as blank lines.
+fn blank_between(before: Option<&Syntax>, after: Option<&Syntax>) -> bool {
+    match (
+        before.and_then(|n| n.last_line()),
+        after.and_then(|n| n.first_line()),
+    ) {
+        (Some(before_line), Some(after_line)) => after_line.0 > before_line.0 + 1,
+        _ => true,
+    }
+}
+
+/// Change the novel siblings `nodes[start..end]` to
+/// `nodes[start - 1..end - 1]`, assuming `nodes[start - 1]` is
+/// unchanged and has the same content as `nodes[end - 1]`.
+fn slide_up<'a>(nodes: &[&'a Syntax<'a>], start: usize, end: usize) {
+    let unchanged = nodes[start - 1];
+    let novel = nodes[end - 1];
+    if let Some(Unchanged(opposite)) = unchanged.info().change.get() {
+        novel.set_change_deep(Unchanged(opposite));
+        opposite.set_change_deep(Unchanged(novel));
+        unchanged.set_change_deep(Novel);
+    }
+}
+
+/// Change the novel siblings `nodes[start..end]` to
+/// `nodes[start + 1..end + 1]`, assuming `nodes[end]` is unchanged and
+/// has the same content as `nodes[start]`.
+fn slide_down<'a>(nodes: &[&'a Syntax<'a>], start: usize, end: usize) {
+    let unchanged = nodes[end];
+    let novel = nodes[start];
+    if let Some(Unchanged(opposite)) = unchanged.info().change.get() {
+        novel.set_change_deep(Unchanged(opposite));
+        opposite.set_change_deep(Unchanged(novel));
+        unchanged.set_change_deep(Novel);
+    }
+}
+
+/// When a run of novel siblings could equally be shown earlier or
+/// later, because the nodes at either end have the same content, move
+/// it so it starts after a blank line and ends before one.
+///
+/// The graph search doesn't consider blank lines, so an inserted block
+/// can otherwise start halfway through a previous, identical block.
+pub fn slide_novel_runs<'a>(nodes: &[&'a Syntax<'a>]) {
+    let mut i = 0;
+    while i < nodes.len() {
+        if !nodes[i].is_novel_deep() {
+            if let List { children, .. } = nodes[i] {
+                slide_novel_runs(children);
+            }
+            i += 1;
+            continue;
+        }
+
+        let mut end = i;
+        while end < nodes.len() && nodes[end].is_novel_deep() {
+            end += 1;
+        }
+
+        // How far can the run move up or down?
+        let mut up = 0;
+        while i > up
+            && nodes[i - up - 1].is_unchanged_deep()
+            && nodes[i - up - 1].equal_content(nodes[end - up - 1])
+        {
+            up += 1;
+        }
+        let mut down = 0;
+        while end + down < nodes.len()
+            && nodes[end + down].is_unchanged_deep()
+            && nodes[end + down].equal_content(nodes[i + down])
+        {
+            down += 1;
+        }
+
+        let score = |start: usize, end: usize| {
+            let starts_blank =
+                blank_between(start.checked_sub(1).map(|i| nodes[i]), Some(nodes[start]));
+            let ends_blank = blank_between(Some(nodes[end - 1]), nodes.get(end).copied());
+            2 * starts_blank as usize + ends_blank as usize
+        };
+        // Prefer the current position, then the lowest position.
+        let mut best_offset: isize = 0;
+        let mut best_score = score(i, end);
+        for offset in (-(up as isize)..=down as isize).rev() {
+            let offset_score = score(
+                (i as isize + offset) as usize,
+                (end as isize + offset) as usize,
+            );
+            if offset_score > best_score {
+                best_offset = offset;
+                best_score = offset_score;
+            }
+        }
+
+        let (mut start, mut run_end) = (i, end);
+        while best_offset < 0 {
+            slide_up(nodes, start, run_end);
+            start -= 1;
+            run_end -= 1;
+            best_offset += 1;
+        }
+        while best_offset > 0 {
+            slide_down(nodes, start, run_end);
+            start += 1;
+            run_end += 1;
+            best_offset -= 1;
+        }
+
+        i = max(end, run_end);
+    }
+}
+
+/// Find nodes that were only reordered among their siblings, and
+/// mark them as moved rather than novel on both sides.
+///
+/// This runs after the graph search, which only matches nodes in
+/// order. If `[a, b]` becomes `[b, a]`, the search leaves one of the
+/// nodes novel on each side, so we pair up siblings with equal
+/// content here.
+///
+/// Lists are paired whenever their content is equal. Atoms are only
+/// paired if every novel sibling atom has a partner, so a common token
+/// such as `;` isn't shown as moved when it's part of an unrelated
+/// change.
+pub fn mark_moved<'a>(lhs_nodes: &[&'a Syntax<'a>], rhs_nodes: &[&'a Syntax<'a>]) {
+    // Group novel RHS nodes by their delimiters and size, so we only
+    // compare content of plausible candidates.
+    let mut rhs_novel: HashMap<_, Vec<&'a Syntax<'a>>> = HashMap::new();
+    for rhs_node in rhs_nodes {
+        if rhs_node.is_novel_deep() {
+            rhs_novel
+                .entry(rhs_node.shallow_key())
+                .or_default()
+                .push(rhs_node);
+        }
+    }
+
+    let mut pairs = vec![];
+    let mut unpaired_atoms = false;
+    for lhs_node in lhs_nodes {
+        if !lhs_node.is_novel_deep() {
+            continue;
+        }
+        let candidates = rhs_novel.get_mut(&lhs_node.shallow_key());
+        match candidates.and_then(|candidates| {
+            let i = candidates
+                .iter()
+                .position(|rhs_node| lhs_node.equal_content(rhs_node))?;
+            Some(candidates.remove(i))
+        }) {
+            Some(rhs_node) => pairs.push((lhs_node, rhs_node)),
+            None => unpaired_atoms |= matches!(lhs_node, Atom { .. }),
+        }
+    }
+    unpaired_atoms |= rhs_novel
+        .values()
+        .flatten()
+        .any(|rhs_node| matches!(rhs_node, Atom { .. }));
+
+    for (lhs_node, rhs_node) in pairs {
+        if matches!(lhs_node, Atom { .. }) && unpaired_atoms {
+            continue;
+        }
+        lhs_node.set_change_deep(Moved(rhs_node));
+        rhs_node.set_change_deep(Moved(lhs_node));
+    }
+
+    // Children of lists whose delimiters are unchanged may also have
+    // been reordered.
+    for lhs_node in lhs_nodes {
+        if let (
+            List { children, .. },
+            Some(Unchanged(List {
+                children: rhs_children,
+                ..
+            })),
+        ) = (lhs_node, lhs_node.info().change.get())
+        {
+            mark_moved(children, rhs_children);
+        }
+    }
+}
+
+/// Do `lhs` and `rhs` differ at all? This stops at the first
+/// difference, so it's much cheaper than diffing them.
+pub fn has_changes<'a>(lhs: &[&Syntax<'a>], rhs: &[&Syntax<'a>]) -> bool {
+    lhs.len() != rhs.len()
+        || lhs
+            .iter()
+            .zip(rhs)
+            .any(|(lhs_node, rhs_node)| !lhs_node.equal_content(rhs_node))
+}
+
+/// The nodes in `nodes` that have at least one line between `start`
+/// and `end` (inclusive).
+pub fn nodes_in_lines<'a>(
+    nodes: &[&'a Syntax<'a>],
+    start: LineNumber,
+    end: LineNumber,
+) -> Vec<&'a Syntax<'a>> {
+    nodes
+        .iter()
+        .copied()
+        .filter(|node| match (node.first_line(), node.last_line()) {
+            (Some(first), Some(last)) => first <= end && last >= start,
+            _ => false,
+        })
+        .collect()
+}
+
+#[cfg_attr(feature = "profiling", tracing::instrument(skip_all))]
 pub fn init_info<'a>(roots: &[&'a Syntax<'a>]) {
     set_unique_id(roots, 0);
     set_next(roots, None);
@@ -466,9 +768,10 @@ impl<'a> Hash for Syntax<'a> {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum MatchKind {
     Unchanged,
+    Moved,
     Novel,
     UnchangedCommentPart,
     ChangedCommentPart,
@@ -479,6 +782,7 @@ pub struct MatchedPos {
     pub kind: MatchKind,
     pub pos: Vec<SingleLineSpan>,
     pub prev_opposite_pos: Vec<SingleLineSpan>,
+    pub token_kind: TokenKind,
 }
 
 fn split_comment_words(
@@ -514,6 +818,7 @@ fn split_comment_words(
                         offset + word.len(),
                     ),
                     prev_opposite_pos: prev_opposite_pos.to_vec(),
+                    token_kind: TokenKind::Comment,
                 });
                 offset += word.len();
             }
@@ -527,6 +832,7 @@ fn split_comment_words(
                         offset + word.len(),
                     ),
                     prev_opposite_pos: prev_opposite_pos.to_vec(),
+                    token_kind: TokenKind::Comment,
                 });
                 offset += word.len();
             }
@@ -544,6 +850,7 @@ impl MatchedPos {
         ck: ChangeKind,
         pos: Vec<SingleLineSpan>,
         prev_opposite_pos: Vec<SingleLineSpan>,
+        token_kind: TokenKind,
     ) -> Vec<Self> {
         let kind = match ck {
             ReplacedComment(this, opposite) => {
@@ -564,6 +871,7 @@ impl MatchedPos {
                 );
             }
             Unchanged(_) => MatchKind::Unchanged,
+            Moved(_) => MatchKind::Moved,
             Novel => MatchKind::Novel,
         };
 
@@ -571,6 +879,7 @@ impl MatchedPos {
             kind,
             pos,
             prev_opposite_pos,
+            token_kind,
         }]
     }
 }
@@ -600,6 +909,7 @@ pub fn change_positions<'a>(
     positions
 }
 
+#[allow(clippy::only_used_in_recursion)]
 fn change_positions_<'a>(
     nl_pos: &NewlinePositions,
     opposite_nl_pos: &NewlinePositions,
@@ -627,7 +937,7 @@ fn change_positions_<'a>(
                             open_position: opposite_open_pos,
                             ..
                         } => {
-                            *prev_opposite_pos = opposite_open_pos.clone();
+                            *prev_opposite_pos = opposite_open_pos.to_vec();
                         }
                         Atom { .. } => unreachable!(),
                     }
@@ -635,8 +945,9 @@ fn change_positions_<'a>(
 
                 positions.extend(MatchedPos::new(
                     change,
-                    open_position.clone(),
+                    open_position.to_vec(),
                     prev_opposite_pos.clone(),
+                    TokenKind::Delimiter,
                 ));
 
                 change_positions_(
@@ -653,18 +964,24 @@ fn change_positions_<'a>(
                             close_position: opposite_close_pos,
                             ..
                         } => {
-                            *prev_opposite_pos = opposite_close_pos.clone();
+                            *prev_opposite_pos = opposite_close_pos.to_vec();
                         }
                         Atom { .. } => unreachable!(),
                     }
                 }
                 positions.extend(MatchedPos::new(
                     change,
-                    close_position.clone(),
+                    close_position.to_vec(),
                     prev_opposite_pos.clone(),
+                    TokenKind::Delimiter,
                 ));
             }
-            Atom { info, position, .. } => {
+            Atom {
+                info,
+                position,
+                token_kind,
+                ..
+            } => {
                 let change = info
                     .change
                     .get()
@@ -679,14 +996,15 @@ fn change_positions_<'a>(
                             position: opposite_position,
                             ..
                         } => {
-                            *prev_opposite_pos = opposite_position.clone();
+                            *prev_opposite_pos = opposite_position.to_vec();
                         }
                     }
                 }
                 positions.extend(MatchedPos::new(
                     change,
-                    position.clone(),
+                    position.to_vec(),
                     prev_opposite_pos.clone(),
+                    *token_kind,
                 ));
             }
         }
@@ -731,7 +1049,7 @@ fn zip_lines(lhs: &[SingleLineSpan], rhs: &[SingleLineSpan]) -> Vec<(LineNumber,
 
     lhs_lines
         .into_iter()
-        .zip_longest(rhs_lines.into_iter())
+        .zip_longest(rhs_lines)
         .map(|l| match l {
             EitherOrBoth::Both(lhs_line, rhs_line) => (lhs_line, rhs_line),
             EitherOrBoth::Left(lhs_line) => (lhs_line, rhs_last),
@@ -838,6 +1156,39 @@ pub fn matching_lines<'a>(nodes: &[&Syntax<'a>]) -> HashMap<LineNumber, LineNumb
     res
 }
 
+/// For each line in `nodes` where moved nodes start, find the lines
+/// those nodes occupied in the other file. Nested moved nodes are
+/// covered by their outermost moved ancestor, and nodes that stayed on
+/// the same lines are ignored.
+pub fn moved_from<'a>(nodes: &[&Syntax<'a>]) -> HashMap<LineNumber, (LineNumber, LineNumber)> {
+    let mut res = HashMap::new();
+    for node in nodes {
+        moved_from_(node, &mut res);
+    }
+    res
+}
+
+fn moved_from_<'a>(node: &Syntax<'a>, moves: &mut HashMap<LineNumber, (LineNumber, LineNumber)>) {
+    if let Some(Moved(opposite)) = node.info().change.get() {
+        if let (Some(line), Some(first), Some(last)) = (
+            node.first_line(),
+            opposite.first_line(),
+            opposite.last_line(),
+        ) {
+            if (line, node.last_line()) != (first, Some(last)) {
+                let range = moves.entry(line).or_insert((first, last));
+                *range = (min(range.0, first), max(range.1, last));
+            }
+        }
+        return;
+    }
+    if let List { children, .. } = node {
+        for child in children {
+            moved_from_(child, moves);
+        }
+    }
+}
+
 fn matching_lines_<'a>(node: &Syntax<'a>, matches: &mut HashMap<LineNumber, LineNumber>) {
     match node {
         List {
@@ -884,6 +1235,167 @@ fn matching_lines_<'a>(node: &Syntax<'a>, matches: &mut HashMap<LineNumber, Line
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
+    use smallvec::smallvec;
+
+    #[test]
+    fn test_nodes_in_lines() {
+        let arena = Arena::new();
+        let span = |line: usize| {
+            smallvec![SingleLineSpan {
+                line: line.into(),
+                start_col: 0,
+                end_col: 1,
+            }]
+        };
+        let nodes: Vec<&Syntax> = vec![
+            Syntax::new_atom(&arena, span(0), "a"),
+            Syntax::new_list(&arena, "(", span(1), vec![], ")", span(3)),
+            Syntax::new_atom(&arena, span(4), "b"),
+        ];
+
+        let in_lines = nodes_in_lines(&nodes, 2.into(), 2.into());
+        assert_eq!(in_lines.len(), 1);
+        assert!(in_lines[0].equal_content(nodes[1]));
+        assert_eq!(nodes_in_lines(&nodes, 3.into(), 4.into()).len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_mark_moved_reordered_siblings() {
+        use crate::dijkstra::{mark_syntax, SearchConfig};
+        use crate::parse::{find_lang, parse, ConfigDir};
+
+        let arena = Arena::new();
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let changes = |nodes: &[&Syntax]| -> Vec<String> {
+            match nodes[0] {
+                List { children, .. } => children
+                    .iter()
+                    .filter(|child| !matches!(child, Atom { content, .. } if content == ","))
+                    .map(|child| format!("{:?}", child.info().change.get().unwrap()))
+                    .collect(),
+                Atom { .. } => unreachable!(),
+            }
+        };
+        let mark = |lhs_src, rhs_src| {
+            let lhs = parse(&arena, lhs_src, lang);
+            let rhs = parse(&arena, rhs_src, lang);
+            init_info(&lhs);
+            init_info(&rhs);
+            mark_syntax(
+                lhs.first().copied(),
+                rhs.first().copied(),
+                &SearchConfig::default(),
+            );
+            mark_moved(&lhs, &rhs);
+            (changes(&lhs), changes(&rhs))
+        };
+
+        // Lists are moved even if other siblings changed.
+        let (lhs, rhs) = mark("[[1], [2, 2], 3]", "[[2, 2], [1], 4]");
+        assert_eq!(lhs, vec!["Moved", "Unchanged", "Novel"]);
+        assert_eq!(rhs, vec!["Unchanged", "Moved", "Novel"]);
+
+        // Atoms are only moved if the siblings were just reordered.
+        let (lhs, rhs) = mark("[1, 2, 3]", "[3, 1, 2]");
+        assert_eq!(lhs, vec!["Unchanged", "Unchanged", "Moved"]);
+        assert_eq!(rhs, vec!["Moved", "Unchanged", "Unchanged"]);
+    }
+
+    #[test]
+    fn test_slide_novel_runs() {
+        let arena = Arena::new();
+        let atom = |line: usize, content| {
+            let pos = smallvec![SingleLineSpan {
+                line: line.into(),
+                start_col: 0,
+                end_col: 1,
+            }];
+            Syntax::new_atom(&arena, pos, content)
+        };
+        let lhs: Vec<&Syntax> = vec![atom(0, "x"), atom(1, "y"), atom(3, "z")];
+        let rhs: Vec<&Syntax> = vec![
+            atom(0, "x"),
+            atom(1, "y"),
+            atom(3, "x"),
+            atom(4, "y"),
+            atom(6, "z"),
+        ];
+        init_info(&lhs);
+        init_info(&rhs);
+
+        // The insertion starts and ends in the middle of a block.
+        for (lhs_node, rhs_node) in [(lhs[0], rhs[0]), (lhs[1], rhs[3]), (lhs[2], rhs[4])] {
+            lhs_node.set_change(Unchanged(rhs_node));
+            rhs_node.set_change(Unchanged(lhs_node));
+        }
+        rhs[1].set_change(Novel);
+        rhs[2].set_change(Novel);
+
+        slide_novel_runs(&rhs);
+        let changes: Vec<_> = rhs
+            .iter()
+            .map(|node| format!("{:?}", node.info().change.get().unwrap()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec!["Unchanged", "Unchanged", "Novel", "Novel", "Unchanged"]
+        );
+        assert!(
+            matches!(lhs[1].info().change.get(), Some(Unchanged(n)) if n.first_line() == Some(1.into()))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_has_changes() {
+        use crate::parse::{find_lang, parse, ConfigDir};
+
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let arena = Arena::new();
+        let lhs = parse(&arena, "[1, 2]\n[3]\n", lang);
+        // Whitespace isn't a change.
+        assert!(!has_changes(&lhs, &parse(&arena, "[1,\n 2] [3]", lang)));
+        assert!(has_changes(&lhs, &parse(&arena, "[1, 2]\n[4]\n", lang)));
+        assert!(has_changes(&lhs, &parse(&arena, "[1, 2]\n", lang)));
+    }
+
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_moved_from() {
+        use crate::dijkstra::{mark_syntax, SearchConfig};
+        use crate::parse::{find_lang, parse, ConfigDir};
+
+        let lang = find_lang(ConfigDir::read_default_toml(), "json").unwrap();
+        let arena = Arena::new();
+        let lhs = parse(&arena, "[[1,\n 2]]\n[3]\n", lang);
+        let rhs = parse(&arena, "[3]\n[[1,\n 2]]\n", lang);
+        init_info(&lhs);
+        init_info(&rhs);
+        mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &SearchConfig::default(),
+        );
+        mark_moved(&lhs, &rhs);
+
+        // The larger list is unchanged, so the smaller one has moved.
+        let moves = moved_from(&rhs);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves.get(&0.into()), Some(&(2.into(), 2.into())));
+
+        let lhs = parse(&arena, "[1, 2, 3]", lang);
+        let rhs = parse(&arena, "[3, 1, 2]", lang);
+        init_info(&lhs);
+        init_info(&rhs);
+        mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &SearchConfig::default(),
+        );
+        mark_moved(&lhs, &rhs);
+        assert!(moved_from(&rhs).is_empty());
+    }
 
     #[test]
     fn test_aligned_middle() {
@@ -1020,12 +1532,12 @@ mod tests {
 
         let atom = Syntax::new_atom(
             &arena,
-            vec![SingleLineSpan {
+            smallvec![SingleLineSpan {
                 line: 0.into(),
                 start_col: 2,
                 end_col: 3,
             }],
-            "foo".into(),
+            "foo",
         );
         atom.set_change(ChangeKind::Novel);
         let nodes: Vec<&Syntax> = vec![atom];
@@ -1043,7 +1555,7 @@ mod tests {
 
     #[test]
     fn test_comment_and_atom_differ() {
-        let pos = vec![SingleLineSpan {
+        let pos = smallvec![SingleLineSpan {
             line: 0.into(),
             start_col: 2,
             end_col: 3,
@@ -1051,12 +1563,44 @@ mod tests {
 
         let arena = Arena::new();
 
-        let comment = Syntax::new_comment(&arena, pos.clone(), "foo".into());
-        let atom = Syntax::new_atom(&arena, pos, "foo".into());
+        let comment = Syntax::new_comment(&arena, pos.clone(), "foo");
+        let atom = Syntax::new_atom(&arena, pos, "foo");
 
         assert_ne!(comment, atom);
     }
 
+    #[test]
+    fn test_comment_content_borrowed_unless_reindented() {
+        let pos = |line: usize, start_col, end_col| SingleLineSpan {
+            line: line.into(),
+            start_col,
+            end_col,
+        };
+        let arena = Arena::new();
+
+        let comment = Syntax::new_comment(&arena, smallvec![pos(0, 0, 6)], "// foo");
+        assert!(matches!(
+            comment,
+            Atom {
+                content: Cow::Borrowed(_),
+                ..
+            }
+        ));
+
+        let comment = Syntax::new_comment(
+            &arena,
+            smallvec![pos(0, 4, 10), pos(1, 0, 10)],
+            "/* foo\n     bar */",
+        );
+        match comment {
+            Atom {
+                content: Cow::Owned(content),
+                ..
+            } => assert_eq!(content, "/* foo\nbar */"),
+            _ => panic!("Expected an owned comment"),
+        }
+    }
+
     #[test]
     fn test_atom_equality_ignores_change() {
         assert_eq!(
@@ -1066,26 +1610,28 @@ mod tests {
                     ..SyntaxInfo::new(1)
                 },
 
-                position: vec![SingleLineSpan {
+                position: smallvec![SingleLineSpan {
                     line: 1.into(),
                     start_col: 2,
                     end_col: 3
                 }],
                 content: "foo".into(),
                 is_comment: false,
+                token_kind: TokenKind::Other,
             },
             Atom {
                 info: SyntaxInfo {
                     change: Cell::new(None),
                     ..SyntaxInfo::new(1)
                 },
-                position: vec![SingleLineSpan {
+                position: smallvec![SingleLineSpan {
                     line: 1.into(),
                     start_col: 2,
                     end_col: 3
                 }],
                 content: "foo".into(),
                 is_comment: false,
+                token_kind: TokenKind::Other,
             }
         );
     }
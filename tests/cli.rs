@@ -0,0 +1,133 @@
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn invalid_comment_similarity_limit() {
+    let dir = std::env::temp_dir().join(format!("difftastic-cli-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let lhs = dir.join("lhs.txt");
+    let rhs = dir.join("rhs.txt");
+    fs::write(&lhs, "a\n").unwrap();
+    fs::write(&rhs, "b\n").unwrap();
+
+    for limit in &["abc", "-1"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_difftastic"))
+            .arg(format!("--comment-similarity-limit={}", limit))
+            .arg(&lhs)
+            .arg(&rhs)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            format!(
+                "Invalid --comment-similarity-limit {:?}, expected a number\n",
+                limit
+            )
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(feature = "lang-javascript")]
+fn threads_with_comments() {
+    let dir = std::env::temp_dir().join(format!("difftastic-threads-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let lhs = dir.join("lhs.js");
+    let rhs = dir.join("rhs.js");
+    let lhs_src: String = (0..60)
+        .map(|i| format!("// step {} of the old loop\nf({});\n", i, i))
+        .collect();
+    let rhs_src: String = (0..60)
+        .map(|i| format!("// step {} of the new loop\nf({});\n", i, i + i % 2))
+        .collect();
+    fs::write(&lhs, lhs_src).unwrap();
+    fs::write(&rhs, rhs_src).unwrap();
+
+    // The diff doesn't depend on the number of threads.
+    let diff = |threads: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_difftastic"))
+            .args(["--threads", threads])
+            .arg(&lhs)
+            .arg(&rhs)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let four = diff("4");
+    assert!(four.contains("new loop"));
+    assert_eq!(four, diff("2"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(feature = "lang-rust")]
+fn adjacent_definitions_are_separate_hunks() {
+    let dir = std::env::temp_dir().join(format!("difftastic-hunks-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let lhs = dir.join("lhs.rs");
+    let rhs = dir.join("rhs.rs");
+    fs::write(&lhs, "fn foo() {\n    1\n}\nfn baz() {\n    2\n}\n").unwrap();
+    fs::write(&rhs, "fn foo() {\n    3\n}\nfn qux() {\n    2\n}\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_difftastic"))
+        .args(["--output", "inline"])
+        .arg(&lhs)
+        .arg(&rhs)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let headings: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.contains("line changed"))
+        .collect();
+    assert_eq!(headings.len(), 2, "{}", stdout);
+    assert!(headings[0].contains("modified fn foo (1 line changed)"));
+    assert!(headings[1].contains("modified fn qux (1 line changed)"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(feature = "lang-javascript")]
+fn lines_estimates_graph_size_of_range() {
+    let dir = std::env::temp_dir().join(format!("difftastic-lines-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let lhs = dir.join("lhs.js");
+    let rhs = dir.join("rhs.js");
+    let src = |item: &dyn Fn(usize) -> String| -> String {
+        (0..20)
+            .map(|i| {
+                let items: Vec<_> = (0..300).map(item).collect();
+                format!("x{} = [{}];\n", i, items.join(", "))
+            })
+            .collect()
+    };
+    fs::write(&lhs, src(&|j| j.to_string())).unwrap();
+    fs::write(&rhs, src(&|j| format!("a{}", j))).unwrap();
+
+    let diff = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_difftastic"))
+            .args(args)
+            .arg(&lhs)
+            .arg(&rhs)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    // The whole file has too many changes for a syntactic diff, but a
+    // single line doesn't.
+    let too_many = "Too many changes for a syntactic diff";
+    assert!(diff(&[]).contains(too_many));
+    let line = diff(&["--lines", "2-2"]);
+    assert!(!line.contains(too_many), "{}", line);
+    assert!(line.contains("x1 = [a0, a1"), "{}", line);
+
+    fs::remove_dir_all(&dir).unwrap();
+}